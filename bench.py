@@ -0,0 +1,77 @@
+import requests
+import time
+from threading import Thread
+
+# the base URL of the database
+base_url = "http://localhost:8080/bench"
+
+# the number of parallel reader threads to use
+num_readers = 16
+
+# the number of GET requests each reader makes
+reads_per_reader = 500
+
+# the number of keys seeded before reading
+num_keys = 1000
+
+headers = {"Content-Type": "application/json"}
+
+test_data = {
+    "title": "Title",
+    "description": "Test Description",
+    "price": 100.00,
+    "quantity": 100,
+    "category": "test_category",
+    "image": "test_image",
+    "rating": 5.0,
+}
+
+running = True
+
+# seed the store with keys to read back
+def seed():
+    session = requests.Session()
+    for i in range(num_keys):
+        session.put(f"{base_url}/key_{i}", json=test_data, headers=headers)
+
+# a reader making GET requests over the seeded keys
+def reader():
+    session = requests.Session()
+    for i in range(reads_per_reader):
+        session.get(f"{base_url}/key_{i % num_keys}")
+
+# a writer updating keys while the readers run
+def writer():
+    session = requests.Session()
+    i = 0
+    while running:
+        session.patch(f"{base_url}/key_{i % num_keys}", json=test_data, headers=headers)
+        i += 1
+
+def run_read_benchmark():
+    global running
+
+    seed()
+
+    write_thread = Thread(target=writer)
+    write_thread.start()
+
+    start_time = time.time()
+
+    threads = [Thread(target=reader) for _ in range(num_readers)]
+    for thread in threads:
+        thread.start()
+    for thread in threads:
+        thread.join()
+
+    total_time = time.time() - start_time
+
+    running = False
+    write_thread.join()
+
+    total_reads = num_readers * reads_per_reader
+    print(f"Made {total_reads} reads in {total_time} seconds using {num_readers} threads ({total_reads / total_time:.0f} reads/s) with a concurrent writer.")
+
+
+if __name__ == "__main__":
+    run_read_benchmark()
@@ -0,0 +1,14 @@
+fn main() {
+    // Only the gRPC interface has generated code
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/distkv.proto");
+
+        let descriptors = protox::compile(["proto/distkv.proto"], ["proto"]).expect("Could not compile the proto files");
+
+        // The client is only used by the tests
+        tonic_build::configure()
+            .compile_fds(descriptors)
+            .expect("Could not generate the gRPC code");
+    }
+}
@@ -0,0 +1,104 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use time::macros::format_description;
+use time::OffsetDateTime;
+use tracing::warn;
+
+use crate::config::AccessLogFormat;
+
+// Access lines in the Apache Common or Combined Log Format, written next to the
+// tracing output so existing log pipelines can consume them
+pub struct AccessLog {
+    format: AccessLogFormat,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+pub struct RequestInfo {
+    remote: String,
+    time: OffsetDateTime,
+    request_line: String,
+    referer: String,
+    user_agent: String,
+}
+
+impl AccessLog {
+    pub fn open(format: AccessLogFormat, path: Option<&str>) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+            None => Box::new(io::stdout()),
+        };
+
+        Ok(AccessLog {
+            format,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    pub fn request_info(&self, req: &ServiceRequest) -> RequestInfo {
+        RequestInfo {
+            remote: req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_else(|| "-".to_string()),
+            time: OffsetDateTime::now_utc(),
+            request_line: format!("{} {} {:?}", req.method(), req.uri(), req.version()),
+            referer: header_value(req, header::REFERER),
+            user_agent: header_value(req, header::USER_AGENT),
+        }
+    }
+
+    pub fn record<B: MessageBody>(&self, request: &RequestInfo, res: &ServiceResponse<B>, elapsed: Duration) {
+        let line = self.format_line(request, res, elapsed);
+
+        let mut writer = self.writer.lock().unwrap();
+
+        if let Err(e) = writer.write_all(line.as_bytes()).and_then(|_| writer.flush()) {
+            warn!("Could not write access log: {}", e);
+        }
+    }
+
+    fn format_line<B: MessageBody>(&self, request: &RequestInfo, res: &ServiceResponse<B>, elapsed: Duration) -> String {
+        let time = request
+            .time
+            .format(format_description!("[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000"))
+            .unwrap();
+
+        let bytes = match res.response().body().size() {
+            BodySize::Sized(size) if size > 0 => size.to_string(),
+            _ => "-".to_string(),
+        };
+
+        // Bytes are followed by the request duration in microseconds, like %D in Apache
+        let mut line = format!(
+            "{} - - [{}] \"{}\" {} {}",
+            request.remote,
+            time,
+            escape(&request.request_line),
+            res.status().as_u16(),
+            bytes,
+        );
+
+        if self.format == AccessLogFormat::Combined {
+            line.push_str(&format!(" \"{}\" \"{}\"", escape(&request.referer), escape(&request.user_agent)));
+        }
+
+        line.push_str(&format!(" {}\n", elapsed.as_micros()));
+
+        line
+    }
+}
+
+fn header_value(req: &ServiceRequest, name: header::HeaderName) -> String {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-")
+        .to_string()
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
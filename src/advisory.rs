@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, Rng};
+use rand_distr::Alphanumeric;
+use tracing::{info, warn};
+
+const TOKEN_LENGTH: usize = 32;
+
+pub enum LockError {
+    // Held by someone else, with the time left until it expires
+    Held(Duration),
+    NotHeld,
+}
+
+struct Lock {
+    token: String,
+    expires_at: Instant,
+}
+
+// Advisory locks on keys for coordinating clients, held by whoever has the
+// token and released when it expires, so a holder that dies never keeps a key
+// locked for longer than its TTL. They live next to the store without
+// touching the keys, and a restart releases all of them.
+pub struct AdvisoryLocks {
+    locks: Mutex<HashMap<String, Lock>>,
+}
+
+impl AdvisoryLocks {
+    pub fn new() -> Self {
+        AdvisoryLocks {
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes the lock if it is free or expired, or extends it when `token` is the
+    // one holding it, returning the token
+    pub fn acquire(&self, key: &str, ttl: Duration, token: Option<String>) -> Result<String, LockError> {
+        let now = Instant::now();
+        let mut locks = self.locks.lock().unwrap();
+
+        if let Some(lock) = locks.get(key).filter(|lock| lock.expires_at > now) {
+            if token.as_deref() != Some(lock.token.as_str()) {
+                warn!("Lock on {} is already held", key);
+                return Err(LockError::Held(lock.expires_at - now));
+            }
+        }
+
+        let token = token.unwrap_or_else(generate_token);
+
+        locks.insert(
+            key.to_string(),
+            Lock {
+                token: token.clone(),
+                expires_at: now + ttl,
+            },
+        );
+
+        info!("Lock on {} acquired for {:?}", key, ttl);
+
+        Ok(token)
+    }
+
+    pub fn release(&self, key: &str, token: &str) -> Result<(), LockError> {
+        let now = Instant::now();
+        let mut locks = self.locks.lock().unwrap();
+
+        match locks.get(key).filter(|lock| lock.expires_at > now) {
+            Some(lock) if lock.token == token => {
+                locks.remove(key);
+                info!("Lock on {} released", key);
+                Ok(())
+            }
+            Some(lock) => {
+                warn!("Unlock of {} rejected, the token does not hold it", key);
+                Err(LockError::Held(lock.expires_at - now))
+            }
+            None => Err(LockError::NotHeld),
+        }
+    }
+
+    // Drops expired locks, which are already treated as free
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.locks.lock().unwrap().retain(|_, lock| lock.expires_at > now);
+    }
+}
+
+fn generate_token() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .map(char::from)
+        .take(TOKEN_LENGTH)
+        .collect()
+}
@@ -0,0 +1,88 @@
+use std::error::Error;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header;
+use actix_web::http::Method;
+
+use crate::config::{AuthTokens, Config, Scope};
+
+// POST routes that only read, so read tokens can use them
+const READ_ROUTES: [&str; 3] = ["/batch/get", "/batch/exists", "/snapshot-read"];
+
+// Checks the bearer token of every request against the scope its route needs:
+// `admin` for the routes under `/admin/`, `read` for GET and HEAD requests and
+// the lookups above, and `write` for the rest.
+pub struct Auth {
+    tokens: AuthTokens,
+}
+
+impl Auth {
+    pub fn from_config(config: &Config) -> Result<Option<Self>, Box<dyn Error>> {
+        if config.auth_tokens.is_empty() {
+            return Ok(None);
+        }
+
+        if config.auth_tokens.scope("").is_some() {
+            return Err("auth_tokens has an empty token".into());
+        }
+
+        Ok(Some(Auth {
+            tokens: config.auth_tokens.clone(),
+        }))
+    }
+
+    // The scope of the bearer token in an `Authorization` value, for HTTP
+    // requests and gRPC calls alike
+    pub fn scope(&self, authorization: Option<&str>) -> Option<Scope> {
+        let token = authorization.and_then(|value| value.strip_prefix("Bearer ")).map(str::trim)?;
+
+        self.tokens.scope(token)
+    }
+
+    pub fn check(&self, req: &ServiceRequest) -> Result<(), actix_web::HttpResponse> {
+        let authorization = req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+
+        let scope = match self.scope(authorization) {
+            Some(scope) => scope,
+            None => {
+                return Err(actix_web::HttpResponse::Unauthorized()
+                    .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                    .body("Missing or unknown API token"))
+            }
+        };
+
+        let required = required_scope(req);
+
+        if scope < required {
+            tracing::warn!("{} {} rejected, it needs the {} scope", req.method(), req.path(), scope_name(required));
+            return Err(actix_web::HttpResponse::Forbidden()
+                .body(format!("This request needs a token with the {} scope", scope_name(required))));
+        }
+
+        Ok(())
+    }
+}
+
+// The router matches the decoded path, where `/%61dmin/` is `/admin/`, so the
+// scope comes from the pattern of the route it picks rather than the raw path
+fn required_scope(req: &ServiceRequest) -> Scope {
+    let route = req.request().resource_map().match_pattern(req.match_info().as_str()).unwrap_or_default();
+
+    if route.starts_with("/admin/") {
+        Scope::Admin
+    } else if matches!(*req.method(), Method::GET | Method::HEAD)
+        || (*req.method() == Method::POST && READ_ROUTES.contains(&route.as_str()))
+    {
+        Scope::Read
+    } else {
+        Scope::Write
+    }
+}
+
+pub fn scope_name(scope: Scope) -> &'static str {
+    match scope {
+        Scope::Read => "read",
+        Scope::Write => "write",
+        Scope::Admin => "admin",
+    }
+}
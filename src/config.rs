@@ -0,0 +1,604 @@
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+const DEFAULT_CONFIG_PATH: &str = "distkv.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskFormat {
+    Legacy,
+    Cbor,
+}
+
+impl FromStr for DiskFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "legacy" => Ok(DiskFormat::Legacy),
+            "cbor" => Ok(DiskFormat::Cbor),
+            _ => Err(format!("Unknown disk format: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Persistence {
+    On,
+    Off,
+}
+
+impl FromStr for Persistence {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "on" => Ok(Persistence::On),
+            "off" => Ok(Persistence::Off),
+            _ => Err(format!("Unknown persistence mode: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyStrategy {
+    Random,
+    Ulid,
+    Uuid,
+    Snowflake,
+}
+
+impl FromStr for KeyStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "random" => Ok(KeyStrategy::Random),
+            "ulid" => Ok(KeyStrategy::Ulid),
+            "uuid" => Ok(KeyStrategy::Uuid),
+            "snowflake" => Ok(KeyStrategy::Snowflake),
+            _ => Err(format!("Unknown key strategy: {}", value)),
+        }
+    }
+}
+
+// What loading the data file does with a key that appears more than once
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateKeys {
+    // The last one wins and each duplicate is logged
+    Warn,
+    Error,
+    FirstWins,
+    LastWins,
+}
+
+impl FromStr for DuplicateKeys {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "warn" => Ok(DuplicateKeys::Warn),
+            "error" => Ok(DuplicateKeys::Error),
+            "first-wins" => Ok(DuplicateKeys::FirstWins),
+            "last-wins" => Ok(DuplicateKeys::LastWins),
+            _ => Err(format!("Unknown duplicate key handling: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Common,
+    Combined,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            _ => Err(format!("Unknown access log format: {}", value)),
+        }
+    }
+}
+
+// Default TTLs in seconds per namespace, written `ns=secs,ns2=secs` in the
+// environment and as a `[namespace_ttl_secs]` table in the config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct NamespaceTtls(HashMap<String, u64>);
+
+impl NamespaceTtls {
+    pub fn get(&self, namespace: &str) -> Option<u64> {
+        self.0.get(namespace).copied()
+    }
+}
+
+impl FromStr for NamespaceTtls {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (namespace, ttl) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("Expected namespace=seconds, got: {}", entry))?;
+                let ttl = ttl.trim().parse().map_err(|_| format!("Invalid TTL for namespace {}: {}", namespace, ttl))?;
+                Ok((namespace.trim().to_string(), ttl))
+            })
+            .collect::<Result<_, _>>()
+            .map(NamespaceTtls)
+    }
+}
+
+// Limits of a single namespace, on top of the quotas of the whole store
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamespaceQuota {
+    pub max_keys: Option<usize>,
+    pub max_bytes: Option<u64>,
+}
+
+// Quotas per namespace, written `ns=keys/bytes` in the environment with either
+// side left empty for no limit, and as a `[namespace_quotas.ns]` table with
+// `max_keys` and `max_bytes` in the config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct NamespaceQuotas(HashMap<String, NamespaceQuota>);
+
+impl NamespaceQuotas {
+    pub fn get(&self, namespace: &str) -> Option<NamespaceQuota> {
+        self.0.get(namespace).copied()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+}
+
+impl FromStr for NamespaceQuotas {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (namespace, limits) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("Expected namespace=keys/bytes, got: {}", entry))?;
+                let (max_keys, max_bytes) = limits.split_once('/').unwrap_or((limits, ""));
+                let quota = NamespaceQuota {
+                    max_keys: quota_limit(namespace, max_keys)?,
+                    max_bytes: quota_limit(namespace, max_bytes)?,
+                };
+                Ok((namespace.trim().to_string(), quota))
+            })
+            .collect::<Result<_, _>>()
+            .map(NamespaceQuotas)
+    }
+}
+
+fn quota_limit<T: FromStr>(namespace: &str, limit: &str) -> Result<Option<T>, String> {
+    match limit.trim() {
+        "" => Ok(None),
+        limit => limit
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid quota for namespace {}: {}", namespace, limit)),
+    }
+}
+
+// What an API token may do, each scope allowing everything the ones before it do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!("Unknown scope: {}, use read, write or admin", value)),
+        }
+    }
+}
+
+// The scope of each API token, written `token=scope,token2=scope` in the
+// environment and as an `[auth_tokens]` table in the config file. No tokens
+// leaves the API open.
+#[derive(Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct AuthTokens(HashMap<String, Scope>);
+
+impl AuthTokens {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn scope(&self, token: &str) -> Option<Scope> {
+        self.0.get(token).copied()
+    }
+}
+
+// Leaves the tokens themselves out
+impl fmt::Debug for AuthTokens {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AuthTokens({} tokens)", self.0.len())
+    }
+}
+
+impl FromStr for AuthTokens {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (token, scope) = entry.rsplit_once('=').ok_or("Expected token=scope in auth_tokens")?;
+                Ok((token.trim().to_string(), scope.trim().parse()?))
+            })
+            .collect::<Result<_, String>>()
+            .map(AuthTokens)
+    }
+}
+
+// Value fields the store keeps an index of for `GET /by/{field}/{value}`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct IndexedFields(Vec<String>);
+
+impl IndexedFields {
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        self.0.iter()
+    }
+}
+
+impl FromStr for IndexedFields {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(IndexedFields(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|field| !field.is_empty())
+                .map(str::to_string)
+                .collect(),
+        ))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub db_path: String,
+    pub persistence: Persistence,
+    pub read_only: bool,
+    pub create_if_missing: bool,
+    pub disk_format: DiskFormat,
+    pub duplicate_keys: DuplicateKeys,
+    pub compress_threshold: Option<usize>,
+    pub key_strategy: KeyStrategy,
+    pub bind_address: String,
+    pub grpc_address: Option<String>,
+    pub max_payload_size: usize,
+    pub response_compression: bool,
+    pub response_compression_min_bytes: u64,
+    pub max_blob_size: u64,
+    pub max_connections: usize,
+    pub keep_alive: Duration,
+    pub client_request_timeout: Duration,
+    pub max_key_len: usize,
+    pub list_default_limit: usize,
+    pub list_max_limit: usize,
+    pub key_allow: Option<Regex>,
+    pub key_deny: Option<Regex>,
+    pub max_keys: Option<usize>,
+    pub max_bytes: Option<u64>,
+    pub max_namespaces: Option<usize>,
+    pub sweep_interval: Duration,
+    pub soft_delete_window: Option<Duration>,
+    pub stale_grace: Option<Duration>,
+    pub flush_coalesce: Duration,
+    pub flush_cache: bool,
+    pub dedup_values: bool,
+    pub ttl_jitter_pct: u64,
+    pub namespace_ttls: NamespaceTtls,
+    pub namespace_quotas: NamespaceQuotas,
+    pub cors_origins: Option<String>,
+    pub cors_methods: String,
+    pub cors_headers: Option<String>,
+    pub cors_max_age: Option<Duration>,
+    pub auth_tokens: AuthTokens,
+    pub access_log: Option<AccessLogFormat>,
+    pub access_log_path: Option<String>,
+    pub pre_write_webhook: Option<String>,
+    pub webhook_timeout: Duration,
+    pub idempotency_ttl: Duration,
+    pub bloom_filter_size: usize,
+    pub indexed_fields: IndexedFields,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    db_path: Option<String>,
+    persistence: Option<Persistence>,
+    read_only: Option<bool>,
+    create_if_missing: Option<bool>,
+    disk_format: Option<DiskFormat>,
+    duplicate_keys: Option<DuplicateKeys>,
+    compress_threshold: Option<usize>,
+    key_strategy: Option<KeyStrategy>,
+    bind_address: Option<String>,
+    grpc_address: Option<String>,
+    max_payload_size: Option<usize>,
+    response_compression: Option<bool>,
+    response_compression_min_bytes: Option<u64>,
+    max_blob_size: Option<u64>,
+    max_connections: Option<usize>,
+    keep_alive_secs: Option<u64>,
+    client_request_timeout_ms: Option<u64>,
+    max_key_len: Option<usize>,
+    list_default_limit: Option<usize>,
+    list_max_limit: Option<usize>,
+    key_allow: Option<String>,
+    key_deny: Option<String>,
+    max_keys: Option<usize>,
+    max_bytes: Option<u64>,
+    max_namespaces: Option<usize>,
+    sweep_interval_secs: Option<u64>,
+    soft_delete_secs: Option<u64>,
+    stale_grace_secs: Option<u64>,
+    flush_coalesce_ms: Option<u64>,
+    flush_cache: Option<bool>,
+    dedup_values: Option<bool>,
+    ttl_jitter_pct: Option<u64>,
+    namespace_ttl_secs: Option<NamespaceTtls>,
+    namespace_quotas: Option<NamespaceQuotas>,
+    cors_origins: Option<String>,
+    cors_methods: Option<String>,
+    cors_headers: Option<String>,
+    cors_max_age_secs: Option<u64>,
+    auth_tokens: Option<AuthTokens>,
+    access_log: Option<AccessLogFormat>,
+    access_log_path: Option<String>,
+    pre_write_webhook: Option<String>,
+    webhook_timeout_ms: Option<u64>,
+    idempotency_ttl_secs: Option<u64>,
+    bloom_filter_size: Option<usize>,
+    indexed_fields: Option<IndexedFields>,
+}
+
+impl Config {
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        Self::load_with_env(|name| env::var(name).ok())
+    }
+
+    // Like `load`, with the environment variables `env` looks up instead of the
+    // process environment, which tests running side by side cannot share
+    pub fn load_with_env(env: impl Fn(&str) -> Option<String>) -> Result<Self, Box<dyn Error>> {
+        Self::from_file(read_config_file(&env)?, &env)
+    }
+
+    // The settings of a config file, given like `distkv.toml` would hold them,
+    // without any environment variables
+    #[cfg(test)]
+    pub fn from_toml(contents: &str) -> Result<Self, Box<dyn Error>> {
+        Self::from_toml_with_env(contents, |_| None)
+    }
+
+    #[cfg(test)]
+    pub fn from_toml_with_env(contents: &str, env: impl Fn(&str) -> Option<String>) -> Result<Self, Box<dyn Error>> {
+        Self::from_file(toml::from_str(contents)?, &env)
+    }
+
+    fn from_file(file: FileConfig, env: &impl Fn(&str) -> Option<String>) -> Result<Self, Box<dyn Error>> {
+        let config = Config {
+            db_path: env_or(env, "DISTKV_DB_PATH", file.db_path.unwrap_or_else(|| "database.vbank".to_string()))?,
+            persistence: env_or(env, "DISTKV_PERSISTENCE", file.persistence.unwrap_or(Persistence::On))?,
+            read_only: env_or(env, "DISTKV_READ_ONLY", file.read_only.unwrap_or(false))?,
+            create_if_missing: env_or(env, "DISTKV_CREATE_IF_MISSING", file.create_if_missing.unwrap_or(true))?,
+            disk_format: env_or(env, "DISTKV_DISK_FORMAT", file.disk_format.unwrap_or(DiskFormat::Legacy))?,
+            duplicate_keys: env_or(env, "DISTKV_DUPLICATE_KEYS", file.duplicate_keys.unwrap_or(DuplicateKeys::Warn))?,
+            compress_threshold: env_opt(env, "DISTKV_COMPRESS_THRESHOLD")?.or(file.compress_threshold),
+            key_strategy: env_or(env, "DISTKV_KEY_STRATEGY", file.key_strategy.unwrap_or(KeyStrategy::Random))?,
+            bind_address: env_or(
+                env,
+                "DISTKV_BIND_ADDRESS",
+                file.bind_address.unwrap_or_else(|| "127.0.0.1:8080".to_string()),
+            )?,
+            grpc_address: env_opt(env, "DISTKV_GRPC_ADDRESS")?.or(file.grpc_address),
+            max_payload_size: env_or(env, "DISTKV_MAX_PAYLOAD_SIZE", file.max_payload_size.unwrap_or(1024 * 1024))?,
+            response_compression: env_or(
+                env,
+                "DISTKV_RESPONSE_COMPRESSION",
+                file.response_compression.unwrap_or(false),
+            )?,
+            response_compression_min_bytes: env_or(
+                env,
+                "DISTKV_RESPONSE_COMPRESSION_MIN_BYTES",
+                file.response_compression_min_bytes.unwrap_or(1024),
+            )?,
+            max_blob_size: env_or(env, "DISTKV_MAX_BLOB_SIZE", file.max_blob_size.unwrap_or(64 * 1024 * 1024))?,
+            max_connections: env_or(env, "DISTKV_MAX_CONNECTIONS", file.max_connections.unwrap_or(1024))?,
+            keep_alive: Duration::from_secs(env_or(env, "DISTKV_KEEP_ALIVE_SECS", file.keep_alive_secs.unwrap_or(5))?),
+            client_request_timeout: Duration::from_millis(env_or(
+                env,
+                "DISTKV_CLIENT_REQUEST_TIMEOUT_MS",
+                file.client_request_timeout_ms.unwrap_or(5000),
+            )?),
+            max_key_len: env_or(env, "DISTKV_MAX_KEY_LEN", file.max_key_len.unwrap_or(512))?,
+            list_default_limit: env_or(env, "DISTKV_LIST_DEFAULT_LIMIT", file.list_default_limit.unwrap_or(1000))?,
+            list_max_limit: env_or(env, "DISTKV_LIST_MAX_LIMIT", file.list_max_limit.unwrap_or(10_000))?,
+            key_allow: key_pattern("key_allow", env_opt(env, "DISTKV_KEY_ALLOW")?.or(file.key_allow))?,
+            key_deny: key_pattern("key_deny", env_opt(env, "DISTKV_KEY_DENY")?.or(file.key_deny))?,
+            max_keys: env_opt(env, "DISTKV_MAX_KEYS")?.or(file.max_keys),
+            max_bytes: env_opt(env, "DISTKV_MAX_BYTES")?.or(file.max_bytes),
+            max_namespaces: env_opt(env, "DISTKV_MAX_NAMESPACES")?.or(file.max_namespaces),
+            sweep_interval: Duration::from_secs(env_or(
+                env,
+                "DISTKV_SWEEP_INTERVAL_SECS",
+                file.sweep_interval_secs.unwrap_or(1),
+            )?),
+            soft_delete_window: env_opt(env, "DISTKV_SOFT_DELETE_SECS")?
+                .or(file.soft_delete_secs)
+                .map(Duration::from_secs),
+            stale_grace: env_opt(env, "DISTKV_STALE_GRACE_SECS")?
+                .or(file.stale_grace_secs)
+                .map(Duration::from_secs),
+            flush_coalesce: Duration::from_millis(env_or(
+                env,
+                "DISTKV_FLUSH_COALESCE_MS",
+                file.flush_coalesce_ms.unwrap_or(0),
+            )?),
+            flush_cache: env_or(env, "DISTKV_FLUSH_CACHE", file.flush_cache.unwrap_or(false))?,
+            dedup_values: env_or(env, "DISTKV_DEDUP_VALUES", file.dedup_values.unwrap_or(false))?,
+            ttl_jitter_pct: env_or(env, "DISTKV_TTL_JITTER_PCT", file.ttl_jitter_pct.unwrap_or(0))?,
+            namespace_ttls: env_or(env, "DISTKV_NAMESPACE_TTLS", file.namespace_ttl_secs.unwrap_or_default())?,
+            namespace_quotas: env_or(env, "DISTKV_NAMESPACE_QUOTAS", file.namespace_quotas.unwrap_or_default())?,
+            cors_origins: env_opt(env, "DISTKV_CORS_ORIGINS")?.or(file.cors_origins),
+            cors_methods: env_or(
+                env,
+                "DISTKV_CORS_METHODS",
+                file.cors_methods.unwrap_or_else(|| "GET, HEAD, PUT, PATCH, POST, DELETE".to_string()),
+            )?,
+            cors_headers: env_opt(env, "DISTKV_CORS_HEADERS")?.or(file.cors_headers),
+            cors_max_age: env_opt(env, "DISTKV_CORS_MAX_AGE_SECS")?.or(file.cors_max_age_secs).map(Duration::from_secs),
+            auth_tokens: auth_tokens(env, file.auth_tokens)?,
+            access_log: env_opt(env, "DISTKV_ACCESS_LOG")?.or(file.access_log),
+            access_log_path: env_opt(env, "DISTKV_ACCESS_LOG_PATH")?.or(file.access_log_path),
+            pre_write_webhook: env_opt(env, "DISTKV_PRE_WRITE_WEBHOOK")?.or(file.pre_write_webhook),
+            webhook_timeout: Duration::from_millis(env_or(
+                env,
+                "DISTKV_WEBHOOK_TIMEOUT_MS",
+                file.webhook_timeout_ms.unwrap_or(1000),
+            )?),
+            idempotency_ttl: Duration::from_secs(env_or(
+                env,
+                "DISTKV_IDEMPOTENCY_TTL_SECS",
+                file.idempotency_ttl_secs.unwrap_or(300),
+            )?),
+            bloom_filter_size: env_or(env, "DISTKV_BLOOM_FILTER_SIZE", file.bloom_filter_size.unwrap_or(1 << 20))?,
+            indexed_fields: env_or(env, "DISTKV_INDEXED_FIELDS", file.indexed_fields.unwrap_or_default())?,
+        };
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.db_path.is_empty() {
+            return Err("db_path must not be empty".into());
+        }
+        // A missing file usually means a wrong path or an unmounted volume,
+        // starting empty would hide the data wherever it really is
+        if !self.create_if_missing && self.persistence == Persistence::On && !Path::new(&self.db_path).exists() {
+            return Err(format!("No data file at {} and create_if_missing is off", self.db_path).into());
+        }
+        if self.bind_address.is_empty() {
+            return Err("bind_address must not be empty".into());
+        }
+        if self.max_payload_size == 0 || self.max_blob_size == 0 {
+            return Err("max_payload_size and max_blob_size must be greater than 0".into());
+        }
+        if self.max_key_len == 0 {
+            return Err("max_key_len must be greater than 0".into());
+        }
+        if self.list_default_limit == 0 || self.list_default_limit > self.list_max_limit {
+            return Err("list_default_limit must be greater than 0 and at most list_max_limit".into());
+        }
+        if self.max_connections == 0 {
+            return Err("max_connections must be greater than 0".into());
+        }
+        if self.sweep_interval.is_zero() {
+            return Err("sweep_interval_secs must be greater than 0".into());
+        }
+        if self.ttl_jitter_pct > 100 {
+            return Err("ttl_jitter_pct must be at most 100".into());
+        }
+        if self.access_log_path.is_some() && self.access_log.is_none() {
+            return Err("access_log_path requires access_log to be set".into());
+        }
+        if self.grpc_address.is_some() && !cfg!(feature = "grpc") {
+            warn!("grpc_address is set but the server was built without the grpc feature, gRPC stays off");
+        }
+        if self.webhook_timeout.is_zero() {
+            return Err("webhook_timeout_ms must be greater than 0".into());
+        }
+        Ok(())
+    }
+}
+
+fn read_config_file(env: &impl Fn(&str) -> Option<String>) -> Result<FileConfig, Box<dyn Error>> {
+    let path = match env("DISTKV_CONFIG") {
+        Some(path) => path,
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => DEFAULT_CONFIG_PATH.to_string(),
+        None => return Ok(FileConfig::default()),
+    };
+
+    info!("Loading configuration from {}", path);
+
+    let contents = fs::read_to_string(&path).map_err(|e| format!("Could not read config file {}: {}", path, e))?;
+
+    let file = toml::from_str(&contents).map_err(|e| format!("Invalid config file {}: {}", path, e))?;
+
+    Ok(file)
+}
+
+// A bad pattern stops the server like other invalid values, since ignoring a
+// denylist would let through the keys it is meant to keep out
+fn key_pattern(name: &str, pattern: Option<String>) -> Result<Option<Regex>, Box<dyn Error>> {
+    pattern
+        .map(|pattern| Regex::new(&pattern).map_err(|e| format!("{} is not a valid regex: {}", name, e).into()))
+        .transpose()
+}
+
+// Like other settings an invalid value stops the server, which is what keeps
+// the API from being left open, but unlike them it is never logged
+fn auth_tokens(env: &impl Fn(&str) -> Option<String>, file: Option<AuthTokens>) -> Result<AuthTokens, Box<dyn Error>> {
+    match env("DISTKV_AUTH_TOKENS") {
+        Some(value) => value.parse().map_err(|e| format!("Invalid DISTKV_AUTH_TOKENS: {}", e).into()),
+        None => Ok(file.unwrap_or_default()),
+    }
+}
+
+fn env_or<T: FromStr>(env: &impl Fn(&str) -> Option<String>, name: &str, default: T) -> Result<T, Box<dyn Error>> {
+    Ok(env_opt(env, name)?.unwrap_or(default))
+}
+
+// An override that does not parse stops the server like an invalid setting in
+// the file does, rather than quietly leaving the file's value in place
+fn env_opt<T: FromStr>(env: &impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>, Box<dyn Error>> {
+    match env(name) {
+        Some(value) => value.parse().map(Some).map_err(|_| format!("Invalid value for {}: {}", name, value).into()),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,120 @@
+use std::error::Error;
+use std::str::FromStr;
+use std::time::Duration;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::http::Method;
+
+use crate::config::Config;
+
+// Lets browser pages from the allowed origins call the API. Preflight requests
+// are answered here without reaching a handler, and other responses to allowed
+// origins are marked as readable by them.
+pub struct Cors {
+    // Empty allows every origin
+    origins: Vec<String>,
+    methods: Vec<Method>,
+    allow_methods: HeaderValue,
+    // `None` allows whatever headers the preflight asks for
+    allow_headers: Option<HeaderValue>,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    pub fn from_config(config: &Config) -> Result<Option<Self>, Box<dyn Error>> {
+        let origins = match &config.cors_origins {
+            Some(origins) => origins,
+            None => return Ok(None),
+        };
+
+        let origins = match origins.trim() {
+            "*" => Vec::new(),
+            origins => list(origins).map(str::to_string).collect(),
+        };
+
+        let methods = list(&config.cors_methods)
+            .map(|method| Method::from_str(&method.to_uppercase()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid method in cors_methods: {}", e))?;
+
+        let allow_methods = methods.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+
+        let allow_headers = match &config.cors_headers {
+            Some(headers) => Some(HeaderValue::from_str(&list(headers).collect::<Vec<_>>().join(", "))?),
+            None => None,
+        };
+
+        Ok(Some(Cors {
+            origins,
+            methods,
+            allow_methods: HeaderValue::from_str(&allow_methods)?,
+            allow_headers,
+            max_age: config.cors_max_age,
+        }))
+    }
+
+    pub fn is_preflight(req: &ServiceRequest) -> bool {
+        req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    pub fn preflight(&self, req: &ServiceRequest) -> actix_web::HttpResponse {
+        let requested = req
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok());
+
+        let origin = match (self.allowed_origin(req), requested) {
+            (Some(origin), Some(method)) if self.methods.contains(&method) => origin,
+            _ => return actix_web::HttpResponse::Forbidden().body("CORS request not allowed"),
+        };
+
+        let mut response = actix_web::HttpResponse::NoContent();
+
+        response.insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin));
+        response.insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, self.allow_methods.clone()));
+        response.insert_header((header::VARY, "Origin, Access-Control-Request-Method, Access-Control-Request-Headers"));
+
+        let allow_headers = self
+            .allow_headers
+            .clone()
+            .or_else(|| req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS).cloned());
+
+        if let Some(allow_headers) = allow_headers {
+            response.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers));
+        }
+
+        if let Some(max_age) = self.max_age {
+            response.insert_header((header::ACCESS_CONTROL_MAX_AGE, max_age.as_secs()));
+        }
+
+        response.finish()
+    }
+
+    // The `Access-Control-Allow-Origin` to send back, for requests from an
+    // allowed origin using an allowed method
+    pub fn allow_origin(&self, req: &ServiceRequest) -> Option<HeaderValue> {
+        self.allowed_origin(req).filter(|_| self.methods.contains(req.method()))
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap, origin: HeaderValue) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+
+    fn allowed_origin(&self, req: &ServiceRequest) -> Option<HeaderValue> {
+        let origin = req.headers().get(header::ORIGIN)?;
+
+        if self.origins.is_empty() {
+            return Some(HeaderValue::from_static("*"));
+        }
+
+        let allowed = origin.to_str().is_ok_and(|origin| self.origins.iter().any(|allowed| allowed == origin));
+
+        allowed.then(|| origin.clone())
+    }
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
@@ -0,0 +1,223 @@
+// Tonic calls and interceptors return their `Status` errors by value
+#![allow(clippy::result_large_err)]
+
+use std::error::Error;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use actix_web::web;
+use futures_util::Stream;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+use crate::auth::{scope_name, Auth};
+use crate::config::Scope;
+use crate::kvstore::{Change, ErrorKind, KVStore, KVStoreError};
+
+pub(crate) mod proto {
+    #![allow(clippy::all)]
+    #![cfg_attr(not(test), allow(dead_code))]
+    tonic::include_proto!("distkv.v1");
+}
+
+use proto::key_value_server::{KeyValue, KeyValueServer};
+use proto::watch_event::Kind;
+use proto::{
+    DeleteRequest, DeleteResponse, Document, GetRequest, GetResponse, ListRequest, ListResponse, PutRequest,
+    PutResponse, WatchEvent, WatchRequest,
+};
+
+// The gRPC interface from `proto/distkv.proto`, served next to the HTTP API
+// and backed by the same store
+struct KeyValueService {
+    kvs: web::Data<KVStore>,
+    // The webhook client only runs on the HTTP workers, so writes are refused
+    // rather than stored without the check
+    pre_write_webhook: bool,
+}
+
+pub async fn serve(
+    kvs: web::Data<KVStore>,
+    address: &str,
+    pre_write_webhook: bool,
+    auth: Option<Arc<Auth>>,
+) -> Result<(), Box<dyn Error>> {
+    let address = address.parse()?;
+
+    info!("Serving gRPC on {}", address);
+
+    let service = KeyValueService { kvs, pre_write_webhook };
+
+    tonic::transport::Server::builder()
+        .add_service(KeyValueServer::with_interceptor(service, move |request| authenticate(auth.as_deref(), request)))
+        .serve(address)
+        .await?;
+
+    Ok(())
+}
+
+// Records the scope of the bearer token in the `authorization` metadata for
+// the calls to check, like the HTTP API does with the `Authorization` header.
+// Without tokens configured every call may do everything.
+fn authenticate(auth: Option<&Auth>, mut request: Request<()>) -> Result<Request<()>, Status> {
+    let scope = match auth {
+        Some(auth) => {
+            let authorization = request.metadata().get("authorization").and_then(|value| value.to_str().ok());
+            auth.scope(authorization).ok_or_else(|| Status::unauthenticated("Missing or unknown API token"))?
+        }
+        None => Scope::Admin,
+    };
+
+    request.extensions_mut().insert(scope);
+
+    Ok(request)
+}
+
+fn authorize<T>(request: &Request<T>, call: &str, required: Scope) -> Result<(), Status> {
+    match request.extensions().get::<Scope>() {
+        Some(scope) if *scope >= required => Ok(()),
+        _ => {
+            warn!("gRPC {} rejected, it needs the {} scope", call, scope_name(required));
+            Err(Status::permission_denied(format!("This call needs a token with the {} scope", scope_name(required))))
+        }
+    }
+}
+
+fn status(e: Box<dyn Error>) -> Status {
+    let message = e.to_string();
+
+    match e.downcast_ref::<KVStoreError>().map(|e| e.kind()) {
+        Some(ErrorKind::NotFound) => Status::not_found(message),
+        Some(ErrorKind::Conflict) => Status::already_exists(message),
+        Some(ErrorKind::Unavailable) => Status::unavailable(message),
+        Some(ErrorKind::Forbidden) => Status::permission_denied(message),
+        Some(ErrorKind::QuotaExceeded) => Status::resource_exhausted(message),
+        Some(ErrorKind::InvalidInput) => Status::invalid_argument(message),
+        _ => Status::internal(message),
+    }
+}
+
+type WatchStream = Pin<Box<dyn Stream<Item = Result<WatchEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl KeyValue for KeyValueService {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        authorize(&request, "Get", Scope::Read)?;
+
+        let request = request.into_inner();
+
+        let value = self.kvs.get(request.namespace, request.key).await.map_err(status)?;
+
+        Ok(Response::new(GetResponse { value: value.to_string() }))
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        authorize(&request, "Put", Scope::Write)?;
+
+        if self.pre_write_webhook {
+            return Err(Status::failed_precondition("Writes go through the pre-write webhook, use the HTTP API"));
+        }
+
+        let request = request.into_inner();
+
+        let value = serde_json::from_str(&request.value)
+            .map_err(|e| Status::invalid_argument(format!("Value is not valid JSON: {}", e)))?;
+
+        let key = if request.key.is_empty() {
+            let (document, _) = self.kvs.create_document(request.namespace, value, None).await.map_err(status)?;
+            document["key"].as_str().unwrap_or_default().to_string()
+        } else {
+            self.kvs
+                .create_document_with_key(request.namespace, request.key.clone(), value, None)
+                .await
+                .map_err(status)?;
+            request.key
+        };
+
+        Ok(Response::new(PutResponse { key }))
+    }
+
+    async fn delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteResponse>, Status> {
+        authorize(&request, "Delete", Scope::Write)?;
+
+        let request = request.into_inner();
+
+        self.kvs.delete(request.namespace, request.key.clone()).await.map_err(status)?;
+
+        Ok(Response::new(DeleteResponse { key: request.key }))
+    }
+
+    async fn list(&self, request: Request<ListRequest>) -> Result<Response<ListResponse>, Status> {
+        authorize(&request, "List", Scope::Read)?;
+
+        let request = request.into_inner();
+
+        let limit = Some(request.limit as usize).filter(|limit| *limit > 0);
+        let cursor = Some(request.cursor).filter(|cursor| !cursor.is_empty());
+
+        let page = self.kvs.scan(Some(request.prefix), None, limit, cursor).await;
+
+        let documents = page["items"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|document| Document {
+                key: document["key"].as_str().unwrap_or_default().to_string(),
+                value: document["data"].to_string(),
+            })
+            .collect();
+
+        Ok(Response::new(ListResponse {
+            documents,
+            cursor: page["cursor"].as_str().unwrap_or_default().to_string(),
+        }))
+    }
+
+    type WatchStream = WatchStream;
+
+    async fn watch(&self, request: Request<WatchRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        authorize(&request, "Watch", Scope::Read)?;
+
+        let prefix = request.into_inner().prefix;
+        let receiver = self.kvs.subscribe();
+
+        info!("Watch started for prefix {:?}", prefix);
+
+        // The receiver is dropped after reporting a lag, which ends the stream
+        let events = futures_util::stream::unfold(Some(receiver), move |receiver| {
+            let prefix = prefix.clone();
+
+            async move {
+                let mut receiver = receiver?;
+
+                loop {
+                    let event = match receiver.recv().await {
+                        Ok(Change::Put { key, value }) => WatchEvent {
+                            kind: Kind::Put.into(),
+                            key,
+                            value: value.to_string(),
+                        },
+                        Ok(Change::Delete { key }) => WatchEvent {
+                            kind: Kind::Delete.into(),
+                            key,
+                            value: String::new(),
+                        },
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!("Watch for prefix {:?} fell behind by {} changes", prefix, missed);
+                            let error = Status::data_loss(format!("Watch fell behind by {} changes", missed));
+                            return Some((Err(error), None));
+                        }
+                        Err(RecvError::Closed) => return None,
+                    };
+
+                    if event.key.starts_with(&prefix) {
+                        return Some((Ok(event), Some(receiver)));
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(events)))
+    }
+}
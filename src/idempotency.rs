@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::body;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use tracing::{info, warn};
+
+const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+
+enum CachedResponse {
+    InProgress,
+    Done {
+        status: StatusCode,
+        content_type: Option<HeaderValue>,
+        body: Bytes,
+    },
+}
+
+// Remembers the responses of successful requests sent with an `Idempotency-Key`
+// header, so a retried request gets the original response instead of running again
+pub struct IdempotencyCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, CachedResponse)>>,
+}
+
+impl IdempotencyCache {
+    pub fn new(ttl: Duration) -> Self {
+        IdempotencyCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn run<F, Fut>(&self, req: &HttpRequest, handler: F) -> HttpResponse
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = HttpResponse>,
+    {
+        let idempotency_key = match req.headers().get(IDEMPOTENCY_HEADER).and_then(|value| value.to_str().ok()) {
+            Some(idempotency_key) => idempotency_key,
+            None => return handler().await,
+        };
+
+        // A key only replays the request it was first used with
+        let cache_key = format!("{} {} {}", req.method(), req.path(), idempotency_key);
+
+        {
+            let mut entries = self.entries.lock().unwrap();
+
+            match entries.get(&cache_key) {
+                Some((expires_at, CachedResponse::Done { status, content_type, body })) if *expires_at > Instant::now() => {
+                    info!("Replaying response for idempotency key: {}", idempotency_key);
+
+                    let mut response = HttpResponse::build(*status);
+                    response.insert_header(("Idempotent-Replayed", "true"));
+                    if let Some(content_type) = content_type {
+                        response.insert_header((header::CONTENT_TYPE, content_type.clone()));
+                    }
+                    return response.body(body.clone());
+                }
+                Some((expires_at, CachedResponse::InProgress)) if *expires_at > Instant::now() => {
+                    warn!("Request with idempotency key {} is already in progress", idempotency_key);
+                    return HttpResponse::Conflict().body("A request with this Idempotency-Key is already in progress");
+                }
+                _ => {
+                    entries.insert(cache_key.clone(), (Instant::now() + self.ttl, CachedResponse::InProgress));
+                }
+            }
+        }
+
+        let response = handler().await;
+
+        if !response.status().is_success() {
+            self.entries.lock().unwrap().remove(&cache_key);
+            return response;
+        }
+
+        let status = response.status();
+        let content_type = response.headers().get(header::CONTENT_TYPE).cloned();
+        let (response, response_body) = response.into_parts();
+
+        let body = match body::to_bytes(response_body).await {
+            Ok(body) => body,
+            Err(_) => {
+                self.entries.lock().unwrap().remove(&cache_key);
+                return HttpResponse::InternalServerError().body("Could not read response body");
+            }
+        };
+
+        self.entries.lock().unwrap().insert(
+            cache_key,
+            (
+                Instant::now() + self.ttl,
+                CachedResponse::Done {
+                    status,
+                    content_type,
+                    body: body.clone(),
+                },
+            ),
+        );
+
+        response.set_body(body).map_into_boxed_body()
+    }
+
+    // Drops the responses whose window has passed, called by the sweeper
+    pub fn prune(&self) {
+        let now = Instant::now();
+
+        self.entries.lock().unwrap().retain(|_, (expires_at, _)| *expires_at > now);
+    }
+}
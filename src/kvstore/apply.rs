@@ -0,0 +1,154 @@
+use serde_json::Value;
+use std::error::Error;
+use tracing::{info, warn};
+
+use super::{decode_value, not_found, number_value, ErrorKind, KVStore, KVStoreError, Update, WriteFilter};
+
+// The closed set of transforms `POST /{namespace}/{key}/apply` can run on a
+// value, nothing else is ever evaluated
+pub enum Operation {
+    Add(f64),
+    Subtract(f64),
+    Multiply(f64),
+    Divide(f64),
+    Append(String),
+    Prepend(String),
+    Upper,
+    Lower,
+    Trim,
+}
+
+impl Operation {
+    pub fn parse(op: &str, arg: Option<Value>) -> Result<Self, String> {
+        let number = |arg: Option<Value>| match arg.as_ref().and_then(Value::as_f64) {
+            Some(number) => Ok(number),
+            None => Err(format!("Operation {} takes a number as arg", op)),
+        };
+        let string = |arg: Option<Value>| match arg {
+            Some(Value::String(string)) => Ok(string),
+            _ => Err(format!("Operation {} takes a string as arg", op)),
+        };
+
+        match op {
+            "add" => Ok(Operation::Add(number(arg)?)),
+            "subtract" => Ok(Operation::Subtract(number(arg)?)),
+            "multiply" => Ok(Operation::Multiply(number(arg)?)),
+            "divide" => match number(arg)? {
+                0.0 => Err("Cannot divide by zero".to_string()),
+                divisor => Ok(Operation::Divide(divisor)),
+            },
+            "append" => Ok(Operation::Append(string(arg)?)),
+            "prepend" => Ok(Operation::Prepend(string(arg)?)),
+            "upper" => Ok(Operation::Upper),
+            "lower" => Ok(Operation::Lower),
+            "trim" => Ok(Operation::Trim),
+            _ => Err(format!("Unknown operation: {}", op)),
+        }
+    }
+
+    fn apply(&self, value: &Value) -> Result<Value, String> {
+        match (self, value) {
+            (Operation::Add(arg), Value::Number(number)) => arithmetic(number, *arg, i64::checked_add, |a, b| a + b),
+            (Operation::Subtract(arg), Value::Number(number)) => arithmetic(number, *arg, i64::checked_sub, |a, b| a - b),
+            (Operation::Multiply(arg), Value::Number(number)) => arithmetic(number, *arg, i64::checked_mul, |a, b| a * b),
+            (Operation::Divide(arg), Value::Number(number)) => finite(number.as_f64().unwrap() / arg),
+            (Operation::Append(arg), Value::String(string)) => Ok(Value::String(format!("{}{}", string, arg))),
+            (Operation::Prepend(arg), Value::String(string)) => Ok(Value::String(format!("{}{}", arg, string))),
+            (Operation::Upper, Value::String(string)) => Ok(Value::String(string.to_uppercase())),
+            (Operation::Lower, Value::String(string)) => Ok(Value::String(string.to_lowercase())),
+            (Operation::Trim, Value::String(string)) => Ok(Value::String(string.trim().to_string())),
+            (Operation::Add(_) | Operation::Subtract(_) | Operation::Multiply(_) | Operation::Divide(_), _) => {
+                Err("Value is not a number".to_string())
+            }
+            _ => Err("Value is not a string".to_string()),
+        }
+    }
+}
+
+// Integers stay integers while the result fits, anything else is worked out
+// as a float
+fn arithmetic(
+    number: &serde_json::Number,
+    arg: f64,
+    integer: fn(i64, i64) -> Option<i64>,
+    float: fn(f64, f64) -> f64,
+) -> Result<Value, String> {
+    let exact = match (number.as_i64(), arg.fract() == 0.0 && arg.abs() < i64::MAX as f64) {
+        (Some(number), true) => integer(number, arg as i64),
+        _ => None,
+    };
+
+    match exact {
+        Some(result) => Ok(serde_json::json!(result)),
+        None => finite(float(number.as_f64().unwrap(), arg)),
+    }
+}
+
+// JSON has no infinity or NaN, so a float result that overflowed to one is
+// refused instead of being stored as `null`
+fn finite(result: f64) -> Result<Value, String> {
+    if result.is_finite() {
+        Ok(number_value(result))
+    } else {
+        Err("Result is not a finite number".to_string())
+    }
+}
+
+impl KVStore {
+    // Runs `operation` on the value, or on the field at the dotted `path` in it,
+    // and stores the result under the key lock
+    pub async fn apply_operation(
+        &self,
+        namespace: String,
+        key: String,
+        operation: Operation,
+        path: Option<String>,
+        filter: &impl WriteFilter,
+    ) -> Result<Value, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let updated = self
+            .update_value(&namespace, &key, None, filter, |existing| {
+                let existing = match existing {
+                    Some(entry) => entry,
+                    None => {
+                        warn!("Apply error - Document not found: {}", key);
+                        return Err(not_found(&key));
+                    }
+                };
+
+                let mut value = decode_value(&existing.value);
+
+                let target = match &path {
+                    Some(path) => path.split('.').try_fold(&mut value, |current, segment| current.get_mut(segment)),
+                    None => Some(&mut value),
+                };
+
+                let target = match target {
+                    Some(target) => target,
+                    None => {
+                        warn!("Apply error - {} has no field {}", key, path.as_deref().unwrap_or_default());
+                        return Err(Box::new(KVStoreError::with_kind(
+                            ErrorKind::InvalidInput,
+                            &format!("Field not found: {}", path.as_deref().unwrap_or_default()),
+                        )));
+                    }
+                };
+
+                let updated = operation.apply(target).map_err(|message| {
+                    warn!("Apply error - {}: {}", key, message);
+                    KVStoreError::with_kind(ErrorKind::InvalidInput, &message)
+                })?;
+
+                *target = updated.clone();
+
+                Ok(Update::Write(value, updated))
+            })
+            .await?;
+
+        info!("Operation applied to {}", key);
+
+        Ok(serde_json::json!({ "value": updated }))
+    }
+}
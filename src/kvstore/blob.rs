@@ -0,0 +1,217 @@
+use base64::decode;
+use serde::Deserialize;
+use serde_json::Value;
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use super::{live_entry, Entry, ErrorKind, KVStore, KVStoreError, Map};
+
+const BLOB_DIR: &str = "blobs";
+
+pub struct BlobWriter {
+    file: File,
+    dir: PathBuf,
+    name: String,
+    size: u64,
+}
+
+impl BlobWriter {
+    pub async fn create(dir: PathBuf, name: String) -> io::Result<Self> {
+        fs::create_dir_all(&dir).await?;
+
+        let file = File::create(temp_path(&dir, &name)).await?;
+
+        Ok(BlobWriter { file, dir, name, size: 0 })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.file.write_all(chunk).await?;
+        self.size += chunk.len() as u64;
+        Ok(())
+    }
+
+    // Checks that the written bytes are one well-formed JSON document. The file
+    // is read back through a buffer and every value is skipped as it is parsed,
+    // so no tree is built however large the document is. Malformed JSON is an
+    // error of kind `InvalidData`, or `UnexpectedEof` when the document is cut off.
+    pub async fn check_json(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+
+        let path = temp_path(&self.dir, &self.name);
+
+        tokio::task::spawn_blocking(move || {
+            let reader = io::BufReader::new(std::fs::File::open(path)?);
+            let mut deserializer = serde_json::Deserializer::from_reader(reader);
+
+            serde::de::IgnoredAny::deserialize(&mut deserializer)?;
+            deserializer.end()?;
+
+            Ok(())
+        })
+        .await?
+    }
+
+    pub async fn finish(mut self) -> io::Result<(String, u64)> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        fs::rename(temp_path(&self.dir, &self.name), blob_path(&self.dir, &self.name)).await?;
+        Ok((self.name, self.size))
+    }
+
+    pub async fn abort(self) {
+        drop(self.file);
+        _ = fs::remove_file(temp_path(&self.dir, &self.name)).await;
+    }
+}
+
+fn temp_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!(".{}.tmp", name))
+}
+
+fn blob_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(name)
+}
+
+fn blob_reference(name: &str, size: u64) -> Value {
+    serde_json::json!({ "blob": name, "size": size })
+}
+
+fn parse_blob_reference(value: &Value) -> Option<(String, u64)> {
+    let name = value.get("blob")?.as_str()?;
+    let size = value.get("size")?.as_u64()?;
+
+    // Only names we generated ourselves may be resolved to a path
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    Some((name.to_string(), size))
+}
+
+impl KVStore {
+    // Blobs are kept next to the data file, so every store has a directory of its own
+    fn blob_dir(&self) -> PathBuf {
+        Path::new(&self.config.db_path).with_file_name(BLOB_DIR)
+    }
+
+    pub async fn create_blob_writer(&self) -> Result<BlobWriter, Box<dyn Error>> {
+        self.check_writable()?;
+
+        let name = Self::generate_random_string(16);
+
+        Ok(BlobWriter::create(self.blob_dir(), name).await?)
+    }
+
+    pub async fn store_blob(&self, key: String, name: String, size: u64) -> Result<String, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let previous = {
+            let _key = self.locks.lock(&key);
+            let mut kvs = self.store.write();
+
+            let string_value = serde_json::to_string(&blob_reference(&name, size)).unwrap();
+
+            let encoded_value = base64::encode(string_value);
+
+            self.check_quota(&kvs, None, &key, &encoded_value)
+                .map(|_| self.put_entry(&mut kvs, None, key.clone(), Entry::new(encoded_value)))
+        };
+
+        let previous = match previous {
+            Ok(previous) => previous,
+            Err(e) => {
+                _ = fs::remove_file(blob_path(&self.blob_dir(), &name)).await;
+                return Err(e);
+            }
+        };
+
+        self.persist().await;
+
+        if let Some((old_name, _)) = previous.and_then(|entry| decode_blob_reference(&entry.value)) {
+            if old_name != name {
+                _ = fs::remove_file(blob_path(&self.blob_dir(), &old_name)).await;
+            }
+        }
+
+        info!("Blob stored: {} ({} bytes)", key, size);
+
+        Ok(format!("Blob stored: {}", key))
+    }
+
+    pub async fn get_blob(&self, key: String) -> Result<(PathBuf, u64), Box<dyn Error>> {
+
+        let store = self.store.read();
+
+        match live_entry(&store, &key).and_then(|entry| decode_blob_reference(&entry.value)) {
+            Some((name, size)) => {
+                info!("Grabbing blob: {}", key);
+                Ok((blob_path(&self.blob_dir(), &name), size))
+            }
+            None => {
+                warn!("Blob not found: {}", key);
+                Err(Box::new(KVStoreError::with_kind(
+                    ErrorKind::NotFound,
+                    &format!("Blob not found: {}", key),
+                )))
+            }
+        }
+    }
+
+    pub async fn delete_blob(&self, key: String) -> Result<String, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let name = {
+            let _key = self.locks.lock(&key);
+            let mut store = self.store.write();
+
+            match live_entry(&store, &key).and_then(|entry| decode_blob_reference(&entry.value)) {
+                Some((name, _)) => {
+                    self.remove_entry(&mut store, &key);
+                    name
+                }
+                None => {
+                    warn!("Delete error - Blob not found: {}", key);
+                    return Err(Box::new(KVStoreError::with_kind(
+                        ErrorKind::NotFound,
+                        &format!("Blob not found: {}", key),
+                    )));
+                }
+            }
+        };
+
+        self.persist().await;
+
+        _ = fs::remove_file(blob_path(&self.blob_dir(), &name)).await;
+
+        info!("Blob deleted: {}", key);
+
+        Ok(format!("Blob deleted: {}", key))
+    }
+
+    // Removes the files behind every blob reference in a map that was dropped from the store
+    pub(super) async fn remove_blobs(&self, kvs: &Map) {
+        for entry in kvs.values() {
+            if let Some((name, _)) = decode_blob_reference(&entry.value) {
+                _ = fs::remove_file(blob_path(&self.blob_dir(), &name)).await;
+            }
+        }
+    }
+}
+
+fn decode_blob_reference(value: &str) -> Option<(String, u64)> {
+    let decoded_value = decode(value).ok()?;
+
+    let json_value: Value = serde_json::from_slice(&decoded_value).ok()?;
+
+    parse_blob_reference(&json_value)
+}
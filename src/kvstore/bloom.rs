@@ -0,0 +1,71 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+const HASHES: u64 = 4;
+
+// A counting bloom filter over the keys in the store, so lookups of missing
+// keys can usually stop before touching the map. Counters make removals
+// possible, and a counter that overflows stays saturated, so a key that was
+// inserted is never reported as missing.
+pub struct BloomFilter {
+    counters: Vec<AtomicU8>,
+}
+
+impl BloomFilter {
+    pub fn new(size: usize) -> Self {
+        BloomFilter {
+            counters: (0..size).map(|_| AtomicU8::new(0)).collect(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.counters.is_empty()
+    }
+
+    pub fn insert(&self, key: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        for index in self.indexes(key) {
+            _ = self.counters[index].fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| count.checked_add(1));
+        }
+    }
+
+    pub fn remove(&self, key: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        for index in self.indexes(key) {
+            _ = self.counters[index].fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| match count {
+                0 | u8::MAX => None,
+                count => Some(count - 1),
+            });
+        }
+    }
+
+    pub fn may_contain(&self, key: &str) -> bool {
+        !self.is_enabled() || self.indexes(key).all(|index| self.counters[index].load(Ordering::SeqCst) > 0)
+    }
+
+    pub fn clear(&self) {
+        for counter in &self.counters {
+            counter.store(0, Ordering::SeqCst);
+        }
+    }
+
+    fn indexes(&self, key: &str) -> impl Iterator<Item = usize> {
+        let size = self.counters.len() as u64;
+
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        // Double hashing derives every index from the two halves of one hash
+        let (first, second) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+
+        (0..HASHES).map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % size) as usize)
+    }
+}
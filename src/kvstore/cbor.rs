@@ -0,0 +1,147 @@
+use base64::decode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+use crate::config::DuplicateKeys;
+
+use super::compression;
+use super::load_entry;
+use super::store::{Entry, Map};
+
+// Written at the start of CBOR data files so they can be told apart from the
+// legacy `key|base64` text format when loading.
+pub const CBOR_MAGIC: &[u8] = b"DISTKV-CBOR-1\n";
+
+// `write_record` writes these fields by hand, so both must change together
+#[derive(Serialize, Deserialize)]
+pub(super) struct Record {
+    pub key: String,
+    // Null when the value is stored compressed instead
+    pub value: Value,
+    #[serde(default)]
+    pub compressed: Option<serde_bytes::ByteBuf>,
+    pub expires_at: Option<u64>,
+    pub deleted_at: Option<u64>,
+    #[serde(default)]
+    pub updated_at: Option<u64>,
+    #[serde(default)]
+    pub version: u64,
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+pub fn read_entries(contents: &[u8], kvstore: &mut Map, duplicates: DuplicateKeys) -> Result<(), Box<dyn Error>> {
+    let mut reader = &contents[CBOR_MAGIC.len()..];
+
+    while !reader.is_empty() {
+        let record: Record = ciborium::de::from_reader(&mut reader)?;
+
+        let json = match &record.compressed {
+            Some(compressed) => compression::decompress(compressed)?,
+            None => serde_json::to_vec(&record.value)?,
+        };
+
+        load_entry(kvstore, record.key, Entry {
+            expires_at: record.expires_at,
+            deleted_at: record.deleted_at,
+            updated_at: record.updated_at,
+            version: record.version,
+            namespace: record.namespace.map(Arc::from),
+            ..Entry::new(base64::encode(json))
+        }, duplicates)?;
+    }
+
+    Ok(())
+}
+
+// Fields of a record in the order `Record` declares them, written by hand so
+// the encoded value can be cached and copied in between the other fields
+const RECORD_FIELDS: u8 = 8;
+const CBOR_MAP: u8 = 0xa0;
+
+// Writes a record the same way serializing a `Record` would. Returns whether
+// the value had to be encoded, rather than coming from the entry's cache.
+pub(super) fn write_record<W: Write>(
+    writer: &mut W,
+    key: &str,
+    entry: &Entry,
+    compress_threshold: Option<usize>,
+    cache: bool,
+) -> Result<bool, Box<dyn Error>> {
+    let (value, encoded) = match entry.encoded.get() {
+        Some(value) => (value.clone(), false),
+        None => {
+            let value: Arc<[u8]> = encode_value(entry, compress_threshold)?.into();
+            if cache {
+                _ = entry.encoded.set(value.clone());
+            }
+            (value, true)
+        }
+    };
+
+    writer.write_all(&[CBOR_MAP | RECORD_FIELDS])?;
+
+    ciborium::ser::into_writer("key", &mut *writer)?;
+    ciborium::ser::into_writer(key, &mut *writer)?;
+    writer.write_all(&value)?;
+    ciborium::ser::into_writer("expires_at", &mut *writer)?;
+    ciborium::ser::into_writer(&entry.expires_at, &mut *writer)?;
+    ciborium::ser::into_writer("deleted_at", &mut *writer)?;
+    ciborium::ser::into_writer(&entry.deleted_at, &mut *writer)?;
+    ciborium::ser::into_writer("updated_at", &mut *writer)?;
+    ciborium::ser::into_writer(&entry.updated_at, &mut *writer)?;
+    ciborium::ser::into_writer("version", &mut *writer)?;
+    ciborium::ser::into_writer(&entry.version, &mut *writer)?;
+    ciborium::ser::into_writer("namespace", &mut *writer)?;
+    ciborium::ser::into_writer(&entry.namespace.as_deref(), &mut *writer)?;
+
+    Ok(encoded)
+}
+
+// The `value` and `compressed` fields of a record with their names
+fn encode_value(entry: &Entry, compress_threshold: Option<usize>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let json = decode(entry.value.as_bytes())?;
+
+    let (value, compressed) = match compress_threshold {
+        Some(threshold) if json.len() >= threshold => {
+            (Value::Null, Some(serde_bytes::ByteBuf::from(compression::compress(&json)?)))
+        }
+        _ => (serde_json::from_slice(&json)?, None),
+    };
+
+    let mut encoded = Vec::new();
+
+    ciborium::ser::into_writer("value", &mut encoded)?;
+    ciborium::ser::into_writer(&value, &mut encoded)?;
+    ciborium::ser::into_writer("compressed", &mut encoded)?;
+    ciborium::ser::into_writer(&compressed, &mut encoded)?;
+
+    Ok(encoded)
+}
+
+// Returns how many values were encoded
+pub fn write_entries<W: Write>(
+    file: W,
+    kvstore: &Map,
+    compress_threshold: Option<usize>,
+    cache: bool,
+) -> Result<u64, Box<dyn Error>> {
+    let mut writer = BufWriter::new(file);
+
+    writer.write_all(CBOR_MAGIC)?;
+
+    let mut encoded = 0;
+
+    for (key, entry) in kvstore.iter() {
+        if write_record(&mut writer, key, entry, compress_threshold, cache)? {
+            encoded += 1;
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(encoded)
+}
@@ -0,0 +1,21 @@
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::{self, Read, Write};
+
+// Written in the data file next to a value that was compressed
+pub const DEFLATE: &str = "deflate";
+
+// Values are only compressed in the data file, the store keeps them as they
+// were written so reads never pay for it.
+pub fn compress(json: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(json)?;
+    encoder.finish()
+}
+
+pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut json = Vec::new();
+    DeflateDecoder::new(data).read_to_end(&mut json)?;
+    Ok(json)
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// One shared copy of every distinct value, with the number of keys holding it,
+// so storing a value many times keeps it in memory once. The last key to let
+// go of a value removes it from the pool.
+pub struct ValuePool {
+    enabled: bool,
+    values: Mutex<HashMap<Arc<str>, usize>>,
+}
+
+impl ValuePool {
+    pub fn new(enabled: bool) -> Self {
+        ValuePool {
+            enabled,
+            values: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // The shared copy of `value` for one more key to hold
+    pub fn acquire(&self, value: Arc<str>) -> Arc<str> {
+        if !self.enabled {
+            return value;
+        }
+
+        let mut values = self.values.lock().unwrap();
+
+        match values.get_key_value(&*value).map(|(shared, _)| shared.clone()) {
+            Some(shared) => {
+                *values.get_mut(&*shared).unwrap() += 1;
+                shared
+            }
+            None => {
+                values.insert(value.clone(), 1);
+                value
+            }
+        }
+    }
+
+    pub fn release(&self, value: &str) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut values = self.values.lock().unwrap();
+
+        if let Some(count) = values.get_mut(value) {
+            *count -= 1;
+
+            if *count == 0 {
+                values.remove(value);
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        self.values.lock().unwrap().clear();
+    }
+
+    // Distinct values held and the bytes saved by not storing the others again
+    pub fn usage(&self) -> (usize, u64) {
+        let values = self.values.lock().unwrap();
+
+        let saved = values.iter().map(|(value, count)| (value.len() * (count - 1)) as u64).sum();
+
+        (values.len(), saved)
+    }
+}
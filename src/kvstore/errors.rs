@@ -1,17 +1,38 @@
 use std::error::Error;
 use std::fmt;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    NotFound,
+    Conflict,
+    Unavailable,
+    Forbidden,
+    QuotaExceeded,
+    InvalidInput,
+    Other,
+}
+
 #[derive(Debug)]
 pub struct KVStoreError {
     message: String,
+    kind: ErrorKind,
 }
 
 impl KVStoreError {
     pub fn new(message: &str) -> Self {
+        Self::with_kind(ErrorKind::Other, message)
+    }
+
+    pub fn with_kind(kind: ErrorKind, message: &str) -> Self {
         KVStoreError {
             message: message.to_string(),
+            kind,
         }
     }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
 }
 
 impl fmt::Display for KVStoreError {
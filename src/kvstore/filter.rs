@@ -0,0 +1,170 @@
+use serde_json::Value;
+use std::error::Error;
+use std::sync::Arc;
+use tracing::warn;
+
+use super::{decode_value, live_entry, Entry, ErrorKind, KVStore, KVStoreError};
+
+// Rewrites or rejects the value of a write before it is stored, which the
+// pre-write webhook does for every HTTP write. No lock is held while it runs,
+// so writes whose value comes from the store read it first and only go ahead
+// if the key still holds it once the filter is done.
+pub trait WriteFilter {
+    fn is_active(&self) -> bool;
+
+    async fn filter(&self, key: &str, value: Value) -> Result<Value, Box<dyn Error>>;
+}
+
+// What a write derived from the current value of a key does, with what it reports
+pub(super) enum Update<R> {
+    Write(Value, R),
+    Keep(R),
+}
+
+// The state of a key when its value was read for the filter, and the value the
+// filter gave back for the key it is written to
+pub(super) struct Filtered {
+    read: Option<(u64, Arc<str>)>,
+    value: Option<Arc<str>>,
+}
+
+impl Filtered {
+    fn read(entry: Option<&Entry>) -> Option<(u64, Arc<str>)> {
+        entry.map(|entry| (entry.version, entry.value.clone()))
+    }
+
+    // Fails if the key no longer holds what was read from it
+    pub(super) fn check(&self, key: &str, entry: Option<&Entry>) -> Result<(), Box<dyn Error>> {
+        let unchanged = match (&self.read, entry) {
+            (Some((version, value)), Some(entry)) => *version == entry.version && Arc::ptr_eq(value, &entry.value),
+            (None, None) => true,
+            _ => false,
+        };
+
+        if unchanged {
+            Ok(())
+        } else {
+            Err(changed(key))
+        }
+    }
+
+    // `entry` with the filtered value in place of the one that was read
+    pub(super) fn rewrite(&self, entry: Entry) -> Entry {
+        match &self.value {
+            Some(value) => {
+                let mut rewritten = Entry::new(value.clone());
+                rewritten.expires_at = entry.expires_at;
+                rewritten.namespace = entry.namespace;
+                rewritten
+            }
+            None => entry,
+        }
+    }
+}
+
+pub(super) fn changed(key: &str) -> Box<dyn Error> {
+    warn!("Write error - {} changed while the pre-write webhook ran", key);
+    Box::new(KVStoreError::with_kind(
+        ErrorKind::Conflict,
+        &format!("Document changed while the pre-write webhook ran, retry: {}", key),
+    ))
+}
+
+impl KVStore {
+    // Writes the value `compute` derives from the live entry of the key, which
+    // keeps its expiry while a created key gets `default_expiry`
+    pub(super) async fn update_value<R>(
+        &self,
+        namespace: &str,
+        key: &str,
+        default_expiry: Option<u64>,
+        filter: &impl WriteFilter,
+        compute: impl Fn(Option<&Entry>) -> Result<Update<R>, Box<dyn Error>>,
+    ) -> Result<R, Box<dyn Error>> {
+
+        let filtered = if filter.is_active() {
+            let (read, update) = {
+                let snapshot = self.store.read();
+                let existing = live_entry(&snapshot, key);
+
+                (Filtered::read(existing), compute(existing)?)
+            };
+
+            match update {
+                Update::Write(value, result) => {
+                    let value = base64::encode(serde_json::to_string(&filter.filter(key, value).await?).unwrap());
+                    Some((Filtered { read, value: Some(value.into()) }, result))
+                }
+                Update::Keep(result) => return Ok(result),
+            }
+        } else {
+            None
+        };
+
+        let result = {
+            let _key = self.locks.lock(key);
+            let snapshot = self.store.read();
+
+            let existing = live_entry(&snapshot, key);
+
+            let (value, result): (Arc<str>, R) = match filtered {
+                Some((filtered, result)) => {
+                    filtered.check(key, existing)?;
+                    (filtered.value.unwrap(), result)
+                }
+                None => match compute(existing)? {
+                    Update::Write(value, result) => (base64::encode(serde_json::to_string(&value).unwrap()).into(), result),
+                    Update::Keep(result) => return Ok(result),
+                },
+            };
+
+            let mut entry = Entry::new(value);
+            entry.expires_at = existing.and_then(|entry| entry.expires_at).or(default_expiry);
+
+            let mut kvs = self.store.write();
+
+            self.check_quota(&kvs, Some(namespace), key, &entry.value)?;
+
+            self.put_entry(&mut kvs, Some(namespace), key.to_string(), entry);
+
+            result
+        };
+
+        self.persist().await;
+
+        Ok(result)
+    }
+
+    // Runs the filter on the live values of the `(source, target)` keys for the
+    // targets they are written to, or None when it is off. Missing sources are
+    // left for the write to report.
+    pub(super) async fn filter_copies(
+        &self,
+        filter: &impl WriteFilter,
+        copies: &[(&str, &str)],
+    ) -> Result<Option<Vec<Filtered>>, Box<dyn Error>> {
+
+        if !filter.is_active() {
+            return Ok(None);
+        }
+
+        let snapshot = self.store.read();
+
+        let mut filtered = Vec::new();
+        for (source, target) in copies {
+            let existing = live_entry(&snapshot, source);
+
+            let value = match existing {
+                Some(entry) => {
+                    let value = filter.filter(target, decode_value(&entry.value)).await?;
+                    Some(base64::encode(serde_json::to_string(&value).unwrap()).into())
+                }
+                None => None,
+            };
+
+            filtered.push(Filtered { read: Filtered::read(existing), value });
+        }
+
+        Ok(Some(filtered))
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::config::{Config, DiskFormat};
+
+use super::{now, write_kvstore, Store};
+
+// Writes the store to the data file. With a coalescing window, writers that
+// ask for a flush within the window share one write of the file, and each of
+// them only returns once a write that includes its change has finished.
+pub struct Flusher {
+    store: Arc<Store>,
+    dirty_keys: Arc<Mutex<HashSet<String>>>,
+    last_flush: Arc<AtomicU64>,
+    config: Config,
+    // Starts as `disk_format` and changes when the file is rewritten in
+    // another format. Held for the whole of every write, so no write of the
+    // old format can land after a rewrite.
+    format: Mutex<DiskFormat>,
+    window: Duration,
+    // Numbers every flush request, a write covers every request numbered up
+    // to the value it read before loading the snapshot
+    requested: AtomicU64,
+    scheduled: AtomicBool,
+    completed: watch::Sender<u64>,
+    flushes: AtomicU64,
+    encodes: AtomicU64,
+}
+
+impl Flusher {
+    pub fn new(
+        store: Arc<Store>,
+        dirty_keys: Arc<Mutex<HashSet<String>>>,
+        last_flush: Arc<AtomicU64>,
+        config: &Config,
+    ) -> Self {
+        Flusher {
+            store,
+            dirty_keys,
+            last_flush,
+            config: config.clone(),
+            format: Mutex::new(config.disk_format),
+            window: config.flush_coalesce,
+            requested: AtomicU64::new(0),
+            scheduled: AtomicBool::new(false),
+            completed: watch::channel(0).0,
+            flushes: AtomicU64::new(0),
+            encodes: AtomicU64::new(0),
+        }
+    }
+
+    // Files actually written, however many writers asked for them
+    pub fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::SeqCst)
+    }
+
+    // Values encoded for the data file, with `DISTKV_FLUSH_CACHE` about one per
+    // write of a value rather than one per key on every flush
+    pub fn encodes(&self) -> u64 {
+        self.encodes.load(Ordering::SeqCst)
+    }
+
+    pub fn format(&self) -> DiskFormat {
+        *self.format.lock().unwrap()
+    }
+
+    // Writes the whole file in `format` now and in every later flush
+    pub fn rewrite(&self, format: DiskFormat) {
+        let mut current = self.format.lock().unwrap();
+
+        // Cached values are encoded for the format they were written in
+        if *current != format && self.config.flush_cache {
+            let mut kvs = self.store.write();
+
+            for entry in kvs.values_mut() {
+                entry.encoded = OnceLock::new();
+            }
+        }
+
+        *current = format;
+
+        self.write(format);
+    }
+
+    pub async fn persist(self: &Arc<Self>) {
+        if self.window.is_zero() {
+            self.flush();
+            return;
+        }
+
+        // Taken after the change is in the store, so any write that reads a
+        // number at least this high also writes the change
+        let ticket = self.requested.fetch_add(1, Ordering::SeqCst) + 1;
+
+        // The write runs in its own task, a writer whose request is dropped
+        // while waiting cannot leave the others hanging
+        if !self.scheduled.swap(true, Ordering::SeqCst) {
+            let flusher = self.clone();
+
+            tokio::spawn(async move {
+                tokio::time::sleep(flusher.window).await;
+
+                // Cleared before reading the count, so a request the write
+                // might miss always schedules the next one
+                flusher.scheduled.store(false, Ordering::SeqCst);
+                let covered = flusher.requested.load(Ordering::SeqCst);
+
+                flusher.flush();
+
+                flusher.completed.send_modify(|completed| *completed = (*completed).max(covered));
+            });
+        }
+
+        let mut completed = self.completed.subscribe();
+        _ = completed.wait_for(|completed| *completed >= ticket).await;
+    }
+
+    pub fn flush(&self) {
+        let format = self.format.lock().unwrap();
+
+        self.write(*format);
+    }
+
+    fn write(&self, format: DiskFormat) {
+        let _span = crate::telemetry::enabled().then(|| tracing::info_span!("flush", path = %self.config.db_path).entered());
+
+        // Taken before the snapshot is loaded, so keys changed while the file is
+        // being written are marked dirty again for the next flush
+        self.dirty_keys.lock().unwrap().clear();
+
+        let encoded = write_kvstore(
+            &self.store,
+            &self.config.db_path,
+            format,
+            self.config.compress_threshold,
+            self.config.flush_cache,
+        )
+        .expect("Error writing to disk");
+
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        self.encodes.fetch_add(encoded, Ordering::SeqCst);
+        self.last_flush.store(now(), Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,181 @@
+use base64::decode;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+
+use super::cbor::{Record, CBOR_MAGIC};
+use super::compression;
+
+// Only the first problems are listed in the report, the counts cover all of them
+const MAX_REPORTED_PROBLEMS: usize = 100;
+
+#[derive(Default)]
+struct Report {
+    valid: u64,
+    corrupt: u64,
+    duplicate_keys: u64,
+    unparseable_values: u64,
+    keys: HashSet<String>,
+    problems: Vec<Value>,
+}
+
+impl Report {
+    fn problem(&mut self, record: usize, problem: String) {
+        if self.problems.len() < MAX_REPORTED_PROBLEMS {
+            self.problems.push(serde_json::json!({ "record": record, "problem": problem }));
+        }
+    }
+
+    fn check_key(&mut self, record: usize, key: &str) {
+        if !self.keys.insert(key.to_string()) {
+            self.duplicate_keys += 1;
+            self.problem(record, format!("Duplicate key: {}", key));
+        }
+    }
+
+    fn check_value(&mut self, record: usize, json: &[u8]) {
+        match serde_json::from_slice::<Value>(json) {
+            Ok(_) => self.valid += 1,
+            Err(e) => {
+                self.unparseable_values += 1;
+                self.problem(record, format!("Value is not valid JSON: {}", e));
+            }
+        }
+    }
+}
+
+// Checks a data file without loading it into the store. Records are numbered
+// from 1, by line in the legacy format and by position in the CBOR format.
+pub fn check_file(path: &str) -> Result<Value, Box<dyn Error>> {
+    let contents = fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+
+    let mut report = Report::default();
+
+    let format = if contents.starts_with(CBOR_MAGIC) {
+        check_cbor(&contents[CBOR_MAGIC.len()..], &mut report);
+        "cbor"
+    } else {
+        check_legacy(&contents, &mut report);
+        "legacy"
+    };
+
+    let healthy = report.corrupt == 0 && report.duplicate_keys == 0 && report.unparseable_values == 0;
+
+    Ok(serde_json::json!({
+        "path": path,
+        "format": format,
+        "healthy": healthy,
+        "valid": report.valid,
+        "corrupt": report.corrupt,
+        "duplicate_keys": report.duplicate_keys,
+        "unparseable_values": report.unparseable_values,
+        "problems": report.problems,
+    }))
+}
+
+fn check_legacy(contents: &[u8], report: &mut Report) {
+    for (index, line) in contents.split(|&byte| byte == b'\n').enumerate() {
+        let record = index + 1;
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let line = match std::str::from_utf8(line) {
+            Ok(line) => line,
+            Err(_) => {
+                report.corrupt += 1;
+                report.problem(record, "Line is not valid UTF-8".to_string());
+                continue;
+            }
+        };
+
+        let fields: Vec<&str> = line.split('|').collect();
+
+        let (key, value) = match (fields.first(), fields.get(1)) {
+            (Some(key), Some(value)) if !key.is_empty() && !value.is_empty() => (*key, *value),
+            _ => {
+                report.corrupt += 1;
+                report.problem(record, "Line is missing its key or value".to_string());
+                continue;
+            }
+        };
+
+        report.check_key(record, key);
+
+        let timestamps_valid = fields
+            .iter()
+            .skip(2)
+            .take(3)
+            .chain(fields.get(6))
+            .all(|field| field.is_empty() || field.parse::<u64>().is_ok());
+
+        if !timestamps_valid || fields.len() > 8 {
+            report.corrupt += 1;
+            report.problem(record, format!("Invalid metadata fields for key: {}", key));
+            continue;
+        }
+
+        let value = value.trim_matches('"');
+
+        let decoded = match decode(value) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                report.corrupt += 1;
+                report.problem(record, format!("Value of {} is not valid base64: {}", key, e));
+                continue;
+            }
+        };
+
+        let json = match fields.get(5) {
+            Some(&compression::DEFLATE) => match compression::decompress(&decoded) {
+                Ok(json) => json,
+                Err(e) => {
+                    report.corrupt += 1;
+                    report.problem(record, format!("Value of {} does not decompress: {}", key, e));
+                    continue;
+                }
+            },
+            Some(compression) if !compression.is_empty() => {
+                report.corrupt += 1;
+                report.problem(record, format!("Unknown compression for {}: {}", key, compression));
+                continue;
+            }
+            _ => decoded,
+        };
+
+        report.check_value(record, &json);
+    }
+}
+
+fn check_cbor(mut reader: &[u8], report: &mut Report) {
+    let mut record = 0;
+
+    while !reader.is_empty() {
+        record += 1;
+
+        let entry: Record = match ciborium::de::from_reader(&mut reader) {
+            Ok(entry) => entry,
+            Err(e) => {
+                // Records are not delimited, so nothing after a broken one can be read
+                report.corrupt += 1;
+                report.problem(record, format!("Unreadable record, the rest of the file is skipped: {}", e));
+                return;
+            }
+        };
+
+        report.check_key(record, &entry.key);
+
+        match &entry.compressed {
+            Some(compressed) => match compression::decompress(compressed) {
+                Ok(json) => report.check_value(record, &json),
+                Err(e) => {
+                    report.corrupt += 1;
+                    report.problem(record, format!("Value of {} does not decompress: {}", entry.key, e));
+                }
+            },
+            None => report.valid += 1,
+        }
+    }
+}
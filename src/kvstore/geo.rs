@@ -0,0 +1,146 @@
+use serde_json::{Map, Value};
+use std::error::Error;
+use tracing::{info, warn};
+
+use super::{decode_value, live_entry, not_found, ErrorKind, KVStore, KVStoreError, Update, WriteFilter};
+
+// Mean radius of the Earth
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+fn geo_point(point: &Value) -> Option<(f64, f64)> {
+    Some((point.get("lat")?.as_f64()?, point.get("lng")?.as_f64()?))
+}
+
+fn is_geo_collection(value: &Map<String, Value>) -> bool {
+    value.values().all(|point| geo_point(point).is_some())
+}
+
+fn not_a_geo_collection(key: &str) -> Box<dyn Error> {
+    Box::new(KVStoreError::with_kind(
+        ErrorKind::InvalidInput,
+        &format!("Document is not a geo collection: {}", key),
+    ))
+}
+
+fn check_coordinates(lat: f64, lng: f64) -> Result<(), Box<dyn Error>> {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lng) {
+        return Err(Box::new(KVStoreError::with_kind(
+            ErrorKind::InvalidInput,
+            &format!("Invalid coordinates: lat {}, lng {}", lat, lng),
+        )));
+    }
+
+    Ok(())
+}
+
+// Great-circle distance between two points in degrees, by the haversine formula
+fn distance_km((lat1, lng1): (f64, f64), (lat2, lng2): (f64, f64)) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lng = (lng2 - lng1).to_radians();
+
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lng / 2.0).sin().powi(2);
+
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+// Geo collections are objects of named `{"lat": ..., "lng": ...}` points in
+// degrees. Nearby queries scan every point of the collection, which is fine
+// for the few thousand points a single key reasonably holds.
+impl KVStore {
+    // Adds the point, or moves it when the name is already in the collection
+    pub async fn add_geo_point(
+        &self,
+        namespace: String,
+        key: String,
+        name: String,
+        lat: f64,
+        lng: f64,
+        filter: &impl WriteFilter,
+    ) -> Result<Value, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let default_expiry = self.expiry_for(&namespace, None)?;
+
+        check_coordinates(lat, lng)?;
+
+        let (added, size) = self
+            .update_value(&namespace, &key, default_expiry, filter, |existing| {
+                let mut points = match existing.map(|entry| decode_value(&entry.value)) {
+                    Some(Value::Object(points)) if is_geo_collection(&points) => points,
+                    Some(_) => {
+                        warn!("Geo add error - {} is not a geo collection", key);
+                        return Err(not_a_geo_collection(&key));
+                    }
+                    None => Map::new(),
+                };
+
+                let added = points.insert(name.clone(), serde_json::json!({ "lat": lat, "lng": lng })).is_none();
+                let size = points.len();
+
+                Ok(Update::Write(Value::Object(points), (added, size)))
+            })
+            .await?;
+
+        info!("Geo point {} in {}, {} points", if added { "added" } else { "moved" }, key, size);
+
+        Ok(serde_json::json!({ "added": added, "size": size }))
+    }
+
+    // Points within `radius_km` of the given coordinates, closest first
+    pub async fn nearby_points(
+        &self,
+        namespace: String,
+        key: String,
+        lat: f64,
+        lng: f64,
+        radius_km: f64,
+    ) -> Result<Value, Box<dyn Error>> {
+
+        _ = namespace;
+
+        check_coordinates(lat, lng)?;
+
+        if radius_km.is_nan() || radius_km < 0.0 {
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::InvalidInput,
+                &format!("Invalid radius: {}", radius_km),
+            )));
+        }
+
+        let snapshot = self.store.read();
+
+        let points = match live_entry(&snapshot, &key).map(|entry| decode_value(&entry.value)) {
+            Some(Value::Object(points)) if is_geo_collection(&points) => points,
+            Some(_) => {
+                warn!("Nearby error - {} is not a geo collection", key);
+                return Err(not_a_geo_collection(&key));
+            }
+            None => {
+                warn!("Nearby error - Document not found: {}", key);
+                return Err(not_found(&key));
+            }
+        };
+
+        let mut nearby: Vec<(f64, String, (f64, f64))> = points
+            .iter()
+            .map(|(name, point)| {
+                let point = geo_point(point).unwrap();
+                (distance_km((lat, lng), point), name.clone(), point)
+            })
+            .filter(|(distance, _, _)| *distance <= radius_km)
+            .collect();
+
+        nearby.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+
+        let nearby = nearby
+            .into_iter()
+            .map(|(distance, name, (lat, lng))| {
+                serde_json::json!({ "name": name, "lat": lat, "lng": lng, "distance_km": distance })
+            })
+            .collect();
+
+        Ok(Value::Array(nearby))
+    }
+}
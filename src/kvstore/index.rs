@@ -0,0 +1,76 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use serde_json::Value;
+
+use super::lookup_path;
+
+// Keys by the value they hold in each configured field, kept up to date by
+// every write so lookups by a field never scan the store. Strings are indexed
+// as they are and numbers and booleans as their JSON text, other values and
+// values without the field are left out.
+pub struct FieldIndex {
+    fields: HashMap<String, Mutex<BTreeMap<String, BTreeSet<String>>>>,
+}
+
+impl FieldIndex {
+    pub fn new<'a>(fields: impl IntoIterator<Item = &'a String>) -> Self {
+        FieldIndex {
+            fields: fields.into_iter().map(|field| (field.clone(), Mutex::new(BTreeMap::new()))).collect(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.fields.is_empty()
+    }
+
+    pub fn is_indexed(&self, field: &str) -> bool {
+        self.fields.contains_key(field)
+    }
+
+    pub fn insert(&self, key: &str, value: &Value) {
+        for (field, index) in &self.fields {
+            if let Some(indexed) = indexed_value(value, field) {
+                index.lock().unwrap().entry(indexed).or_default().insert(key.to_string());
+            }
+        }
+    }
+
+    pub fn remove(&self, key: &str, value: &Value) {
+        for (field, index) in &self.fields {
+            if let Some(indexed) = indexed_value(value, field) {
+                let mut index = index.lock().unwrap();
+
+                if let Some(keys) = index.get_mut(&indexed) {
+                    keys.remove(key);
+
+                    if keys.is_empty() {
+                        index.remove(&indexed);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        for index in self.fields.values() {
+            index.lock().unwrap().clear();
+        }
+    }
+
+    // In key order, empty for fields that are not indexed
+    pub fn get(&self, field: &str, value: &str) -> Vec<String> {
+        self.fields
+            .get(field)
+            .and_then(|index| index.lock().unwrap().get(value).map(|keys| keys.iter().cloned().collect()))
+            .unwrap_or_default()
+    }
+}
+
+pub fn indexed_value(value: &Value, field: &str) -> Option<String> {
+    match lookup_path(value, field)? {
+        Value::String(value) => Some(value.clone()),
+        value @ (Value::Number(_) | Value::Bool(_)) => Some(value.to_string()),
+        _ => None,
+    }
+}
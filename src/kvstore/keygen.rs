@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::KeyStrategy;
+
+use super::KVStore;
+
+// Milliseconds since 2023-01-01, the start of snowflake timestamps
+const SNOWFLAKE_EPOCH: u64 = 1_672_531_200_000;
+const SNOWFLAKE_SEQUENCE_BITS: u64 = 12;
+const SNOWFLAKE_NODE_BITS: u64 = 10;
+// There is only one node for now
+const SNOWFLAKE_NODE: u64 = 0;
+
+// Generates the keys of documents created without one
+pub struct KeyGenerator {
+    strategy: KeyStrategy,
+    // Millisecond and sequence of the last snowflake
+    snowflake: Mutex<(u64, u64)>,
+    // Generated keys that were already taken, a rising count means the
+    // keyspace is getting crowded for the strategy
+    collisions: AtomicU64,
+}
+
+impl KeyGenerator {
+    pub fn new(strategy: KeyStrategy) -> Self {
+        KeyGenerator {
+            strategy,
+            snowflake: Mutex::new((0, 0)),
+            collisions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_collision(&self) {
+        self.collisions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn collisions(&self) -> u64 {
+        self.collisions.load(Ordering::Relaxed)
+    }
+
+    pub fn generate(&self) -> String {
+        match self.strategy {
+            KeyStrategy::Random => KVStore::generate_random_string(8),
+            KeyStrategy::Ulid => ulid::Ulid::new().to_string(),
+            KeyStrategy::Uuid => uuid::Uuid::new_v4().to_string(),
+            KeyStrategy::Snowflake => self.snowflake().to_string(),
+        }
+    }
+
+    // 41 bits of milliseconds, 10 bits of node and a 12 bit sequence that
+    // waits for the next millisecond once it runs out
+    fn snowflake(&self) -> u64 {
+        let mut last = self.snowflake.lock().unwrap();
+        let max_sequence = (1 << SNOWFLAKE_SEQUENCE_BITS) - 1;
+
+        let mut millis = epoch_millis().max(last.0);
+        let sequence = if millis == last.0 { last.1 + 1 } else { 0 };
+
+        let sequence = if sequence > max_sequence {
+            while millis <= last.0 {
+                millis = epoch_millis();
+            }
+            0
+        } else {
+            sequence
+        };
+
+        *last = (millis, sequence);
+
+        (millis << (SNOWFLAKE_NODE_BITS + SNOWFLAKE_SEQUENCE_BITS)) | (SNOWFLAKE_NODE << SNOWFLAKE_SEQUENCE_BITS) | sequence
+    }
+}
+
+fn epoch_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64 - SNOWFLAKE_EPOCH
+}
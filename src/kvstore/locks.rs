@@ -0,0 +1,51 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+
+const STRIPES: usize = 256;
+
+// Serializes the writers of each key, so a read-modify-write like an increment
+// can read and compute from the current snapshot and only take the store write
+// lock to publish its result. Keys share one of a fixed set of stripes.
+//
+// Every writer takes the stripes of the keys it touches before the store write
+// lock, and the guards must never be held across an `.await`, since another task
+// on the same worker could then block on them.
+pub struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+}
+
+impl KeyLocks {
+    pub fn new() -> Self {
+        KeyLocks {
+            stripes: (0..STRIPES).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    pub fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        self.stripes[stripe(key)].lock().unwrap()
+    }
+
+    // Stripes are always taken in ascending order, so multi-key writers cannot
+    // deadlock with each other
+    pub fn lock_many<'a, I>(&self, keys: I) -> Vec<MutexGuard<'_, ()>>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut stripes: Vec<usize> = keys.into_iter().map(stripe).collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+
+        stripes.into_iter().map(|index| self.stripes[index].lock().unwrap()).collect()
+    }
+
+    pub fn lock_all(&self) -> Vec<MutexGuard<'_, ()>> {
+        self.stripes.iter().map(|stripe| stripe.lock().unwrap()).collect()
+    }
+}
+
+fn stripe(key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % STRIPES
+}
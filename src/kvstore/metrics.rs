@@ -0,0 +1,53 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Upper bounds in seconds of the histogram buckets, the last bucket is +Inf
+const BUCKETS: [f64; 10] = [0.000_001, 0.000_01, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 1.0];
+
+// A Prometheus histogram updated with atomics only, so recording a value
+// never waits on anything itself
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, out: &mut String, name: &str, help: &str) {
+        _ = writeln!(out, "# HELP {} {}", name, help);
+        _ = writeln!(out, "# TYPE {} histogram", name);
+
+        // Bucket counts are cumulative in the exposition format
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+
+        _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        _ = writeln!(out, "{}_sum {}", name, sum);
+        _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+pub fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    _ = writeln!(out, "# HELP {} {}", name, help);
+    _ = writeln!(out, "# TYPE {} counter", name);
+    _ = writeln!(out, "{} {}", name, value);
+}
@@ -1,170 +1,2518 @@
 use base64::decode;
 use rand::{thread_rng, Rng};
 use rand_distr::Alphanumeric;
+use regex::RegexBuilder;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::error::Error;
 use std::fs;
 use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::cmp::{Ordering as CmpOrdering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
 use std::sync::{Arc, Mutex};
-use std::{collections::BTreeMap, fs::File};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+use std::fs::File;
 use tracing::{info, warn};
 
+use crate::config::{Config, DiskFormat, DuplicateKeys, Persistence};
+
+mod apply;
+mod blob;
+mod bloom;
+mod cbor;
+mod compression;
+mod dedup;
 mod errors;
-use errors::KVStoreError;
+mod filter;
+mod flush;
+mod fsck;
+mod geo;
+mod index;
+mod keygen;
+mod locks;
+mod metrics;
+mod namespaces;
+mod sample;
+mod series;
+mod snapshot;
+use snapshot::stream_ndjson;
+mod store;
+pub use apply::Operation;
+pub use errors::{ErrorKind, KVStoreError};
+pub use filter::WriteFilter;
+pub use fsck::check_file;
+use bloom::BloomFilter;
+use dedup::ValuePool;
+use filter::{changed, Update};
+use flush::Flusher;
+use index::{indexed_value, FieldIndex};
+use keygen::KeyGenerator;
+use locks::KeyLocks;
+use metrics::render_counter;
+use namespaces::NamespaceUsage;
+use sample::KeySample;
+use store::{prefix_range, prefix_range_after, Entry, Map, Store};
+
+// Regexes run in linear time, these only bound how much memory a pattern may use
+const MAX_REGEX_LENGTH: usize = 1024;
+const MAX_REGEX_SIZE: usize = 1024 * 1024;
+
+// Upper bounds in bytes of the value size buckets reported by `size_histogram`
+const SIZE_BUCKETS: [(&str, u64); 4] = [
+    ("<1KB", 1024),
+    ("1-10KB", 10 * 1024),
+    ("10-100KB", 100 * 1024),
+    (">100KB", u64::MAX),
+];
+
+// Most keys a comma-separated GET may ask for
+const MAX_MULTI_GET_KEYS: usize = 100;
+
+// Changes a watcher may fall behind by before it is disconnected
+const CHANGE_BACKLOG: usize = 1024;
+
+// One in this many keys is kept in the sample estimated counts are read from
+const KEY_SAMPLE_RATE: u64 = 64;
+
+// Estimated counts below this are counted exactly instead, which walking the
+// ordered keys makes cheap
+const EXACT_COUNT_BELOW: u64 = 10_000;
+
+// Finding the newest keys scans the whole store, this bounds the result it keeps
+const MAX_RECENT_KEYS: usize = 1000;
+
+// The JSON type of a value, named like `GET /{namespace}/{key}/type` reports it
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    Object,
+    Array,
+    String,
+    Number,
+    Boolean,
+    Null,
+}
+
+impl ValueType {
+    fn matches(self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (ValueType::Object, Value::Object(_))
+                | (ValueType::Array, Value::Array(_))
+                | (ValueType::String, Value::String(_))
+                | (ValueType::Number, Value::Number(_))
+                | (ValueType::Boolean, Value::Bool(_))
+                | (ValueType::Null, Value::Null)
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct KV {
+    key: String,
+    // Dump lines name it `value`, so a dump can be diffed like a snapshot
+    #[serde(alias = "value")]
+    data: Value,
+}
+
+// A write to a key as seen by watchers
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+pub enum Change {
+    Put { key: String, value: Value },
+    Delete { key: String },
+}
+
+// The outcome of a batch write for each key, in the order they were given
+pub type BatchResults = Vec<(String, Result<(), Box<dyn Error>>)>;
+
+// What a conditional write requires of the key's current state
+#[derive(Debug, Clone, Copy)]
+pub enum Precondition {
+    // The key is at this version, 0 meaning it must not exist
+    Version(u64),
+    // The key was not written after this many seconds since the epoch, or
+    // does not exist
+    UnmodifiedSince(u64),
+}
+
+pub struct KVStore {
+    pub store: Arc<Store>,
+    maintenance: Arc<AtomicBool>,
+    used_bytes: Arc<AtomicU64>,
+    namespaces: Arc<NamespaceUsage>,
+    dirty_keys: Arc<Mutex<HashSet<String>>>,
+    last_flush: Arc<AtomicU64>,
+    flusher: Arc<Flusher>,
+    keys: Arc<KeyGenerator>,
+    bloom: Arc<BloomFilter>,
+    index: Arc<FieldIndex>,
+    values: Arc<ValuePool>,
+    sample: Arc<KeySample>,
+    locks: Arc<KeyLocks>,
+    changes: tokio::sync::broadcast::Sender<Change>,
+    // Tells ETags of this run apart from those of earlier runs, since the store
+    // generation starts over on every start
+    boot_id: String,
+    config: Config,
+}
+
+impl KVStore {
+    pub fn new(config: &Config) -> Self {
+
+        info!("Starting in-memory key-value store");
+
+        let store = Arc::new(Store::new(Map::new()));
+        let dirty_keys = Arc::new(Mutex::new(HashSet::new()));
+        let last_flush = Arc::new(AtomicU64::new(0));
+
+        let kvs = KVStore {
+            flusher: Arc::new(Flusher::new(store.clone(), dirty_keys.clone(), last_flush.clone(), config)),
+            store,
+            maintenance: Arc::new(AtomicBool::new(false)),
+            used_bytes: Arc::new(AtomicU64::new(0)),
+            namespaces: Arc::new(NamespaceUsage::new()),
+            dirty_keys,
+            last_flush,
+            keys: Arc::new(KeyGenerator::new(config.key_strategy)),
+            bloom: Arc::new(BloomFilter::new(config.bloom_filter_size)),
+            index: Arc::new(FieldIndex::new(config.indexed_fields.iter())),
+            values: Arc::new(ValuePool::new(config.dedup_values)),
+            sample: Arc::new(KeySample::new(KEY_SAMPLE_RATE)),
+            locks: Arc::new(KeyLocks::new()),
+            changes: tokio::sync::broadcast::channel(CHANGE_BACKLOG).0,
+            boot_id: Self::generate_random_string(8),
+            config: config.clone(),
+        };
+        if config.persistence == Persistence::Off {
+            info!("Persistence is off, the store is kept in memory only");
+            return kvs;
+        }
+        if config.read_only && fs::metadata(&config.db_path).is_err() {
+            warn!("Read-only mode and no data file at {}, serving an empty store", config.db_path);
+            return kvs;
+        }
+        {
+            let loaded = read_kvstore(&config.db_path, config.duplicate_keys).unwrap();
+
+            let mut store = kvs.store.write();
+            *store = loaded;
+            kvs.index_loaded(&mut store);
+        }
+        kvs
+    }
+
+    fn generate_random_string(key_length: usize) -> String {
+        let rng = thread_rng();
+
+        let chars: Vec<char> = rng
+            .sample_iter(&Alphanumeric)
+            .map(|x| x as char)
+            .take(key_length)
+            .collect();
+        chars.into_iter().collect()
+    }
+
+    // Recomputes the byte counts, the bloom filter, the key sample, the field
+    // indexes and the shared values for a map read from disk
+    fn index_loaded(&self, kvs: &mut Map) {
+        let used_bytes = kvs.iter().map(|(key, entry)| entry_size(key, &entry.value)).sum();
+        self.used_bytes.store(used_bytes, Ordering::SeqCst);
+
+        self.namespaces.clear();
+        for (key, entry) in kvs.iter() {
+            if let Some(namespace) = &entry.namespace {
+                self.namespaces.add(namespace, entry_size(key, &entry.value));
+            }
+        }
+
+        self.bloom.clear();
+        self.sample.clear();
+        for key in kvs.keys() {
+            self.bloom.insert(key);
+            self.sample.insert(key);
+        }
+
+        if self.index.is_enabled() {
+            self.index.clear();
+            for (key, entry) in kvs.iter() {
+                self.index.insert(key, &decode_value(&entry.value));
+            }
+        }
+
+        if self.values.is_enabled() {
+            self.values.clear();
+            for entry in kvs.values_mut() {
+                entry.value = self.values.acquire(entry.value.clone());
+            }
+        }
+    }
+
+    pub fn set_maintenance(&self, on: bool) {
+        self.maintenance.store(on, Ordering::SeqCst);
+
+        if on {
+            warn!("Maintenance mode enabled, writes are disabled");
+        } else {
+            info!("Maintenance mode disabled, writes are enabled");
+        }
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::SeqCst)
+    }
+
+    async fn persist(&self) {
+        if self.config.persistence == Persistence::Off || self.config.read_only {
+            return;
+        }
+
+        self.flusher.persist().await;
+    }
+
+    fn mark_dirty(&self, key: &str) {
+        if self.config.persistence == Persistence::Off {
+            return;
+        }
+
+        let mut dirty_keys = self.dirty_keys.lock().unwrap();
+
+        if !dirty_keys.contains(key) {
+            dirty_keys.insert(key.to_string());
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), Box<dyn Error>> {
+        if self.config.read_only {
+            warn!("Write rejected - read-only mode");
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::Forbidden,
+                "Store is read-only, writes are disabled",
+            )));
+        }
+        if self.in_maintenance() {
+            warn!("Write rejected - maintenance mode");
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::Unavailable,
+                "Store is in maintenance mode, writes are disabled",
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_key(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        if key.len() > self.config.max_key_len {
+            warn!("Write rejected - key of {} bytes is longer than {}", key.len(), self.config.max_key_len);
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::InvalidInput,
+                &format!("Key is longer than {} bytes", self.config.max_key_len),
+            )));
+        }
+
+        // The denylist wins, a key matching both patterns is rejected
+        let denied = self.config.key_deny.as_ref().is_some_and(|deny| deny.is_match(key));
+        let allowed = self.config.key_allow.as_ref().is_none_or(|allow| allow.is_match(key));
+
+        if denied || !allowed {
+            warn!("Write rejected - key is not allowed: {}", key);
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::Forbidden,
+                &format!("Key is not allowed: {}", key),
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Every write of a value passes through here, so it also checks the key.
+    // Writes without a namespace count against the one the key already has.
+    fn check_quota(&self, kvs: &Map, namespace: Option<&str>, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        self.check_key(key)?;
+
+        let namespace = namespace.filter(|namespace| !namespace.is_empty());
+
+        // The legacy data file separates fields with `|`
+        if namespace.is_some_and(|namespace| namespace.contains('|')) {
+            warn!("Write rejected - namespace contains |");
+            return Err(Box::new(KVStoreError::with_kind(ErrorKind::InvalidInput, "Namespace may not contain |")));
+        }
+
+        let existing = kvs.get(key);
+
+        if let Some(namespace) = namespace {
+            self.check_namespace_count(namespace, existing)?;
+        }
+
+        if let Some(namespace) = namespace.or_else(|| existing.and_then(|existing| existing.namespace.as_deref())) {
+            self.check_namespace_quota(namespace, existing, key, value)?;
+        }
+
+        if let Some(max_keys) = self.config.max_keys {
+            if existing.is_none() && kvs.len() >= max_keys {
+                return Err(key_quota_exceeded(max_keys));
+            }
+        }
+
+        if let Some(max_bytes) = self.config.max_bytes {
+            let used_bytes = self.used_bytes.load(Ordering::SeqCst)
+                - existing.map(|existing| entry_size(key, &existing.value)).unwrap_or(0)
+                + entry_size(key, value);
+
+            if used_bytes > max_bytes {
+                return Err(storage_quota_exceeded(max_bytes));
+            }
+        }
+
+        Ok(())
+    }
+
+    // A namespace is added by the first key written to it, unless that key is
+    // the last one of the namespace it leaves
+    fn check_namespace_count(&self, namespace: &str, existing: Option<&Entry>) -> Result<(), Box<dyn Error>> {
+        let max_namespaces = match self.config.max_namespaces {
+            Some(max_namespaces) => max_namespaces,
+            None => return Ok(()),
+        };
+
+        if self.namespaces.get(namespace).keys > 0 {
+            return Ok(());
+        }
+
+        let leaves_one = existing
+            .and_then(|existing| existing.namespace.as_deref())
+            .is_some_and(|previous| self.namespaces.get(previous).keys == 1);
+
+        if self.namespaces.count() - usize::from(leaves_one) >= max_namespaces {
+            warn!("Write rejected - namespace quota of {} exceeded by {}", max_namespaces, namespace);
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::QuotaExceeded,
+                &format!("Namespace quota exceeded: limit is {} namespaces", max_namespaces),
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn check_namespace_quota(
+        &self,
+        namespace: &str,
+        existing: Option<&Entry>,
+        key: &str,
+        value: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let quota = match self.config.namespace_quotas.get(namespace) {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+
+        let usage = self.namespaces.get(namespace);
+
+        // A key of another namespace is new to this one
+        let existing = existing.filter(|existing| existing.namespace.as_deref() == Some(namespace));
+
+        if let Some(max_keys) = quota.max_keys {
+            if existing.is_none() && usage.keys >= max_keys {
+                return Err(namespace_key_quota_exceeded(namespace, max_keys));
+            }
+        }
+
+        if let Some(max_bytes) = quota.max_bytes {
+            let used_bytes = usage.bytes
+                - existing.map(|existing| entry_size(key, &existing.value)).unwrap_or(0)
+                + entry_size(key, value);
+
+            if used_bytes > max_bytes {
+                return Err(namespace_storage_quota_exceeded(namespace, max_bytes));
+            }
+        }
+
+        Ok(())
+    }
+
+    // `check_quota` looks at one write against the store as it is, so a batch
+    // that writes all of `entries` under `namespace` together is also checked
+    // against the quotas with every new key and byte of it added up
+    fn check_batch_quota(&self, kvs: &Map, namespace: &str, entries: &[(String, Entry)]) -> Result<(), Box<dyn Error>> {
+        // The last write to a key listed twice is the one that is kept
+        let entries: BTreeMap<&str, &Entry> = entries.iter().map(|(key, entry)| (key.as_str(), entry)).collect();
+
+        let (mut new_keys, mut added_bytes, mut removed_bytes) = (0, 0, 0);
+        let (mut new_namespace_keys, mut removed_namespace_bytes) = (0, 0);
+
+        for (key, entry) in &entries {
+            added_bytes += entry_size(key, &entry.value);
+
+            match kvs.get(*key) {
+                Some(existing) => {
+                    removed_bytes += entry_size(key, &existing.value);
+
+                    if existing.namespace.as_deref() == Some(namespace) {
+                        removed_namespace_bytes += entry_size(key, &existing.value);
+                    } else {
+                        new_namespace_keys += 1;
+                    }
+                }
+                None => {
+                    new_keys += 1;
+                    new_namespace_keys += 1;
+                }
+            }
+        }
+
+        if let Some(max_keys) = self.config.max_keys {
+            if kvs.len() + new_keys > max_keys {
+                return Err(key_quota_exceeded(max_keys));
+            }
+        }
+
+        if let Some(max_bytes) = self.config.max_bytes {
+            if self.used_bytes.load(Ordering::SeqCst) + added_bytes - removed_bytes > max_bytes {
+                return Err(storage_quota_exceeded(max_bytes));
+            }
+        }
+
+        if let Some(quota) = self.config.namespace_quotas.get(namespace) {
+            let usage = self.namespaces.get(namespace);
+
+            if let Some(max_keys) = quota.max_keys {
+                if usage.keys + new_namespace_keys > max_keys {
+                    return Err(namespace_key_quota_exceeded(namespace, max_keys));
+                }
+            }
+
+            if let Some(max_bytes) = quota.max_bytes {
+                if usage.bytes + added_bytes - removed_namespace_bytes > max_bytes {
+                    return Err(namespace_storage_quota_exceeded(namespace, max_bytes));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // A write without a namespace, or with the empty one that batch routes
+    // default to, leaves the key in the one it has or the one the entry was
+    // copied with
+    fn put_entry(&self, kvs: &mut Map, namespace: Option<&str>, key: String, mut entry: Entry) -> Option<Entry> {
+        let key_length = key.len();
+        let namespace = namespace.filter(|namespace| !namespace.is_empty());
+
+        entry.updated_at = Some(now_millis());
+        entry.version = kvs.get(&key).map(|previous| previous.version).unwrap_or(0) + 1;
+
+        let previous_namespace = kvs.get(&key).and_then(|previous| previous.namespace.clone());
+
+        entry.namespace = match (namespace, previous_namespace) {
+            (Some(namespace), Some(previous)) if *previous == *namespace => Some(previous),
+            (Some(namespace), _) => Some(namespace.into()),
+            (None, previous) => entry.namespace.or(previous),
+        };
+
+        if let Some(namespace) = &entry.namespace {
+            self.namespaces.add(namespace, entry_size(&key, &entry.value));
+        }
+
+        self.mark_dirty(&key);
+
+        self.used_bytes.fetch_add(entry_size(&key, &entry.value), Ordering::SeqCst);
+
+        match kvs.get(&key) {
+            Some(previous) if self.index.is_enabled() => self.index.remove(&key, &decode_value(&previous.value)),
+            Some(_) => {}
+            None => {
+                self.bloom.insert(&key);
+                self.sample.insert(&key);
+            }
+        }
+
+        if self.index.is_enabled() {
+            self.index.insert(&key, &decode_value(&entry.value));
+        }
+
+        entry.value = self.values.acquire(entry.value);
+
+        self.publish(|| Change::Put {
+            key: key.clone(),
+            value: decode_value(&entry.value),
+        });
+
+        let previous = kvs.insert(key, entry);
+
+        if let Some(previous) = &previous {
+            self.used_bytes.fetch_sub((key_length + previous.value.len()) as u64, Ordering::SeqCst);
+            if let Some(namespace) = &previous.namespace {
+                self.namespaces.remove(namespace, (key_length + previous.value.len()) as u64);
+            }
+            self.values.release(&previous.value);
+        }
+
+        previous
+    }
+
+    fn remove_entry(&self, kvs: &mut Map, key: &str) -> Option<Entry> {
+        let previous = kvs.remove(key);
+
+        if let Some(previous) = &previous {
+            self.mark_dirty(key);
+            self.publish(|| Change::Delete { key: key.to_string() });
+            self.bloom.remove(key);
+            self.sample.remove(key);
+            if self.index.is_enabled() {
+                self.index.remove(key, &decode_value(&previous.value));
+            }
+            self.values.release(&previous.value);
+            self.used_bytes.fetch_sub(entry_size(key, &previous.value), Ordering::SeqCst);
+            if let Some(namespace) = &previous.namespace {
+                self.namespaces.remove(namespace, entry_size(key, &previous.value));
+            }
+        }
+
+        previous
+    }
+
+    // Values are only decoded for the change when someone is watching
+    fn publish(&self, change: impl FnOnce() -> Change) {
+        if self.changes.receiver_count() > 0 {
+            _ = self.changes.send(change());
+        }
+    }
+
+    // Changes with every write to the store. Keys that expire only change it
+    // once the sweeper removes them.
+    pub fn etag(&self) -> String {
+        format!("\"{}-{}\"", self.boot_id, self.store.generation())
+    }
+
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+
+    pub fn document_count(&self) -> usize {
+        self.store.read().len()
+    }
+
+    // The lock every writer of `key` takes, for tests of what waits on it
+    #[cfg(test)]
+    pub fn lock_key(&self, key: &str) -> std::sync::MutexGuard<'_, ()> {
+        self.locks.lock(key)
+    }
+
+    pub async fn stats(&self) -> Value {
+        let documents = self.store.read().len();
+        let dirty_keys = self.dirty_keys.lock().unwrap().len();
+        let last_flush = self.last_flush.load(Ordering::SeqCst);
+        let (unique_values, saved_bytes) = self.values.usage();
+
+        serde_json::json!({
+            "documents": documents,
+            "maintenance": self.in_maintenance(),
+            "read_only": self.config.read_only,
+            "usage": {
+                "keys": documents,
+                "bytes": self.used_bytes.load(Ordering::SeqCst),
+                "max_keys": self.config.max_keys,
+                "max_bytes": self.config.max_bytes,
+                "namespace_count": self.namespaces.count(),
+                "max_namespaces": self.config.max_namespaces,
+                "namespaces": self.namespace_usage(),
+            },
+            "dedup": self.values.is_enabled().then(|| serde_json::json!({
+                "unique_values": unique_values,
+                "saved_bytes": saved_bytes,
+            })),
+            "last_flush_ts": (last_flush > 0).then_some(last_flush),
+            "dirty_keys_since_flush": dirty_keys,
+            "pending_flush": dirty_keys > 0,
+        })
+    }
+
+    // Usage of every namespace holding keys or having a quota, against its quota
+    fn namespace_usage(&self) -> Value {
+        let usage = self.namespaces.snapshot();
+
+        let mut namespaces: BTreeMap<&str, Value> = BTreeMap::new();
+
+        for namespace in usage.keys().map(|namespace| &**namespace).chain(self.config.namespace_quotas.names()) {
+            let used = usage.get(namespace).copied().unwrap_or_default();
+            let quota = self.config.namespace_quotas.get(namespace).unwrap_or_default();
+
+            namespaces.insert(namespace, serde_json::json!({
+                "keys": used.keys,
+                "bytes": used.bytes,
+                "max_keys": quota.max_keys,
+                "max_bytes": quota.max_bytes,
+            }));
+        }
+
+        serde_json::json!(namespaces)
+    }
+
+    // Who is on the store locks right now, where the wait histograms in the
+    // metrics only show how long past waits took
+    pub fn lock_snapshot(&self) -> Value {
+        serde_json::json!({
+            "write": self.store.write_lock_state(),
+            "flush": self.store.flush_lock_state(),
+        })
+    }
+
+    pub async fn metrics(&self) -> String {
+        let mut metrics = String::new();
+
+        self.store.write_lock_wait.render(
+            &mut metrics,
+            "distkv_write_lock_wait_seconds",
+            "Time spent waiting for the store write lock",
+        );
+        self.store.flush_lock_wait.render(
+            &mut metrics,
+            "distkv_flush_lock_wait_seconds",
+            "Time spent waiting for the data file flush lock",
+        );
+
+        render_counter(
+            &mut metrics,
+            "distkv_key_collisions_total",
+            "Generated keys that were already taken",
+            self.keys.collisions(),
+        );
+        render_counter(
+            &mut metrics,
+            "distkv_flushes_total",
+            "Writes of the data file",
+            self.flusher.flushes(),
+        );
+        render_counter(
+            &mut metrics,
+            "distkv_value_encodes_total",
+            "Values encoded for the data file",
+            self.flusher.encodes(),
+        );
+
+        metrics
+    }
+
+    pub async fn size_histogram(&self) -> Value {
+        let kvs = self.store.read();
+        let now = now();
+
+        let mut buckets = [0u64; SIZE_BUCKETS.len()];
+
+        for entry in kvs.values().filter(|entry| entry.is_live(now)) {
+            let size = decoded_len(&entry.value);
+            let bucket = SIZE_BUCKETS.iter().position(|(_, limit)| size < *limit).unwrap();
+            buckets[bucket] += 1;
+        }
+
+        let buckets: serde_json::Map<String, Value> = SIZE_BUCKETS
+            .iter()
+            .zip(buckets)
+            .map(|((name, _), count)| (name.to_string(), Value::from(count)))
+            .collect();
+
+        serde_json::json!({ "buckets": buckets })
+    }
+
+    // Also returns how many keys were generated before a free one was found.
+    // Without a `ttl_seconds` the namespace's default TTL applies, 0 means none.
+    pub async fn create_document(
+        &self,
+        namespace: String,
+        value: Value,
+        ttl_seconds: Option<u64>,
+    ) -> Result<(Value, u32), Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let expires_at = self.expiry_for(&namespace, ttl_seconds)?;
+
+        let mut attempts = 0;
+
+        let key = {
+            let (key, _key) = loop {
+                let key = self.keys.generate();
+                let lock = self.locks.lock(&key);
+                attempts += 1;
+
+                if !self.store.read().contains_key(&key) {
+                    break (key, lock);
+                }
+
+                warn!("Generated key {} is already taken, retrying", key);
+                self.keys.record_collision();
+            };
+
+            let mut kvs = self.store.write();
+
+            let string_value = serde_json::to_string(&value).unwrap();
+    
+            let encoded_value = base64::encode(string_value);
+
+            self.check_quota(&kvs, Some(&namespace), &key, &encoded_value)?;
+
+            let mut entry = Entry::new(encoded_value);
+            entry.expires_at = expires_at;
+
+            self.put_entry(&mut kvs, Some(&namespace), key.to_string(), entry);
+
+            key
+        };
+
+        self.persist().await;
+
+        info!("Document created: {}", key);
+
+        Ok((serde_json::json!(KV { key, data: value }), attempts))
+    }
+
+    pub async fn create_document_with_key(
+        &self,
+        namespace: String,
+        key: String,
+        value: Value,
+        ttl_seconds: Option<u64>,
+    ) -> Result<String, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let expires_at = self.expiry_for(&namespace, ttl_seconds)?;
+
+        {
+            let _key = self.locks.lock(&key);
+            let mut kvs = self.store.write();
+
+            if live_entry(&kvs, &key).is_some() {
+                return Err(Box::new(KVStoreError::with_kind(
+                    ErrorKind::Conflict,
+                    &format!("Document already exists with key: {}", key),
+                )));
+            }
+
+            let string_value = serde_json::to_string(&value).unwrap();
+    
+            let encoded_value = base64::encode(string_value);
+
+            self.check_quota(&kvs, Some(&namespace), &key, &encoded_value)?;
+
+            let mut entry = Entry::new(encoded_value);
+            entry.expires_at = expires_at;
+
+            self.put_entry(&mut kvs, Some(&namespace), key.to_string(), entry);
+        }
+
+        self.persist().await;
+
+        info!("Document created: {}", key);
+
+        Ok(format!("Document created: {}", key))
+    }
+
+    // Writes the document only if `precondition` holds for the key, returning
+    // the new version. When it does not, nothing is written and the inner
+    // error holds the current version, 0 if the key does not exist.
+    pub async fn put_if(
+        &self,
+        namespace: String,
+        key: String,
+        value: Value,
+        precondition: Precondition,
+    ) -> Result<Result<u64, u64>, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let default_expiry = self.expiry_for(&namespace, None)?;
+
+        let version = {
+            let _key = self.locks.lock(&key);
+            let snapshot = self.store.read();
+
+            let existing = live_entry(&snapshot, &key);
+            let current = existing.map(|entry| entry.version).unwrap_or(0);
+
+            match precondition {
+                Precondition::Version(expected) if current != expected => {
+                    warn!("Version mismatch for {}: expected {}, found {}", key, expected, current);
+                    return Ok(Err(current));
+                }
+                // Modification times are kept in milliseconds but compared at
+                // the second precision of HTTP dates
+                Precondition::UnmodifiedSince(since)
+                    if existing
+                        .and_then(|entry| entry.updated_at)
+                        .is_some_and(|updated_at| updated_at / 1000 > since) =>
+                {
+                    warn!("Document {} was modified after {}", key, since);
+                    return Ok(Err(current));
+                }
+                _ => {}
+            }
+
+            let encoded_value = base64::encode(serde_json::to_string(&value).unwrap());
+
+            let mut entry = Entry::new(encoded_value);
+            entry.expires_at = existing.and_then(|entry| entry.expires_at).or(default_expiry);
+
+            let mut kvs = self.store.write();
+
+            self.check_quota(&kvs, Some(&namespace), &key, &entry.value)?;
+
+            self.put_entry(&mut kvs, Some(&namespace), key.clone(), entry);
+
+            kvs[&key].version
+        };
+
+        self.persist().await;
+
+        info!("Document {} written at version {}", key, version);
+
+        Ok(Ok(version))
+    }
+
+    // Creates the key or replaces its value in one step, returning whether it
+    // was created. A replaced value keeps its expiry unless `ttl_seconds` is given.
+    pub async fn upsert(
+        &self,
+        namespace: String,
+        key: String,
+        value: Value,
+        ttl_seconds: Option<u64>,
+    ) -> Result<bool, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let expires_at = self.expiry_for(&namespace, ttl_seconds)?;
+
+        let created = {
+            let _key = self.locks.lock(&key);
+            let mut kvs = self.store.write();
+
+            let existing = live_entry(&kvs, &key);
+            let created = existing.is_none();
+
+            let encoded_value = base64::encode(serde_json::to_string(&value).unwrap());
+
+            let mut entry = Entry::new(encoded_value);
+            entry.expires_at = match (ttl_seconds, existing) {
+                (None, Some(existing)) => existing.expires_at,
+                _ => expires_at,
+            };
+
+            self.check_quota(&kvs, Some(&namespace), &key, &entry.value)?;
+
+            self.put_entry(&mut kvs, Some(&namespace), key.clone(), entry);
+
+            created
+        };
+
+        self.persist().await;
+
+        if created {
+            info!("Document created: {}", key);
+        } else {
+            info!("Document updated: {}", key);
+        }
+
+        Ok(created)
+    }
+
+    // When the key was last written in milliseconds since the epoch, unknown for
+    // documents written before this was tracked
+    pub async fn last_modified(&self, namespace: String, key: String) -> Option<u64> {
+
+        _ = namespace;
+
+        live_entry(&self.store.read(), &key).and_then(|entry| entry.updated_at)
+    }
+
+    // Waits until the key is at a version other than `version`, where 0 means
+    // missing, and returns its value and new version. `None` means nothing
+    // changed within the timeout, and a key that was deleted is not found.
+    pub async fn wait_for_change(
+        &self,
+        namespace: String,
+        key: String,
+        version: u64,
+        timeout: Duration,
+    ) -> Result<Option<(Value, u64)>, Box<dyn Error>> {
+
+        _ = namespace;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        // Subscribed before the first check, so a write right after it is not missed
+        let mut changes = self.subscribe();
+
+        loop {
+            let current = live_entry(&self.store.read(), &key).map(|entry| (decode_value(&entry.value), entry.version));
+
+            match current {
+                Some((value, current)) if current != version => return Ok(Some((value, current))),
+                None if version != 0 => {
+                    return Err(Box::new(KVStoreError::with_kind(
+                        ErrorKind::NotFound,
+                        &format!("Document not found: {}", key),
+                    )))
+                }
+                _ => {}
+            }
+
+            // Changes to other keys are skipped, and a lag means some change may
+            // have been missed, so the key is checked again either way
+            loop {
+                match tokio::time::timeout_at(deadline, changes.recv()).await {
+                    Err(_) | Ok(Err(RecvError::Closed)) => return Ok(None),
+                    Ok(Err(RecvError::Lagged(_))) => break,
+                    Ok(Ok(Change::Put { key: changed, .. } | Change::Delete { key: changed })) if changed == key => break,
+                    Ok(Ok(_)) => {}
+                }
+            }
+        }
+    }
+
+    pub async fn version(&self, namespace: String, key: String) -> Result<u64, Box<dyn Error>> {
+
+        _ = namespace;
+
+        match live_entry(&self.store.read(), &key) {
+            Some(entry) => Ok(entry.version),
+            None => Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::NotFound,
+                &format!("Document not found: {}", key),
+            ))),
+        }
+    }
+
+    pub async fn insert(&self, namespace: String, key: String, value: Value) -> Result<String, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let default_expiry = self.expiry_for(&namespace, None)?;
+        
+        let _key = self.locks.lock(&key);
+        let mut store = self.store.write();
+
+        let string_value = serde_json::to_string(&value).unwrap();
+
+        let encoded_value = base64::encode(string_value);
+
+        self.check_quota(&store, Some(&namespace), &key, &encoded_value)?;
+
+        info!("Document updated: {}", key);
+
+        let mut entry = Entry::new(encoded_value);
+        entry.expires_at = live_entry(&store, &key).and_then(|entry| entry.expires_at).or(default_expiry);
+
+        self.put_entry(&mut store, Some(&namespace), key.clone(), entry);
+
+        Ok(format!("Document updated: {}", key))
+    }
+
+    pub async fn get(&self, namespace: String, key: String) -> Result<Value, Box<dyn Error>> {
+        
+        _ = namespace;
+
+        let store = self.store.read();
+
+        let entry = match self.bloom.may_contain(&key).then(|| live_entry(&store, &key)).flatten() {
+            Some(entry) => entry,
+            None => {
+                warn!("Document not found: {}", key);
+                return Err(Box::new(KVStoreError::with_kind(
+                    ErrorKind::NotFound,
+                    format!("Document not found: {}", key).as_str(),
+                )));
+            }
+        };
+
+        info!("Grabbing key: {}", key);
+
+        let decoded_value = decode(entry.value.as_bytes()).unwrap();
+
+        let json_value: Value = serde_json::from_slice(&decoded_value).unwrap();
+
+        // info!("{}", serde_json::from_value::<String>(json_value.clone()).unwrap());
+
+        Ok(json_value)
+    }
+
+    fn stale_grace(&self) -> u64 {
+        self.config.stale_grace.map(|grace| grace.as_secs()).unwrap_or(0)
+    }
+
+    // The value of a key that expired within `DISTKV_STALE_GRACE_SECS`, for
+    // readers that would rather have an old value than none while it is
+    // written again
+    pub async fn get_stale(&self, namespace: String, key: String) -> Option<Value> {
+
+        _ = namespace;
+
+        let store = self.store.read();
+
+        let entry = store.get(&key).filter(|entry| entry.is_stale(now(), self.stale_grace()))?;
+
+        info!("Serving stale key: {}", key);
+
+        Some(decode_value(&entry.value))
+    }
+
+    pub async fn increment_field(
+        &self,
+        namespace: String,
+        key: String,
+        path: String,
+        by: f64,
+        filter: &impl WriteFilter,
+    ) -> Result<Value, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let default_expiry = self.expiry_for(&namespace, None)?;
+
+        let updated = self
+            .update_value(&namespace, &key, default_expiry, filter, |existing| {
+                let mut value = match existing {
+                    Some(entry) => decode_value(&entry.value),
+                    None => serde_json::json!({}),
+                };
+
+                let updated = increment_path(&mut value, &path, by).map_err(|message| {
+                    warn!("Increment error - {}: {}", key, message);
+                    KVStoreError::with_kind(ErrorKind::InvalidInput, &message)
+                })?;
+
+                Ok(Update::Write(value, updated))
+            })
+            .await?;
+
+        info!("Field {} incremented on {}", path, key);
+
+        Ok(serde_json::json!({ "value": updated }))
+    }
+
+    // Keeps only the elements of an array value from `start` to `stop`, both
+    // included and counted from the end when negative, and returns how many are
+    // left. A range outside the array leaves it empty.
+    pub async fn trim_list(
+        &self,
+        namespace: String,
+        key: String,
+        start: i64,
+        stop: i64,
+        filter: &impl WriteFilter,
+    ) -> Result<usize, Box<dyn Error>> {
+
+        _ = namespace;
+
+        self.check_writable()?;
+
+        // Trimming only shrinks the value, so it stays in the namespace it is in
+        let (trimmed, length) = self
+            .update_value("", &key, None, filter, |existing| {
+                let existing = match existing {
+                    Some(entry) => entry,
+                    None => {
+                        warn!("Trim error - Document not found: {}", key);
+                        return Err(not_found(&key));
+                    }
+                };
+
+                let mut items = match decode_value(&existing.value) {
+                    Value::Array(items) => items,
+                    _ => {
+                        warn!("Trim error - {} is not an array", key);
+                        return Err(not_an_array(&key));
+                    }
+                };
+
+                let length = items.len();
+                let range = trim_range(length, start, stop);
+
+                if range.len() == length {
+                    return Ok(Update::Keep((false, length)));
+                }
+
+                items.truncate(range.end);
+                items.drain(..range.start);
+
+                let length = items.len();
+
+                Ok(Update::Write(Value::Array(items), (true, length)))
+            })
+            .await?;
+
+        if trimmed {
+            info!("Document {} trimmed to {} items", key, length);
+        }
+
+        Ok(length)
+    }
+
+    // Adds members that are not in the array yet, or removes every occurrence of
+    // them, treating a missing key as an empty set. Adding to a missing key
+    // creates it.
+    pub async fn update_set(
+        &self,
+        namespace: String,
+        key: String,
+        members: Vec<Value>,
+        add: bool,
+        filter: &impl WriteFilter,
+    ) -> Result<Value, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let default_expiry = self.expiry_for(&namespace, None)?;
+
+        let (changed, size) = self
+            .update_value(&namespace, &key, default_expiry, filter, |existing| {
+                let mut items = match existing.map(|entry| decode_value(&entry.value)) {
+                    Some(Value::Array(items)) => items,
+                    Some(_) => {
+                        warn!("Set error - {} is not an array", key);
+                        return Err(not_an_array(&key));
+                    }
+                    None => Vec::new(),
+                };
+
+                let size = items.len();
+
+                if add {
+                    for member in &members {
+                        if !items.contains(member) {
+                            items.push(member.clone());
+                        }
+                    }
+                } else {
+                    items.retain(|item| !members.contains(item));
+                }
+
+                let changed = items.len().abs_diff(size);
+                let size = items.len();
+
+                if changed == 0 {
+                    return Ok(Update::Keep((changed, size)));
+                }
+
+                Ok(Update::Write(Value::Array(items), (changed, size)))
+            })
+            .await?;
+
+        if changed > 0 {
+            info!("{} {} members of {}", if add { "Added" } else { "Removed" }, changed, key);
+        }
+
+        Ok(serde_json::json!({ if add { "added" } else { "removed" }: changed, "size": size }))
+    }
+
+    // Whether the array holds `member` as a string, or as the JSON it parses as
+    // so numbers and booleans can be looked up too
+    pub async fn set_contains(&self, namespace: String, key: String, member: String) -> Result<bool, Box<dyn Error>> {
+
+        _ = namespace;
+
+        let snapshot = self.store.read();
+
+        let items = match live_entry(&snapshot, &key).map(|entry| decode_value(&entry.value)) {
+            Some(Value::Array(items)) => items,
+            Some(_) => {
+                warn!("Set error - {} is not an array", key);
+                return Err(not_an_array(&key));
+            }
+            None => return Ok(false),
+        };
+
+        let parsed = serde_json::from_str::<Value>(&member).ok();
+        let member = Value::String(member);
+
+        Ok(items.iter().any(|item| *item == member || Some(item) == parsed.as_ref()))
+    }
+
+    pub async fn get_many(&self, namespace: String, keys: Vec<&str>) -> Result<Value, Box<dyn Error>> {
+
+        _ = namespace;
+
+        check_multi_get(keys.len())?;
+
+        let store = self.store.read();
+
+        let documents: serde_json::Map<String, Value> = keys
+            .into_iter()
+            .filter(|key| self.bloom.may_contain(key))
+            .filter_map(|key| live_entry(&store, key).map(|entry| (key.to_string(), decode_value(&entry.value))))
+            .collect();
+
+        info!("Grabbing {} keys", documents.len());
+
+        Ok(Value::Object(documents))
+    }
+
+    // Like `get_many`, but the values come back in the order of `keys` with
+    // `null` for the keys that do not exist
+    pub async fn get_ordered(&self, keys: Vec<String>) -> Result<Value, Box<dyn Error>> {
+
+        check_multi_get(keys.len())?;
+
+        let store = self.store.read();
+
+        let values: Vec<Value> = keys
+            .iter()
+            .map(|key| {
+                Some(key)
+                    .filter(|key| self.bloom.may_contain(key))
+                    .and_then(|key| live_entry(&store, key))
+                    .map(|entry| decode_value(&entry.value))
+                    .unwrap_or(Value::Null)
+            })
+            .collect();
+
+        info!("Grabbing {} keys in order", values.len());
+
+        Ok(Value::Array(values))
+    }
+
+    // Whether each key exists, from one snapshot. No values are decoded, so
+    // there is no limit on the number of keys besides the request size.
+    pub async fn exists_many(&self, keys: Vec<String>) -> Value {
+        let store = self.store.read();
+
+        let existing: serde_json::Map<String, Value> = keys
+            .into_iter()
+            .map(|key| {
+                let exists = self.bloom.may_contain(&key) && live_entry(&store, &key).is_some();
+                (key, Value::Bool(exists))
+            })
+            .collect();
+
+        info!("Checked {} keys for existence", existing.len());
+
+        Value::Object(existing)
+    }
+
+    // Every value from the one snapshot loaded here, so a multi-key write lands
+    // either entirely or not at all in the result. The bloom filter is skipped
+    // since it tracks the live store, and a key deleted after the snapshot was
+    // loaded could otherwise go missing from it.
+    pub async fn snapshot_read(&self, keys: Vec<String>) -> Result<Value, Box<dyn Error>> {
+
+        check_multi_get(keys.len())?;
+
+        let store = self.store.read();
+
+        let values: serde_json::Map<String, Value> = keys
+            .into_iter()
+            .map(|key| {
+                let value = live_entry(&store, &key).map(|entry| decode_value(&entry.value));
+                (key, value.unwrap_or(Value::Null))
+            })
+            .collect();
+
+        info!("Read {} keys from one snapshot", values.len());
+
+        Ok(Value::Object(values))
+    }
+
+    // Stores `value` if the key is absent or the value beats the current one,
+    // the greater one when `greater` is set and the lesser one otherwise
+    pub async fn set_if_extreme(
+        &self,
+        namespace: String,
+        key: String,
+        value: Value,
+        greater: bool,
+        filter: &impl WriteFilter,
+    ) -> Result<Value, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let default_expiry = self.expiry_for(&namespace, None)?;
+
+        let number = match &value {
+            Value::Number(number) => number.clone(),
+            _ => {
+                warn!("Rejected non-numeric value for {}", key);
+                return Err(Box::new(KVStoreError::with_kind(ErrorKind::InvalidInput, "Value must be a number")));
+            }
+        };
+
+        let (changed, current) = self
+            .update_value(&namespace, &key, default_expiry, filter, |existing| {
+                let current = match existing.map(|entry| decode_value(&entry.value)) {
+                    Some(Value::Number(current)) => Some(current),
+                    Some(_) => {
+                        warn!("Compare error - {} is not a number", key);
+                        return Err(Box::new(KVStoreError::with_kind(
+                            ErrorKind::InvalidInput,
+                            &format!("Document is not a number: {}", key),
+                        )));
+                    }
+                    None => None,
+                };
+
+                let wanted = if greater { CmpOrdering::Greater } else { CmpOrdering::Less };
+
+                match current {
+                    Some(current) if compare_numbers(&number, &current) != wanted => Ok(Update::Keep((false, current))),
+                    _ => Ok(Update::Write(value.clone(), (true, number.clone()))),
+                }
+            })
+            .await?;
+
+        if changed {
+            info!("Document {} set to {}", key, current);
+        }
+
+        Ok(serde_json::json!({ "changed": changed, "value": current }))
+    }
+
+    pub async fn get_type(&self, namespace: String, key: String) -> Result<&'static str, Box<dyn Error>> {
+
+        _ = namespace;
+
+        let store = self.store.read();
+
+        match live_entry(&store, &key) {
+            Some(entry) => Ok(json_type(&decode_value(&entry.value))),
+            None => {
+                warn!("Document not found: {}", key);
+                Err(Box::new(KVStoreError::with_kind(
+                    ErrorKind::NotFound,
+                    &format!("Document not found: {}", key),
+                )))
+            }
+        }
+    }
+
+    // How much space the key takes in memory and in the data file. The file is
+    // rewritten whole on every flush, so a record has no fixed offset to report.
+    pub async fn storage(&self, namespace: String, key: String) -> Result<Value, Box<dyn Error>> {
+
+        _ = namespace;
+
+        let store = self.store.read();
+
+        let entry = match live_entry(&store, &key) {
+            Some(entry) => entry,
+            None => {
+                warn!("Document not found: {}", key);
+                return Err(Box::new(KVStoreError::with_kind(
+                    ErrorKind::NotFound,
+                    &format!("Document not found: {}", key),
+                )));
+            }
+        };
+
+        let threshold = self.config.compress_threshold;
+
+        let (format, record_bytes) = match self.flusher.format() {
+            DiskFormat::Legacy => ("legacy", legacy_line(&key, entry, threshold, false)?.0.len()),
+            DiskFormat::Cbor => {
+                let mut record = Vec::new();
+                cbor::write_record(&mut record, &key, entry, threshold, false)?;
+                ("cbor", record.len())
+            }
+        };
+
+        Ok(serde_json::json!({
+            "key": key,
+            "format": format,
+            "value_bytes": decoded_len(&entry.value),
+            "encoded_bytes": entry.value.len(),
+            "record_bytes": record_bytes,
+            "compressed": is_compressed(&entry.value, threshold),
+        }))
+    }
+
+    pub async fn exists(&self, namespace: String, key: String) -> bool {
+
+        _ = namespace;
+
+        self.bloom.may_contain(&key) && live_entry(&self.store.read(), &key).is_some()
+    }
+
+    pub async fn delete(&self, namespace: String, key: String) -> Result<String, Box<dyn Error>> {
+
+        _ = namespace;
+
+        self.check_writable()?;
+
+        {
+            let _key = self.locks.lock(&key);
+            let mut store = self.store.write();
+
+            if live_entry(&store, &key).is_none() {
+                warn!("Delete error - Document not found: {}", key);
+                return Err(Box::new(KVStoreError::with_kind(
+                    ErrorKind::NotFound,
+                    &format!("Document not found: {}", key),
+                )));
+            }
+
+            if self.config.soft_delete_window.is_some() {
+                store.get_mut(&key).unwrap().deleted_at = Some(now());
+                self.mark_dirty(&key);
+                self.publish(|| Change::Delete { key: key.clone() });
+            } else {
+                self.remove_entry(&mut store, &key);
+            }
+        }
+
+        self.persist().await;
+
+        info!("Document deleted: {}", key);
+
+        Ok(format!("Document deleted: {}", key))
+    }
+
+    // Deletes the key like `delete` and returns the value it held, so no other
+    // reader can see the value once it was taken
+    pub async fn take(&self, namespace: String, key: String) -> Result<Value, Box<dyn Error>> {
+
+        _ = namespace;
+
+        self.check_writable()?;
+
+        let value = {
+            let _key = self.locks.lock(&key);
+            let mut store = self.store.write();
+
+            let value = match live_entry(&store, &key) {
+                Some(entry) => decode_value(&entry.value),
+                None => {
+                    warn!("Take error - Document not found: {}", key);
+                    return Err(not_found(&key));
+                }
+            };
+
+            if self.config.soft_delete_window.is_some() {
+                store.get_mut(&key).unwrap().deleted_at = Some(now());
+                self.mark_dirty(&key);
+                self.publish(|| Change::Delete { key: key.clone() });
+            } else {
+                self.remove_entry(&mut store, &key);
+            }
+
+            value
+        };
+
+        self.persist().await;
+
+        info!("Document taken: {}", key);
+
+        Ok(value)
+    }
+
+    pub async fn undelete(&self, namespace: String, key: String) -> Result<String, Box<dyn Error>> {
+
+        _ = namespace;
+
+        self.check_writable()?;
+
+        {
+            let _key = self.locks.lock(&key);
+            let mut store = self.store.write();
+
+            let restorable = match (store.get(&key), self.config.soft_delete_window) {
+                (Some(entry), Some(window)) => entry
+                    .deleted_at
+                    .is_some_and(|deleted_at| deleted_at + window.as_secs() > now() && !entry.is_expired(now())),
+                _ => false,
+            };
+
+            if !restorable {
+                warn!("Undelete error - No deleted document: {}", key);
+                return Err(Box::new(KVStoreError::with_kind(
+                    ErrorKind::NotFound,
+                    &format!("No deleted document found: {}", key),
+                )));
+            }
+
+            store.get_mut(&key).unwrap().deleted_at = None;
+            self.mark_dirty(&key);
+            self.publish(|| Change::Put {
+                key: key.clone(),
+                value: decode_value(&store[&key].value),
+            });
+        }
+
+        self.persist().await;
+
+        info!("Document restored: {}", key);
+
+        Ok(format!("Document restored: {}", key))
+    }
+
+    pub async fn move_document(
+        &self,
+        namespace: String,
+        key: String,
+        to: String,
+        overwrite: bool,
+        filter: &impl WriteFilter,
+    ) -> Result<String, Box<dyn Error>> {
+
+        _ = namespace;
+
+        self.check_writable()?;
+
+        let filtered = self.filter_copies(filter, &[(&key, &to)]).await?;
+
+        {
+            let _keys = self.locks.lock_many([key.as_str(), to.as_str()]);
+            let mut kvs = self.store.write();
+
+            if live_entry(&kvs, &key).is_none() {
+                warn!("Move error - Document not found: {}", key);
+                return Err(Box::new(KVStoreError::with_kind(
+                    ErrorKind::NotFound,
+                    &format!("Document not found: {}", key),
+                )));
+            }
+
+            if let Some(filtered) = &filtered {
+                filtered[0].check(&key, live_entry(&kvs, &key))?;
+            }
+
+            self.check_key(&to)?;
+
+            if !overwrite && live_entry(&kvs, &to).is_some() {
+                warn!("Move error - Document already exists with key: {}", to);
+                return Err(Box::new(KVStoreError::with_kind(
+                    ErrorKind::Conflict,
+                    &format!("Document already exists with key: {}", to),
+                )));
+            }
+
+            let mut entry = self.remove_entry(&mut kvs, &key).unwrap();
+
+            if let Some(filtered) = &filtered {
+                entry = filtered[0].rewrite(entry);
+            }
+
+            self.put_entry(&mut kvs, None, to.clone(), entry);
+        }
+
+        self.persist().await;
+
+        info!("Document moved: {} -> {}", key, to);
+
+        Ok(format!("Document moved: {} -> {}", key, to))
+    }
+
+    pub async fn copy_document(
+        &self,
+        namespace: String,
+        key: String,
+        to: String,
+        overwrite: bool,
+        filter: &impl WriteFilter,
+    ) -> Result<String, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let filtered = self.filter_copies(filter, &[(&key, &to)]).await?;
+
+        {
+            let _keys = self.locks.lock_many([key.as_str(), to.as_str()]);
+            let mut kvs = self.store.write();
+
+            if let Some(filtered) = &filtered {
+                filtered[0].check(&key, live_entry(&kvs, &key))?;
+            }
+
+            let mut entry = match live_entry(&kvs, &key) {
+                Some(entry) => entry.clone(),
+                None => {
+                    warn!("Copy error - Document not found: {}", key);
+                    return Err(Box::new(KVStoreError::with_kind(
+                        ErrorKind::NotFound,
+                        &format!("Document not found: {}", key),
+                    )));
+                }
+            };
+
+            if !overwrite && live_entry(&kvs, &to).is_some() {
+                warn!("Copy error - Document already exists with key: {}", to);
+                return Err(Box::new(KVStoreError::with_kind(
+                    ErrorKind::Conflict,
+                    &format!("Document already exists with key: {}", to),
+                )));
+            }
+
+            if let Some(filtered) = &filtered {
+                entry = filtered[0].rewrite(entry);
+            }
+
+            self.check_quota(&kvs, Some(&namespace), &to, &entry.value)?;
+
+            self.put_entry(&mut kvs, Some(&namespace), to.clone(), entry);
+        }
+
+        self.persist().await;
+
+        info!("Document copied: {} -> {}", key, to);
+
+        Ok(format!("Document copied: {}", to))
+    }
+
+    // Moves every key starting with `from` to the same key starting with `to`
+    // instead, all in one write. Keys only change length, so the quotas are not
+    // checked again.
+    pub async fn rename_prefix(
+        &self,
+        from: String,
+        to: String,
+        overwrite: bool,
+        filter: &impl WriteFilter,
+    ) -> Result<Value, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        if from.is_empty() {
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::InvalidInput,
+                "The prefix to rename must not be empty",
+            )));
+        }
+
+        let renamed_key = |key: &str| format!("{}{}", to, &key[from.len()..]);
+
+        let filtered = if filter.is_active() {
+            let sources = live_prefix_keys(&self.store.read(), &from);
+            let renames: Vec<(String, String)> = sources.iter().map(|key| (key.clone(), renamed_key(key))).collect();
+
+            let copies: Vec<(&str, &str)> = renames.iter().map(|(key, target)| (key.as_str(), target.as_str())).collect();
+            self.filter_copies(filter, &copies).await?.map(|filtered| (sources, filtered))
+        } else {
+            None
+        };
+
+        let renamed = {
+            // The keys are only known once the store is read
+            let _keys = self.locks.lock_all();
+            let mut kvs = self.store.write();
+
+            let sources = live_prefix_keys(&kvs, &from);
+
+            if let Some((read, filtered)) = &filtered {
+                if *read != sources {
+                    return Err(changed(&from));
+                }
+
+                for (key, filtered) in sources.iter().zip(filtered) {
+                    filtered.check(key, live_entry(&kvs, key))?;
+                }
+            }
+
+            let renames: Vec<(String, String)> = sources.iter().map(|key| (key.clone(), renamed_key(key))).collect();
+
+            for (_, target) in &renames {
+                self.check_key(target)?;
+
+                // A target that is itself renamed is free by the time it is written
+                let taken = live_entry(&kvs, target).is_some() && !target.starts_with(&from);
+
+                if !overwrite && taken {
+                    warn!("Rename error - Document already exists with key: {}", target);
+                    return Err(Box::new(KVStoreError::with_kind(
+                        ErrorKind::Conflict,
+                        &format!("Document already exists with key: {}", target),
+                    )));
+                }
+            }
+
+            let entries: Vec<Entry> = sources.iter().filter_map(|key| self.remove_entry(&mut kvs, key)).collect();
+
+            for (position, ((_, target), mut entry)) in renames.iter().zip(entries).enumerate() {
+                if let Some((_, filtered)) = &filtered {
+                    entry = filtered[position].rewrite(entry);
+                }
+
+                self.put_entry(&mut kvs, None, target.clone(), entry);
+            }
+
+            renames.len()
+        };
+
+        if renamed > 0 {
+            self.persist().await;
+        }
+
+        info!("Renamed {} documents from {} to {}", renamed, from, to);
+
+        Ok(serde_json::json!({ "renamed": renamed }))
+    }
+
+    // Exchanges the values of two keys, each keeping its own expiry. Swapping
+    // two missing keys changes nothing, but a single missing key is an error.
+    pub async fn swap_documents(&self, a: String, b: String, filter: &impl WriteFilter) -> Result<Value, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let filtered = self.filter_copies(filter, &[(&b, &a), (&a, &b)]).await?;
+
+        {
+            let _keys = self.locks.lock_many([a.as_str(), b.as_str()]);
+            let mut kvs = self.store.write();
+
+            if let Some(filtered) = &filtered {
+                filtered[0].check(&b, live_entry(&kvs, &b))?;
+                filtered[1].check(&a, live_entry(&kvs, &a))?;
+            }
+
+            let (first, second) = match (live_entry(&kvs, &a), live_entry(&kvs, &b)) {
+                (Some(first), Some(second)) => (first.clone(), second.clone()),
+                (None, None) => {
+                    info!("Swap skipped, neither document exists: {} <-> {}", a, b);
+                    return Ok(serde_json::json!({ "swapped": false }));
+                }
+                (first, _) => {
+                    let missing = if first.is_none() { &a } else { &b };
+                    warn!("Swap error - Document not found: {}", missing);
+                    return Err(Box::new(KVStoreError::with_kind(
+                        ErrorKind::NotFound,
+                        &format!("Document not found: {}", missing),
+                    )));
+                }
+            };
+
+            let mut swapped_a = Entry::new(second.value);
+            swapped_a.expires_at = first.expires_at;
+
+            let mut swapped_b = Entry::new(first.value);
+            swapped_b.expires_at = second.expires_at;
+
+            if let Some(filtered) = &filtered {
+                swapped_a = filtered[0].rewrite(swapped_a);
+                swapped_b = filtered[1].rewrite(swapped_b);
+            }
+
+            self.put_entry(&mut kvs, None, a.clone(), swapped_a);
+            self.put_entry(&mut kvs, None, b.clone(), swapped_b);
+        }
+
+        self.persist().await;
+
+        info!("Documents swapped: {} <-> {}", a, b);
+
+        Ok(serde_json::json!({ "swapped": true }))
+    }
+
+    pub async fn expire_documents(&self, keys: Vec<String>, ttl_seconds: u64) -> Result<BatchResults, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let now = now();
+
+        let expiries = keys
+            .iter()
+            .map(|_| self.jittered_expiry(now, ttl_seconds))
+            .collect::<Result<Vec<u64>, _>>()?;
+
+        let mut results = BatchResults::new();
+        {
+            let _keys = self.locks.lock_many(keys.iter().map(String::as_str));
+            let mut kvs = self.store.write();
+
+            for (key, expires_at) in keys.into_iter().zip(expiries) {
+                let result = match live_entry(&kvs, &key) {
+                    Some(_) => {
+                        kvs.get_mut(&key).unwrap().expires_at = Some(expires_at);
+                        self.mark_dirty(&key);
+                        Ok(())
+                    }
+                    None => Err(not_found(&key)),
+                };
+
+                results.push((key, result));
+            }
+        }
+
+        let updated = results.iter().filter(|(_, result)| result.is_ok()).count();
+
+        if updated > 0 {
+            self.persist().await;
+        }
+
+        info!("Expiry set on {} documents, {} missing", updated, results.len() - updated);
+
+        Ok(results)
+    }
+
+    // Replaces or creates every document like `PATCH /{namespace}/{key}`, under
+    // one lock and one write to disk. A key that fails leaves the others written.
+    pub async fn put_documents(
+        &self,
+        namespace: String,
+        documents: Vec<(String, Value, Option<u64>)>,
+    ) -> Result<BatchResults, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let documents: Vec<_> = documents
+            .into_iter()
+            .map(|(key, value, ttl_seconds)| (key, value, self.expiry_for(&namespace, ttl_seconds)))
+            .collect();
+
+        let mut results = BatchResults::new();
+        {
+            let _keys = self.locks.lock_many(documents.iter().map(|(key, _, _)| key.as_str()));
+            let mut kvs = self.store.write();
+
+            for (key, value, expires_at) in documents {
+                let encoded_value = base64::encode(serde_json::to_string(&value).unwrap());
+
+                let result = expires_at.and_then(|expires_at| {
+                    self.check_quota(&kvs, Some(&namespace), &key, &encoded_value)?;
+
+                    let mut entry = Entry::new(encoded_value);
+                    entry.expires_at = expires_at;
+
+                    self.put_entry(&mut kvs, Some(&namespace), key.clone(), entry);
+                    Ok(())
+                });
+
+                results.push((key, result));
+            }
+        }
+
+        let written = results.iter().filter(|(_, result)| result.is_ok()).count();
+
+        if written > 0 {
+            self.persist().await;
+        }
+
+        info!("Batch put wrote {} documents, {} failed", written, results.len() - written);
+
+        Ok(results)
+    }
+
+    // Deletes every listed document like `DELETE /{namespace}/{key}`, under one
+    // lock and one write to disk
+    pub async fn delete_documents(&self, keys: Vec<String>) -> Result<BatchResults, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let mut results = BatchResults::new();
+        {
+            let _keys = self.locks.lock_many(keys.iter().map(String::as_str));
+            let mut kvs = self.store.write();
+
+            for key in keys {
+                let result = match live_entry(&kvs, &key) {
+                    Some(_) if self.config.soft_delete_window.is_some() => {
+                        kvs.get_mut(&key).unwrap().deleted_at = Some(now());
+                        self.mark_dirty(&key);
+                        self.publish(|| Change::Delete { key: key.clone() });
+                        Ok(())
+                    }
+                    Some(_) => {
+                        self.remove_entry(&mut kvs, &key);
+                        Ok(())
+                    }
+                    None => Err(not_found(&key)),
+                };
+
+                results.push((key, result));
+            }
+        }
+
+        let deleted = results.iter().filter(|(_, result)| result.is_ok()).count();
+
+        if deleted > 0 {
+            self.persist().await;
+        }
+
+        info!("Batch delete removed {} documents, {} missing", deleted, results.len() - deleted);
+
+        Ok(results)
+    }
+
+    // How many items a listing returns, the requested limit or the default one,
+    // never more than `list_max_limit`
+    fn list_limit(&self, limit: Option<usize>) -> usize {
+        limit.unwrap_or(self.config.list_default_limit).min(self.config.list_max_limit)
+    }
+
+    // When a written value expires: after `ttl_seconds` if given, otherwise after
+    // the namespace's default TTL if it has one, jittered like every TTL. A TTL
+    // of 0 never expires.
+    fn expiry_for(&self, namespace: &str, ttl_seconds: Option<u64>) -> Result<Option<u64>, Box<dyn Error>> {
+        match ttl_seconds.or_else(|| self.config.namespace_ttls.get(namespace)) {
+            Some(0) | None => Ok(None),
+            Some(ttl_seconds) => self.jittered_expiry(now(), ttl_seconds).map(Some),
+        }
+    }
+
+    // The expiry of a TTL set at `now`, spread randomly by up to `ttl_jitter_pct`
+    // percent either way so keys given the same TTL together do not all expire
+    // in the same sweep. The TTL is rejected if its latest expiry is out of
+    // range, whichever one is drawn.
+    fn jittered_expiry(&self, now: u64, ttl_seconds: u64) -> Result<u64, Box<dyn Error>> {
+        // At most the TTL itself, since `ttl_jitter_pct` is at most 100
+        let spread = (u128::from(ttl_seconds) * u128::from(self.config.ttl_jitter_pct) / 100) as u64;
+
+        let latest = ttl_seconds
+            .checked_add(spread)
+            .and_then(|longest| now.checked_add(longest))
+            .ok_or_else(|| ttl_out_of_range(ttl_seconds))?;
+
+        if spread == 0 {
+            return Ok(latest);
+        }
+
+        Ok(thread_rng().gen_range(latest - 2 * spread..=latest))
+    }
+
+    // Replaced values keep their expiry and created keys get the default TTL of
+    // `namespace`, like `PUT` with `if_version`
+    pub async fn compare_and_swap(
+        &self,
+        namespace: String,
+        operations: Vec<(String, Value, Value)>,
+        filter: &impl WriteFilter,
+    ) -> Result<Value, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let default_expiry = self.expiry_for(&namespace, None)?;
+
+        // The new values do not depend on the current ones, so they are filtered
+        // before anything is compared
+        let mut filtered = Vec::new();
+        for (key, expected, new) in operations {
+            let new = filter.filter(&key, new).await?;
+            filtered.push((key, expected, new));
+        }
+        let operations = filtered;
+
+        let keys: Vec<String> = operations.iter().map(|(key, _, _)| key.clone()).collect();
+        {
+            // Values are compared against the snapshot while the key locks hold
+            // off other writers of these keys
+            let _keys = self.locks.lock_many(keys.iter().map(String::as_str));
+            let snapshot = self.store.read();
+
+            let failed: Vec<&str> = operations
+                .iter()
+                .filter(|(key, expected, _)| {
+                    let current = live_entry(&snapshot, key).map(|entry| decode_value(&entry.value));
+                    current.as_ref().unwrap_or(&Value::Null) != expected
+                })
+                .map(|(key, _, _)| key.as_str())
+                .collect();
+
+            if !failed.is_empty() {
+                warn!("Compare-and-swap failed for keys: {}", failed.join(", "));
+                return Err(Box::new(KVStoreError::with_kind(
+                    ErrorKind::Conflict,
+                    &format!("Compare-and-swap failed for keys: {}", failed.join(", ")),
+                )));
+            }
+
+            let mut kvs = self.store.write();
+
+            let mut entries = Vec::new();
+            for (key, _, new) in operations {
+                let string_value = serde_json::to_string(&new).unwrap();
+
+                let mut entry = Entry::new(base64::encode(string_value));
+                entry.expires_at = live_entry(&kvs, &key).and_then(|entry| entry.expires_at).or(default_expiry);
+
+                self.check_quota(&kvs, Some(&namespace), &key, &entry.value)?;
+
+                entries.push((key, entry));
+            }
+
+            self.check_batch_quota(&kvs, &namespace, &entries)?;
+
+            for (key, entry) in entries {
+                self.put_entry(&mut kvs, Some(&namespace), key, entry);
+            }
+        }
+
+        self.persist().await;
+
+        info!("Compare-and-swap updated {} documents", keys.len());
+
+        Ok(serde_json::json!({ "updated": keys }))
+    }
+
+    pub async fn sweep_expired(&self) -> usize {
+        // Expired documents are already hidden from reads, and a read-only store
+        // must not change
+        if self.config.read_only {
+            return 0;
+        }
+
+        let (removed, _) = self.remove_expired().await;
+
+        removed
+    }
+
+    // Runs the sweep straight away, for reclaiming space before a backup
+    // without waiting for the sweeper. Returns how many documents were removed
+    // and how many bytes of keys and encoded values that freed.
+    pub async fn vacuum(&self) -> Result<Value, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let (removed, reclaimed_bytes) = self.remove_expired().await;
+
+        Ok(serde_json::json!({ "removed": removed, "reclaimed_bytes": reclaimed_bytes }))
+    }
+
+    async fn remove_expired(&self) -> (usize, u64) {
+        let now = now();
+
+        // No key locks are taken, a writer racing the sweep at worst brings
+        // back an expired key that the next sweep removes
+        let (removed, reclaimed_bytes) = {
+            let mut kvs = self.store.write();
+
+            let window = self.config.soft_delete_window.map(|window| window.as_secs()).unwrap_or(0);
+
+            // Expired keys are kept through their grace period to be served stale
+            let grace = self.stale_grace();
+
+            let expired: Vec<String> = kvs
+                .iter()
+                .filter(|(_, entry)| {
+                    entry.is_expired(now.saturating_sub(grace))
+                        || entry.deleted_at.is_some_and(|deleted_at| deleted_at + window <= now)
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+
+            let reclaimed_bytes: u64 = expired
+                .iter()
+                .filter_map(|key| self.remove_entry(&mut kvs, key).map(|entry| entry_size(key, &entry.value)))
+                .sum();
+
+            (expired.len(), reclaimed_bytes)
+        };
+
+        if removed > 0 {
+            self.persist().await;
+
+            info!("Removed {} expired or deleted documents ({} bytes)", removed, reclaimed_bytes);
+        }
+
+        (removed, reclaimed_bytes)
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct KV {
-    key: String,
-    data: Value,
-}
+    // Writes the data file in `format` from the store as it is, and keeps using
+    // that format for every later write
+    pub async fn rewrite(&self, format: DiskFormat) -> Result<Value, Box<dyn Error>> {
 
-pub struct KVStore {
-    pub store: Arc<Mutex<BTreeMap<String, String>>>,
-}
+        self.check_writable()?;
 
-impl KVStore {
-    pub fn new() -> Self {
+        if self.config.persistence == Persistence::Off {
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::InvalidInput,
+                "Persistence is off, there is no data file to rewrite",
+            )));
+        }
 
-        info!("Starting in-memory key-value store");
+        let previous = self.flusher.format();
 
-        let kvs = KVStore {
-            store: Arc::new(Mutex::new(BTreeMap::new())),
+        self.flusher.rewrite(format);
+
+        let bytes = fs::metadata(&self.config.db_path)?.len();
+
+        info!("Data file rewritten as {:?}, was {:?}", format, previous);
+
+        Ok(serde_json::json!({
+            "format": format,
+            "previous_format": previous,
+            "documents": self.document_count(),
+            "bytes": bytes,
+        }))
+    }
+
+    pub async fn fsck(&self) -> Result<Value, Box<dyn Error>> {
+        if self.config.persistence == Persistence::Off {
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::InvalidInput,
+                "Persistence is off, there is no data file to check",
+            )));
+        }
+
+        // Keeps a flush from rewriting the file while it is read
+        let _flush = self.store.flush_lock();
+
+        let report = check_file(&self.config.db_path)?;
+
+        info!("Checked data file {}: {}", self.config.db_path, report);
+
+        Ok(report)
+    }
+
+    // Replaces the store with the data file as it is on disk, for files managed
+    // outside the server. The file must pass the same checks as `fsck`, and
+    // writes not yet flushed are dropped.
+    pub async fn reload(&self) -> Result<Value, Box<dyn Error>> {
+        if self.config.persistence == Persistence::Off {
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::InvalidInput,
+                "Persistence is off, there is no data file to reload",
+            )));
+        }
+
+        let loaded = {
+            // Keeps a flush from rewriting the file while it is read
+            let _flush = self.store.flush_lock();
+
+            let report = check_file(&self.config.db_path)?;
+
+            if report["healthy"] != Value::Bool(true) {
+                warn!("Reload rejected, the data file has problems: {}", report);
+                return Err(Box::new(KVStoreError::with_kind(
+                    ErrorKind::InvalidInput,
+                    &format!("Data file has problems, not reloading: {}", report),
+                )));
+            }
+
+            read_kvstore(&self.config.db_path, self.config.duplicate_keys)?
         };
+
+        let count = loaded.len();
         {
-            read_kvstore(&kvs.store).unwrap();
+            let _keys = self.locks.lock_all();
+            let mut kvs = self.store.write();
+
+            *kvs = loaded;
+            self.index_loaded(&mut kvs);
+            self.dirty_keys.lock().unwrap().clear();
         }
-        kvs
-    }
 
-    fn generate_random_string(key_length: usize) -> String {
-        let rng = thread_rng();
+        warn!("Store reloaded from {}, {} documents", self.config.db_path, count);
 
-        let chars: Vec<char> = rng
-            .sample_iter(&Alphanumeric)
-            .map(|x| x as char)
-            .take(key_length)
-            .collect();
-        chars.into_iter().collect()
+        Ok(serde_json::json!({ "loaded": count }))
     }
 
-    pub async fn create_document(&self, namespace: String, value: Value) -> Result<String, Box<dyn Error>> {
+    pub async fn truncate(&self) -> Result<Value, Box<dyn Error>> {
 
-        _ = namespace;
+        self.check_writable()?;
 
-        let mut key = Self::generate_random_string(8);
-        {
-            let mut kvs = self.store.lock().unwrap();
+        let removed = {
+            let _keys = self.locks.lock_all();
+            let mut kvs = self.store.write();
 
-            while kvs.contains_key(&key) {
-                key = Self::generate_random_string(8);
+            let removed = std::mem::take(&mut *kvs);
+
+            for key in removed.keys() {
+                self.mark_dirty(key);
+                self.publish(|| Change::Delete { key: key.clone() });
             }
 
-            let string_value = serde_json::to_string(&value).unwrap();
-    
-            let encoded_value = base64::encode(string_value);
+            self.used_bytes.store(0, Ordering::SeqCst);
+            self.namespaces.clear();
+            self.bloom.clear();
+            self.index.clear();
+            self.values.clear();
+            self.sample.clear();
 
-            kvs.insert(key.to_string(), encoded_value);
-        }
+            removed
+        };
 
-        write_kvstore(&self.store).expect("Error writing to disk");
+        self.persist().await;
 
-        info!("Document created: {}", key);
+        self.remove_blobs(&removed).await;
 
-        Ok(format!("Document created: {}", key))
+        warn!("Store truncated, removed {} documents", removed.len());
+
+        Ok(serde_json::json!({ "removed": removed.len() }))
     }
 
-    pub async fn create_document_with_key(
+    pub async fn find_keys(
         &self,
-        namespace: String,
-        key: String,
-        value: Value,
-    ) -> Result<String, Box<dyn Error>> {
-
-        _ = namespace;
+        regex: Option<String>,
+        prefix: Option<String>,
+        value_type: Option<ValueType>,
+        limit: Option<u64>,
+    ) -> Result<Value, Box<dyn Error>> {
 
-        {
-            let mut kvs = self.store.lock().unwrap();
-            if kvs.contains_key(&key.to_string()) {
-                return Err(Box::new(KVStoreError::new(&format!("Document already exists with key: {}", key))));
+        let pattern = match regex {
+            Some(regex) if regex.len() > MAX_REGEX_LENGTH => {
+                warn!("Rejected regex longer than {} characters", MAX_REGEX_LENGTH);
+                return Err(Box::new(KVStoreError::with_kind(
+                    ErrorKind::InvalidInput,
+                    &format!("Regex is longer than {} characters", MAX_REGEX_LENGTH),
+                )));
             }
+            Some(regex) => match RegexBuilder::new(&regex).size_limit(MAX_REGEX_SIZE).build() {
+                Ok(pattern) => Some(pattern),
+                Err(e) => {
+                    warn!("Rejected invalid regex: {}", regex);
+                    return Err(Box::new(KVStoreError::with_kind(
+                        ErrorKind::InvalidInput,
+                        &format!("Invalid regex: {}", e),
+                    )));
+                }
+            },
+            None => None,
+        };
 
-            let string_value = serde_json::to_string(&value).unwrap();
-    
-            let encoded_value = base64::encode(string_value);
+        let limit = self.list_limit(limit.map(|limit| limit as usize));
+
+        let kvs = self.store.read();
+        let now = now();
+
+        let prefix = prefix.unwrap_or_default();
+
+        // Values are only decoded when filtering by their type
+        let keys: Vec<&String> = prefix_range(&kvs, &prefix)
+            .filter(|(key, entry)| {
+                entry.is_live(now)
+                    && pattern.as_ref().is_none_or(|pattern| pattern.is_match(key))
+                    && value_type.is_none_or(|value_type| value_type.matches(&decode_value(&entry.value)))
+            })
+            .map(|(key, _)| key)
+            .take(limit)
+            .collect();
+
+        info!("Returning {} matching keys", keys.len());
+
+        Ok(serde_json::json!(keys))
+    }
 
-            kvs.insert(key.to_string(), encoded_value);
+    // Live keys whose `field` holds `value`, in key order, found through the
+    // index instead of a scan
+    pub async fn find_by(&self, field: String, value: String) -> Result<Value, Box<dyn Error>> {
+
+        if !self.index.is_indexed(&field) {
+            warn!("Rejected lookup by field {} which is not indexed", field);
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::InvalidInput,
+                &format!("Field {} is not indexed", field),
+            )));
         }
 
-        write_kvstore(&self.store).expect("Error writing to disk");
+        let kvs = self.store.read();
+
+        // The index is updated before a write is published, so each key is
+        // checked against the snapshot being read
+        let keys: Vec<String> = self
+            .index
+            .get(&field, &value)
+            .into_iter()
+            .filter(|key| {
+                live_entry(&kvs, key).is_some_and(|entry| {
+                    indexed_value(&decode_value(&entry.value), &field).as_deref() == Some(value.as_str())
+                })
+            })
+            .collect();
 
-        info!("Document created: {}", key);
+        info!("Found {} keys with {} = {}", keys.len(), field, value);
 
-        Ok(format!("Document created: {}", key))
+        Ok(serde_json::json!(keys))
     }
 
-    pub async fn insert(&self, namespace: String, key: String, value: Value) -> Result<String, Box<dyn Error>> {
+    pub async fn recent_keys(&self, limit: Option<usize>) -> Value {
+        let limit = limit.unwrap_or(20).min(MAX_RECENT_KEYS);
 
-        _ = namespace;
-        
-        let mut store = self.store.lock().unwrap();
+        let kvs = self.store.read();
+        let now = now();
 
-        info!("Document updated: {}", key);
+        // Keeps the `limit` newest entries seen so far, oldest on top
+        let mut newest = BinaryHeap::with_capacity(limit + 1);
 
-        let string_value = serde_json::to_string(&value).unwrap();
+        for (key, entry) in kvs.iter() {
+            if let (true, Some(updated_at)) = (entry.is_live(now), entry.updated_at) {
+                newest.push(Reverse((updated_at, key)));
 
-        let encoded_value = base64::encode(string_value);
+                if newest.len() > limit {
+                    newest.pop();
+                }
+            }
+        }
+
+        let keys: Vec<Value> = newest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((updated_at, key))| serde_json::json!({ "key": key, "updated_at": updated_at }))
+            .collect();
 
-        store.insert(key.clone(), encoded_value);
+        info!("Returning {} recently modified keys", keys.len());
 
-        Ok(format!("Document updated: {}", key))
+        serde_json::json!(keys)
     }
 
-    pub async fn get(&self, namespace: String, key: String) -> Result<Value, Box<dyn Error>> {
-        
-        _ = namespace;
+    pub async fn changes_since(&self, since: u64, values: bool, limit: Option<usize>) -> Value {
+        let since = since.saturating_mul(1000);
+        let limit = self.list_limit(limit);
+
+        let kvs = self.store.read();
+        let now = now();
+
+        let mut changes: Vec<(u64, &String, &Entry)> = kvs
+            .iter()
+            .filter(|(_, entry)| entry.is_live(now))
+            .filter_map(|(key, entry)| {
+                entry
+                    .updated_at
+                    .filter(|updated_at| *updated_at >= since)
+                    .map(|updated_at| (updated_at, key, entry))
+            })
+            .collect();
 
-        let store = self.store.lock().unwrap();
+        changes.sort_unstable_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(b.1)));
+        changes.truncate(limit);
+
+        let changes: Vec<Value> = changes
+            .into_iter()
+            .map(|(updated_at, key, entry)| {
+                let mut change = serde_json::json!({ "key": key, "updated_at": updated_at });
+                if values {
+                    change["value"] = decode_value(&entry.value);
+                }
+                change
+            })
+            .collect();
 
-        if !store.contains_key(&key) {
-            warn!("Document not found: {}", key);
-            return Err(Box::new(KVStoreError::new(
-                format!("Document not found: {}", key).as_str(),
-            )));
+        info!("Returning {} changed keys", changes.len());
+
+        serde_json::json!(changes)
+    }
+
+    pub async fn scan(
+        &self,
+        prefix: Option<String>,
+        filter: Option<(String, String)>,
+        limit: Option<usize>,
+        cursor: Option<String>,
+    ) -> Value {
+        let kvs = self.store.read();
+        let now = now();
+
+        let prefix = prefix.unwrap_or_default();
+        let limit = self.list_limit(limit);
+
+        let mut items = Vec::new();
+        let mut next_cursor = None;
+
+        for (key, entry) in prefix_range_after(&kvs, &prefix, cursor.as_deref()) {
+            if !entry.is_live(now) {
+                continue;
+            }
+
+            let value = decode_value(&entry.value);
+
+            let matches = filter.as_ref().is_none_or(|(field, expected)| {
+                lookup_path(&value, field).is_some_and(|found| match found {
+                    Value::String(found) => found == expected,
+                    found => serde_json::from_str::<Value>(expected).is_ok_and(|expected| expected == *found),
+                })
+            });
+
+            if !matches {
+                continue;
+            }
+
+            if items.len() == limit {
+                next_cursor = items.last().map(|item: &KV| item.key.clone());
+                break;
+            }
+
+            items.push(KV {
+                key: key.to_string(),
+                data: value,
+            });
         }
 
-        info!("Grabbing key: {}", key);
+        info!("Scan returning {} documents", items.len());
 
-        let value = store.get(&key).unwrap();
+        serde_json::json!({ "items": items, "cursor": next_cursor })
+    }
 
-        let decoded_value = decode(value.to_owned()).unwrap();
+    // One level of the key hierarchy under `prefix`: the distinct folders up to
+    // the next `delimiter` with how many keys each holds, and the keys with no
+    // delimiter left. Counting needs every key under the prefix, so this walks
+    // them once instead of seeking past each folder.
+    pub async fn tree(
+        &self,
+        prefix: Option<String>,
+        delimiter: String,
+        limit: Option<usize>,
+    ) -> Result<Value, Box<dyn Error>> {
 
-        let json_value: Value = serde_json::from_slice(&decoded_value).unwrap();
+        if delimiter.is_empty() {
+            return Err(Box::new(KVStoreError::with_kind(ErrorKind::InvalidInput, "Delimiter must not be empty")));
+        }
 
-        // info!("{}", serde_json::from_value::<String>(json_value.clone()).unwrap());
+        let kvs = self.store.read();
+        let now = now();
 
-        Ok(json_value)
+        let prefix = prefix.unwrap_or_default();
+        let limit = self.list_limit(limit);
+
+        let mut folders: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut keys = Vec::new();
+
+        for (key, _) in prefix_range(&kvs, &prefix).filter(|(_, entry)| entry.is_live(now)) {
+            match key[prefix.len()..].find(&delimiter) {
+                Some(position) => *folders.entry(&key[..prefix.len() + position + delimiter.len()]).or_default() += 1,
+                None => keys.push(key.as_str()),
+            }
+        }
+
+        keys.sort_unstable();
+
+        let truncated = folders.len() > limit || keys.len() > limit;
+
+        let folders: Vec<Value> = folders
+            .into_iter()
+            .take(limit)
+            .map(|(folder, count)| serde_json::json!({ "prefix": folder, "count": count }))
+            .collect();
+
+        keys.truncate(limit);
+
+        info!("Tree of {} returning {} folders and {} keys", prefix, folders.len(), keys.len());
+
+        Ok(serde_json::json!({
+            "prefix": prefix,
+            "delimiter": delimiter,
+            "folders": folders,
+            "keys": keys,
+            "truncated": truncated,
+        }))
     }
 
-    pub async fn delete(&self, namespace: String, key: String) -> Result<String, Box<dyn Error>> {
+    pub async fn count_keys(&self, prefix: Option<String>, estimate: bool) -> Value {
+        let kvs = self.store.read();
+        let now = now();
 
-        _ = namespace;
-        
-        let mut store = self.store.lock().unwrap();
+        let prefix = prefix.unwrap_or_default();
 
-        if store.contains_key(&key.to_string()) {
-            store.remove(&key.to_string());
+        if estimate {
+            let rate = self.sample.rate();
+            let sampled = self.sample.with_prefix(&prefix);
+            let count = sampled.iter().filter(|key| live_entry(&kvs, key).is_some()).count() as u64 * rate;
 
-            info!("Document deleted: {}", key);
+            // Every key is sampled with a chance of one in `rate`, so about 95%
+            // of estimates are within two standard deviations of the count
+            if cfg!(feature = "hashmap") || count >= EXACT_COUNT_BELOW {
+                let error_bound = (2.0 * (count as f64 * (rate - 1) as f64).sqrt()).ceil() as u64;
 
-            Ok(format!("Document deleted: {}", key))
-        } else {
-            warn!("Delete error - Document not found: {}", key);
-            Err(Box::new(KVStoreError::new(&format!(
-                "Document not found: {}",
-                key
-            ))))
+                return serde_json::json!({
+                    "prefix": prefix,
+                    "count": count,
+                    "estimated": true,
+                    "error_bound": error_bound,
+                });
+            }
+        }
+
+        let count = prefix_range(&kvs, &prefix).filter(|(_, entry)| entry.is_live(now)).count();
+
+        if estimate {
+            return serde_json::json!({ "prefix": prefix, "count": count, "estimated": false, "error_bound": 0 });
         }
+
+        serde_json::json!({ "prefix": prefix, "count": count })
     }
 
     pub async fn list_documents(
@@ -176,19 +2524,21 @@ impl KVStore {
 
         _ = namespace;
         
-        let kvs = &self.store.lock().unwrap();
+        let kvs = &self.store.read();
         let mut kv_list = Vec::new();
 
         let skip = skip.unwrap_or(0);
-        let limit = limit.unwrap_or(1000);
+        let limit = self.list_limit(limit.map(|limit| limit as usize));
+
+        let now = now();
 
         let mut count = 0;
-        for (key, value) in kvs.iter().skip(skip.clone() as usize) {
+        for (key, entry) in kvs.iter().filter(|(_, entry)| entry.is_live(now)).skip(skip as usize) {
             if count >= limit {
                 break;
             }
 
-            let decoded_value = decode(value.to_owned()).unwrap();
+            let decoded_value = decode(entry.value.as_bytes()).unwrap();
 
             let json_value: Value = serde_json::from_slice(&decoded_value).unwrap();
 
@@ -208,41 +2558,271 @@ impl KVStore {
 
         Ok(serde_json::json!(kv_list))
     }
+
+    // Streams the same documents as `list_documents` as NDJSON, one line per
+    // document. The walk runs over the snapshot taken when the listing started
+    // and only a few chunks are buffered at a time, so large listings never
+    // sit in memory all at once.
+    pub fn stream_documents(
+        &self,
+        namespace: String,
+        skip: Option<u64>,
+        limit: Option<u64>,
+    ) -> tokio::sync::mpsc::Receiver<String> {
+
+        _ = namespace;
+
+        let skip = skip.unwrap_or(0) as usize;
+        let limit = self.list_limit(limit.map(|limit| limit as usize));
+
+        stream_ndjson(self.store.read(), skip, limit)
+    }
 }
 
 impl Clone for KVStore {
     fn clone(&self) -> Self {
+        let store = Arc::new(Store::new((*self.store.read()).clone()));
+
         KVStore {
-            store: Arc::new(Mutex::new(self.store.lock().unwrap().clone())),
+            flusher: Arc::new(Flusher::new(
+                store.clone(),
+                self.dirty_keys.clone(),
+                self.last_flush.clone(),
+                &self.config,
+            )),
+            store,
+            maintenance: self.maintenance.clone(),
+            used_bytes: self.used_bytes.clone(),
+            namespaces: self.namespaces.clone(),
+            dirty_keys: self.dirty_keys.clone(),
+            last_flush: self.last_flush.clone(),
+            keys: self.keys.clone(),
+            bloom: self.bloom.clone(),
+            index: self.index.clone(),
+            values: self.values.clone(),
+            sample: self.sample.clone(),
+            locks: self.locks.clone(),
+            changes: self.changes.clone(),
+            boot_id: self.boot_id.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn ttl_out_of_range(ttl_seconds: u64) -> Box<dyn Error> {
+    warn!("Write rejected - TTL of {} seconds is out of range", ttl_seconds);
+    KVStoreError::with_kind(ErrorKind::InvalidInput, &format!("TTL is out of range: {} seconds", ttl_seconds)).into()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+// Size of the JSON value behind a base64 encoded entry, without decoding it
+fn decoded_len(value: &str) -> u64 {
+    let padding = value.bytes().rev().take_while(|&byte| byte == b'=').count();
+
+    (value.len() / 4 * 3 - padding) as u64
+}
+
+fn live_prefix_keys(kvs: &Map, prefix: &str) -> Vec<String> {
+    let now = now();
+
+    prefix_range(kvs, prefix)
+        .filter(|(_, entry)| entry.is_live(now))
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+fn live_entry<'a>(kvs: &'a Map, key: &str) -> Option<&'a Entry> {
+    kvs.get(key).filter(|entry| entry.is_live(now()))
+}
+
+fn not_found(key: &str) -> Box<dyn Error> {
+    Box::new(KVStoreError::with_kind(ErrorKind::NotFound, &format!("Document not found: {}", key)))
+}
+
+fn not_an_array(key: &str) -> Box<dyn Error> {
+    Box::new(KVStoreError::with_kind(ErrorKind::InvalidInput, &format!("Document is not an array: {}", key)))
+}
+
+fn check_multi_get(count: usize) -> Result<(), Box<dyn Error>> {
+    if count > MAX_MULTI_GET_KEYS {
+        warn!("Rejected multi-get of {} keys", count);
+        return Err(Box::new(KVStoreError::with_kind(
+            ErrorKind::InvalidInput,
+            &format!("At most {} keys can be fetched at once", MAX_MULTI_GET_KEYS),
+        )));
+    }
+
+    Ok(())
+}
+
+fn decode_value(value: &str) -> Value {
+    let decoded_value = decode(value).unwrap();
+
+    serde_json::from_slice(&decoded_value).unwrap()
+}
+
+fn lookup_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |current, segment| current.get(segment))
+}
+
+fn increment_path(value: &mut Value, path: &str, by: f64) -> Result<Value, String> {
+    let mut current = value;
+
+    for segment in path.split('.') {
+        if segment.is_empty() {
+            return Err(format!("Invalid field path: {}", path));
+        }
+
+        if current.is_null() {
+            *current = serde_json::json!({});
         }
+
+        current = match current {
+            Value::Object(map) => map.entry(segment).or_insert(Value::Null),
+            _ => return Err(format!("Cannot navigate into a non-object at {}", segment)),
+        };
+    }
+
+    let updated = match &*current {
+        Value::Null => number_value(by),
+        Value::Number(number) => match (number.as_i64(), by.fract() == 0.0 && by.abs() < i64::MAX as f64) {
+            (Some(integer), true) => match integer.checked_add(by as i64) {
+                Some(sum) => serde_json::json!(sum),
+                None => return Err(format!("Field {} would overflow", path)),
+            },
+            _ => number_value(number.as_f64().unwrap() + by),
+        },
+        _ => return Err(format!("Field {} is not a number", path)),
+    };
+
+    // JSON has no infinity, the field would silently become null
+    if updated.is_null() {
+        return Err(format!("Field {} would overflow", path));
+    }
+
+    *current = updated.clone();
+
+    Ok(updated)
+}
+
+// The indices an inclusive `start..=stop` keeps of `len` items, where negative
+// indices count back from the end, clamped to the items that exist
+fn trim_range(len: usize, start: i64, stop: i64) -> std::ops::Range<usize> {
+    let resolve = |index: i64| if index < 0 { len as i64 + index } else { index };
+
+    let start = resolve(start).max(0);
+    let stop = resolve(stop).min(len as i64 - 1);
+
+    if start > stop {
+        return 0..0;
+    }
+
+    start as usize..stop as usize + 1
+}
+
+fn compare_numbers(a: &serde_json::Number, b: &serde_json::Number) -> CmpOrdering {
+    match (a.as_i64(), b.as_i64(), a.as_u64(), b.as_u64()) {
+        (Some(a), Some(b), _, _) => a.cmp(&b),
+        (_, _, Some(a), Some(b)) => a.cmp(&b),
+        _ => a.as_f64().unwrap().partial_cmp(&b.as_f64().unwrap()).unwrap_or(CmpOrdering::Equal),
+    }
+}
+
+fn number_value(number: f64) -> Value {
+    if number.fract() == 0.0 && number.abs() < i64::MAX as f64 {
+        serde_json::json!(number as i64)
+    } else {
+        serde_json::json!(number)
     }
 }
 
-fn check_file_exists() -> File {
-    let path = "database.vbank";
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::String(_) => "string",
+        Value::Number(_) => "number",
+        Value::Bool(_) => "boolean",
+        Value::Null => "null",
+    }
+}
+
+fn entry_size(key: &str, value: &str) -> u64 {
+    (key.len() + value.len()) as u64
+}
+
+fn key_quota_exceeded(max_keys: usize) -> Box<dyn Error> {
+    warn!("Write rejected - key quota of {} exceeded", max_keys);
+    Box::new(KVStoreError::with_kind(
+        ErrorKind::QuotaExceeded,
+        &format!("Key quota exceeded: limit is {} keys", max_keys),
+    ))
+}
+
+fn storage_quota_exceeded(max_bytes: u64) -> Box<dyn Error> {
+    warn!("Write rejected - storage quota of {} bytes exceeded", max_bytes);
+    Box::new(KVStoreError::with_kind(
+        ErrorKind::QuotaExceeded,
+        &format!("Storage quota exceeded: limit is {} bytes", max_bytes),
+    ))
+}
+
+fn namespace_key_quota_exceeded(namespace: &str, max_keys: usize) -> Box<dyn Error> {
+    warn!("Write rejected - key quota of {} in namespace {} exceeded", max_keys, namespace);
+    Box::new(KVStoreError::with_kind(
+        ErrorKind::QuotaExceeded,
+        &format!("Key quota of namespace {} exceeded: limit is {} keys", namespace, max_keys),
+    ))
+}
+
+fn namespace_storage_quota_exceeded(namespace: &str, max_bytes: u64) -> Box<dyn Error> {
+    warn!("Write rejected - storage quota of {} bytes in namespace {} exceeded", max_bytes, namespace);
+    Box::new(KVStoreError::with_kind(
+        ErrorKind::QuotaExceeded,
+        &format!("Storage quota of namespace {} exceeded: limit is {} bytes", namespace, max_bytes),
+    ))
+}
+
+fn check_file_exists(path: &str) -> File {
     let file_exists = fs::metadata(path).is_ok();
     if file_exists {
         match File::open(path) {
-            Ok(file) => return file,
+            Ok(file) => file,
             Err(error) => panic!("Problem opening the file: {:?}", error),
-        };
+        }
     } else {
         File::create(path).unwrap();
 
         match File::open(path) {
-            Ok(file) => return file,
+            Ok(file) => file,
             Err(error) => panic!("Problem opening the file: {:?}", error),
         }
     }
 }
 
-fn read_kvstore(kvstore: &Arc<Mutex<BTreeMap<String, String>>>) -> Result<(), Box<dyn Error>> {
-    let mut file = check_file_exists();
-    let mut contents = String::new();
+fn read_kvstore(path: &str, duplicates: DuplicateKeys) -> Result<Map, Box<dyn Error>> {
+    let mut file = check_file_exists(path);
+    let mut contents = Vec::new();
+
+    file.read_to_end(&mut contents)?;
 
-    file.read_to_string(&mut contents)?;
+    let mut kvstore_file = Map::new();
 
-    let mut kvstore_file = kvstore.lock().unwrap();
+    if contents.starts_with(cbor::CBOR_MAGIC) {
+        cbor::read_entries(&contents, &mut kvstore_file, duplicates)?;
+
+        info!("Loaded {} documents from disk", kvstore_file.len());
+        return Ok(kvstore_file);
+    }
+
+    let contents = String::from_utf8(contents)?;
 
     for line in contents.lines() {
         let mut kv = line.split("|");
@@ -251,6 +2831,18 @@ fn read_kvstore(kvstore: &Arc<Mutex<BTreeMap<String, String>>>) -> Result<(), Bo
 
         let value = kv.next().unwrap_or("");
 
+        let expires_at = kv.next().and_then(|expires_at| expires_at.parse().ok());
+
+        let deleted_at = kv.next().and_then(|deleted_at| deleted_at.parse().ok());
+
+        let updated_at = kv.next().and_then(|updated_at| updated_at.parse().ok());
+
+        let compression = kv.next().unwrap_or("");
+
+        let version = kv.next().and_then(|version| version.parse().ok()).unwrap_or(0);
+
+        let namespace = kv.next().filter(|namespace| !namespace.is_empty()).map(Arc::from);
+
         if key.is_empty() || value.is_empty() {
             continue;
         }
@@ -261,23 +2853,126 @@ fn read_kvstore(kvstore: &Arc<Mutex<BTreeMap<String, String>>>) -> Result<(), Bo
             value
         };
 
-        kvstore_file.insert(key.to_string(), value.to_string());
+        let value = if compression == compression::DEFLATE {
+            base64::encode(compression::decompress(&decode(value)?)?)
+        } else {
+            value.to_string()
+        };
+
+        load_entry(&mut kvstore_file, key.to_string(), Entry {
+            expires_at,
+            deleted_at,
+            updated_at,
+            version,
+            namespace,
+            ..Entry::new(value)
+        }, duplicates)?;
     }
     let count = kvstore_file.len();
     info!("Loaded {} documents from disk", count);
+    Ok(kvstore_file)
+}
+
+// Adds an entry read from the data file, where a key should only appear once.
+// Files put together from partial or merged copies can repeat keys.
+fn load_entry(kvs: &mut Map, key: String, entry: Entry, duplicates: DuplicateKeys) -> Result<(), Box<dyn Error>> {
+    let previous = match kvs.insert(key.clone(), entry) {
+        Some(previous) => previous,
+        None => return Ok(()),
+    };
+
+    match duplicates {
+        DuplicateKeys::Warn => warn!("Duplicate key in data file, keeping the last one: {}", key),
+        DuplicateKeys::Error => {
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::InvalidInput,
+                &format!("Duplicate key in data file: {}", key),
+            )));
+        }
+        DuplicateKeys::FirstWins => {
+            kvs.insert(key, previous);
+        }
+        DuplicateKeys::LastWins => {}
+    }
+
     Ok(())
 }
 
-pub fn write_kvstore(kvstore: &Arc<Mutex<BTreeMap<String, String>>>) -> Result<(), Box<dyn Error>> {
+// Returns how many values had to be encoded, with `cache` the others were
+// copied from what an earlier flush encoded
+pub fn write_kvstore(
+    kvstore: &Store,
+    path: &str,
+    format: DiskFormat,
+    compress_threshold: Option<usize>,
+    cache: bool,
+) -> Result<u64, Box<dyn Error>> {
     info!("Writing to data to disk");
 
-    let mut file = File::create("./database.vbank")?;
-    let kvstore_file = kvstore.lock().unwrap();
-    for (key, value) in kvstore_file.iter() {
+    let _flush = kvstore.flush_lock();
+    let mut file = File::create(path)?;
+    let kvstore_file = kvstore.read();
+
+    if format == DiskFormat::Cbor {
+        return cbor::write_entries(file, &kvstore_file, compress_threshold, cache);
+    }
+
+    let mut encoded = 0;
 
-        let value = value.replace("|", "\\|");
+    for (key, entry) in kvstore_file.iter() {
+        let (line, line_encoded) = legacy_line(key, entry, compress_threshold, cache)?;
+        file.write_all(line.as_bytes())?;
 
-        file.write_all(format!("{}|{}\n", key, value).as_bytes())?;
+        if line_encoded {
+            encoded += 1;
+        }
     }
-    Ok(())
+    Ok(encoded)
+}
+
+// One `key|value|expires_at|deleted_at|updated_at|compression|version|namespace`
+// line of the legacy format, without the trailing empty fields, and whether the
+// value had to be encoded for it. Only compressed values take encoding, the
+// others are written as they are kept in memory.
+fn legacy_line(
+    key: &str,
+    entry: &Entry,
+    compress_threshold: Option<usize>,
+    cache: bool,
+) -> Result<(String, bool), Box<dyn Error>> {
+    let compress = is_compressed(&entry.value, compress_threshold);
+
+    let (value, compression, encoded) = match (compress, entry.encoded.get()) {
+        (false, _) => (entry.value.replace("|", "\\|"), "", false),
+        (true, Some(cached)) => (String::from_utf8_lossy(cached).into_owned(), compression::DEFLATE, false),
+        (true, None) => {
+            let value = base64::encode(compression::compress(&decode(entry.value.as_bytes())?)?);
+            if cache {
+                _ = entry.encoded.set(value.as_bytes().into());
+            }
+            (value, compression::DEFLATE, true)
+        }
+    };
+
+    let mut fields = vec![
+        key.to_string(),
+        value,
+        entry.expires_at.map(|expires_at| expires_at.to_string()).unwrap_or_default(),
+        entry.deleted_at.map(|deleted_at| deleted_at.to_string()).unwrap_or_default(),
+        entry.updated_at.map(|updated_at| updated_at.to_string()).unwrap_or_default(),
+        compression.to_string(),
+        Some(entry.version).filter(|version| *version > 0).map(|version| version.to_string()).unwrap_or_default(),
+        entry.namespace.as_deref().unwrap_or_default().to_string(),
+    ];
+
+    while fields.last().is_some_and(|field| field.is_empty()) {
+        fields.pop();
+    }
+
+    Ok((format!("{}\n", fields.join("|")), encoded))
+}
+
+// Values with JSON of at least `compress_threshold` bytes are stored deflated
+fn is_compressed(value: &str, compress_threshold: Option<usize>) -> bool {
+    compress_threshold.is_some_and(|threshold| decoded_len(value) as usize >= threshold)
 }
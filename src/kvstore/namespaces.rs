@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub keys: usize,
+    pub bytes: u64,
+}
+
+// Keys and bytes held by each namespace, kept up to date on every write so the
+// namespace quotas never scan the store. A namespace is dropped once its last
+// key is, so only namespaces holding keys are listed.
+pub struct NamespaceUsage {
+    usage: Mutex<HashMap<Arc<str>, Usage>>,
+}
+
+impl NamespaceUsage {
+    pub fn new() -> Self {
+        NamespaceUsage {
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn add(&self, namespace: &Arc<str>, bytes: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(namespace.clone()).or_default();
+
+        usage.keys += 1;
+        usage.bytes += bytes;
+    }
+
+    pub fn remove(&self, namespace: &str, bytes: u64) {
+        let mut usage = self.usage.lock().unwrap();
+
+        if let Some(namespace_usage) = usage.get_mut(namespace) {
+            namespace_usage.keys -= 1;
+            namespace_usage.bytes -= bytes;
+
+            if namespace_usage.keys == 0 {
+                usage.remove(namespace);
+            }
+        }
+    }
+
+    pub fn get(&self, namespace: &str) -> Usage {
+        self.usage.lock().unwrap().get(namespace).copied().unwrap_or_default()
+    }
+
+    pub fn count(&self) -> usize {
+        self.usage.lock().unwrap().len()
+    }
+
+    pub fn snapshot(&self) -> HashMap<Arc<str>, Usage> {
+        self.usage.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.usage.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::ops::Bound;
+use std::sync::Mutex;
+
+// Every key whose hash falls in one of `rate` buckets, kept in order so the
+// sampled keys under any prefix can be found without walking the store. The
+// hash makes it a random sample whatever the keys look like.
+pub struct KeySample {
+    rate: u64,
+    keys: Mutex<BTreeSet<String>>,
+}
+
+impl KeySample {
+    pub fn new(rate: u64) -> Self {
+        KeySample {
+            rate,
+            keys: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn insert(&self, key: &str) {
+        if self.is_sampled(key) {
+            self.keys.lock().unwrap().insert(key.to_string());
+        }
+    }
+
+    pub fn remove(&self, key: &str) {
+        if self.is_sampled(key) {
+            self.keys.lock().unwrap().remove(key);
+        }
+    }
+
+    pub fn clear(&self) {
+        self.keys.lock().unwrap().clear();
+    }
+
+    pub fn with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.keys
+            .lock()
+            .unwrap()
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    fn is_sampled(&self, key: &str) -> bool {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish().is_multiple_of(self.rate)
+    }
+}
@@ -0,0 +1,107 @@
+use serde_json::Value;
+use std::error::Error;
+use tracing::{info, warn};
+
+use super::{decode_value, live_entry, not_found, now_millis, ErrorKind, KVStore, KVStoreError, Update, WriteFilter};
+
+fn point_ts(point: &Value) -> Option<u64> {
+    point.get("ts")?.as_u64()
+}
+
+fn not_a_series(key: &str) -> Box<dyn Error> {
+    Box::new(KVStoreError::with_kind(
+        ErrorKind::InvalidInput,
+        &format!("Document is not a time series: {}", key),
+    ))
+}
+
+// Time series are arrays of `{"ts": ..., "val": ...}` points kept in `ts`
+// order, with `ts` in unix milliseconds. Any array of such points written
+// through the other routes is a series too.
+impl KVStore {
+    // Adds a point in `ts` order and then drops the oldest points beyond
+    // `max_len`, and those more than `window_ms` older than the newest point
+    #[allow(clippy::too_many_arguments)]
+    pub async fn append_point(
+        &self,
+        namespace: String,
+        key: String,
+        ts: Option<u64>,
+        val: Value,
+        max_len: Option<usize>,
+        window_ms: Option<u64>,
+        filter: &impl WriteFilter,
+    ) -> Result<Value, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let default_expiry = self.expiry_for(&namespace, None)?;
+
+        let ts = ts.unwrap_or_else(now_millis);
+
+        let (length, trimmed) = self
+            .update_value(&namespace, &key, default_expiry, filter, |existing| {
+                let mut points = match existing.map(|entry| decode_value(&entry.value)) {
+                    Some(Value::Array(points)) if points.iter().all(|point| point_ts(point).is_some()) => points,
+                    Some(_) => {
+                        warn!("Append error - {} is not a time series", key);
+                        return Err(not_a_series(&key));
+                    }
+                    None => Vec::new(),
+                };
+
+                let position = points.partition_point(|point| point_ts(point).unwrap() <= ts);
+                points.insert(position, serde_json::json!({ "ts": ts, "val": val }));
+
+                let mut oldest_kept = 0;
+
+                if let Some(max_len) = max_len {
+                    oldest_kept = points.len().saturating_sub(max_len);
+                }
+
+                if let Some(window_ms) = window_ms {
+                    let newest = point_ts(points.last().unwrap()).unwrap();
+                    let outside = points.partition_point(|point| point_ts(point).unwrap().saturating_add(window_ms) < newest);
+                    oldest_kept = oldest_kept.max(outside);
+                }
+
+                points.drain(..oldest_kept);
+
+                let length = points.len();
+
+                Ok(Update::Write(Value::Array(points), (length, oldest_kept)))
+            })
+            .await?;
+
+        info!("Point appended to {}, {} points after trimming {}", key, length, trimmed);
+
+        Ok(serde_json::json!({ "ts": ts, "length": length, "trimmed": trimmed }))
+    }
+
+    // Points with a `ts` after `since`, oldest first
+    pub async fn points_since(&self, namespace: String, key: String, since: Option<u64>) -> Result<Value, Box<dyn Error>> {
+
+        _ = namespace;
+
+        let snapshot = self.store.read();
+
+        let points = match live_entry(&snapshot, &key).map(|entry| decode_value(&entry.value)) {
+            Some(Value::Array(points)) if points.iter().all(|point| point_ts(point).is_some()) => points,
+            Some(_) => {
+                warn!("Points error - {} is not a time series", key);
+                return Err(not_a_series(&key));
+            }
+            None => {
+                warn!("Points error - Document not found: {}", key);
+                return Err(not_found(&key));
+            }
+        };
+
+        let first = match since {
+            Some(since) => points.partition_point(|point| point_ts(point).unwrap() <= since),
+            None => 0,
+        };
+
+        Ok(Value::Array(points[first..].to_vec()))
+    }
+}
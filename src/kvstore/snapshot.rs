@@ -0,0 +1,314 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, Receiver};
+use tracing::info;
+
+use super::{decode_value, now, Entry, ErrorKind, KVStore, KVStoreError, Map, WriteFilter, KV};
+
+// Only the first differing keys of each kind are listed, the counts cover all of them
+const MAX_LISTED_KEYS: usize = 1000;
+
+// Documents serialized into each chunk of a stream, and how many chunks may wait
+// for a slow client before the walk pauses
+const STREAM_CHUNK_DOCUMENTS: usize = 100;
+const STREAM_CHUNK_BACKLOG: usize = 4;
+
+// Compares an NDJSON snapshot, one `{"key": ..., "data": ...}` line per
+// document as written by the export, against the store as it was when the
+// diff started. Lines are fed in as they arrive so the snapshot is never held
+// in memory.
+pub struct SnapshotDiff {
+    local: Arc<Map>,
+    now: u64,
+    line: usize,
+    remote_keys: HashSet<String>,
+    only_remote: KeyList,
+    different: KeyList,
+    same: usize,
+}
+
+#[derive(Default)]
+struct KeyList {
+    keys: Vec<String>,
+    count: usize,
+}
+
+impl KeyList {
+    fn push(&mut self, key: &str) {
+        if self.keys.len() < MAX_LISTED_KEYS {
+            self.keys.push(key.to_string());
+        }
+        self.count += 1;
+    }
+}
+
+impl SnapshotDiff {
+    pub fn add_line(&mut self, line: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.line += 1;
+
+        if line.iter().all(|byte| byte.is_ascii_whitespace()) {
+            return Ok(());
+        }
+
+        let document: KV = serde_json::from_slice(line).map_err(|e| {
+            KVStoreError::with_kind(
+                ErrorKind::InvalidInput,
+                &format!("Invalid snapshot line {}: {}", self.line, e),
+            )
+        })?;
+
+        if !self.remote_keys.insert(document.key.clone()) {
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::InvalidInput,
+                &format!("Duplicate key in snapshot line {}: {}", self.line, document.key),
+            )));
+        }
+
+        match self.local.get(&document.key).filter(|entry| entry.is_live(self.now)) {
+            Some(entry) if decode_value(&entry.value) == document.data => self.same += 1,
+            Some(_) => self.different.push(&document.key),
+            None => self.only_remote.push(&document.key),
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Value {
+        let mut only_local = KeyList::default();
+
+        for (key, _) in self.local.iter().filter(|(_, entry)| entry.is_live(self.now)) {
+            if !self.remote_keys.contains(key) {
+                only_local.push(key);
+            }
+        }
+
+        serde_json::json!({
+            "only_local": only_local.keys,
+            "only_remote": self.only_remote.keys,
+            "different": self.different.keys,
+            "counts": {
+                "only_local": only_local.count,
+                "only_remote": self.only_remote.count,
+                "different": self.different.count,
+                "same": self.same,
+            },
+        })
+    }
+}
+
+// One line of a dump for moving data between stores, like
+// `{"key": ..., "value": ..., "ttl": ...}` with `ttl` the seconds the key has
+// left. Lines of a snapshot name the value `data` and are read as dump lines
+// without a TTL.
+#[derive(Serialize, Deserialize)]
+struct DumpLine {
+    key: String,
+    #[serde(alias = "data")]
+    value: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<i64>,
+}
+
+// The documents of a dump being imported, checked line by line as they arrive
+// and written together once the whole dump has been read
+pub struct SnapshotImport {
+    line: usize,
+    parse_strings: bool,
+    keys: HashSet<String>,
+    documents: Vec<(String, Value, Option<u64>)>,
+}
+
+impl SnapshotImport {
+    pub fn add_line(&mut self, line: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.line += 1;
+
+        if line.iter().all(|byte| byte.is_ascii_whitespace()) {
+            return Ok(());
+        }
+
+        let document: DumpLine = serde_json::from_slice(line).map_err(|e| {
+            KVStoreError::with_kind(
+                ErrorKind::InvalidInput,
+                &format!("Invalid dump line {}: {}", self.line, e),
+            )
+        })?;
+
+        if !self.keys.insert(document.key.clone()) {
+            return Err(Box::new(KVStoreError::with_kind(
+                ErrorKind::InvalidInput,
+                &format!("Duplicate key in dump line {}: {}", self.line, document.key),
+            )));
+        }
+
+        // Redis only stores strings, so JSON documents come out of it as strings
+        // holding their JSON text
+        let value = match document.value {
+            Value::String(text) if self.parse_strings => serde_json::from_str(&text).unwrap_or(Value::String(text)),
+            value => value,
+        };
+
+        // Redis reports -1 for keys that never expire
+        let ttl = document.ttl.filter(|ttl| *ttl > 0).map(|ttl| ttl as u64);
+
+        self.documents.push((document.key, value, ttl));
+
+        Ok(())
+    }
+}
+
+impl KVStore {
+    pub fn export_snapshot(&self) -> Receiver<String> {
+        stream_ndjson(self.store.read(), 0, usize::MAX)
+    }
+
+    pub fn export_dump(&self) -> Receiver<String> {
+        stream_lines(self.store.read(), 0, usize::MAX, dump_line)
+    }
+
+    pub fn import_snapshot(&self, parse_strings: bool) -> SnapshotImport {
+        SnapshotImport {
+            line: 0,
+            parse_strings,
+            keys: HashSet::new(),
+            documents: Vec::new(),
+        }
+    }
+
+    // Writes every document of the dump under one lock and one write to disk.
+    // Keys that already exist are left alone unless `replace` is set, and keys
+    // over a quota or rejected by the key rules are reported without stopping
+    // the others.
+    pub async fn finish_import(
+        &self,
+        import: SnapshotImport,
+        replace: bool,
+        filter: &impl WriteFilter,
+    ) -> Result<Value, Box<dyn Error>> {
+
+        self.check_writable()?;
+
+        let mut imported = 0;
+        let mut skipped = 0;
+        let mut failed = Vec::new();
+
+        // Documents the filter rejects are reported like those over a quota
+        let mut documents = Vec::new();
+        for (key, value, ttl) in import.documents {
+            match filter.filter(&key, value).await {
+                Ok(value) => documents.push((key, value, ttl)),
+                Err(e) => failed.push((key, e.to_string())),
+            }
+        }
+        {
+            let _keys = self.locks.lock_many(documents.iter().map(|(key, _, _)| key.as_str()));
+            let mut kvs = self.store.write();
+            let now = now();
+
+            for (key, value, ttl) in documents {
+                if !replace && kvs.get(&key).is_some_and(|entry| entry.is_live(now)) {
+                    skipped += 1;
+                    continue;
+                }
+
+                let encoded_value = base64::encode(serde_json::to_string(&value).unwrap());
+
+                match self.check_quota(&kvs, None, &key, &encoded_value) {
+                    Ok(()) => {
+                        let mut entry = Entry::new(encoded_value);
+                        entry.expires_at = ttl.map(|ttl| now + ttl);
+
+                        self.put_entry(&mut kvs, None, key, entry);
+                        imported += 1;
+                    }
+                    Err(e) => failed.push((key, e.to_string())),
+                }
+            }
+        }
+
+        if imported > 0 {
+            self.persist().await;
+        }
+
+        info!("Imported {} documents, skipped {} and {} failed", imported, skipped, failed.len());
+
+        Ok(serde_json::json!({
+            "imported": imported,
+            "skipped": skipped,
+            "failed": failed.len(),
+            "errors": failed
+                .into_iter()
+                .take(MAX_LISTED_KEYS)
+                .map(|(key, error)| serde_json::json!({ "key": key, "error": error }))
+                .collect::<Vec<_>>(),
+        }))
+    }
+
+    pub fn diff_snapshot(&self) -> SnapshotDiff {
+        SnapshotDiff {
+            local: self.store.read(),
+            now: now(),
+            line: 0,
+            remote_keys: HashSet::new(),
+            only_remote: KeyList::default(),
+            different: KeyList::default(),
+            same: 0,
+        }
+    }
+}
+
+pub(super) fn stream_ndjson(kvs: Arc<Map>, skip: usize, limit: usize) -> Receiver<String> {
+    stream_lines(kvs, skip, limit, snapshot_line)
+}
+
+fn snapshot_line(key: &str, entry: &Entry, _now: u64) -> String {
+    serde_json::to_string(&KV {
+        key: key.to_string(),
+        data: decode_value(&entry.value),
+    })
+    .unwrap()
+}
+
+fn dump_line(key: &str, entry: &Entry, now: u64) -> String {
+    serde_json::to_string(&DumpLine {
+        key: key.to_string(),
+        value: decode_value(&entry.value),
+        ttl: entry.expires_at.map(|expires_at| expires_at.saturating_sub(now).max(1) as i64),
+    })
+    .unwrap()
+}
+
+// Walks the live documents of a snapshot on a separate task, sending them as
+// chunks of NDJSON lines. The channel only holds a few chunks, so the walk waits
+// for a slow client instead of buffering the store, and stops once the receiver
+// is dropped. The snapshot is never locked, writes carry on during the transfer.
+fn stream_lines(kvs: Arc<Map>, skip: usize, limit: usize, line: fn(&str, &Entry, u64) -> String) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel(STREAM_CHUNK_BACKLOG);
+
+    tokio::spawn(async move {
+        let now = now();
+        let mut chunk = String::new();
+        let mut count = 0;
+
+        for (key, entry) in kvs.iter().filter(|(_, entry)| entry.is_live(now)).skip(skip).take(limit) {
+            chunk.push_str(&line(key, entry, now));
+            chunk.push('\n');
+            count += 1;
+
+            if count % STREAM_CHUNK_DOCUMENTS == 0 && sender.send(std::mem::take(&mut chunk)).await.is_err() {
+                info!("Document stream closed by the client after {} documents", count);
+                return;
+            }
+        }
+
+        if !chunk.is_empty() {
+            _ = sender.send(chunk).await;
+        }
+
+        info!("Streamed {} documents after skipping {}", count, skip);
+    });
+
+    receiver
+}
@@ -0,0 +1,259 @@
+use arc_swap::ArcSwap;
+use serde::Serialize;
+#[cfg(not(feature = "hashmap"))]
+use std::collections::BTreeMap;
+#[cfg(feature = "hashmap")]
+use std::collections::HashMap;
+#[cfg(not(feature = "hashmap"))]
+use std::ops::Bound;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::Instant;
+
+use super::metrics::Histogram;
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    // Shared between entries holding the same value with `DISTKV_DEDUP_VALUES`
+    pub value: Arc<str>,
+    pub expires_at: Option<u64>,
+    pub deleted_at: Option<u64>,
+    // Unix time in milliseconds of the last write to the value, so writes made
+    // within the same second still keep their order
+    pub updated_at: Option<u64>,
+    // Bumped on every write to the value, 0 for entries written before versions
+    // were tracked
+    pub version: u64,
+    // The namespace of the last write that named one, which the key counts
+    // against for `DISTKV_NAMESPACE_QUOTAS`
+    pub namespace: Option<Arc<str>>,
+    // The value as the data file stores it, kept between flushes with
+    // `DISTKV_FLUSH_CACHE`. Every write of a value makes a new entry, so the
+    // cache never outlives the value it was encoded from.
+    pub encoded: OnceLock<Arc<[u8]>>,
+}
+
+impl Entry {
+    pub fn new(value: impl Into<Arc<str>>) -> Self {
+        Entry {
+            value: value.into(),
+            expires_at: None,
+            deleted_at: None,
+            updated_at: None,
+            version: 0,
+            namespace: None,
+            encoded: OnceLock::new(),
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    // Expired less than `grace` seconds ago and not deleted
+    pub fn is_stale(&self, now: u64, grace: u64) -> bool {
+        self.deleted_at.is_none() && self.is_expired(now) && !self.is_expired(now.saturating_sub(grace))
+    }
+
+    pub fn is_live(&self, now: u64) -> bool {
+        self.deleted_at.is_none() && !self.is_expired(now)
+    }
+}
+
+// The BTreeMap keeps keys ordered, which listing and prefix scans rely on. The
+// `hashmap` feature trades that ordering for faster point lookups.
+#[cfg(not(feature = "hashmap"))]
+pub type Map = BTreeMap<String, Entry>;
+#[cfg(feature = "hashmap")]
+pub type Map = HashMap<String, Entry>;
+
+// Entries whose key starts with `prefix`, in key order with the BTreeMap
+#[cfg(not(feature = "hashmap"))]
+pub fn prefix_range<'a>(map: &'a Map, prefix: &'a str) -> impl Iterator<Item = (&'a String, &'a Entry)> + 'a {
+    map.range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+        .take_while(move |(key, _)| key.starts_with(prefix))
+}
+
+#[cfg(feature = "hashmap")]
+pub fn prefix_range<'a>(map: &'a Map, prefix: &'a str) -> impl Iterator<Item = (&'a String, &'a Entry)> + 'a {
+    map.iter().filter(move |(key, _)| key.starts_with(prefix))
+}
+
+// Like `prefix_range`, but only the keys sorting after `after`, used to resume
+// a scan from a cursor
+#[cfg(not(feature = "hashmap"))]
+pub fn prefix_range_after<'a>(
+    map: &'a Map,
+    prefix: &'a str,
+    after: Option<&'a str>,
+) -> Box<dyn Iterator<Item = (&'a String, &'a Entry)> + 'a> {
+    match after {
+        Some(after) if after >= prefix => Box::new(
+            map.range::<str, _>((Bound::Excluded(after), Bound::Unbounded))
+                .take_while(move |(key, _)| key.starts_with(prefix)),
+        ),
+        _ => Box::new(prefix_range(map, prefix)),
+    }
+}
+
+#[cfg(feature = "hashmap")]
+pub fn prefix_range_after<'a>(
+    map: &'a Map,
+    prefix: &'a str,
+    after: Option<&'a str>,
+) -> Box<dyn Iterator<Item = (&'a String, &'a Entry)> + 'a> {
+    let mut entries: Vec<(&String, &Entry)> = prefix_range(map, prefix)
+        .filter(|(key, _)| after.is_none_or(|after| key.as_str() > after))
+        .collect();
+
+    entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+    Box::new(entries.into_iter())
+}
+
+// Readers load the current snapshot without locking, writers are serialized by
+// a mutex and publish a modified copy of the map when their guard is dropped.
+pub struct Store {
+    current: ArcSwap<Map>,
+    writer: TrackedMutex,
+    flusher: TrackedMutex,
+    // Bumped every time a modified map is published
+    generation: AtomicU64,
+    pub write_lock_wait: Histogram,
+    pub flush_lock_wait: Histogram,
+}
+
+impl Store {
+    pub fn new(map: Map) -> Self {
+        Store {
+            current: ArcSwap::from_pointee(map),
+            writer: TrackedMutex::default(),
+            flusher: TrackedMutex::default(),
+            generation: AtomicU64::new(0),
+            write_lock_wait: Histogram::default(),
+            flush_lock_wait: Histogram::default(),
+        }
+    }
+
+    pub fn read(&self) -> Arc<Map> {
+        self.current.load_full()
+    }
+
+    // Bumped after the new map is visible, so a generation read before a
+    // snapshot never belongs to an older map than the snapshot
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    // Held while writing the file so flushes cannot interleave and the last one
+    // to finish always writes the latest snapshot.
+    pub fn flush_lock(&self) -> TrackedGuard<'_> {
+        let started = Instant::now();
+        let lock = self.flusher.lock();
+        self.flush_lock_wait.observe(started.elapsed());
+        lock
+    }
+
+    pub fn write(&self) -> StoreWriteGuard<'_> {
+        let started = Instant::now();
+        let lock = self.writer.lock();
+        self.write_lock_wait.observe(started.elapsed());
+
+        StoreWriteGuard {
+            store: self,
+            map: self.current.load_full(),
+            dirty: false,
+            _lock: lock,
+        }
+    }
+
+    pub fn write_lock_state(&self) -> LockState {
+        self.writer.state()
+    }
+
+    pub fn flush_lock_state(&self) -> LockState {
+        self.flusher.state()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LockState {
+    pub held: bool,
+    pub waiting: usize,
+}
+
+// A mutex that keeps count of the callers blocked on it and whether it is held,
+// for a point-in-time view of contention next to the wait histograms. Both are
+// updated outside the mutex, so they can be briefly off while it changes hands.
+#[derive(Default)]
+struct TrackedMutex {
+    mutex: Mutex<()>,
+    waiting: AtomicUsize,
+    held: AtomicBool,
+}
+
+impl TrackedMutex {
+    fn lock(&self) -> TrackedGuard<'_> {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        let guard = self.mutex.lock().unwrap();
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        self.held.store(true, Ordering::SeqCst);
+
+        TrackedGuard {
+            held: &self.held,
+            _guard: guard,
+        }
+    }
+
+    fn state(&self) -> LockState {
+        LockState {
+            held: self.held.load(Ordering::SeqCst),
+            waiting: self.waiting.load(Ordering::SeqCst),
+        }
+    }
+}
+
+pub struct TrackedGuard<'a> {
+    held: &'a AtomicBool,
+    _guard: MutexGuard<'a, ()>,
+}
+
+// Runs before the mutex guard is dropped, so the next holder always sets the
+// flag after it was cleared here
+impl Drop for TrackedGuard<'_> {
+    fn drop(&mut self) {
+        self.held.store(false, Ordering::SeqCst);
+    }
+}
+
+pub struct StoreWriteGuard<'a> {
+    store: &'a Store,
+    map: Arc<Map>,
+    dirty: bool,
+    _lock: TrackedGuard<'a>,
+}
+
+impl Deref for StoreWriteGuard<'_> {
+    type Target = Map;
+
+    fn deref(&self) -> &Map {
+        &self.map
+    }
+}
+
+impl DerefMut for StoreWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Map {
+        self.dirty = true;
+        Arc::make_mut(&mut self.map)
+    }
+}
+
+impl Drop for StoreWriteGuard<'_> {
+    fn drop(&mut self) {
+        if self.dirty {
+            self.store.current.store(self.map.clone());
+            self.store.generation.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
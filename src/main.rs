@@ -1,26 +1,398 @@
 use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::{Compress, Condition};
 use actix_web::{
     web,
     App,
+    HttpMessage,
     HttpServer,
     Responder,
     get,
+    post,
     put,
     patch,
     delete,
 };
+use futures_util::future::Either;
+use futures_util::{StreamExt, TryFutureExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::Instrument;
+use tracing_subscriber::filter::LevelFilter;
 use serde::Deserialize;
 use serde_json::Value;
 
+mod access_log;
+use access_log::AccessLog;
+
+mod advisory;
+use advisory::{AdvisoryLocks, LockError};
+
+mod auth;
+use auth::Auth;
+
+mod config;
+use config::{Config, DiskFormat};
+
+mod cors;
+use cors::Cors;
+
+#[cfg(feature = "grpc")]
+mod grpc;
+
+mod idempotency;
+use idempotency::IdempotencyCache;
+
 mod kvstore;
-use kvstore::KVStore;
+use kvstore::{check_file, BatchResults, ErrorKind, KVStore, KVStoreError, Operation, Precondition, ValueType};
+
 use tracing::log::info;
 
+mod rpc;
+
+mod telemetry;
+use telemetry::LogLevel;
+
+#[cfg(test)]
+mod tests;
+
+mod webhook;
+use webhook::{PreWriteHook, Rejection};
+
+// Longest a subscriber may wait for a change in one request
+const MAX_SUBSCRIBE_TIMEOUT_SECS: u64 = 300;
+
+// How long an advisory lock is held when the request does not say, and the
+// longest it may be held for without being extended
+const DEFAULT_LOCK_TTL_SECS: u64 = 30;
+const MAX_LOCK_TTL_SECS: u64 = 3600;
+
 #[derive(Debug, Deserialize)]
 pub struct ListQuery {
     skip: Option<u64>,
     limit: Option<u64>,
+    pretty: Option<bool>,
+    stream: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeysQuery {
+    regex: Option<String>,
+    prefix: Option<String>,
+    value_type: Option<ValueType>,
+    limit: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangesQuery {
+    since: u64,
+    values: Option<bool>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScanQuery {
+    prefix: Option<String>,
+    filter_field: Option<String>,
+    filter_eq: Option<String>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TreeQuery {
+    prefix: Option<String>,
+    delimiter: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CountQuery {
+    prefix: Option<String>,
+    estimate: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecentQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetQuery {
+    pretty: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceQuery {
+    on: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PutQuery {
+    if_version: Option<u64>,
+    ttl_seconds: Option<u64>,
+    upsert: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateQuery {
+    ttl_seconds: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteQuery {
+    idempotent: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TruncateQuery {
+    confirm: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExpireRequest {
+    keys: Vec<String>,
+    ttl_seconds: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchGetRequest {
+    keys: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchPutItem {
+    key: String,
+    value: Value,
+    ttl_seconds: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchPutQuery {
+    namespace: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CasOperation {
+    key: String,
+    expected: Value,
+    new: Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IncrementQuery {
+    path: String,
+    by: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlobQuery {
+    validate: Option<BlobValidation>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobValidation {
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenamePrefixQuery {
+    from: String,
+    to: String,
+    overwrite: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SwapQuery {
+    a: String,
+    b: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LockQuery {
+    ttl_seconds: Option<u64>,
+    token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockQuery {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TrimQuery {
+    start: i64,
+    stop: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MemberQuery {
+    member: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RewriteQuery {
+    format: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    replace: Option<bool>,
+    parse_strings: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AppendRequest {
+    ts: Option<u64>,
+    val: Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AppendQuery {
+    max_len: Option<usize>,
+    window_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PointsQuery {
+    since: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GeoAddRequest {
+    name: String,
+    lat: f64,
+    lng: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NearbyQuery {
+    lat: f64,
+    lng: f64,
+    radius_km: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApplyRequest {
+    op: String,
+    arg: Option<Value>,
+    path: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TargetQuery {
+    to: String,
+    overwrite: Option<bool>,
+}
+
+fn error_response(e: Box<dyn Error>) -> actix_web::HttpResponse {
+    actix_web::HttpResponse::build(error_status(e.as_ref())).body(e.to_string())
+}
+
+fn error_status(e: &(dyn Error + 'static)) -> StatusCode {
+    if let Some(rejection) = e.downcast_ref::<Rejection>() {
+        return rejection.status();
+    }
+
+    match e.downcast_ref::<KVStoreError>().map(|e| e.kind()) {
+        Some(ErrorKind::NotFound) => StatusCode::NOT_FOUND,
+        Some(ErrorKind::Conflict) => StatusCode::CONFLICT,
+        Some(ErrorKind::Unavailable) => StatusCode::SERVICE_UNAVAILABLE,
+        Some(ErrorKind::Forbidden) => StatusCode::FORBIDDEN,
+        Some(ErrorKind::QuotaExceeded) => StatusCode::INSUFFICIENT_STORAGE,
+        Some(ErrorKind::InvalidInput) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// The body of every batch write, one result per key in request order with the
+// status a single-key request would have returned, so clients can retry only
+// the keys that failed
+fn batch_response(results: Vec<Value>) -> actix_web::HttpResponse {
+    let failed = results.iter().filter(|result| result.get("error").is_some()).count();
+
+    actix_web::HttpResponse::Ok().json(serde_json::json!({
+        "results": results,
+        "succeeded": results.len() - failed,
+        "failed": failed,
+    }))
+}
+
+fn batch_results(results: BatchResults) -> Vec<Value> {
+    results
+        .into_iter()
+        .map(|(key, result)| match result {
+            Ok(()) => serde_json::json!({ "key": key, "status": StatusCode::OK.as_u16() }),
+            Err(e) => serde_json::json!({ "key": key, "status": error_status(e.as_ref()).as_u16(), "error": e.to_string() }),
+        })
+        .collect()
+}
+
+fn is_not_found(e: &(dyn Error + 'static)) -> bool {
+    e.downcast_ref::<KVStoreError>().is_some_and(|e| e.kind() == ErrorKind::NotFound)
+}
+
+fn is_not_modified(req: &actix_web::HttpRequest, etag: &str) -> bool {
+    let if_none_match = match req.headers().get(header::IF_NONE_MATCH).and_then(|value| value.to_str().ok()) {
+        Some(if_none_match) => if_none_match,
+        None => return false,
+    };
+
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
+
+fn not_modified(etag: String) -> actix_web::HttpResponse {
+    actix_web::HttpResponse::NotModified().insert_header((header::ETAG, etag)).finish()
+}
+
+fn with_etag(mut response: actix_web::HttpResponse, etag: String) -> actix_web::HttpResponse {
+    if let Ok(etag) = header::HeaderValue::from_str(&etag) {
+        response.headers_mut().insert(header::ETAG, etag);
+    }
+    response
+}
+
+// Seconds since the epoch from `If-Unmodified-Since`, a date that cannot be
+// parsed is ignored like the header was not sent
+fn unmodified_since(req: &actix_web::HttpRequest) -> Option<u64> {
+    let header::IfUnmodifiedSince(date) = req.get_header()?;
+
+    SystemTime::from(date).duration_since(UNIX_EPOCH).ok().map(|since| since.as_secs())
+}
+
+fn with_last_modified(mut response: actix_web::HttpResponse, updated_at: u64) -> actix_web::HttpResponse {
+    let date = header::HttpDate::from(UNIX_EPOCH + Duration::from_millis(updated_at));
+
+    if let Ok(date) = header::HeaderValue::from_str(&date.to_string()) {
+        response.headers_mut().insert(header::LAST_MODIFIED, date);
+    }
+    response
+}
+
+fn conditional_put_response(result: Result<Result<u64, u64>, Box<dyn Error>>) -> actix_web::HttpResponse {
+    match result {
+        Ok(Ok(version)) => actix_web::HttpResponse::Ok().json(serde_json::json!({ "version": version })),
+        Ok(Err(current)) => actix_web::HttpResponse::PreconditionFailed().json(serde_json::json!({ "version": current })),
+        Err(e) => error_response(e),
+    }
+}
+
+fn json_response(value: &Value, pretty: Option<bool>) -> actix_web::HttpResponse {
+    if pretty.unwrap_or(false) {
+        actix_web::HttpResponse::Ok()
+            .content_type("application/json")
+            .body(serde_json::to_string_pretty(value).unwrap())
+    } else {
+        actix_web::HttpResponse::Ok().json(value)
+    }
 }
 
 fn print_ascii_art() {
@@ -41,103 +413,1454 @@ fn print_ascii_art() {
     );
 }
 
+// One event with everything an operator needs to confirm what the server is
+// running with, leaving out anything that could carry credentials
+fn log_startup(config: &Config, documents: usize) {
+    let pre_write_webhook = config.pre_write_webhook.as_deref().map(redact_url);
+
+    tracing::info!(
+        version = env!("CARGO_PKG_VERSION"),
+        db_path = %config.db_path,
+        bind_address = %config.bind_address,
+        grpc_address = config.grpc_address.as_deref(),
+        persistence = ?config.persistence,
+        disk_format = ?config.disk_format,
+        read_only = config.read_only,
+        flush_coalesce_ms = config.flush_coalesce.as_millis() as u64,
+        flush_cache = config.flush_cache,
+        max_keys = config.max_keys,
+        max_bytes = config.max_bytes,
+        pre_write_webhook = pre_write_webhook.as_deref(),
+        documents,
+        "Server starting"
+    );
+}
+
+// Drops the user info and the query string of a URL, where tokens usually go
+fn redact_url(url: &str) -> String {
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => (format!("{}://", scheme), rest),
+        None => (String::new(), url),
+    };
+
+    let (rest, query) = match rest.find(['?', '#']) {
+        Some(at) => (&rest[..at], true),
+        None => (rest, false),
+    };
+
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+
+    let (host, user_info) = match authority.rsplit_once('@') {
+        Some((_, host)) => (host, true),
+        None => (authority, false),
+    };
+
+    let redacted = if user_info || query { " (redacted)" } else { "" };
+
+    format!("{}{}{}{}", scheme, host, path, redacted)
+}
+
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    tracing_subscriber::fmt::init();
+    let telemetry_guard = telemetry::init();
+
+    if std::env::args().nth(1).as_deref() == Some("fsck") {
+        return fsck();
+    }
 
     print_ascii_art();
 
-    let kvs: KVStore = KVStore::new();
-
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(kvs.clone()))
-            .service(index)
-            .service(get_key)
-            .service(create_document)
-            .service(create_document_with_key)
-            .service(update_document)
-            .service(delete_document)
-            .service(list_documents)
-    })
-    .workers(1)
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await?;
+    let config = Config::load()?;
+
+    let kvs = web::Data::new(KVStore::new(&config));
+
+    log_startup(&config, kvs.document_count());
+
+    let idempotency = web::Data::new(IdempotencyCache::new(config.idempotency_ttl));
+
+    let advisory_locks = web::Data::new(AdvisoryLocks::new());
+
+    let log_level = web::Data::new(telemetry_guard.log_level());
+
+    let sweeper = kvs.clone();
+    let idempotency_sweeper = idempotency.clone();
+    let lock_sweeper = advisory_locks.clone();
+    let sweep_interval = config.sweep_interval;
+
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(sweep_interval);
+        loop {
+            interval.tick().await;
+            sweeper.sweep_expired().await;
+            idempotency_sweeper.prune();
+            lock_sweeper.prune();
+        }
+    });
+
+    let state = AppState {
+        kvs: kvs.clone(),
+        config: config.clone(),
+        idempotency,
+        advisory_locks,
+        log_level,
+        access_log: match config.access_log {
+            Some(format) => Some(Arc::new(AccessLog::open(format, config.access_log_path.as_deref())?)),
+            None => None,
+        },
+        cors: Cors::from_config(&config)?.map(Arc::new),
+        auth: Auth::from_config(&config)?.map(Arc::new),
+    };
+
+    #[cfg(feature = "grpc")]
+    if let Some(address) = config.grpc_address.clone() {
+        let kvs = kvs.clone();
+        let pre_write_webhook = config.pre_write_webhook.is_some();
+        let auth = state.auth.clone();
+
+        actix_web::rt::spawn(async move {
+            if let Err(e) = grpc::serve(kvs, &address, pre_write_webhook, auth).await {
+                tracing::error!("gRPC server on {} stopped: {}", address, e);
+            }
+        });
+    }
+
+    HttpServer::new(move || app(state.clone()))
+        .workers(1)
+        .max_connections(config.max_connections)
+        .keep_alive(config.keep_alive)
+        .client_request_timeout(config.client_request_timeout)
+        .bind(config.bind_address.as_str())?
+        .run()
+        .await?;
 
     Ok(())
 }
 
-#[get("/")]
-async fn index() -> impl Responder {
-    info!("Index page requested");
-    "VBank Key-Value Store v0.6.1 Online"
+// Everything the routes and middleware share, built once and cloned into the
+// app of every worker
+#[derive(Clone)]
+struct AppState {
+    kvs: web::Data<KVStore>,
+    config: Config,
+    idempotency: web::Data<IdempotencyCache>,
+    advisory_locks: web::Data<AdvisoryLocks>,
+    log_level: web::Data<LogLevel>,
+    access_log: Option<Arc<AccessLog>>,
+    cors: Option<Arc<Cors>>,
+    auth: Option<Arc<Auth>>,
 }
 
-#[get("/{namespace}/{key}")]
-async fn get_key(kvs: web::Data<KVStore>, path: web::Path<(String, String)>) -> impl Responder {
+fn app(
+    state: AppState,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let AppState {
+        kvs,
+        config,
+        idempotency,
+        advisory_locks,
+        log_level,
+        access_log,
+        cors,
+        auth,
+    } = state;
 
-    let (namespace, key) = path.into_inner();
+    let max_payload_size = config.max_payload_size;
+    let response_compression = config.response_compression;
+    let response_compression_min_bytes = config.response_compression_min_bytes;
+    let pre_write_hook = web::Data::new(PreWriteHook::new(&config));
 
-    match kvs.get(namespace.clone(), key.clone()).await {
-        Ok(response) => actix_web::HttpResponse::Ok().json(response),
-        Err(e) => actix_web::HttpResponse::NotFound().body(e.to_string()),
+    App::new()
+        // Innermost, so rejected requests are still logged and get CORS headers
+        .wrap_fn(move |req, srv| match auth.as_ref().map(|auth| auth.check(&req)) {
+            Some(Err(rejection)) => Either::Left(std::future::ready(Ok(req.into_response(rejection)))),
+            _ => Either::Right(srv.call(req).map_ok(ServiceResponse::map_into_boxed_body)),
+        })
+        .wrap_fn(move |req, srv| {
+            let access_log = access_log.clone();
+            let request = access_log.as_ref().map(|access_log| access_log.request_info(&req));
+            let started = Instant::now();
+
+            let span = if telemetry::enabled() {
+                tracing::info_span!("request", method = %req.method(), path = %req.path(), status = tracing::field::Empty)
+            } else {
+                tracing::Span::none()
+            };
+
+            let response = span.in_scope(|| srv.call(req));
+
+            async move {
+                let response = response.await?;
+
+                tracing::Span::current().record("status", response.status().as_u16());
+
+                if let (Some(access_log), Some(request)) = (access_log, request) {
+                    access_log.record(&request, &response, started.elapsed());
+                }
+
+                Ok(response)
+            }
+            .instrument(span)
+        })
+        // Runs inside `Compress`, which leaves responses that already have a
+        // `Content-Encoding` alone, so small bodies are sent as they are
+        .wrap_fn(move |req, srv| {
+            let response = srv.call(req);
+
+            async move {
+                let mut response = response.await?;
+
+                let small = matches!(
+                    response.response().body().size(),
+                    BodySize::Sized(size) if size < response_compression_min_bytes
+                );
+
+                if response_compression && small {
+                    response.headers_mut().insert(header::CONTENT_ENCODING, header::HeaderValue::from_static("identity"));
+                }
+
+                Ok(response)
+            }
+        })
+        .wrap(Condition::new(response_compression, Compress::default()))
+        // Outermost, so preflights are answered before any other middleware
+        // and every response to an allowed origin gets the CORS headers
+        .wrap_fn(move |req, srv| {
+            let cors = cors.clone();
+            let origin = cors.as_ref().and_then(|cors| cors.allow_origin(&req));
+
+            let response = match &cors {
+                Some(cors) if Cors::is_preflight(&req) => {
+                    let preflight = cors.preflight(&req);
+                    Either::Left(std::future::ready(Ok(req.into_response(preflight))))
+                }
+                _ => Either::Right(srv.call(req).map_ok(ServiceResponse::map_into_boxed_body)),
+            };
+
+            async move {
+                let mut response = response.await?;
+
+                if let (Some(cors), Some(origin)) = (cors, origin) {
+                    if response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none() {
+                        cors.apply(response.headers_mut(), origin);
+                    }
+                }
+
+                Ok(response)
+            }
+        })
+        .app_data(kvs.clone())
+        .app_data(web::Data::new(config))
+        .app_data(pre_write_hook)
+        .app_data(idempotency.clone())
+        .app_data(advisory_locks.clone())
+        .app_data(log_level.clone())
+        .app_data(web::JsonConfig::default().limit(max_payload_size))
+        .app_data(web::PayloadConfig::new(max_payload_size))
+        .service(index)
+        .service(stats)
+        .service(stats_sizes)
+        .service(metrics)
+        .service(lock_snapshot)
+        .service(set_maintenance)
+        .service(set_log_level)
+        .service(truncate_store)
+        .service(fsck_data_file)
+        .service(reload_data_file)
+        .service(swap_documents)
+        .service(rename_prefix)
+        .service(vacuum_store)
+        .service(rewrite_data_file)
+        .service(export_snapshot)
+        .service(diff_snapshot)
+        .service(export_dump)
+        .service(import_dump)
+        .service(batch_expire)
+        .service(batch_put)
+        .service(batch_delete)
+        .service(batch_get)
+        .service(batch_exists)
+        .service(snapshot_read)
+        .service(batch_cas)
+        .service(find_keys)
+        .service(find_by)
+        .service(recent_keys)
+        .service(count_keys)
+        .service(scan_documents)
+        .service(key_tree)
+        .service(json_rpc)
+        .service(changes_since)
+        .service(upload_blob)
+        .service(download_blob)
+        .service(delete_blob)
+        .service(get_series)
+        .service(get_key)
+        .service(create_document)
+        .service(create_document_with_key)
+        .service(update_document)
+        .service(delete_document)
+        .service(list_documents)
+        .service(take_document)
+        .service(lock_key)
+        .service(unlock_key)
+        .service(move_document)
+        .service(copy_document)
+        .service(undelete_document)
+        .service(get_type)
+        .service(key_exists)
+        .service(get_version)
+        .service(subscribe_key)
+        .service(get_storage)
+        .service(increment_field)
+        .service(apply_operation)
+        .service(trim_list)
+        .service(geo_add)
+        .service(geo_nearby)
+        .service(set_add)
+        .service(set_remove)
+        .service(set_has)
+        .service(append_point)
+        .service(get_points)
+        .service(set_max)
+        .service(set_min)
+}
+
+// `vbank fsck` checks the configured data file and exits without serving,
+// failing when it finds problems
+fn fsck() -> Result<(), Box<dyn Error>> {
+    let config = Config::load()?;
+
+    let report = check_file(&config.db_path)?;
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    if report["healthy"] != true {
+        std::process::exit(1);
     }
+
+    Ok(())
 }
 
-#[put("/{namespace}/")]
-async fn create_document(kvs: web::Data<KVStore>, namespace: web::Path<String>, value: web::Json<Value>) -> impl Responder {
-    match kvs.create_document(namespace.clone(), value.clone()).await {
-        Ok(response) => actix_web::HttpResponse::Created().body(response),
-        Err(e) => actix_web::HttpResponse::InternalServerError().body(e.to_string()),
+#[post("/admin/fsck")]
+async fn fsck_data_file(kvs: web::Data<KVStore>) -> impl Responder {
+    match kvs.fsck().await {
+        Ok(report) => actix_web::HttpResponse::Ok().json(report),
+        Err(e) => error_response(e),
     }
 }
 
-#[put("/{namespace}/{key}")]
-async fn create_document_with_key(
+#[post("/admin/reload")]
+async fn reload_data_file(kvs: web::Data<KVStore>) -> impl Responder {
+    match kvs.reload().await {
+        Ok(report) => actix_web::HttpResponse::Ok().json(report),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/admin/rewrite")]
+async fn rewrite_data_file(kvs: web::Data<KVStore>, query: web::Query<RewriteQuery>) -> impl Responder {
+
+    let format = match query.format.parse::<DiskFormat>() {
+        Ok(format) => format,
+        Err(e) => return actix_web::HttpResponse::BadRequest().body(e),
+    };
+
+    match kvs.rewrite(format).await {
+        Ok(report) => actix_web::HttpResponse::Ok().json(report),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/admin/vacuum")]
+async fn vacuum_store(kvs: web::Data<KVStore>) -> impl Responder {
+    match kvs.vacuum().await {
+        Ok(report) => actix_web::HttpResponse::Ok().json(report),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/admin/rename-prefix")]
+async fn rename_prefix(
     kvs: web::Data<KVStore>,
-    path: web::Path<(String, String)>,
-    value: web::Json<Value>,
+    hook: web::Data<PreWriteHook>,
+    query: web::Query<RenamePrefixQuery>,
 ) -> impl Responder {
 
-    let (namespace, key) = path.into_inner();
+    let query = query.into_inner();
 
-    match kvs.create_document_with_key(namespace.clone(), key.clone(), value.clone()).await {
-        Ok(response) => actix_web::HttpResponse::Created().body(response),
-        Err(e) => actix_web::HttpResponse::InternalServerError().body(e.to_string()),
+    match kvs.rename_prefix(query.from, query.to, query.overwrite.unwrap_or(false), hook.get_ref()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
     }
 }
 
-#[patch("/{namespace}/{key}")]
-async fn update_document(
+#[post("/admin/swap")]
+async fn swap_documents(
     kvs: web::Data<KVStore>,
-    path: web::Path<(String, String)>,
-    value: web::Json<Value>,
+    hook: web::Data<PreWriteHook>,
+    query: web::Query<SwapQuery>,
 ) -> impl Responder {
 
-    let (namespace, key) = path.into_inner();
+    let query = query.into_inner();
 
-    match kvs.insert(namespace.clone(), key.clone(), value.clone()).await {
-        Ok(response) => actix_web::HttpResponse::Ok().body(response),
-        Err(e) => actix_web::HttpResponse::InternalServerError().body(e.to_string()),
+    match kvs.swap_documents(query.a, query.b, hook.get_ref()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
     }
 }
 
-#[delete("/{namespace}/{key}")]
-async fn delete_document(kvs: web::Data<KVStore>, path: web::Path<(String, String)>) -> impl Responder {
+#[post("/rpc")]
+async fn json_rpc(kvs: web::Data<KVStore>, hook: web::Data<PreWriteHook>, body: web::Bytes) -> impl Responder {
+    match rpc::handle(&kvs, &hook, &body).await {
+        Some(response) => actix_web::HttpResponse::Ok().json(response),
+        None => actix_web::HttpResponse::NoContent().finish(),
+    }
+}
 
-    let (namespace, key) = path.into_inner();
+#[get("/")]
+async fn index() -> impl Responder {
+    info!("Index page requested");
+    "VBank Key-Value Store v0.6.1 Online"
+}
 
-    match kvs.delete(namespace.clone(), key.clone()).await {
-        Ok(response) => actix_web::HttpResponse::Ok().body(response),
-        Err(e) => actix_web::HttpResponse::InternalServerError().body(e.to_string()),
-    }
+#[get("/stats")]
+async fn stats(kvs: web::Data<KVStore>) -> impl Responder {
+    actix_web::HttpResponse::Ok().json(kvs.stats().await)
 }
 
-#[get("/{namespace}/list/")]
-async fn list_documents(kvs: web::Data<KVStore>, namespace: web::Path<String>, query: web::Query<ListQuery>) -> impl Responder {
-    match kvs.list_documents(namespace.clone(), query.skip, query.limit).await {
-        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+#[get("/metrics")]
+async fn metrics(kvs: web::Data<KVStore>) -> impl Responder {
+    actix_web::HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(kvs.metrics().await)
+}
+
+#[get("/stats/sizes")]
+async fn stats_sizes(kvs: web::Data<KVStore>) -> impl Responder {
+    actix_web::HttpResponse::Ok().json(kvs.size_histogram().await)
+}
+
+#[get("/admin/locks")]
+async fn lock_snapshot(kvs: web::Data<KVStore>) -> impl Responder {
+    actix_web::HttpResponse::Ok().json(kvs.lock_snapshot())
+}
+
+#[post("/admin/maintenance")]
+async fn set_maintenance(kvs: web::Data<KVStore>, query: web::Query<MaintenanceQuery>) -> impl Responder {
+    kvs.set_maintenance(query.on);
+
+    actix_web::HttpResponse::Ok().json(serde_json::json!({ "maintenance": query.on }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogLevelQuery {
+    level: String,
+}
+
+#[put("/admin/log-level")]
+async fn set_log_level(log_level: web::Data<LogLevel>, query: web::Query<LogLevelQuery>) -> impl Responder {
+
+    let level: LevelFilter = match query.level.parse() {
+        Ok(level) => level,
+        Err(_) => {
+            return actix_web::HttpResponse::BadRequest()
+                .body(format!("Unknown log level: {}, use off, error, warn, info, debug or trace", query.level))
+        }
+    };
+
+    let previous = log_level.current().map(|previous| previous.to_string());
+
+    if let Err(e) = log_level.set(level) {
+        return actix_web::HttpResponse::InternalServerError().body(e);
+    }
+
+    // Logged at warn so the change is recorded even when lowering the level
+    tracing::warn!("Log level changed from {} to {}", previous.as_deref().unwrap_or("unknown"), level);
+
+    actix_web::HttpResponse::Ok().json(serde_json::json!({
+        "level": level.to_string(),
+        "previous": previous,
+    }))
+}
+
+#[get("/admin/snapshot")]
+async fn export_snapshot(kvs: web::Data<KVStore>) -> impl Responder {
+    ndjson_response(kvs.export_snapshot())
+}
+
+// Sends NDJSON chunks as they are produced, the next chunk is only received
+// once the previous one was written to the client
+fn ndjson_response(receiver: tokio::sync::mpsc::Receiver<String>) -> actix_web::HttpResponse {
+    let chunks = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let chunk = receiver.recv().await?;
+        Some((Ok::<_, actix_web::Error>(web::Bytes::from(chunk)), receiver))
+    });
+
+    actix_web::HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(chunks)
+}
+
+#[post("/admin/diff")]
+async fn diff_snapshot(kvs: web::Data<KVStore>, config: web::Data<Config>, payload: web::Payload) -> impl Responder {
+
+    let mut diff = kvs.diff_snapshot();
+
+    if let Err(response) = read_ndjson(payload, config.max_blob_size, |line| diff.add_line(line)).await {
+        return response;
+    }
+
+    actix_web::HttpResponse::Ok().json(diff.finish())
+}
+
+#[get("/admin/dump")]
+async fn export_dump(kvs: web::Data<KVStore>) -> impl Responder {
+    ndjson_response(kvs.export_dump())
+}
+
+#[post("/admin/import")]
+async fn import_dump(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    config: web::Data<Config>,
+    query: web::Query<ImportQuery>,
+    payload: web::Payload,
+) -> impl Responder {
+
+    let mut import = kvs.import_snapshot(query.parse_strings.unwrap_or(false));
+
+    if let Err(response) = read_ndjson(payload, config.max_blob_size, |line| import.add_line(line)).await {
+        return response;
+    }
+
+    match kvs.finish_import(import, query.replace.unwrap_or(false), hook.get_ref()).await {
+        Ok(report) => actix_web::HttpResponse::Ok().json(report),
+        Err(e) => error_response(e),
+    }
+}
+
+// Feeds every line of an NDJSON body to `add_line` as it arrives, failing with
+// the response to send for bodies over `max_size` or lines that are rejected
+async fn read_ndjson(
+    mut payload: web::Payload,
+    max_size: u64,
+    mut add_line: impl FnMut(&[u8]) -> Result<(), Box<dyn Error>>,
+) -> Result<(), actix_web::HttpResponse> {
+
+    let mut pending = Vec::new();
+    let mut size = 0;
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| actix_web::HttpResponse::BadRequest().body(e.to_string()))?;
+
+        size += chunk.len() as u64;
+        if size > max_size {
+            return Err(actix_web::HttpResponse::PayloadTooLarge()
+                .body(format!("Body exceeds the maximum size of {} bytes", max_size)));
+        }
+
+        pending.extend_from_slice(&chunk);
+
+        let mut start = 0;
+        while let Some(end) = pending[start..].iter().position(|&byte| byte == b'\n') {
+            add_line(&pending[start..start + end]).map_err(error_response)?;
+            start += end + 1;
+        }
+        pending.drain(..start);
+    }
+
+    add_line(&pending).map_err(error_response)
+}
+
+#[delete("/admin/all")]
+async fn truncate_store(kvs: web::Data<KVStore>, query: web::Query<TruncateQuery>) -> impl Responder {
+
+    if !query.confirm.unwrap_or(false) {
+        return actix_web::HttpResponse::BadRequest().body("Pass confirm=true to delete every document");
+    }
+
+    match kvs.truncate().await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/batch/expire")]
+async fn batch_expire(kvs: web::Data<KVStore>, body: web::Json<ExpireRequest>) -> impl Responder {
+
+    let request = body.into_inner();
+
+    match kvs.expire_documents(request.keys, request.ttl_seconds).await {
+        Ok(results) => batch_response(batch_results(results)),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/batch/put")]
+async fn batch_put(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    query: web::Query<BatchPutQuery>,
+    body: web::Json<Vec<BatchPutItem>>,
+) -> impl Responder {
+
+    let items = body.into_inner();
+
+    // Documents the webhook rejects fail on their own, with its status and body
+    let mut rejected = Vec::new();
+    let mut documents = Vec::new();
+
+    for (position, item) in items.into_iter().enumerate() {
+        match hook.apply(Some(&item.key), item.value).await {
+            Ok(value) => documents.push((item.key, value, item.ttl_seconds)),
+            Err(rejection) => {
+                let status = rejection.status().as_u16();
+
+                rejected.push((position, serde_json::json!({ "key": item.key, "status": status, "error": rejection.to_string() })));
+            }
+        }
+    }
+
+    let namespace = query.into_inner().namespace.unwrap_or_default();
+
+    let mut results = match kvs.put_documents(namespace, documents).await {
+        Ok(results) => batch_results(results),
+        Err(e) => return error_response(e),
+    };
+
+    for (position, result) in rejected {
+        results.insert(position, result);
+    }
+
+    batch_response(results)
+}
+
+#[post("/batch/delete")]
+async fn batch_delete(kvs: web::Data<KVStore>, body: web::Json<BatchGetRequest>) -> impl Responder {
+    match kvs.delete_documents(body.into_inner().keys).await {
+        Ok(results) => batch_response(batch_results(results)),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/batch/get")]
+async fn batch_get(kvs: web::Data<KVStore>, body: web::Json<BatchGetRequest>) -> impl Responder {
+    match kvs.get_ordered(body.into_inner().keys).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/batch/exists")]
+async fn batch_exists(kvs: web::Data<KVStore>, body: web::Json<BatchGetRequest>) -> impl Responder {
+    actix_web::HttpResponse::Ok().json(kvs.exists_many(body.into_inner().keys).await)
+}
+
+#[post("/snapshot-read")]
+async fn snapshot_read(kvs: web::Data<KVStore>, body: web::Json<BatchGetRequest>) -> impl Responder {
+    match kvs.snapshot_read(body.into_inner().keys).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/batch/cas")]
+async fn batch_cas(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    query: web::Query<BatchPutQuery>,
+    body: web::Json<Vec<CasOperation>>,
+) -> impl Responder {
+
+    let operations = body
+        .into_inner()
+        .into_iter()
+        .map(|operation| (operation.key, operation.expected, operation.new))
+        .collect();
+
+    let namespace = query.into_inner().namespace.unwrap_or_default();
+
+    match kvs.compare_and_swap(namespace, operations, hook.get_ref()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/keys")]
+async fn find_keys(req: actix_web::HttpRequest, kvs: web::Data<KVStore>, query: web::Query<KeysQuery>) -> impl Responder {
+
+    let etag = kvs.etag();
+    if is_not_modified(&req, &etag) {
+        return not_modified(etag);
+    }
+
+    let query = query.into_inner();
+
+    match kvs.find_keys(query.regex, query.prefix, query.value_type, query.limit).await {
+        Ok(response) => with_etag(actix_web::HttpResponse::Ok().json(response), etag),
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/by/{field}/{value}")]
+async fn find_by(kvs: web::Data<KVStore>, path: web::Path<(String, String)>) -> impl Responder {
+
+    let (field, value) = path.into_inner();
+
+    match kvs.find_by(field, value).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/recent")]
+async fn recent_keys(kvs: web::Data<KVStore>, query: web::Query<RecentQuery>) -> impl Responder {
+    actix_web::HttpResponse::Ok().json(kvs.recent_keys(query.limit).await)
+}
+
+#[get("/scan")]
+async fn scan_documents(kvs: web::Data<KVStore>, query: web::Query<ScanQuery>) -> impl Responder {
+
+    let query = query.into_inner();
+
+    let filter = match (query.filter_field, query.filter_eq) {
+        (Some(field), Some(expected)) => Some((field, expected)),
+        (None, None) => None,
+        _ => return actix_web::HttpResponse::BadRequest().body("filter_field and filter_eq must be passed together"),
+    };
+
+    actix_web::HttpResponse::Ok().json(kvs.scan(query.prefix, filter, query.limit, query.cursor).await)
+}
+
+#[get("/tree")]
+async fn key_tree(kvs: web::Data<KVStore>, query: web::Query<TreeQuery>) -> impl Responder {
+
+    let query = query.into_inner();
+
+    let delimiter = query.delimiter.unwrap_or_else(|| ":".to_string());
+
+    match kvs.tree(query.prefix, delimiter, query.limit).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/count")]
+async fn count_keys(kvs: web::Data<KVStore>, query: web::Query<CountQuery>) -> impl Responder {
+    let query = query.into_inner();
+
+    actix_web::HttpResponse::Ok().json(kvs.count_keys(query.prefix, query.estimate.unwrap_or(false)).await)
+}
+
+#[get("/changes")]
+async fn changes_since(kvs: web::Data<KVStore>, query: web::Query<ChangesQuery>) -> impl Responder {
+    actix_web::HttpResponse::Ok().json(kvs.changes_since(query.since, query.values.unwrap_or(false), query.limit).await)
+}
+
+#[get("/{namespace}/{key}")]
+async fn get_key(
+    kvs: web::Data<KVStore>,
+    path: web::Path<(String, String)>,
+    query: web::Query<GetQuery>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    // A key that exists is returned even if it contains a comma
+    if key.contains(',') && !kvs.exists(namespace.clone(), key.clone()).await {
+        return match kvs.get_many(namespace, key.split(',').collect()).await {
+            Ok(response) => json_response(&response, query.pretty),
+            Err(e) => error_response(e),
+        };
+    }
+
+    // Read before the value, so a write in between makes the date older than
+    // the value rather than newer and a conditional write is refused
+    let last_modified = kvs.last_modified(namespace.clone(), key.clone()).await;
+
+    match kvs.get(namespace.clone(), key.clone()).await {
+        Ok(response) => match last_modified {
+            Some(updated_at) => with_last_modified(json_response(&response, query.pretty), updated_at),
+            None => json_response(&response, query.pretty),
+        },
+        Err(e) => match kvs.get_stale(namespace, key).await {
+            Some(response) => {
+                let mut response = json_response(&response, query.pretty);
+                response.headers_mut().insert(
+                    header::HeaderName::from_static("x-stale"),
+                    header::HeaderValue::from_static("true"),
+                );
+                response
+            }
+            None => actix_web::HttpResponse::NotFound().body(e.to_string()),
+        },
+    }
+}
+
+#[put("/{namespace}/")]
+async fn create_document(
+    req: actix_web::HttpRequest,
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    idempotency: web::Data<IdempotencyCache>,
+    namespace: web::Path<String>,
+    query: web::Query<CreateQuery>,
+    value: web::Json<Value>,
+) -> impl Responder {
+
+    idempotency.run(&req, || async move {
+        let value = match hook.apply(None, value.into_inner()).await {
+            Ok(value) => value,
+            Err(rejection) => return rejection.response(),
+        };
+
+        match kvs.create_document(namespace.clone(), value, query.ttl_seconds).await {
+            Ok((response, attempts)) => actix_web::HttpResponse::Created()
+                .insert_header(("X-Key-Attempts", attempts.to_string()))
+                .json(response),
+            Err(e) => error_response(e),
+        }
+    })
+    .await
+}
+
+#[put("/{namespace}/{key}")]
+async fn create_document_with_key(
+    req: actix_web::HttpRequest,
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    idempotency: web::Data<IdempotencyCache>,
+    path: web::Path<(String, String)>,
+    query: web::Query<PutQuery>,
+    value: web::Json<Value>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+    let unmodified_since = unmodified_since(&req);
+
+    idempotency.run(&req, || async move {
+        let value = match hook.apply(Some(&key), value.into_inner()).await {
+            Ok(value) => value,
+            Err(rejection) => return rejection.response(),
+        };
+
+        let precondition = match (query.if_version, unmodified_since) {
+            (Some(expected), _) => Some(Precondition::Version(expected)),
+            (None, Some(since)) => Some(Precondition::UnmodifiedSince(since)),
+            (None, None) => None,
+        };
+
+        if let Some(precondition) = precondition {
+            return conditional_put_response(kvs.put_if(namespace, key, value, precondition).await);
+        }
+
+        if query.upsert.unwrap_or(false) {
+            return match kvs.upsert(namespace, key, value, query.ttl_seconds).await {
+                Ok(true) => actix_web::HttpResponse::Created().json(serde_json::json!({ "created": true })),
+                Ok(false) => actix_web::HttpResponse::Ok().json(serde_json::json!({ "created": false })),
+                Err(e) => error_response(e),
+            };
+        }
+
+        match kvs.create_document_with_key(namespace.clone(), key.clone(), value, query.ttl_seconds).await {
+            Ok(response) => actix_web::HttpResponse::Created().body(response),
+            Err(e) => error_response(e),
+        }
+    })
+    .await
+}
+
+#[patch("/{namespace}/{key}")]
+async fn update_document(
+    req: actix_web::HttpRequest,
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    path: web::Path<(String, String)>,
+    value: web::Json<Value>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    let value = match hook.apply(Some(&key), value.into_inner()).await {
+        Ok(value) => value,
+        Err(rejection) => return rejection.response(),
+    };
+
+    if let Some(since) = unmodified_since(&req) {
+        return conditional_put_response(kvs.put_if(namespace, key, value, Precondition::UnmodifiedSince(since)).await);
+    }
+
+    match kvs.insert(namespace.clone(), key.clone(), value).await {
+        Ok(response) => actix_web::HttpResponse::Ok().body(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[delete("/{namespace}/{key}")]
+async fn delete_document(
+    kvs: web::Data<KVStore>,
+    path: web::Path<(String, String)>,
+    query: web::Query<DeleteQuery>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.delete(namespace.clone(), key.clone()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().body(response),
+        Err(e) if query.idempotent.unwrap_or(false) && is_not_found(e.as_ref()) => {
+            actix_web::HttpResponse::Ok().body(format!("Document already absent: {}", key))
+        }
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/{namespace}/list/")]
+async fn list_documents(
+    req: actix_web::HttpRequest,
+    kvs: web::Data<KVStore>,
+    namespace: web::Path<String>,
+    query: web::Query<ListQuery>,
+) -> impl Responder {
+
+    let etag = kvs.etag();
+    if is_not_modified(&req, &etag) {
+        return not_modified(etag);
+    }
+
+    if query.stream.unwrap_or(false) {
+        return with_etag(ndjson_response(kvs.stream_documents(namespace.clone(), query.skip, query.limit)), etag);
+    }
+
+    match kvs.list_documents(namespace.clone(), query.skip, query.limit).await {
+        Ok(response) => with_etag(json_response(&response, query.pretty), etag),
         Err(e) => actix_web::HttpResponse::InternalServerError().body(e.to_string()),
     }
 }
+
+#[post("/{namespace}/{key}/take")]
+async fn take_document(kvs: web::Data<KVStore>, path: web::Path<(String, String)>) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.take(namespace, key).await {
+        Ok(value) => actix_web::HttpResponse::Ok().json(value),
+        Err(e) => error_response(e),
+    }
+}
+
+// Advisory locks do not stop anyone writing the key, they only let clients
+// that agree to take the lock first keep out of each other's way
+#[post("/{namespace}/{key}/lock")]
+async fn lock_key(
+    locks: web::Data<AdvisoryLocks>,
+    path: web::Path<(String, String)>,
+    query: web::Query<LockQuery>,
+) -> impl Responder {
+
+    let (_, key) = path.into_inner();
+    let query = query.into_inner();
+
+    let ttl_seconds = query.ttl_seconds.unwrap_or(DEFAULT_LOCK_TTL_SECS);
+
+    if !(1..=MAX_LOCK_TTL_SECS).contains(&ttl_seconds) {
+        return actix_web::HttpResponse::BadRequest()
+            .body(format!("ttl_seconds must be between 1 and {}", MAX_LOCK_TTL_SECS));
+    }
+
+    match locks.acquire(&key, Duration::from_secs(ttl_seconds), query.token) {
+        Ok(token) => actix_web::HttpResponse::Ok().json(serde_json::json!({
+            "token": token,
+            "expires_in": ttl_seconds,
+        })),
+        Err(e) => lock_error_response(&key, e),
+    }
+}
+
+#[post("/{namespace}/{key}/unlock")]
+async fn unlock_key(
+    locks: web::Data<AdvisoryLocks>,
+    path: web::Path<(String, String)>,
+    query: web::Query<UnlockQuery>,
+) -> impl Responder {
+
+    let (_, key) = path.into_inner();
+
+    match locks.release(&key, &query.token) {
+        Ok(()) => actix_web::HttpResponse::NoContent().finish(),
+        Err(e) => lock_error_response(&key, e),
+    }
+}
+
+fn lock_error_response(key: &str, e: LockError) -> actix_web::HttpResponse {
+    match e {
+        LockError::Held(remaining) => actix_web::HttpResponse::Conflict()
+            .insert_header((header::RETRY_AFTER, remaining.as_secs().max(1)))
+            .body(format!("Lock on {} is held by another token", key)),
+        LockError::NotHeld => actix_web::HttpResponse::NotFound().body(format!("Lock on {} is not held", key)),
+    }
+}
+
+#[post("/{namespace}/{key}/move")]
+async fn move_document(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    path: web::Path<(String, String)>,
+    query: web::Query<TargetQuery>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.move_document(namespace, key, query.to.clone(), query.overwrite.unwrap_or(false), hook.get_ref()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().body(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/{namespace}/{key}/copy")]
+async fn copy_document(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    path: web::Path<(String, String)>,
+    query: web::Query<TargetQuery>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.copy_document(namespace, key, query.to.clone(), query.overwrite.unwrap_or(false), hook.get_ref()).await {
+        Ok(response) => actix_web::HttpResponse::Created().body(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[put("/blob/{key}")]
+async fn upload_blob(
+    kvs: web::Data<KVStore>,
+    config: web::Data<Config>,
+    key: web::Path<String>,
+    query: web::Query<BlobQuery>,
+    mut payload: web::Payload,
+) -> impl Responder {
+
+    let mut writer = match kvs.create_blob_writer().await {
+        Ok(writer) => writer,
+        Err(e) => return error_response(e),
+    };
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                writer.abort().await;
+                return actix_web::HttpResponse::BadRequest().body(e.to_string());
+            }
+        };
+
+        if writer.size() + chunk.len() as u64 > config.max_blob_size {
+            writer.abort().await;
+            return actix_web::HttpResponse::PayloadTooLarge()
+                .body(format!("Blob exceeds the maximum size of {} bytes", config.max_blob_size));
+        }
+
+        if let Err(e) = writer.write(&chunk).await {
+            writer.abort().await;
+            return actix_web::HttpResponse::InternalServerError().body(e.to_string());
+        }
+    }
+
+    if query.validate == Some(BlobValidation::Json) {
+        if let Err(e) = writer.check_json().await {
+            writer.abort().await;
+            return match e.kind() {
+                std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => {
+                    actix_web::HttpResponse::BadRequest().body(format!("Blob is not valid JSON: {}", e))
+                }
+                _ => actix_web::HttpResponse::InternalServerError().body(e.to_string()),
+            };
+        }
+    }
+
+    let (name, size) = match writer.finish().await {
+        Ok(blob) => blob,
+        Err(e) => return actix_web::HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    match kvs.store_blob(key.clone(), name, size).await {
+        Ok(response) => actix_web::HttpResponse::Created().body(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/blob/{key}")]
+async fn download_blob(kvs: web::Data<KVStore>, req: actix_web::HttpRequest, key: web::Path<String>) -> impl Responder {
+
+    let (path, size) = match kvs.get_blob(key.clone()).await {
+        Ok(blob) => blob,
+        Err(e) => return error_response(e),
+    };
+
+    // Every upload gets a file of its own, so its name tells versions apart
+    let etag = format!("\"{}\"", path.file_name().unwrap_or_default().to_string_lossy());
+
+    let range = match blob_range(&req, &etag, size) {
+        Ok(range) => range,
+        Err(()) => {
+            return actix_web::HttpResponse::RangeNotSatisfiable()
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                .finish();
+        }
+    };
+
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) => return actix_web::HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    let mut response = match range {
+        Some((start, _)) => {
+            if let Err(e) = file.seek(std::io::SeekFrom::Start(start)).await {
+                return actix_web::HttpResponse::InternalServerError().body(e.to_string());
+            }
+            actix_web::HttpResponse::PartialContent()
+        }
+        None => actix_web::HttpResponse::Ok(),
+    };
+
+    let (start, end) = range.unwrap_or((0, size.saturating_sub(1)));
+    let length = if size == 0 { 0 } else { end - start + 1 };
+
+    // Ranges count bytes of the blob as stored, so they are never compressed
+    if range.is_some() {
+        response.insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size)));
+        response.insert_header((header::CONTENT_ENCODING, "identity"));
+    }
+
+    response
+        .content_type("application/octet-stream")
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header((header::ETAG, etag))
+        .no_chunking(length)
+        .streaming(tokio_util::io::ReaderStream::new(file.take(length)))
+}
+
+// The inclusive byte range a `Range` header asks for, or `None` for the whole
+// blob. Only single ranges are served, the whole blob is sent for several
+// ranges, for malformed headers, and when `If-Range` names an older upload.
+fn blob_range(req: &actix_web::HttpRequest, etag: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let range = match req.headers().get(header::RANGE).and_then(|range| range.to_str().ok()) {
+        Some(range) => range,
+        None => return Ok(None),
+    };
+
+    if req.headers().get(header::IF_RANGE).is_some_and(|if_range| if_range != etag) {
+        return Ok(None);
+    }
+
+    match range.parse::<header::Range>() {
+        Ok(header::Range::Bytes(specs)) if specs.len() == 1 => specs[0].to_satisfiable_range(size).map(Some).ok_or(()),
+        _ => Ok(None),
+    }
+}
+
+#[delete("/blob/{key}")]
+async fn delete_blob(kvs: web::Data<KVStore>, key: web::Path<String>) -> impl Responder {
+    match kvs.delete_blob(key.clone()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().body(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/{namespace}/{key}/undelete")]
+async fn undelete_document(kvs: web::Data<KVStore>, path: web::Path<(String, String)>) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.undelete(namespace, key).await {
+        Ok(response) => actix_web::HttpResponse::Ok().body(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/{namespace}/{key}/type")]
+async fn get_type(kvs: web::Data<KVStore>, path: web::Path<(String, String)>) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.get_type(namespace, key).await {
+        Ok(value_type) => actix_web::HttpResponse::Ok().json(serde_json::json!({ "type": value_type })),
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/{namespace}/{key}/storage")]
+async fn get_storage(kvs: web::Data<KVStore>, path: web::Path<(String, String)>) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.storage(namespace, key).await {
+        Ok(report) => actix_web::HttpResponse::Ok().json(report),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscribeQuery {
+    version: u64,
+    timeout_secs: Option<u64>,
+}
+
+#[get("/{namespace}/{key}/subscribe")]
+async fn subscribe_key(
+    kvs: web::Data<KVStore>,
+    path: web::Path<(String, String)>,
+    query: web::Query<SubscribeQuery>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    let timeout = Duration::from_secs(query.timeout_secs.unwrap_or(30).min(MAX_SUBSCRIBE_TIMEOUT_SECS));
+
+    match kvs.wait_for_change(namespace, key.clone(), query.version, timeout).await {
+        Ok(Some((value, version))) => {
+            actix_web::HttpResponse::Ok().json(serde_json::json!({ "key": key, "data": value, "version": version }))
+        }
+        Ok(None) => actix_web::HttpResponse::NotModified().finish(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/{namespace}/{key}/version")]
+async fn get_version(kvs: web::Data<KVStore>, path: web::Path<(String, String)>) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.version(namespace, key).await {
+        Ok(version) => actix_web::HttpResponse::Ok().json(serde_json::json!({ "version": version })),
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/{namespace}/{key}/exists")]
+async fn key_exists(kvs: web::Data<KVStore>, path: web::Path<(String, String)>) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    actix_web::HttpResponse::Ok().json(serde_json::json!({ "exists": kvs.exists(namespace, key).await }))
+}
+
+#[post("/{namespace}/{key}/max")]
+async fn set_max(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    path: web::Path<(String, String)>,
+    value: web::Json<Value>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.set_if_extreme(namespace, key, value.into_inner(), true, hook.get_ref()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/{namespace}/{key}/min")]
+async fn set_min(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    path: web::Path<(String, String)>,
+    value: web::Json<Value>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.set_if_extreme(namespace, key, value.into_inner(), false, hook.get_ref()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/{namespace}/{key}/incr-field")]
+async fn increment_field(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    path: web::Path<(String, String)>,
+    query: web::Query<IncrementQuery>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.increment_field(namespace, key, query.path.clone(), query.by.unwrap_or(1.0), hook.get_ref()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/{namespace}/{key}/trim")]
+async fn trim_list(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    path: web::Path<(String, String)>,
+    query: web::Query<TrimQuery>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.trim_list(namespace, key, query.start, query.stop, hook.get_ref()).await {
+        Ok(length) => actix_web::HttpResponse::Ok().json(serde_json::json!({ "length": length })),
+        Err(e) => error_response(e),
+    }
+}
+
+// Registered before the set routes, which `/geo/{key}/add` would match too
+#[post("/geo/{key}/add")]
+async fn geo_add(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    path: web::Path<String>,
+    point: web::Json<GeoAddRequest>,
+) -> impl Responder {
+
+    let key = path.into_inner();
+    let point = point.into_inner();
+
+    match kvs.add_geo_point("geo".to_string(), key, point.name, point.lat, point.lng, hook.get_ref()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/geo/{key}/nearby")]
+async fn geo_nearby(kvs: web::Data<KVStore>, path: web::Path<String>, query: web::Query<NearbyQuery>) -> impl Responder {
+
+    let key = path.into_inner();
+
+    match kvs.nearby_points("geo".to_string(), key, query.lat, query.lng, query.radius_km).await {
+        Ok(points) => actix_web::HttpResponse::Ok().json(points),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/{namespace}/{key}/add")]
+async fn set_add(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    path: web::Path<(String, String)>,
+    members: web::Json<Vec<Value>>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.update_set(namespace, key, members.into_inner(), true, hook.get_ref()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/{namespace}/{key}/remove")]
+async fn set_remove(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    path: web::Path<(String, String)>,
+    members: web::Json<Vec<Value>>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.update_set(namespace, key, members.into_inner(), false, hook.get_ref()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/{namespace}/{key}/has")]
+async fn set_has(
+    kvs: web::Data<KVStore>,
+    path: web::Path<(String, String)>,
+    query: web::Query<MemberQuery>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.set_contains(namespace, key, query.into_inner().member).await {
+        Ok(has) => actix_web::HttpResponse::Ok().json(serde_json::json!({ "has": has })),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/{namespace}/{key}/append")]
+async fn append_point(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    path: web::Path<(String, String)>,
+    query: web::Query<AppendQuery>,
+    point: web::Json<AppendRequest>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+    let point = point.into_inner();
+
+    if query.max_len == Some(0) {
+        return actix_web::HttpResponse::BadRequest().body("max_len must be greater than 0");
+    }
+
+    let window_ms = query.window_secs.map(|window_secs| window_secs.saturating_mul(1000));
+
+    match kvs.append_point(namespace, key, point.ts, point.val, query.max_len, window_ms, hook.get_ref()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/{namespace}/{key}/points")]
+async fn get_points(
+    kvs: web::Data<KVStore>,
+    path: web::Path<(String, String)>,
+    query: web::Query<PointsQuery>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+
+    match kvs.points_since(namespace, key, query.since).await {
+        Ok(points) => actix_web::HttpResponse::Ok().json(points),
+        Err(e) => error_response(e),
+    }
+}
+
+// `GET /ts/{key}` reads a series like `GET /ts/{key}/points` does, so it is
+// registered before `get_key` takes every `/{namespace}/{key}`
+#[get("/ts/{key}")]
+async fn get_series(kvs: web::Data<KVStore>, key: web::Path<String>, query: web::Query<PointsQuery>) -> impl Responder {
+    match kvs.points_since("ts".to_string(), key.into_inner(), query.since).await {
+        Ok(points) => actix_web::HttpResponse::Ok().json(points),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/{namespace}/{key}/apply")]
+async fn apply_operation(
+    kvs: web::Data<KVStore>,
+    hook: web::Data<PreWriteHook>,
+    path: web::Path<(String, String)>,
+    request: web::Json<ApplyRequest>,
+) -> impl Responder {
+
+    let (namespace, key) = path.into_inner();
+    let request = request.into_inner();
+
+    let operation = match Operation::parse(&request.op, request.arg) {
+        Ok(operation) => operation,
+        Err(e) => return actix_web::HttpResponse::BadRequest().body(e),
+    };
+
+    match kvs.apply_operation(namespace, key, operation, request.path, hook.get_ref()).await {
+        Ok(response) => actix_web::HttpResponse::Ok().json(response),
+        Err(e) => error_response(e),
+    }
+}
@@ -0,0 +1,229 @@
+use std::error::Error;
+
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::kvstore::{ErrorKind, KVStore, KVStoreError};
+use crate::webhook::{PreWriteHook, Rejection};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+// Store errors use codes from the range the spec leaves to the server
+const NOT_FOUND: i64 = -32001;
+const CONFLICT: i64 = -32002;
+const UNAVAILABLE: i64 = -32003;
+const FORBIDDEN: i64 = -32004;
+const QUOTA_EXCEEDED: i64 = -32005;
+const WRITE_REJECTED: i64 = -32006;
+
+// Namespaces share one keyspace, calls without one still need a value to pass on
+const DEFAULT_NAMESPACE: &str = "rpc";
+
+struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+
+    fn from_store(e: Box<dyn Error>) -> Self {
+        let code = match e.downcast_ref::<KVStoreError>().map(|e| e.kind()) {
+            Some(ErrorKind::NotFound) => NOT_FOUND,
+            Some(ErrorKind::Conflict) => CONFLICT,
+            Some(ErrorKind::Unavailable) => UNAVAILABLE,
+            Some(ErrorKind::Forbidden) => FORBIDDEN,
+            Some(ErrorKind::QuotaExceeded) => QUOTA_EXCEEDED,
+            Some(ErrorKind::InvalidInput) => INVALID_PARAMS,
+            _ => INTERNAL_ERROR,
+        };
+
+        RpcError::new(code, &e.to_string())
+    }
+
+    fn into_value(self) -> Value {
+        let mut error = serde_json::json!({ "code": self.code, "message": self.message });
+
+        if let Some(data) = self.data {
+            error["data"] = data;
+        }
+
+        error
+    }
+}
+
+#[derive(Deserialize)]
+struct KeyParams {
+    namespace: Option<String>,
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct PutParams {
+    namespace: Option<String>,
+    key: Option<String>,
+    value: Value,
+    ttl_seconds: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ListParams {
+    namespace: Option<String>,
+    skip: Option<u64>,
+    limit: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct BatchGetParams {
+    namespace: Option<String>,
+    keys: Vec<String>,
+}
+
+// Answers a JSON-RPC 2.0 body, a single call or a batch of them, with the
+// response to send back. Notifications get no response, so a body of only
+// notifications returns `None`.
+pub async fn handle(kvs: &KVStore, hook: &PreWriteHook, body: &[u8]) -> Option<Value> {
+    let body: Value = match serde_json::from_slice(body) {
+        Ok(body) => body,
+        Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, &e.to_string()))),
+    };
+
+    match body {
+        Value::Array(calls) if calls.is_empty() => {
+            Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "Batch must not be empty")))
+        }
+        Value::Array(calls) => {
+            let mut responses = Vec::new();
+
+            for call in calls {
+                if let Some(response) = handle_call(kvs, hook, call).await {
+                    responses.push(response);
+                }
+            }
+
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        call => handle_call(kvs, hook, call).await,
+    }
+}
+
+async fn handle_call(kvs: &KVStore, hook: &PreWriteHook, call: Value) -> Option<Value> {
+    let id = call.get("id").cloned();
+
+    let method = match (call.get("jsonrpc").and_then(Value::as_str), call.get("method").and_then(Value::as_str)) {
+        (Some("2.0"), Some(method)) => method.to_string(),
+        _ => {
+            return Some(error_response(
+                id.unwrap_or(Value::Null),
+                RpcError::new(INVALID_REQUEST, "Expected an object with \"jsonrpc\": \"2.0\" and a \"method\""),
+            ))
+        }
+    };
+
+    let params = call.get("params").cloned().unwrap_or(Value::Null);
+
+    let result = call_method(kvs, hook, &method, params).await;
+
+    // A call without an id is a notification and is never answered
+    let id = id?;
+
+    Some(match result {
+        Ok(result) => serde_json::json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(e) => error_response(id, e),
+    })
+}
+
+async fn call_method(kvs: &KVStore, hook: &PreWriteHook, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "get" => {
+            let params: KeyParams = parse_params(params)?;
+
+            kvs.get(namespace(params.namespace), params.key).await.map_err(RpcError::from_store)
+        }
+        "put" => {
+            let params: PutParams = parse_params(params)?;
+
+            let value = match hook.apply(params.key.as_deref(), params.value).await {
+                Ok(value) => value,
+                Err(rejection) => return Err(rejected_write(rejection)),
+            };
+
+            match params.key {
+                Some(key) => kvs
+                    .create_document_with_key(namespace(params.namespace), key, value, params.ttl_seconds)
+                    .await
+                    .map(Value::String),
+                None => kvs
+                    .create_document(namespace(params.namespace), value, params.ttl_seconds)
+                    .await
+                    .map(|(document, _)| document),
+            }
+            .map_err(RpcError::from_store)
+        }
+        "delete" => {
+            let params: KeyParams = parse_params(params)?;
+
+            kvs.delete(namespace(params.namespace), params.key)
+                .await
+                .map(Value::String)
+                .map_err(RpcError::from_store)
+        }
+        "list" => {
+            let params: ListParams = parse_params(params)?;
+
+            kvs.list_documents(namespace(params.namespace), params.skip, params.limit)
+                .await
+                .map_err(RpcError::from_store)
+        }
+        "batch_get" => {
+            let params: BatchGetParams = parse_params(params)?;
+
+            kvs.get_many(namespace(params.namespace), params.keys.iter().map(String::as_str).collect())
+                .await
+                .map_err(RpcError::from_store)
+        }
+        _ => {
+            warn!("Unknown RPC method: {}", method);
+            Err(RpcError::new(METHOD_NOT_FOUND, &format!("Unknown method: {}", method)))
+        }
+    }
+}
+
+// Methods without parameters of their own, like `list`, accept leaving them out
+fn parse_params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    let params = match params {
+        Value::Null => serde_json::json!({}),
+        params => params,
+    };
+
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, &e.to_string()))
+}
+
+fn namespace(namespace: Option<String>) -> String {
+    namespace.unwrap_or_else(|| DEFAULT_NAMESPACE.to_string())
+}
+
+// The webhook's reply is the body of the HTTP response it would have sent
+fn rejected_write(rejection: Rejection) -> RpcError {
+    RpcError {
+        code: WRITE_REJECTED,
+        message: rejection.to_string(),
+        data: Some(serde_json::json!({ "status": rejection.status().as_u16() })),
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    serde_json::json!({ "jsonrpc": "2.0", "error": error.into_value(), "id": id })
+}
@@ -0,0 +1,175 @@
+use std::env;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::reload;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Registry;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Full,
+    Json,
+    Pretty,
+    Compact,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "full" => Ok(LogFormat::Full),
+            "json" => Ok(LogFormat::Json),
+            "pretty" => Ok(LogFormat::Pretty),
+            "compact" => Ok(LogFormat::Compact),
+            _ => Err(format!("Unknown log format: {}", value)),
+        }
+    }
+}
+
+// Changes the level of the running subscriber, shared with the admin endpoint
+#[derive(Clone)]
+pub struct LogLevel {
+    handle: reload::Handle<LevelFilter, Registry>,
+}
+
+impl LogLevel {
+    pub fn current(&self) -> Option<LevelFilter> {
+        self.handle.clone_current()
+    }
+
+    pub fn set(&self, level: LevelFilter) -> Result<(), String> {
+        self.handle.reload(level).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+impl LogLevel {
+    // A level of its own that no subscriber reads, for tests that change it.
+    // The handle only works while its layer is alive, so the layer is leaked.
+    pub fn detached(level: LevelFilter) -> Self {
+        let (layer, handle) = reload::Layer::new(level);
+        std::mem::forget(layer);
+
+        LogLevel { handle }
+    }
+
+    // The level of the `reload` layer of a subscriber set up by the test
+    pub fn from_handle(handle: reload::Handle<LevelFilter, Registry>) -> Self {
+        LogLevel { handle }
+    }
+}
+
+// Keeps the span exporter alive and flushes the spans still queued when dropped
+pub struct Telemetry {
+    log_level: LogLevel,
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl Telemetry {
+    pub fn log_level(&self) -> LogLevel {
+        self.log_level.clone()
+    }
+}
+
+// Logging starts before the configuration is loaded, so these two are only read
+// from the environment. The subscriber is not running yet to log bad values.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("Ignoring invalid value for {}: {}", name, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+// Sets up logging at `DISTKV_LOG_LEVEL` in `DISTKV_LOG_FORMAT`, and with the
+// `otel` feature exports spans over OTLP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set
+pub fn init() -> Telemetry {
+    let level = env_or("DISTKV_LOG_LEVEL", LevelFilter::INFO);
+    let format = env_or("DISTKV_LOG_FORMAT", LogFormat::Full);
+
+    let (filter, handle) = reload::Layer::new(level);
+    let log_level = LogLevel { handle };
+
+    // Only the layer matching the format is set, the others stay `None`
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with((format == LogFormat::Full).then(tracing_subscriber::fmt::layer))
+        .with((format == LogFormat::Json).then(|| tracing_subscriber::fmt::layer().json()))
+        .with((format == LogFormat::Pretty).then(|| tracing_subscriber::fmt::layer().pretty()))
+        .with((format == LogFormat::Compact).then(|| tracing_subscriber::fmt::layer().compact()));
+
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TracerProvider as _;
+
+        if std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_ok() {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_tonic()
+                .build()
+                .expect("Could not create the OTLP exporter");
+
+            let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+                .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+                .with_resource(opentelemetry_sdk::Resource::new(vec![opentelemetry::KeyValue::new(
+                    "service.name",
+                    "distkv",
+                )]))
+                .build();
+
+            registry
+                .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("distkv")))
+                .init();
+
+            ENABLED.store(true, Ordering::SeqCst);
+
+            return Telemetry {
+                log_level,
+                provider: Some(provider),
+            };
+        }
+
+        registry.init();
+
+        Telemetry {
+            log_level,
+            provider: None,
+        }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+
+        Telemetry { log_level }
+    }
+}
+
+// Whether spans are exported, requests and flushes only open spans when they are
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Opens request and flush spans from now on without an exporter, for tests that
+// read them with a subscriber of their own
+#[cfg(test)]
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            _ = provider.shutdown();
+        }
+    }
+}
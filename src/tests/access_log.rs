@@ -0,0 +1,50 @@
+use actix_web::http::header;
+use regex::Regex;
+use serde_json::json;
+
+use super::{get, put, send, start};
+
+#[actix_web::test]
+async fn requests_produce_combined_log_lines() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("access.log");
+
+    let (app, _) = start(&format!("access_log = \"combined\"\naccess_log_path = {:?}", path.to_str().unwrap())).await;
+
+    send(&app, put("/kv/a", json!({ "n": 1 }))).await;
+    send(&app, get("/kv/missing?x=1").insert_header((header::USER_AGENT, "test \"agent\""))).await;
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", log);
+
+    let line = Regex::new(
+        r#"^\S+ - - \[\d{2}/[A-Z][a-z]{2}/\d{4}:\d{2}:\d{2}:\d{2} \+0000\] "(\S+) (\S+) HTTP/1\.1" (\d{3}) (\S+) "(.*)" "(.*)" \d+$"#,
+    )
+    .unwrap();
+
+    let put = line.captures(lines[0]).unwrap_or_else(|| panic!("Unexpected log line: {}", lines[0]));
+    assert_eq!(&put[1], "PUT");
+    assert_eq!(&put[2], "/kv/a");
+    assert_eq!(&put[3], "201");
+    assert_eq!(&put[5], "-");
+
+    let get = line.captures(lines[1]).unwrap_or_else(|| panic!("Unexpected log line: {}", lines[1]));
+    assert_eq!(&get[1], "GET");
+    assert_eq!(&get[2], "/kv/missing?x=1");
+    assert_eq!(&get[3], "404");
+    assert_eq!(&get[6], r#"test \"agent\""#);
+}
+
+#[actix_web::test]
+async fn common_log_lines_leave_out_the_headers() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("access.log");
+
+    let (app, _) = start(&format!("access_log = \"common\"\naccess_log_path = {:?}", path.to_str().unwrap())).await;
+
+    send(&app, get("/kv/missing")).await;
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    assert!(Regex::new(r#"^\S+ - - \[[^\]]+\] "GET /kv/missing HTTP/1\.1" 404 \S+ \d+\n$"#).unwrap().is_match(&log), "{}", log);
+}
@@ -0,0 +1,611 @@
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{data_file, get, patch, put, send, sorted, start};
+use crate::kvstore::check_file;
+
+#[actix_web::test]
+async fn maintenance_blocks_writes_and_allows_reads() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+
+    let reply = send(&app, TestRequest::post().uri("/admin/maintenance?on=true")).await;
+    assert_eq!(reply.json(), json!({ "maintenance": true }));
+    assert_eq!(send(&app, get("/stats")).await.json()["maintenance"], json!(true));
+
+    assert_eq!(send(&app, patch("/kv/a", json!(2))).await.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(send(&app, put("/kv/b", json!(2))).await.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        send(&app, TestRequest::delete().uri("/kv/a")).await.status,
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!(1));
+
+    send(&app, TestRequest::post().uri("/admin/maintenance?on=false")).await;
+    assert_eq!(send(&app, get("/stats")).await.json()["maintenance"], json!(false));
+
+    assert_eq!(send(&app, patch("/kv/a", json!(2))).await.status, StatusCode::OK);
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!(2));
+}
+
+#[actix_web::test]
+async fn stats_report_unflushed_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let (app, _) = start(&format!("persistence = \"on\"\n{}", data_file(&dir))).await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+
+    let stats = send(&app, get("/stats")).await.json();
+    assert!(stats["last_flush_ts"].is_u64());
+    assert_eq!(stats["dirty_keys_since_flush"], json!(0));
+    assert_eq!(stats["pending_flush"], json!(false));
+
+    // PATCH only changes the key in memory
+    send(&app, patch("/kv/a", json!(2))).await;
+    send(&app, patch("/kv/b", json!(3))).await;
+    send(&app, patch("/kv/b", json!(4))).await;
+
+    let stats = send(&app, get("/stats")).await.json();
+    assert_eq!(stats["dirty_keys_since_flush"], json!(2));
+    assert_eq!(stats["pending_flush"], json!(true));
+
+    send(&app, put("/kv/c", json!(5))).await;
+
+    let stats = send(&app, get("/stats")).await.json();
+    assert_eq!(stats["dirty_keys_since_flush"], json!(0));
+    assert_eq!(stats["pending_flush"], json!(false));
+}
+
+#[actix_web::test]
+async fn truncate_needs_confirm() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+
+    let reply = send(&app, TestRequest::delete().uri("/admin/all")).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+    let reply = send(&app, TestRequest::delete().uri("/admin/all?confirm=false")).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+
+    assert_eq!(kvs.document_count(), 1);
+}
+
+#[actix_web::test]
+async fn truncate_empties_the_store_and_the_data_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let toml = format!("persistence = \"on\"\n{}", data_file(&dir));
+
+    {
+        let (app, kvs) = start(&toml).await;
+
+        send(&app, put("/kv/a", json!(1))).await;
+        send(&app, put("/kv/b", json!(2))).await;
+
+        let reply = send(&app, TestRequest::delete().uri("/admin/all?confirm=true")).await;
+        assert_eq!(reply.json(), json!({ "removed": 2 }));
+
+        assert_eq!(kvs.document_count(), 0);
+        assert_eq!(send(&app, get("/kv/a")).await.status, StatusCode::NOT_FOUND);
+        assert_eq!(send(&app, get("/stats")).await.json()["documents"], json!(0));
+    }
+
+    assert_eq!(std::fs::metadata(dir.path().join("database.vbank")).unwrap().len(), 0);
+
+    let (_, kvs) = start(&toml).await;
+    assert_eq!(kvs.document_count(), 0);
+}
+
+#[actix_web::test]
+async fn size_histogram_counts_values_per_bucket() {
+    let (app, _) = start("").await;
+
+    // Sizes are of the JSON text, so a string is two bytes longer than its contents
+    let sizes = [("a", 10), ("b", 1021), ("c", 1022), ("d", 50 * 1024), ("e", 200 * 1024)];
+    for (key, len) in sizes {
+        send(&app, put(&format!("/kv/{}", key), json!("x".repeat(len)))).await;
+    }
+
+    assert_eq!(
+        send(&app, get("/stats/sizes")).await.json(),
+        json!({ "buckets": { "<1KB": 2, "1-10KB": 1, "10-100KB": 1, ">100KB": 1 } })
+    );
+}
+
+#[actix_web::test]
+async fn fsck_reports_a_healthy_data_file() {
+    for format in ["legacy", "cbor"] {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = format!("persistence = \"on\"\ndisk_format = \"{}\"\ncompress_threshold = 64", format);
+        let (app, _) = start(&format!("{}\n{}", toml, data_file(&dir))).await;
+
+        send(&app, put("/kv/a", json!({ "n": 1 }))).await;
+        send(&app, put("/kv/b?ttl_seconds=60", json!("x".repeat(100)))).await;
+        send(&app, put("/kv/c", json!([1, 2]))).await;
+
+        let reply = send(&app, TestRequest::post().uri("/admin/fsck")).await;
+        assert_eq!(reply.status, StatusCode::OK);
+
+        let report = reply.json();
+        assert_eq!(report["format"], json!(format));
+        assert_eq!(report["healthy"], json!(true), "{}", report);
+        assert_eq!(report["valid"], json!(3));
+        assert_eq!(report["problems"], json!([]));
+    }
+}
+
+#[test]
+fn fsck_counts_each_kind_of_problem_in_a_legacy_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("database.vbank");
+
+    let value = |json: &str| base64::encode(json);
+    let lines = [
+        format!("a|{}", value(r#"{"n":1}"#)),
+        format!("a|{}", value("2")),
+        "no value".to_string(),
+        "b|not base64!".to_string(),
+        format!("c|{}", value("not json")),
+        format!("d|{}|soon", value("3")),
+        format!("e|{}|||1700000000000", value("[]")),
+    ];
+    std::fs::write(&path, lines.join("\n")).unwrap();
+
+    let report = check_file(path.to_str().unwrap()).unwrap();
+
+    assert_eq!(report["format"], json!("legacy"));
+    assert_eq!(report["healthy"], json!(false));
+    assert_eq!(report["valid"], json!(3));
+    assert_eq!(report["corrupt"], json!(3));
+    assert_eq!(report["duplicate_keys"], json!(1));
+    assert_eq!(report["unparseable_values"], json!(1));
+
+    let problems = report["problems"].as_array().unwrap();
+    let records: Vec<u64> = problems.iter().map(|problem| problem["record"].as_u64().unwrap()).collect();
+    assert_eq!(records, [2, 3, 4, 5, 6]);
+}
+
+#[actix_web::test]
+async fn fsck_stops_at_a_truncated_cbor_record() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("database.vbank");
+
+    {
+        let (app, _) = start(&format!("persistence = \"on\"\ndisk_format = \"cbor\"\n{}", data_file(&dir))).await;
+        send(&app, put("/kv/a", json!(1))).await;
+        send(&app, put("/kv/b", json!("two"))).await;
+    }
+
+    let contents = std::fs::read(&path).unwrap();
+    std::fs::write(&path, &contents[..contents.len() - 3]).unwrap();
+
+    let report = check_file(path.to_str().unwrap()).unwrap();
+
+    assert_eq!(report["format"], json!("cbor"));
+    assert_eq!(report["healthy"], json!(false));
+    assert_eq!(report["valid"], json!(1));
+    assert_eq!(report["corrupt"], json!(1));
+    assert_eq!(report["problems"][0]["record"], json!(2));
+}
+
+#[actix_web::test]
+async fn fsck_without_persistence_is_rejected() {
+    let (app, _) = start("").await;
+
+    let reply = send(&app, TestRequest::post().uri("/admin/fsck")).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+}
+
+fn metric(metrics: &str, name: &str) -> f64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(name).and_then(|value| value.strip_prefix(' ')))
+        .unwrap_or_else(|| panic!("No {} in {}", name, metrics))
+        .parse()
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn metrics_record_the_time_writes_wait_for_the_lock() {
+    let (app, kvs) = start("").await;
+
+    let metrics = String::from_utf8(send(&app, get("/metrics")).await.body.to_vec()).unwrap();
+    let count = metric(&metrics, "distkv_write_lock_wait_seconds_count");
+
+    // Another thread holds the write lock while the write is sent
+    let (held, wait) = std::sync::mpsc::channel();
+    let holder = std::thread::spawn({
+        let kvs = kvs.clone();
+        move || {
+            let _lock = kvs.store.write();
+            held.send(()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(200));
+        }
+    });
+    wait.recv().unwrap();
+
+    assert_eq!(send(&app, put("/kv/a", json!(1))).await.status, StatusCode::CREATED);
+    holder.join().unwrap();
+
+    let reply = send(&app, get("/metrics")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+
+    let metrics = String::from_utf8(reply.body.to_vec()).unwrap();
+    assert!(metric(&metrics, "distkv_write_lock_wait_seconds_count") > count);
+    assert!(metric(&metrics, "distkv_write_lock_wait_seconds_sum") >= 0.1, "{}", metrics);
+    assert!(
+        metric(&metrics, "distkv_write_lock_wait_seconds_bucket{le=\"0.1\"}")
+            < metric(&metrics, "distkv_write_lock_wait_seconds_bucket{le=\"1\"}"),
+        "{}",
+        metrics
+    );
+    assert!(metrics.contains("# TYPE distkv_flush_lock_wait_seconds histogram"));
+}
+
+fn diff(snapshot: impl Into<Vec<u8>>) -> TestRequest {
+    TestRequest::post().uri("/admin/diff").set_payload(snapshot.into())
+}
+
+#[actix_web::test]
+async fn diff_of_an_identical_snapshot_finds_no_drift() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!({ "n": 1 }))).await;
+    send(&app, put("/kv/b", json!([1, 2]))).await;
+
+    let reply = send(&app, get("/admin/snapshot")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.body.split(|&byte| byte == b'\n').filter(|line| !line.is_empty()).count(), 2);
+
+    let report = send(&app, diff(reply.body.to_vec())).await.json();
+    assert_eq!(report["counts"], json!({ "only_local": 0, "only_remote": 0, "different": 0, "same": 2 }));
+    assert_eq!(report["only_local"], json!([]));
+}
+
+#[actix_web::test]
+async fn diff_of_a_divergent_snapshot_lists_the_drifted_keys() {
+    let (primary, _) = start("").await;
+    let (replica, _) = start("").await;
+
+    let documents = [("same", json!(1)), ("changed", json!("old")), ("primary:1", json!(2)), ("primary:2", json!(3))];
+    for (key, value) in documents {
+        send(&primary, put(&format!("/kv/{}", key), value)).await;
+    }
+    for (key, value) in [("same", json!(1)), ("changed", json!("new")), ("replica", json!(4))] {
+        send(&replica, put(&format!("/kv/{}", key), value)).await;
+    }
+
+    let snapshot = send(&primary, get("/admin/snapshot")).await.body.to_vec();
+
+    let report = send(&replica, diff(snapshot)).await.json();
+    assert_eq!(report["counts"], json!({ "only_local": 1, "only_remote": 2, "different": 1, "same": 1 }));
+    assert_eq!(report["only_local"], json!(["replica"]));
+    assert_eq!(sorted(report["only_remote"].clone()), json!(["primary:1", "primary:2"]));
+    assert_eq!(report["different"], json!(["changed"]));
+}
+
+#[actix_web::test]
+async fn diff_rejects_invalid_snapshots() {
+    let (app, _) = start("").await;
+
+    let reply = send(&app, diff("{\"key\": \"a\", \"data\": 1}\nnot json\n")).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8_lossy(&reply.body).contains("line 2"));
+
+    let line = "{\"key\": \"a\", \"data\": 1}\n";
+    let reply = send(&app, diff(line.repeat(2))).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8_lossy(&reply.body).contains("Duplicate key"));
+}
+
+#[actix_web::test]
+async fn read_only_mode_rejects_writes_and_serves_reads() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("database.vbank");
+
+    {
+        let (app, _) = start(&format!("persistence = \"on\"\n{}", data_file(&dir))).await;
+        send(&app, put("/kv/a", json!({ "n": 1 }))).await;
+        send(&app, put("/kv/b", json!(2))).await;
+    }
+    let contents = std::fs::read(&path).unwrap();
+
+    let (app, kvs) = start(&format!("persistence = \"on\"\nread_only = true\n{}", data_file(&dir))).await;
+
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!({ "n": 1 }));
+    assert_eq!(sorted(send(&app, get("/keys")).await.json()), json!(["a", "b"]));
+
+    let writes = [
+        put("/kv/c", json!(3)),
+        patch("/kv/a", json!(4)),
+        TestRequest::delete().uri("/kv/b"),
+        TestRequest::post().uri("/kv/a/incr-field?path=n"),
+        TestRequest::post().uri("/kv/a/copy?to=d"),
+    ];
+    for write in writes {
+        assert_eq!(send(&app, write).await.status, StatusCode::FORBIDDEN);
+    }
+
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!({ "n": 1 }));
+    assert_eq!(kvs.document_count(), 2);
+    assert_eq!(send(&app, get("/stats")).await.json()["read_only"], json!(true));
+    assert_eq!(std::fs::read(&path).unwrap(), contents);
+}
+
+#[actix_web::test]
+async fn read_only_mode_without_a_data_file_serves_an_empty_store() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let (app, kvs) = start(&format!("persistence = \"on\"\nread_only = true\n{}", data_file(&dir))).await;
+
+    assert_eq!(kvs.document_count(), 0);
+    assert_eq!(send(&app, put("/kv/a", json!(1))).await.status, StatusCode::FORBIDDEN);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[actix_web::test]
+async fn snapshot_export_waits_for_a_slow_consumer() {
+    let (app, kvs) = start("").await;
+
+    for n in 0..1000 {
+        send(&app, put(&format!("/kv/doc:{:04}", n), json!({ "n": n }))).await;
+    }
+
+    let mut receiver = kvs.export_snapshot();
+    let mut keys = std::collections::HashSet::new();
+
+    while let Some(chunk) = receiver.recv().await {
+        for line in chunk.lines() {
+            let document: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert!(keys.insert(document["key"].as_str().unwrap().to_string()));
+        }
+
+        // While the consumer is slow the export only runs a few chunks ahead,
+        // and writes are not held back by it
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(receiver.len() <= 4, "{} chunks buffered", receiver.len());
+
+        assert_eq!(send(&app, put(&format!("/kv/later:{}", keys.len()), json!(1))).await.status, StatusCode::CREATED);
+    }
+
+    // Every document of the snapshot and none written after it started
+    assert_eq!(keys.len(), 1000);
+    assert!(keys.iter().all(|key| key.starts_with("doc:")));
+    assert_eq!(kvs.document_count(), 1010);
+}
+
+#[actix_web::test]
+async fn reload_picks_up_an_edited_data_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("database.vbank");
+
+    let (app, kvs) = start(&format!("persistence = \"on\"\n{}", data_file(&dir))).await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, put("/kv/b", json!(2))).await;
+
+    let lines = [
+        format!("b|{}", base64::encode(r#"{"edited":true}"#)),
+        format!("c|{}", base64::encode("3")),
+    ];
+    std::fs::write(&path, lines.join("\n")).unwrap();
+
+    // Nothing changes until the reload
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!(2));
+
+    let reply = send(&app, TestRequest::post().uri("/admin/reload")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "loaded": 2 }));
+
+    assert_eq!(send(&app, get("/kv/a")).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!({ "edited": true }));
+    assert_eq!(send(&app, get("/kv/c")).await.json(), json!(3));
+    assert_eq!(kvs.document_count(), 2);
+}
+
+#[actix_web::test]
+async fn reload_of_a_broken_data_file_keeps_the_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("database.vbank");
+
+    let (app, _) = start(&format!("persistence = \"on\"\n{}", data_file(&dir))).await;
+    send(&app, put("/kv/a", json!(1))).await;
+
+    std::fs::write(&path, format!("b|{}\nbroken line", base64::encode("2"))).unwrap();
+
+    let reply = send(&app, TestRequest::post().uri("/admin/reload")).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8_lossy(&reply.body).contains("problems"));
+
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!(1));
+    assert_eq!(send(&app, get("/kv/b")).await.status, StatusCode::NOT_FOUND);
+
+    let (app, _) = start("").await;
+    assert_eq!(send(&app, TestRequest::post().uri("/admin/reload")).await.status, StatusCode::BAD_REQUEST);
+}
+
+// A dump like the one the README builds from `redis-cli`, with JSON kept in
+// Redis as strings and Redis' `-1` for keys without a TTL
+const REDIS_DUMP: &str = r#"{"key":"user:1","value":"{\"name\":\"ada\",\"tags\":[\"a\",\"b\"]}","ttl":-1}
+{"key":"user:2","value":"{\"name\":\"bob\"}","ttl":600}
+{"key":"greeting","value":"hello","ttl":null}
+{"key":"count","value":"42"}
+"#;
+
+fn import(query: &str, dump: &str) -> TestRequest {
+    TestRequest::post().uri(&format!("/admin/import{}", query)).set_payload(dump.to_string())
+}
+
+#[actix_web::test]
+async fn a_redis_dump_survives_a_round_trip() {
+    let (app, _) = start("").await;
+
+    let reply = send(&app, import("?parse_strings=true", REDIS_DUMP)).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "imported": 4, "skipped": 0, "failed": 0, "errors": [] }));
+
+    assert_eq!(send(&app, get("/kv/user:1")).await.json(), json!({ "name": "ada", "tags": ["a", "b"] }));
+    assert_eq!(send(&app, get("/kv/greeting")).await.json(), json!("hello"));
+    assert_eq!(send(&app, get("/kv/count")).await.json(), json!(42));
+
+    let dump = send(&app, get("/admin/dump")).await;
+    assert_eq!(dump.status, StatusCode::OK);
+
+    let mut lines: Vec<serde_json::Value> = String::from_utf8_lossy(&dump.body)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    lines.sort_by_key(|line| line["key"].as_str().unwrap().to_string());
+
+    // Only the key imported with a TTL has one, and it is what was left of it
+    let ttl = lines[3]["ttl"].as_u64().unwrap();
+    assert!(ttl > 590 && ttl <= 600, "{}", ttl);
+    assert!(lines.iter().take(3).all(|line| line.get("ttl").is_none()));
+
+    // A new store holds the same documents once the dump is imported into it
+    let (copy, _) = start("").await;
+    let reply = send(&copy, import("", &String::from_utf8_lossy(&dump.body))).await;
+    assert_eq!(reply.json()["imported"], json!(4));
+
+    for key in ["user:1", "user:2", "greeting", "count"] {
+        let uri = format!("/kv/{}", key);
+        assert_eq!(send(&copy, get(&uri)).await.json(), send(&app, get(&uri)).await.json(), "{}", key);
+    }
+    assert_eq!(sorted(send(&copy, get("/keys")).await.json()), sorted(send(&app, get("/keys")).await.json()));
+}
+
+#[actix_web::test]
+async fn importing_skips_existing_keys_unless_replace_is_passed() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/greeting", json!("hi"))).await;
+
+    let reply = send(&app, import("", REDIS_DUMP)).await;
+    assert_eq!(reply.json()["imported"], json!(3));
+    assert_eq!(reply.json()["skipped"], json!(1));
+    assert_eq!(send(&app, get("/kv/greeting")).await.json(), json!("hi"));
+
+    // Without `parse_strings` the strings Redis held are kept as they are
+    assert_eq!(send(&app, get("/kv/count")).await.json(), json!("42"));
+
+    let reply = send(&app, import("?replace=true", REDIS_DUMP)).await;
+    assert_eq!(reply.json()["imported"], json!(4));
+    assert_eq!(send(&app, get("/kv/greeting")).await.json(), json!("hello"));
+}
+
+#[actix_web::test]
+async fn malformed_dumps_are_rejected_before_anything_is_written() {
+    let (app, kvs) = start("").await;
+
+    let malformed = format!("{}not json\n", REDIS_DUMP);
+    let reply = send(&app, import("", &malformed)).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8_lossy(&reply.body).contains("Invalid dump line 5"));
+
+    let duplicate = format!("{}{{\"key\":\"count\",\"value\":1}}\n", REDIS_DUMP);
+    let reply = send(&app, import("", &duplicate)).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8_lossy(&reply.body).contains("Duplicate key in dump line 5: count"));
+
+    assert_eq!(kvs.document_count(), 0);
+}
+
+#[actix_web::test]
+async fn rewrite_converts_the_data_file_between_formats() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("database.vbank");
+    let toml = format!("persistence = \"on\"\ndisk_format = \"legacy\"\n{}", data_file(&dir));
+
+    {
+        let (app, _) = start(&toml).await;
+        send(&app, put("/kv/a", json!({ "n": 1 }))).await;
+        send(&app, put("/kv/b", json!("two"))).await;
+
+        let reply = send(&app, TestRequest::post().uri("/admin/rewrite?format=cbor")).await;
+        assert_eq!(reply.status, StatusCode::OK);
+
+        let report = reply.json();
+        assert_eq!(report["format"], json!("cbor"));
+        assert_eq!(report["previous_format"], json!("legacy"));
+        assert_eq!(report["documents"], json!(2));
+        assert_eq!(report["bytes"], json!(std::fs::metadata(&path).unwrap().len()));
+        assert_eq!(check_file(path.to_str().unwrap()).unwrap()["format"], json!("cbor"));
+
+        // Later writes keep the new format
+        send(&app, put("/kv/c", json!(3))).await;
+        assert_eq!(check_file(path.to_str().unwrap()).unwrap()["format"], json!("cbor"));
+        assert_eq!(check_file(path.to_str().unwrap()).unwrap()["valid"], json!(3));
+    }
+
+    // The configured legacy store still loads the file and converts it back
+    let (app, _) = start(&toml).await;
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!({ "n": 1 }));
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!("two"));
+    assert_eq!(send(&app, get("/kv/c")).await.json(), json!(3));
+
+    let reply = send(&app, TestRequest::post().uri("/admin/rewrite?format=legacy")).await;
+    assert_eq!(reply.json()["previous_format"], json!("legacy"));
+    assert_eq!(check_file(path.to_str().unwrap()).unwrap()["format"], json!("legacy"));
+    assert_eq!(check_file(path.to_str().unwrap()).unwrap()["valid"], json!(3));
+}
+
+#[actix_web::test]
+async fn rewrite_rejects_unknown_formats_and_stores_without_a_data_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("database.vbank");
+    let (app, _) = start(&format!("persistence = \"on\"\ndisk_format = \"legacy\"\n{}", data_file(&dir))).await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+
+    let reply = send(&app, TestRequest::post().uri("/admin/rewrite?format=xml")).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+    assert_eq!(check_file(path.to_str().unwrap()).unwrap()["format"], json!("legacy"));
+
+    let (app, _) = start("").await;
+    let reply = send(&app, TestRequest::post().uri("/admin/rewrite?format=cbor")).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn locks_report_the_holder_and_its_waiters() {
+    let (app, kvs) = start("").await;
+
+    let idle = json!({ "write": { "held": false, "waiting": 0 }, "flush": { "held": false, "waiting": 0 } });
+    assert_eq!(send(&app, get("/admin/locks")).await.json(), idle);
+
+    // One thread holds the write lock until told to let go, another waits for it
+    let (held, wait_held) = std::sync::mpsc::channel();
+    let (release, wait_release) = std::sync::mpsc::channel::<()>();
+    let holder = std::thread::spawn({
+        let kvs = kvs.clone();
+        move || {
+            let _lock = kvs.store.write();
+            held.send(()).unwrap();
+            wait_release.recv().unwrap();
+        }
+    });
+    wait_held.recv().unwrap();
+
+    let waiter = std::thread::spawn({
+        let kvs = kvs.clone();
+        move || drop(kvs.store.write())
+    });
+
+    let mut locks = send(&app, get("/admin/locks")).await.json();
+    for _ in 0..100 {
+        if locks["write"]["waiting"] == json!(1) {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        locks = send(&app, get("/admin/locks")).await.json();
+    }
+    assert_eq!(locks["write"], json!({ "held": true, "waiting": 1 }));
+    assert_eq!(locks["flush"], json!({ "held": false, "waiting": 0 }));
+
+    release.send(()).unwrap();
+    holder.join().unwrap();
+    waiter.join().unwrap();
+
+    assert_eq!(send(&app, get("/admin/locks")).await.json(), idle);
+}
@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{get, put, send, start};
+
+fn lock(uri: &str) -> TestRequest {
+    TestRequest::post().uri(uri)
+}
+
+#[actix_web::test]
+async fn a_held_lock_conflicts_until_it_is_released() {
+    let (app, _) = start("").await;
+
+    let reply = send(&app, lock("/kv/job/lock?ttl_seconds=60")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json()["expires_in"], json!(60));
+    let token = reply.json()["token"].as_str().unwrap().to_string();
+    assert_eq!(token.len(), 32);
+
+    let reply = send(&app, lock("/kv/job/lock")).await;
+    assert_eq!(reply.status, StatusCode::CONFLICT);
+    let retry_after: u64 = reply.headers.get("Retry-After").unwrap().to_str().unwrap().parse().unwrap();
+    assert!((59..=60).contains(&retry_after), "{}", retry_after);
+
+    // Other keys are not locked, and a lock does not stop writes
+    assert_eq!(send(&app, lock("/kv/other/lock")).await.status, StatusCode::OK);
+    assert_eq!(send(&app, put("/kv/job", json!(1))).await.status, StatusCode::CREATED);
+
+    // The holder extends it, anyone else is refused
+    let reply = send(&app, lock(&format!("/kv/job/lock?ttl_seconds=120&token={}", token))).await;
+    assert_eq!(reply.json(), json!({ "token": token, "expires_in": 120 }));
+    assert_eq!(send(&app, lock("/kv/job/unlock?token=wrong")).await.status, StatusCode::CONFLICT);
+
+    assert_eq!(send(&app, lock(&format!("/kv/job/unlock?token={}", token))).await.status, StatusCode::NO_CONTENT);
+    assert_eq!(send(&app, lock(&format!("/kv/job/unlock?token={}", token))).await.status, StatusCode::NOT_FOUND);
+
+    let reply = send(&app, lock("/kv/job/lock")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_ne!(reply.json()["token"], json!(token));
+    assert_eq!(send(&app, get("/kv/job")).await.json(), json!(1));
+}
+
+#[actix_web::test]
+async fn a_lock_frees_itself_when_its_ttl_runs_out() {
+    let (app, _) = start("").await;
+
+    let reply = send(&app, lock("/kv/job/lock?ttl_seconds=1")).await;
+    let token = reply.json()["token"].as_str().unwrap().to_string();
+    assert_eq!(send(&app, lock("/kv/job/lock")).await.status, StatusCode::CONFLICT);
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    let reply = send(&app, lock("/kv/job/lock")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+
+    // The old holder no longer has it
+    assert_eq!(send(&app, lock(&format!("/kv/job/unlock?token={}", token))).await.status, StatusCode::CONFLICT);
+}
+
+#[actix_web::test]
+async fn lock_ttls_outside_the_range_are_rejected() {
+    let (app, _) = start("").await;
+
+    for ttl in [0, 3601] {
+        let reply = send(&app, lock(&format!("/kv/job/lock?ttl_seconds={}", ttl))).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST, "{}", ttl);
+    }
+    assert_eq!(send(&app, lock("/kv/job/lock?ttl_seconds=3600")).await.json()["expires_in"], json!(3600));
+}
@@ -0,0 +1,104 @@
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{get, post, put, send, start};
+use crate::auth::Auth;
+use crate::config::{AuthTokens, Config};
+
+const TOKENS: &str = "[auth_tokens]\nr3ad = \"read\"\nwr1te = \"write\"\nadm1n = \"admin\"";
+
+fn with_token(req: TestRequest, token: &str) -> TestRequest {
+    req.insert_header(("Authorization", format!("Bearer {}", token)))
+}
+
+#[actix_web::test]
+async fn requests_without_a_known_token_are_unauthorized() {
+    let (app, _) = start(TOKENS).await;
+
+    let requests = [
+        get("/kv/a"),
+        with_token(get("/kv/a"), "wrong"),
+        get("/kv/a").insert_header(("Authorization", "r3ad")),
+    ];
+    for req in requests {
+        let reply = send(&app, req).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(reply.headers.get("WWW-Authenticate").unwrap(), "Bearer");
+    }
+}
+
+#[actix_web::test]
+async fn a_read_token_cannot_write() {
+    let (app, kvs) = start(TOKENS).await;
+
+    send(&app, with_token(put("/kv/a", json!(1)), "wr1te")).await;
+
+    assert_eq!(send(&app, with_token(get("/kv/a"), "r3ad")).await.json(), json!(1));
+    let reply = send(&app, with_token(post("/batch/get", json!({ "keys": ["a"] })), "r3ad")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+
+    // The lookups over POST are only reads with that method
+    let writes = [
+        put("/kv/b", json!(2)),
+        post("/kv/a/max", json!(5)),
+        TestRequest::delete().uri("/kv/a"),
+        post("/batch/put", json!([{ "key": "c", "value": 3 }])),
+        put("/batch/get", json!(4)),
+    ];
+    for write in writes {
+        let reply = send(&app, with_token(write, "r3ad")).await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+        assert!(String::from_utf8_lossy(&reply.body).contains("write scope"));
+    }
+
+    assert_eq!(kvs.document_count(), 1);
+    assert_eq!(send(&app, with_token(get("/kv/a"), "r3ad")).await.json(), json!(1));
+}
+
+#[actix_web::test]
+async fn admin_routes_need_the_admin_scope_however_the_path_is_written() {
+    let (app, _) = start(TOKENS).await;
+
+    for uri in ["/admin/locks", "/%61dmin/locks", "/%61%64%6d%69%6e/locks", "/admin/%6Cocks"] {
+        for token in ["r3ad", "wr1te"] {
+            let reply = send(&app, with_token(get(uri), token)).await;
+            assert_eq!(reply.status, StatusCode::FORBIDDEN, "{} with {}", uri, token);
+            assert!(String::from_utf8_lossy(&reply.body).contains("admin scope"));
+        }
+
+        let reply = send(&app, with_token(get(uri), "adm1n")).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", uri);
+    }
+
+    let reply = send(&app, with_token(TestRequest::post().uri("/%61dmin/vacuum"), "wr1te")).await;
+    assert_eq!(reply.status, StatusCode::FORBIDDEN);
+
+    // Keys are not admin routes, whatever namespace they are read through
+    send(&app, with_token(put("/kv/a", json!(1)), "wr1te")).await;
+    assert_eq!(send(&app, with_token(get("/admin/a"), "r3ad")).await.json(), json!(1));
+}
+
+#[actix_web::test]
+async fn every_request_is_allowed_without_tokens() {
+    let (app, _) = start("").await;
+
+    assert_eq!(send(&app, put("/kv/a", json!(1))).await.status, StatusCode::CREATED);
+    assert_eq!(send(&app, get("/admin/locks")).await.status, StatusCode::OK);
+}
+
+#[test]
+fn tokens_are_read_from_the_environment_format_and_checked() {
+    let tokens: AuthTokens = "r3ad=read, adm1n=admin".parse().unwrap();
+    assert!(tokens.scope("adm1n").is_some());
+    assert!(tokens.scope("wrong").is_none());
+
+    assert!("r3ad".parse::<AuthTokens>().is_err());
+    assert!("r3ad=everything".parse::<AuthTokens>().is_err());
+
+    assert!(Config::from_toml("persistence = \"off\"\n[auth_tokens]\nr3ad = \"root\"").is_err());
+
+    // An empty token would let requests with an empty bearer through
+    let config = super::config("[auth_tokens]\n\"\" = \"admin\"");
+    assert!(Auth::from_config(&config).is_err());
+}
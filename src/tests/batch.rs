@@ -0,0 +1,133 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::StatusCode;
+use serde_json::json;
+
+use super::{get, post, put, send, start};
+
+#[actix_web::test]
+async fn cas_writes_every_key_when_all_match() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, put("/kv/b", json!(2))).await;
+
+    let reply = send(&app, post("/batch/cas", json!([
+        { "key": "a", "expected": 1, "new": 10 },
+        { "key": "b", "expected": 2, "new": 20 },
+        { "key": "c", "expected": null, "new": 30 },
+    ]))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!(10));
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!(20));
+    assert_eq!(send(&app, get("/kv/c")).await.json(), json!(30));
+}
+
+#[actix_web::test]
+async fn cas_writes_nothing_when_a_key_does_not_match() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, put("/kv/b", json!(2))).await;
+
+    let reply = send(&app, post("/batch/cas", json!([
+        { "key": "a", "expected": 1, "new": 10 },
+        { "key": "b", "expected": 3, "new": 20 },
+        { "key": "c", "expected": null, "new": 30 },
+    ]))).await;
+    assert_eq!(reply.status, StatusCode::CONFLICT);
+    assert!(String::from_utf8_lossy(&reply.body).contains('b'));
+
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!(1));
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!(2));
+    assert!(!kvs.store.read().contains_key("c"));
+}
+
+#[actix_web::test]
+async fn cas_keeps_the_expiry() {
+    let (app, kvs) = start("[namespace_ttl_secs]\nsessions = 30").await;
+
+    send(&app, put("/kv/a?ttl_seconds=600", json!(1))).await;
+    let expires_at = kvs.store.read().get("a").and_then(|entry| entry.expires_at);
+
+    let reply = send(&app, post("/batch/cas?namespace=sessions", json!([
+        { "key": "a", "expected": 1, "new": 2 },
+        { "key": "b", "expected": null, "new": 1 },
+    ]))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+
+    assert_eq!(kvs.store.read().get("a").and_then(|entry| entry.expires_at), expires_at);
+
+    // A key that the swap creates gets its namespace's default TTL
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let expires_at = kvs.store.read().get("b").and_then(|entry| entry.expires_at).unwrap();
+    assert!((now + 29..=now + 30).contains(&expires_at));
+}
+
+// The status of each result in order, and the `succeeded` and `failed` counts
+fn statuses(reply: &serde_json::Value) -> (Vec<u64>, u64, u64) {
+    let results = reply["results"].as_array().unwrap();
+    let statuses = results.iter().map(|result| result["status"].as_u64().unwrap()).collect();
+    (statuses, reply["succeeded"].as_u64().unwrap(), reply["failed"].as_u64().unwrap())
+}
+
+#[actix_web::test]
+async fn batch_put_reports_each_key_and_writes_the_others() {
+    let (app, kvs) = start("max_key_len = 8").await;
+
+    let documents = json!([
+        { "key": "a", "value": 1 },
+        { "key": "much-too-long", "value": 2 },
+        { "key": "b", "value": { "n": 3 }, "ttl_seconds": 60 },
+        { "key": "c", "value": 4, "ttl_seconds": u64::MAX },
+    ]);
+    let reply = send(&app, post("/batch/put", documents)).await;
+    assert_eq!(reply.status, StatusCode::OK);
+
+    let reply = reply.json();
+    assert_eq!(statuses(&reply), (vec![200, 400, 200, 400], 2, 2));
+    assert_eq!(reply["results"][0], json!({ "key": "a", "status": 200 }));
+    assert_eq!(reply["results"][1]["key"], json!("much-too-long"));
+    assert!(reply["results"][1]["error"].as_str().unwrap().contains("longer than 8 bytes"));
+
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!(1));
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!({ "n": 3 }));
+    assert!(kvs.store.read()["b"].expires_at.is_some());
+    assert_eq!(kvs.document_count(), 2);
+}
+
+#[actix_web::test]
+async fn batch_delete_reports_missing_keys() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, put("/kv/b", json!(2))).await;
+    send(&app, put("/kv/c", json!(3))).await;
+
+    let reply = send(&app, post("/batch/delete", json!({ "keys": ["a", "missing", "c"] }))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+
+    let reply = reply.json();
+    assert_eq!(statuses(&reply), (vec![200, 404, 200], 2, 1));
+    assert_eq!(reply["results"][1]["key"], json!("missing"));
+    assert!(reply["results"][1]["error"].is_string());
+    assert!(reply["results"][0].get("error").is_none());
+
+    assert_eq!(kvs.document_count(), 1);
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!(2));
+}
+
+#[actix_web::test]
+async fn batch_writes_are_rejected_whole_in_read_only_mode() {
+    let (app, _) = start("read_only = true").await;
+
+    let writes = [
+        post("/batch/put", json!([{ "key": "a", "value": 1 }])),
+        post("/batch/delete", json!({ "keys": ["a"] })),
+        post("/batch/expire", json!({ "keys": ["a"], "ttl_seconds": 60 })),
+    ];
+    for write in writes {
+        assert_eq!(send(&app, write).await.status, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,128 @@
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{data_file, get, send, start};
+
+#[actix_web::test]
+async fn large_blob_round_trips() {
+    let dir = tempfile::tempdir().unwrap();
+    let (app, _) = start(&data_file(&dir)).await;
+
+    let data: Vec<u8> = (0..8 * 1024 * 1024).map(|i: u32| (i * 31 % 251) as u8).collect();
+
+    let reply = send(&app, TestRequest::put().uri("/blob/video").set_payload(data.clone())).await;
+    assert_eq!(reply.status, StatusCode::CREATED);
+
+    let reply = send(&app, get("/blob/video")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert!(reply.body == data, "Downloaded blob differs from the upload");
+
+    let reference = send(&app, get("/kv/video")).await.json();
+    assert_eq!(reference["size"], json!(data.len()));
+
+    let name = reference["blob"].as_str().unwrap();
+    assert_eq!(std::fs::metadata(dir.path().join("blobs").join(name)).unwrap().len(), data.len() as u64);
+}
+
+#[actix_web::test]
+async fn blob_over_max_blob_size_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let (app, kvs) = start(&format!("{}\nmax_blob_size = 1024", data_file(&dir))).await;
+
+    let reply = send(&app, TestRequest::put().uri("/blob/big").set_payload(vec![0u8; 2048])).await;
+    assert_eq!(reply.status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(kvs.document_count(), 0);
+
+    // The partial upload is removed again
+    assert_eq!(std::fs::read_dir(dir.path().join("blobs")).unwrap().count(), 0);
+}
+
+#[actix_web::test]
+async fn large_json_blob_is_validated_when_asked() {
+    let dir = tempfile::tempdir().unwrap();
+    let (app, kvs) = start(&data_file(&dir)).await;
+
+    let rows: Vec<_> = (0..100_000).map(|n| json!({ "id": n, "tags": ["a", "b"], "nested": { "n": n } })).collect();
+    let document = serde_json::to_vec(&rows).unwrap();
+    assert!(document.len() > 4 * 1024 * 1024);
+
+    let upload = |uri: &str, body: &[u8]| TestRequest::put().uri(uri).set_payload(body.to_vec());
+
+    let reply = send(&app, upload("/blob/rows?validate=json", &document)).await;
+    assert_eq!(reply.status, StatusCode::CREATED);
+
+    let reply = send(&app, get("/blob/rows")).await;
+    assert!(reply.body == document, "Downloaded blob differs from the upload");
+
+    // Cut off, with trailing data and not JSON at all
+    let invalid = [&document[..document.len() - 1], &[&document[..], b" {}"].concat()[..], b"not json"];
+    for body in invalid {
+        let reply = send(&app, upload("/blob/bad?validate=json", body)).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        assert!(String::from_utf8_lossy(&reply.body).contains("not valid JSON"));
+    }
+    assert_eq!(send(&app, get("/blob/bad")).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(kvs.document_count(), 1);
+    assert_eq!(std::fs::read_dir(dir.path().join("blobs")).unwrap().count(), 1);
+
+    // Without validation the same bytes are stored as they are
+    let reply = send(&app, upload("/blob/bad", b"not json")).await;
+    assert_eq!(reply.status, StatusCode::CREATED);
+    assert_eq!(&send(&app, get("/blob/bad")).await.body[..], b"not json");
+}
+
+#[actix_web::test]
+async fn blob_ranges_are_served_as_partial_content() {
+    let dir = tempfile::tempdir().unwrap();
+    let (app, _) = start(&data_file(&dir)).await;
+
+    let data: Vec<u8> = (0..1000).map(|i: u32| (i % 251) as u8).collect();
+    send(&app, TestRequest::put().uri("/blob/file").set_payload(data.clone())).await;
+
+    let range = |range: &str| get("/blob/file").insert_header(("Range", range.to_string()));
+
+    for (header, start, end) in [("bytes=100-199", 100, 199), ("bytes=900-", 900, 999), ("bytes=-50", 950, 999)] {
+        let reply = send(&app, range(header)).await;
+        assert_eq!(reply.status, StatusCode::PARTIAL_CONTENT, "{}", header);
+        assert_eq!(reply.headers.get("Content-Range").unwrap(), format!("bytes {}-{}/1000", start, end).as_str());
+        assert!(reply.body[..] == data[start..=end], "{} returned other bytes", header);
+    }
+
+    // A range past the end is clamped to the blob
+    let reply = send(&app, range("bytes=990-5000")).await;
+    assert_eq!(reply.headers.get("Content-Range").unwrap(), "bytes 990-999/1000");
+    assert_eq!(reply.body.len(), 10);
+
+    let reply = send(&app, range("bytes=1000-")).await;
+    assert_eq!(reply.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(reply.headers.get("Content-Range").unwrap(), "bytes */1000");
+
+    // Without a range, or with one that cannot be read, the whole blob is sent
+    for request in [get("/blob/file"), range("bytes=0-10,20-30"), range("lines=1-2")] {
+        let reply = send(&app, request).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert!(reply.body == data, "Downloaded blob differs from the upload");
+    }
+}
+
+#[actix_web::test]
+async fn if_range_naming_an_earlier_upload_gets_the_whole_blob() {
+    let dir = tempfile::tempdir().unwrap();
+    let (app, _) = start(&data_file(&dir)).await;
+
+    send(&app, TestRequest::put().uri("/blob/file").set_payload(vec![1u8; 100])).await;
+    let etag = send(&app, get("/blob/file")).await.headers.get("ETag").unwrap().to_str().unwrap().to_string();
+
+    let resume = || get("/blob/file").insert_header(("Range", "bytes=50-")).insert_header(("If-Range", etag.clone()));
+
+    let reply = send(&app, resume()).await;
+    assert_eq!(reply.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(reply.body.len(), 50);
+
+    send(&app, TestRequest::put().uri("/blob/file").set_payload(vec![2u8; 100])).await;
+
+    let reply = send(&app, resume()).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert!(reply.body[..] == [2u8; 100][..], "The new upload was not sent");
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::fs;
+use std::time::Duration;
+
+use tempfile::TempDir;
+
+use crate::config::{Config, Persistence};
+use crate::kvstore::KVStore;
+
+#[test]
+fn file_settings_are_parsed() {
+    let config = Config::from_toml(
+        r#"
+        db_path = "/tmp/distkv-test.vbank"
+        persistence = "off"
+        bind_address = "0.0.0.0:9000"
+        flush_coalesce_ms = 250
+        max_key_len = 64
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.db_path, "/tmp/distkv-test.vbank");
+    assert_eq!(config.persistence, Persistence::Off);
+    assert_eq!(config.bind_address, "0.0.0.0:9000");
+    assert_eq!(config.flush_coalesce, Duration::from_millis(250));
+    assert_eq!(config.max_key_len, 64);
+}
+
+#[test]
+fn unknown_and_invalid_file_settings_are_rejected() {
+    assert!(Config::from_toml("persistence = \"off\"\nbind_adress = \"0.0.0.0:9000\"").is_err());
+    assert!(Config::from_toml("persistence = \"off\"\nmax_key_len = \"long\"").is_err());
+    assert!(Config::from_toml("persistence = \"off\"\ndb_path = \"\"").is_err());
+}
+
+// The environment as a map, so tests never change the one other tests read
+fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars: HashMap<String, String> =
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+
+    move |name| vars.get(name).cloned()
+}
+
+#[test]
+fn env_vars_override_the_config_file() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("distkv.toml");
+    fs::write(&path, "persistence = \"off\"\nbind_address = \"0.0.0.0:9000\"\nmax_key_len = 64\n").unwrap();
+    let path = path.to_str().unwrap();
+
+    let from_file = Config::load_with_env(env(&[("DISTKV_CONFIG", path)])).unwrap();
+    assert_eq!(from_file.bind_address, "0.0.0.0:9000");
+
+    let overridden = Config::load_with_env(env(&[("DISTKV_CONFIG", path), ("DISTKV_BIND_ADDRESS", "127.0.0.1:9001")]));
+    let overridden = overridden.unwrap();
+    assert_eq!(overridden.bind_address, "127.0.0.1:9001");
+    assert_eq!(overridden.max_key_len, 64);
+
+    let error = Config::load_with_env(env(&[("DISTKV_CONFIG", "/nonexistent/distkv.toml")])).unwrap_err();
+    assert!(error.to_string().contains("Could not read config file"), "{}", error);
+}
+
+#[test]
+fn env_vars_take_precedence_over_file_settings_and_defaults() {
+    let toml = "persistence = \"off\"\nmax_keys = 10\nread_only = false";
+
+    let overrides = env(&[("DISTKV_MAX_KEYS", "20"), ("DISTKV_MAX_KEY_LEN", "32")]);
+    let config = Config::from_toml_with_env(toml, overrides).unwrap();
+    assert_eq!(config.max_keys, Some(20));
+    assert_eq!(config.max_key_len, 32);
+    assert!(!config.read_only);
+
+    let config = Config::from_toml_with_env(toml, env(&[])).unwrap();
+    assert_eq!(config.max_keys, Some(10));
+    assert_eq!(config.max_key_len, 512);
+}
+
+#[test]
+fn invalid_env_vars_stop_the_start() {
+    let toml = "persistence = \"off\"\nmax_keys = 10";
+
+    for (name, value) in [
+        ("DISTKV_READ_ONLY", "yes"),
+        ("DISTKV_MAX_KEYS", "10k"),
+        ("DISTKV_PERSISTENCE", "sometimes"),
+        ("DISTKV_KEEP_ALIVE_SECS", "-1"),
+    ] {
+        let error = Config::from_toml_with_env(toml, env(&[(name, value)])).unwrap_err();
+        assert!(error.to_string().contains(name), "{}", error);
+    }
+
+    // Values that parse are still checked like the file's
+    assert!(Config::from_toml_with_env(toml, env(&[("DISTKV_MAX_KEY_LEN", "0")])).is_err());
+    assert!(Config::from_toml_with_env(toml, env(&[("DISTKV_AUTH_TOKENS", "r3ad=everything")])).is_err());
+}
+
+#[test]
+fn a_missing_data_file_is_created_unless_create_if_missing_is_off() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("database.vbank");
+    let db_path = format!("persistence = \"on\"\ndb_path = {:?}\n", path.to_str().unwrap());
+
+    let error = Config::from_toml(&format!("{}create_if_missing = false", db_path)).unwrap_err();
+    assert!(error.to_string().contains("No data file at"), "{}", error);
+
+    // Nothing is checked with persistence off
+    let off = format!("persistence = \"off\"\ndb_path = {:?}\ncreate_if_missing = false", path.to_str().unwrap());
+    assert!(Config::from_toml(&off).is_ok());
+
+    let config = Config::from_toml(&db_path).unwrap();
+    assert!(config.create_if_missing);
+
+    KVStore::new(&config);
+    assert!(path.exists());
+
+    assert!(Config::from_toml(&format!("{}create_if_missing = false", db_path)).is_ok());
+}
@@ -0,0 +1,77 @@
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{get, put, send, start};
+
+const CORS: &str = r#"
+cors_origins = "https://app.example.com, https://admin.example.com"
+cors_methods = "get, put"
+cors_headers = "Content-Type, Authorization"
+cors_max_age_secs = 600
+"#;
+
+fn preflight(origin: &str, method: &str) -> TestRequest {
+    TestRequest::default()
+        .method(actix_web::http::Method::OPTIONS)
+        .uri("/kv/a")
+        .insert_header(("Origin", origin))
+        .insert_header(("Access-Control-Request-Method", method))
+}
+
+#[actix_web::test]
+async fn preflights_carry_the_max_age_and_allowed_methods() {
+    let (app, _) = start(CORS).await;
+
+    let reply = send(&app, preflight("https://app.example.com", "PUT")).await;
+    assert_eq!(reply.status, StatusCode::NO_CONTENT);
+    assert_eq!(reply.headers.get("Access-Control-Allow-Origin").unwrap(), "https://app.example.com");
+    assert_eq!(reply.headers.get("Access-Control-Allow-Methods").unwrap(), "GET, PUT");
+    assert_eq!(reply.headers.get("Access-Control-Allow-Headers").unwrap(), "Content-Type, Authorization");
+    assert_eq!(reply.headers.get("Access-Control-Max-Age").unwrap(), "600");
+
+    // Methods and origins that are not listed are refused
+    for (origin, method) in [("https://app.example.com", "DELETE"), ("https://evil.example.com", "GET")] {
+        let reply = send(&app, preflight(origin, method)).await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN, "{} {}", origin, method);
+        assert!(reply.headers.get("Access-Control-Allow-Origin").is_none());
+    }
+}
+
+#[actix_web::test]
+async fn responses_to_allowed_origins_are_marked_readable() {
+    let (app, _) = start(CORS).await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+
+    let reply = send(&app, get("/kv/a").insert_header(("Origin", "https://admin.example.com"))).await;
+    assert_eq!(reply.json(), json!(1));
+    assert_eq!(reply.headers.get("Access-Control-Allow-Origin").unwrap(), "https://admin.example.com");
+    assert!(reply.headers.get_all("Vary").any(|vary| vary == "Origin"));
+
+    let reply = send(&app, get("/kv/a").insert_header(("Origin", "https://evil.example.com"))).await;
+    assert_eq!(reply.json(), json!(1));
+    assert!(reply.headers.get("Access-Control-Allow-Origin").is_none());
+}
+
+#[actix_web::test]
+async fn any_origin_and_requested_headers_are_allowed_without_lists() {
+    let (app, _) = start("cors_origins = \"*\"").await;
+
+    let request = preflight("https://anywhere.example", "DELETE").insert_header(("Access-Control-Request-Headers", "X-Trace"));
+    let reply = send(&app, request).await;
+    assert_eq!(reply.status, StatusCode::NO_CONTENT);
+    assert_eq!(reply.headers.get("Access-Control-Allow-Origin").unwrap(), "*");
+    assert_eq!(reply.headers.get("Access-Control-Allow-Headers").unwrap(), "X-Trace");
+    assert!(reply.headers.get("Access-Control-Max-Age").is_none());
+}
+
+#[actix_web::test]
+async fn cors_is_off_by_default() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+
+    let reply = send(&app, get("/kv/a").insert_header(("Origin", "https://app.example.com"))).await;
+    assert!(reply.headers.get("Access-Control-Allow-Origin").is_none());
+}
@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{get, put, send, start};
+
+#[actix_web::test]
+async fn soft_deleted_key_can_be_restored() {
+    let (app, _) = start("soft_delete_secs = 60").await;
+
+    send(&app, put("/kv/a", json!({ "n": 1 }))).await;
+    send(&app, put("/kv/b", json!(2))).await;
+
+    assert_eq!(send(&app, TestRequest::delete().uri("/kv/a")).await.status, StatusCode::OK);
+    assert_eq!(send(&app, get("/kv/a")).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(send(&app, get("/kv/list/")).await.json(), json!([{ "key": "b", "data": 2 }]));
+
+    let reply = send(&app, TestRequest::post().uri("/kv/a/undelete")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!({ "n": 1 }));
+}
+
+#[actix_web::test]
+async fn soft_deleted_key_is_removed_after_the_window() {
+    let (app, kvs) = start("soft_delete_secs = 1").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, TestRequest::delete().uri("/kv/a")).await;
+
+    // The tombstone stays until the sweeper runs after the window
+    assert!(kvs.store.read().contains_key("a"));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(kvs.sweep_expired().await, 1);
+
+    assert!(!kvs.store.read().contains_key("a"));
+    assert_eq!(send(&app, TestRequest::post().uri("/kv/a/undelete")).await.status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn undelete_without_soft_deletes_is_not_found() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, TestRequest::delete().uri("/kv/a")).await;
+
+    assert_eq!(send(&app, TestRequest::post().uri("/kv/a/undelete")).await.status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn idempotent_deletes_succeed_when_the_key_is_gone() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+
+    let reply = send(&app, TestRequest::delete().uri("/kv/a?idempotent=true")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(kvs.document_count(), 0);
+
+    let reply = send(&app, TestRequest::delete().uri("/kv/a?idempotent=true")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+
+    // Without the flag a missing key is still an error
+    assert_eq!(send(&app, TestRequest::delete().uri("/kv/a")).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(send(&app, TestRequest::delete().uri("/kv/a?idempotent=false")).await.status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn take_returns_the_value_and_deletes_the_key() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/job:1", json!({ "task": "resize" }))).await;
+
+    let reply = send(&app, TestRequest::post().uri("/kv/job:1/take")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "task": "resize" }));
+
+    assert_eq!(send(&app, get("/kv/job:1")).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(kvs.document_count(), 0);
+
+    // Only one taker gets the value
+    assert_eq!(send(&app, TestRequest::post().uri("/kv/job:1/take")).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(send(&app, TestRequest::post().uri("/kv/missing/take")).await.status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn taken_keys_are_soft_deleted_within_the_window() {
+    let (app, _) = start("soft_delete_secs = 60").await;
+
+    send(&app, put("/kv/job:1", json!("work"))).await;
+
+    assert_eq!(send(&app, TestRequest::post().uri("/kv/job:1/take")).await.json(), json!("work"));
+    assert_eq!(send(&app, get("/kv/job:1")).await.status, StatusCode::NOT_FOUND);
+
+    assert_eq!(send(&app, TestRequest::post().uri("/kv/job:1/undelete")).await.status, StatusCode::OK);
+    assert_eq!(send(&app, get("/kv/job:1")).await.json(), json!("work"));
+}
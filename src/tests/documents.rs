@@ -0,0 +1,282 @@
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use regex::Regex;
+use serde_json::json;
+
+use super::{get, post, put, send, start};
+
+#[actix_web::test]
+async fn move_renames_the_key() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/tmp:x", json!({ "n": 1 }))).await;
+
+    let reply = send(&app, TestRequest::post().uri("/kv/tmp:x/move?to=final:x")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+
+    assert_eq!(send(&app, get("/kv/tmp:x")).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(send(&app, get("/kv/final:x")).await.json(), json!({ "n": 1 }));
+}
+
+#[actix_web::test]
+async fn move_onto_an_existing_key_needs_overwrite() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!("a"))).await;
+    send(&app, put("/kv/b", json!("b"))).await;
+
+    let reply = send(&app, TestRequest::post().uri("/kv/a/move?to=b")).await;
+    assert_eq!(reply.status, StatusCode::CONFLICT);
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!("a"));
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!("b"));
+
+    let reply = send(&app, TestRequest::post().uri("/kv/a/move?to=b&overwrite=true")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(send(&app, get("/kv/a")).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!("a"));
+}
+
+#[actix_web::test]
+async fn move_of_a_missing_key_is_not_found() {
+    let (app, kvs) = start("").await;
+
+    let reply = send(&app, TestRequest::post().uri("/kv/missing/move?to=b")).await;
+    assert_eq!(reply.status, StatusCode::NOT_FOUND);
+    assert_eq!(kvs.document_count(), 0);
+}
+
+#[actix_web::test]
+async fn copy_keeps_the_source() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/template", json!({ "plan": "free" }))).await;
+
+    let reply = send(&app, TestRequest::post().uri("/kv/template/copy?to=user:1")).await;
+    assert_eq!(reply.status, StatusCode::CREATED);
+    assert!(String::from_utf8_lossy(&reply.body).contains("user:1"));
+
+    assert_eq!(send(&app, get("/kv/template")).await.json(), json!({ "plan": "free" }));
+    assert_eq!(send(&app, get("/kv/user:1")).await.json(), json!({ "plan": "free" }));
+}
+
+#[actix_web::test]
+async fn copy_onto_an_existing_key_needs_overwrite() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!("a"))).await;
+    send(&app, put("/kv/b", json!("b"))).await;
+
+    let reply = send(&app, TestRequest::post().uri("/kv/a/copy?to=b&overwrite=false")).await;
+    assert_eq!(reply.status, StatusCode::CONFLICT);
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!("b"));
+
+    let reply = send(&app, TestRequest::post().uri("/kv/a/copy?to=b&overwrite=true")).await;
+    assert_eq!(reply.status, StatusCode::CREATED);
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!("a"));
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!("a"));
+}
+
+#[actix_web::test]
+async fn copy_of_a_missing_key_is_not_found() {
+    let (app, kvs) = start("").await;
+
+    let reply = send(&app, TestRequest::post().uri("/kv/missing/copy?to=b")).await;
+    assert_eq!(reply.status, StatusCode::NOT_FOUND);
+    assert_eq!(kvs.document_count(), 0);
+}
+
+#[actix_web::test]
+async fn create_with_a_generated_key_echoes_the_document() {
+    let (app, _) = start("").await;
+
+    let value = json!({ "name": "x", "roles": ["admin"] });
+
+    let reply = send(&app, put("/kv/", value.clone())).await;
+    assert_eq!(reply.status, StatusCode::CREATED);
+
+    let document = reply.json();
+    assert_eq!(document["data"], value);
+
+    let key = document["key"].as_str().unwrap();
+    assert_eq!(send(&app, get(&format!("/kv/{}", key))).await.json(), value);
+}
+
+#[actix_web::test]
+async fn generated_keys_match_the_key_strategy() {
+    let strategies = [
+        ("random", r"^[0-9A-Za-z]{8}$"),
+        ("ulid", r"^[0-9A-HJKMNP-TV-Z]{26}$"),
+        ("uuid", r"^[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}$"),
+        ("snowflake", r"^[0-9]+$"),
+    ];
+
+    for (strategy, pattern) in strategies {
+        let (app, _) = start(&format!("key_strategy = \"{}\"", strategy)).await;
+        let pattern = Regex::new(pattern).unwrap();
+
+        let mut keys = Vec::new();
+        for n in 0..3 {
+            let document = send(&app, put("/kv/", json!(n))).await.json();
+            let key = document["key"].as_str().unwrap().to_string();
+
+            assert!(pattern.is_match(&key), "{} key {}", strategy, key);
+            keys.push(key);
+        }
+
+        // Snowflakes only ever grow, even within one millisecond
+        if strategy == "snowflake" {
+            let ids: Vec<u64> = keys.iter().map(|key| key.parse().unwrap()).collect();
+            assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", ids);
+        }
+    }
+}
+
+#[actix_web::test]
+async fn retries_with_an_idempotency_key_create_one_document() {
+    let (app, kvs) = start("").await;
+
+    let create = || put("/kv/", json!({ "order": 1 })).insert_header(("Idempotency-Key", "order-1"));
+
+    let first = send(&app, create()).await;
+    assert_eq!(first.status, StatusCode::CREATED);
+    assert!(first.headers.get("Idempotent-Replayed").is_none());
+
+    let retry = send(&app, create()).await;
+    assert_eq!(retry.status, StatusCode::CREATED);
+    assert_eq!(retry.headers.get("Idempotent-Replayed").unwrap(), "true");
+    assert_eq!(retry.json(), first.json());
+
+    assert_eq!(kvs.document_count(), 1);
+
+    // Another key is another request
+    let other = send(&app, put("/kv/", json!({ "order": 1 })).insert_header(("Idempotency-Key", "order-2"))).await;
+    assert_ne!(other.json()["key"], first.json()["key"]);
+    assert_eq!(kvs.document_count(), 2);
+}
+
+#[actix_web::test]
+async fn taken_generated_keys_are_retried_and_counted() {
+    let (app, _) = start("key_strategy = \"snowflake\"").await;
+
+    let reply = send(&app, put("/kv/", json!(0))).await;
+    assert_eq!(reply.headers.get("X-Key-Attempts").unwrap(), "1");
+
+    // Takes the first snowflake of every millisecond of the next 20 seconds,
+    // which is what a key generated in a later millisecond gets first
+    let millis = reply.json()["key"].as_str().unwrap().parse::<u64>().unwrap() >> 22;
+    let taken: Vec<_> = (millis + 1..millis + 20_000)
+        .map(|millis| json!({ "key": (millis << 22).to_string(), "value": "taken" }))
+        .collect();
+    assert_eq!(send(&app, post("/batch/put", json!(taken))).await.status, StatusCode::OK);
+
+    let metrics = |body: &[u8]| {
+        let metrics = String::from_utf8_lossy(body).into_owned();
+        let line = metrics.lines().find(|line| line.starts_with("distkv_key_collisions_total ")).unwrap();
+        line.rsplit(' ').next().unwrap().parse::<u64>().unwrap()
+    };
+    assert_eq!(metrics(&send(&app, get("/metrics")).await.body), 0);
+
+    // Past the millisecond of the first key, whose later sequences are free
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+
+    let reply = send(&app, put("/kv/", json!(1))).await;
+    assert_eq!(reply.status, StatusCode::CREATED);
+
+    // The retry can start the next millisecond and collide again
+    let attempts: u64 = reply.headers.get("X-Key-Attempts").unwrap().to_str().unwrap().parse().unwrap();
+    assert!(attempts >= 2);
+
+    let key = reply.json()["key"].as_str().unwrap().to_string();
+    assert_eq!(send(&app, get(&format!("/kv/{}", key))).await.json(), json!(1));
+    assert_eq!(metrics(&send(&app, get("/metrics")).await.body), attempts - 1);
+}
+
+#[actix_web::test]
+async fn swap_exchanges_two_values_and_keeps_their_expiries() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/front?ttl_seconds=600", json!({ "buffer": 1 }))).await;
+    send(&app, put("/kv/back", json!({ "buffer": 2 }))).await;
+
+    let reply = send(&app, TestRequest::post().uri("/admin/swap?a=front&b=back")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "swapped": true }));
+
+    assert_eq!(send(&app, get("/kv/front")).await.json(), json!({ "buffer": 2 }));
+    assert_eq!(send(&app, get("/kv/back")).await.json(), json!({ "buffer": 1 }));
+
+    let snapshot = kvs.store.read();
+    assert!(snapshot["front"].expires_at.is_some());
+    assert!(snapshot["back"].expires_at.is_none());
+}
+
+#[actix_web::test]
+async fn swap_with_a_missing_key_is_not_found() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!("a"))).await;
+
+    for uri in ["/admin/swap?a=a&b=missing", "/admin/swap?a=missing&b=a"] {
+        assert_eq!(send(&app, TestRequest::post().uri(uri)).await.status, StatusCode::NOT_FOUND, "{}", uri);
+    }
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!("a"));
+    assert_eq!(send(&app, get("/kv/missing")).await.status, StatusCode::NOT_FOUND);
+
+    // Both missing leaves nothing to do
+    let reply = send(&app, TestRequest::post().uri("/admin/swap?a=x&b=y")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "swapped": false }));
+}
+
+#[actix_web::test]
+async fn rename_prefix_moves_every_key_under_it() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/old:a?ttl_seconds=600", json!({ "n": 1 }))).await;
+    send(&app, put("/kv/old:b", json!({ "n": 2 }))).await;
+    send(&app, put("/kv/old", json!("no colon"))).await;
+    send(&app, put("/kv/older:c", json!(3))).await;
+
+    let reply = send(&app, TestRequest::post().uri("/admin/rename-prefix?from=old:&to=new:")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "renamed": 2 }));
+
+    assert_eq!(send(&app, get("/kv/new:a")).await.json(), json!({ "n": 1 }));
+    assert_eq!(send(&app, get("/kv/new:b")).await.json(), json!({ "n": 2 }));
+    assert_eq!(send(&app, get("/kv/old:a")).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(send(&app, get("/kv/old:b")).await.status, StatusCode::NOT_FOUND);
+
+    // Keys that only share part of the prefix stay
+    assert_eq!(send(&app, get("/kv/old")).await.json(), json!("no colon"));
+    assert_eq!(send(&app, get("/kv/older:c")).await.json(), json!(3));
+
+    let snapshot = kvs.store.read();
+    assert!(snapshot["new:a"].expires_at.is_some());
+    assert!(snapshot["new:b"].expires_at.is_none());
+    assert_eq!(kvs.document_count(), 4);
+}
+
+#[actix_web::test]
+async fn rename_prefix_onto_existing_keys_needs_overwrite() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/old:a", json!("old a"))).await;
+    send(&app, put("/kv/old:b", json!("old b"))).await;
+    send(&app, put("/kv/new:b", json!("new b"))).await;
+
+    let reply = send(&app, TestRequest::post().uri("/admin/rename-prefix?from=old:&to=new:")).await;
+    assert_eq!(reply.status, StatusCode::CONFLICT);
+
+    // Nothing moved, not even the key without a collision
+    assert_eq!(send(&app, get("/kv/old:a")).await.json(), json!("old a"));
+    assert_eq!(send(&app, get("/kv/new:a")).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(send(&app, get("/kv/new:b")).await.json(), json!("new b"));
+
+    let reply = send(&app, TestRequest::post().uri("/admin/rename-prefix?from=old:&to=new:&overwrite=true")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "renamed": 2 }));
+
+    assert_eq!(send(&app, get("/kv/new:a")).await.json(), json!("old a"));
+    assert_eq!(send(&app, get("/kv/new:b")).await.json(), json!("old b"));
+    assert_eq!(kvs.document_count(), 2);
+}
@@ -0,0 +1,317 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::StatusCode;
+use serde_json::json;
+
+use super::{config, get, patch, post, put, send, start};
+use crate::config::NamespaceTtls;
+use crate::kvstore::KVStore;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[actix_web::test]
+async fn batch_expire_reports_present_and_missing_keys() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, put("/kv/b", json!(2))).await;
+
+    let reply = send(&app, post("/batch/expire", json!({ "keys": ["a", "missing", "b"], "ttl_seconds": 60 }))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+
+    let reply = reply.json();
+    assert_eq!(reply["succeeded"], json!(2));
+    assert_eq!(reply["failed"], json!(1));
+    assert_eq!(
+        reply["results"].as_array().unwrap().iter().map(|result| result["status"].clone()).collect::<Vec<_>>(),
+        [json!(200), json!(404), json!(200)]
+    );
+
+    let snapshot = kvs.store.read();
+    for key in ["a", "b"] {
+        let expires_at = snapshot.get(key).and_then(|entry| entry.expires_at).unwrap();
+        assert!((now() + 59..=now() + 60).contains(&expires_at), "{} expires at {}", key, expires_at);
+    }
+    assert!(!snapshot.contains_key("missing"));
+}
+
+#[actix_web::test]
+async fn out_of_range_ttls_are_rejected() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+
+    for uri in ["/kv/x?ttl_seconds=18446744073709551615", "/kv/a?upsert=true&ttl_seconds=18446744073709551615"] {
+        assert_eq!(send(&app, put(uri, json!(1))).await.status, StatusCode::BAD_REQUEST, "{}", uri);
+    }
+
+    let reply = send(&app, post("/batch/expire", json!({ "keys": ["a"], "ttl_seconds": u64::MAX }))).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+
+    let reply = send(&app, post("/batch/put", json!([
+        { "key": "y", "value": 1, "ttl_seconds": u64::MAX },
+        { "key": "z", "value": 2 },
+    ]))).await;
+    assert_eq!(reply.json()["results"][0]["status"], json!(400));
+    assert_eq!(reply.json()["results"][1]["status"], json!(200));
+
+    // Nothing was written and the store still takes writes
+    assert_eq!(kvs.store.read().get("a").and_then(|entry| entry.expires_at), None);
+    assert!(!kvs.store.read().contains_key("x"));
+    assert_eq!(send(&app, put("/kv/b", json!(2))).await.status, StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn patch_keeps_the_expiry() {
+    let (app, kvs) = start("[namespace_ttl_secs]\nsessions = 30").await;
+
+    send(&app, put("/kv/a?ttl_seconds=600", json!(1))).await;
+    let expires_at = kvs.store.read().get("a").and_then(|entry| entry.expires_at);
+
+    assert_eq!(send(&app, patch("/sessions/a", json!(2))).await.status, StatusCode::OK);
+    assert_eq!(kvs.store.read().get("a").and_then(|entry| entry.expires_at), expires_at);
+
+    // A key that PATCH creates gets its namespace's default TTL
+    send(&app, patch("/sessions/b", json!(1))).await;
+    let expires_at = kvs.store.read().get("b").and_then(|entry| entry.expires_at).unwrap();
+    assert!((now() + 29..=now() + 30).contains(&expires_at));
+}
+
+#[actix_web::test]
+async fn jitter_spreads_batch_expiries_within_the_bound() {
+    let (app, kvs) = start("ttl_jitter_pct = 10").await;
+
+    let keys: Vec<String> = (0..200).map(|n| format!("key:{}", n)).collect();
+    for key in &keys {
+        send(&app, put(&format!("/kv/{}", key), json!(1))).await;
+    }
+
+    let before = now();
+    let reply = send(&app, post("/batch/expire", json!({ "keys": keys, "ttl_seconds": 1000 }))).await;
+    assert_eq!(reply.json()["succeeded"], json!(200));
+    let after = now();
+
+    let snapshot = kvs.store.read();
+    let expiries: Vec<u64> = keys.iter().map(|key| snapshot[key].expires_at.unwrap()).collect();
+
+    for expires_at in &expiries {
+        assert!((before + 900..=after + 1100).contains(expires_at), "expires at {}", expires_at);
+    }
+
+    // 200 draws from 201 seconds are all but certain to spread out
+    let distinct: std::collections::HashSet<_> = expiries.iter().collect();
+    assert!(distinct.len() > 50, "only {} distinct expiries", distinct.len());
+    assert!(expiries.iter().any(|expires_at| *expires_at < before + 1000));
+    assert!(expiries.iter().any(|expires_at| *expires_at > after + 1000));
+}
+
+#[actix_web::test]
+async fn jitter_that_would_overflow_the_ttl_is_rejected() {
+    let (app, kvs) = start("ttl_jitter_pct = 100").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+
+    // The TTL alone fits, but not with the jitter added
+    for ttl_seconds in [u64::MAX, u64::MAX / 2, u64::MAX / 2 - now() / 2 + 1] {
+        let reply = send(&app, post("/batch/expire", json!({ "keys": ["a"], "ttl_seconds": ttl_seconds }))).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST, "{}", ttl_seconds);
+    }
+    assert_eq!(kvs.store.read().get("a").and_then(|entry| entry.expires_at), None);
+
+    let reply = send(&app, post("/batch/expire", json!({ "keys": ["a"], "ttl_seconds": u64::MAX / 4 }))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert!(kvs.store.read().get("a").and_then(|entry| entry.expires_at).is_some());
+}
+
+fn expires_in(kvs: &KVStore, key: &str) -> Option<u64> {
+    kvs.store.read().get(key).and_then(|entry| entry.expires_at).map(|expires_at| expires_at - now())
+}
+
+#[actix_web::test]
+async fn keys_inherit_their_namespace_ttl_unless_given_one() {
+    let (app, kvs) = start("[namespace_ttl_secs]\nsessions = 30\ncache = 5").await;
+
+    send(&app, put("/sessions/a", json!(1))).await;
+    send(&app, put("/cache/b", json!(2))).await;
+    send(&app, put("/sessions/c?ttl_seconds=600", json!(3))).await;
+    send(&app, put("/sessions/d?ttl_seconds=0", json!(4))).await;
+    send(&app, put("/kv/e", json!(5))).await;
+    let generated = send(&app, put("/sessions/", json!(6))).await.json()["key"].as_str().unwrap().to_string();
+
+    assert!(matches!(expires_in(&kvs, "a"), Some(29..=30)));
+    assert!(matches!(expires_in(&kvs, "b"), Some(4..=5)));
+    assert!(matches!(expires_in(&kvs, "c"), Some(599..=600)));
+    assert_eq!(expires_in(&kvs, "d"), None);
+    assert_eq!(expires_in(&kvs, "e"), None);
+
+    assert!(matches!(expires_in(&kvs, &generated), Some(29..=30)));
+}
+
+#[test]
+fn namespace_ttls_are_read_from_the_file_and_the_environment_format() {
+    let config = config("[namespace_ttl_secs]\nsessions = 3600");
+    assert_eq!(config.namespace_ttls.get("sessions"), Some(3600));
+    assert_eq!(config.namespace_ttls.get("kv"), None);
+
+    let ttls: NamespaceTtls = "sessions=3600, cache=60".parse().unwrap();
+    assert_eq!(ttls.get("cache"), Some(60));
+    assert!("sessions".parse::<NamespaceTtls>().is_err());
+    assert!("sessions=soon".parse::<NamespaceTtls>().is_err());
+}
+
+#[actix_web::test]
+async fn vacuum_removes_expired_documents_and_reports_the_bytes() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/session:1?ttl_seconds=1", json!({ "user": 1 }))).await;
+    send(&app, put("/kv/session:2?ttl_seconds=1", json!({ "user": 2 }))).await;
+    send(&app, put("/kv/profile:1?ttl_seconds=600", json!({ "name": "a" }))).await;
+    send(&app, put("/kv/config", json!(true))).await;
+
+    tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+
+    // Keys and base64 encoded values of the two sessions
+    let encoded = |value| base64::encode(serde_json::to_string(&value).unwrap()).len();
+    let reclaimed = "session:1".len() + encoded(json!({ "user": 1 })) + "session:2".len() + encoded(json!({ "user": 2 }));
+
+    let reply = send(&app, post("/admin/vacuum", json!(null))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "removed": 2, "reclaimed_bytes": reclaimed }));
+
+    assert_eq!(kvs.document_count(), 2);
+    assert!(kvs.store.read().get("session:1").is_none());
+    assert_eq!(send(&app, get("/kv/profile:1")).await.json(), json!({ "name": "a" }));
+
+    // Nothing is left to remove
+    let reply = send(&app, post("/admin/vacuum", json!(null))).await;
+    assert_eq!(reply.json(), json!({ "removed": 0, "reclaimed_bytes": 0 }));
+}
+
+#[actix_web::test]
+async fn vacuum_is_refused_in_read_only_mode() {
+    let (app, _) = start("read_only = true").await;
+
+    assert_eq!(send(&app, post("/admin/vacuum", json!(null))).await.status, StatusCode::FORBIDDEN);
+}
+
+// Makes `key` expire `secs` seconds ago
+fn expire_ago(kvs: &KVStore, key: &str, secs: u64) {
+    kvs.store.write().get_mut(key).unwrap().expires_at = Some(now() - secs);
+}
+
+#[actix_web::test]
+async fn keys_are_served_stale_until_the_grace_period_is_over() {
+    let (app, kvs) = start("stale_grace_secs = 10").await;
+
+    send(&app, put("/kv/fresh?ttl_seconds=600", json!("fresh"))).await;
+    let reply = send(&app, get("/kv/fresh")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert!(reply.headers.get("X-Stale").is_none());
+
+    // The grace period ends exactly `stale_grace_secs` after the expiry
+    for (secs, stale) in [(1, true), (8, true), (10, false), (11, false)] {
+        let key = format!("k{}", secs);
+        send(&app, put(&format!("/kv/{}?ttl_seconds=600", key), json!(secs))).await;
+        expire_ago(&kvs, &key, secs);
+
+        let reply = send(&app, get(&format!("/kv/{}", key))).await;
+        if stale {
+            assert_eq!(reply.status, StatusCode::OK, "{} seconds ago", secs);
+            assert_eq!(reply.headers.get("X-Stale").unwrap(), "true");
+            assert_eq!(reply.json(), json!(secs));
+        } else {
+            assert_eq!(reply.status, StatusCode::NOT_FOUND, "{} seconds ago", secs);
+        }
+    }
+}
+
+#[actix_web::test]
+async fn stale_keys_are_expired_for_everything_but_get() {
+    let (app, kvs) = start("stale_grace_secs = 10").await;
+
+    send(&app, put("/kv/a?ttl_seconds=600", json!(1))).await;
+    expire_ago(&kvs, "a", 1);
+
+    assert_eq!(send(&app, get("/kv/a/exists")).await.json(), json!({ "exists": false }));
+    assert_eq!(send(&app, get("/keys")).await.json(), json!([]));
+
+    // The sweeper keeps it until the grace period is over
+    assert_eq!(kvs.sweep_expired().await, 0);
+    assert!(kvs.store.read().contains_key("a"));
+
+    expire_ago(&kvs, "a", 10);
+    assert_eq!(kvs.sweep_expired().await, 1);
+    assert!(kvs.store.read().get("a").is_none());
+
+    // A refresh writes the key as new
+    send(&app, put("/kv/b?ttl_seconds=600", json!(1))).await;
+    expire_ago(&kvs, "b", 1);
+    assert_eq!(send(&app, put("/kv/b?ttl_seconds=600", json!(2))).await.status, StatusCode::CREATED);
+
+    let reply = send(&app, get("/kv/b")).await;
+    assert!(reply.headers.get("X-Stale").is_none());
+    assert_eq!(reply.json(), json!(2));
+}
+
+#[actix_web::test]
+async fn expired_keys_are_not_served_without_a_grace_period() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/a?ttl_seconds=600", json!(1))).await;
+    expire_ago(&kvs, "a", 1);
+
+    assert_eq!(send(&app, get("/kv/a")).await.status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn jitter_spreads_the_ttls_of_keys_created_together() {
+    let (app, kvs) = start("ttl_jitter_pct = 10").await;
+
+    let keys: Vec<String> = (0..200).map(|n| format!("key:{}", n)).collect();
+
+    let before = now();
+    for key in &keys {
+        send(&app, put(&format!("/kv/{}?ttl_seconds=1000", key), json!(1))).await;
+    }
+    let after = now();
+
+    let snapshot = kvs.store.read();
+    let expiries: Vec<u64> = keys.iter().map(|key| snapshot[key].expires_at.unwrap()).collect();
+
+    for expires_at in &expiries {
+        assert!((before + 900..=after + 1100).contains(expires_at), "expires at {}", expires_at);
+    }
+
+    let distinct: std::collections::HashSet<_> = expiries.iter().collect();
+    assert!(distinct.len() > 50, "only {} distinct expiries", distinct.len());
+}
+
+#[actix_web::test]
+async fn jitter_spreads_batch_put_and_namespace_default_ttls() {
+    let (app, kvs) = start("ttl_jitter_pct = 10\n[namespace_ttl_secs]\nsessions = 1000").await;
+
+    let documents: Vec<_> = (0..100)
+        .map(|n| json!({ "key": format!("batch:{}", n), "value": n, "ttl_seconds": 1000 }))
+        .collect();
+
+    let before = now();
+    send(&app, post("/batch/put", json!(documents))).await;
+    for n in 0..100 {
+        send(&app, put(&format!("/sessions/session:{}", n), json!(n))).await;
+    }
+    let after = now();
+
+    let snapshot = kvs.store.read();
+    for prefix in ["batch", "session"] {
+        let expiries: Vec<u64> = (0..100).map(|n| snapshot[&format!("{}:{}", prefix, n)].expires_at.unwrap()).collect();
+
+        for expires_at in &expiries {
+            assert!((before + 900..=after + 1100).contains(expires_at), "{} expires at {}", prefix, expires_at);
+        }
+
+        let distinct: std::collections::HashSet<_> = expiries.iter().collect();
+        assert!(distinct.len() > 25, "only {} distinct {} expiries", distinct.len(), prefix);
+    }
+}
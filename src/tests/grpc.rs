@@ -0,0 +1,176 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web;
+use tonic::transport::Channel;
+use tonic::{Code, Request};
+
+use super::config;
+use crate::auth::Auth;
+use crate::grpc::proto::key_value_client::KeyValueClient;
+use crate::grpc::proto::watch_event::Kind;
+use crate::grpc::proto::{DeleteRequest, GetRequest, ListRequest, PutRequest, WatchRequest};
+use crate::grpc::serve;
+use crate::kvstore::KVStore;
+
+const TOKENS: &str = "[auth_tokens]\nr3ad = \"read\"\nwr1te = \"write\"";
+
+// A gRPC server over a new store on a free port, with a client connected to it
+async fn connect(pre_write_webhook: bool, toml: &str) -> KeyValueClient<Channel> {
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let config = config(toml);
+    let kvs = web::Data::new(KVStore::new(&config));
+    let auth = Auth::from_config(&config).unwrap().map(Arc::new);
+
+    tokio::spawn(async move { serve(kvs, &address.to_string(), pre_write_webhook, auth).await.unwrap() });
+
+    for _ in 0..50 {
+        if let Ok(client) = KeyValueClient::connect(format!("http://{}", address)).await {
+            return client;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    panic!("gRPC server did not start on {}", address);
+}
+
+fn put(key: &str, value: &str) -> PutRequest {
+    PutRequest {
+        namespace: "kv".to_string(),
+        key: key.to_string(),
+        value: value.to_string(),
+    }
+}
+
+fn with_token<T>(message: T, token: &str) -> Request<T> {
+    let mut request = Request::new(message);
+    request.metadata_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+    request
+}
+
+fn key(key: &str) -> GetRequest {
+    GetRequest {
+        namespace: "kv".to_string(),
+        key: key.to_string(),
+    }
+}
+
+#[actix_web::test]
+async fn calls_read_and_write_the_store() {
+    let mut client = connect(false, "").await;
+
+    let created = client.put(put("users:1", r#"{"name":"a"}"#)).await.unwrap().into_inner();
+    assert_eq!(created.key, "users:1");
+    client.put(put("users:2", "2")).await.unwrap();
+    client.put(put("other", "3")).await.unwrap();
+
+    let value = client.get(key("users:1")).await.unwrap().into_inner().value;
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&value).unwrap(), serde_json::json!({ "name": "a" }));
+
+    // Put creates, like the HTTP PUT
+    assert_eq!(client.put(put("users:1", "1")).await.unwrap_err().code(), Code::AlreadyExists);
+    assert_eq!(client.put(put("bad", "{")).await.unwrap_err().code(), Code::InvalidArgument);
+
+    let generated = client.put(put("", "4")).await.unwrap().into_inner().key;
+    assert!(!generated.is_empty());
+    assert_eq!(client.get(key(&generated)).await.unwrap().into_inner().value, "4");
+
+    let page = ListRequest {
+        namespace: "kv".to_string(),
+        prefix: "users:".to_string(),
+        limit: 1,
+        cursor: String::new(),
+    };
+    let first = client.list(page.clone()).await.unwrap().into_inner();
+    assert_eq!(first.documents.len(), 1);
+    assert_eq!(first.documents[0].key, "users:1");
+    assert_eq!(first.cursor, "users:1");
+
+    let second = client.list(ListRequest { cursor: first.cursor, ..page }).await.unwrap().into_inner();
+    assert_eq!(second.documents[0].key, "users:2");
+    assert_eq!(second.documents[0].value, "2");
+    assert_eq!(second.cursor, "");
+
+    let delete = DeleteRequest {
+        namespace: "kv".to_string(),
+        key: "users:2".to_string(),
+    };
+    assert_eq!(client.delete(delete.clone()).await.unwrap().into_inner().key, "users:2");
+    assert_eq!(client.get(key("users:2")).await.unwrap_err().code(), Code::NotFound);
+    assert_eq!(client.delete(delete).await.unwrap_err().code(), Code::NotFound);
+}
+
+#[actix_web::test]
+async fn watch_streams_the_changes_under_a_prefix() {
+    let mut client = connect(false, "").await;
+
+    let mut events = client
+        .watch(WatchRequest {
+            prefix: "users:".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    client.put(put("other", "1")).await.unwrap();
+    client.put(put("users:1", "2")).await.unwrap();
+    client
+        .delete(DeleteRequest {
+            namespace: "kv".to_string(),
+            key: "users:1".to_string(),
+        })
+        .await
+        .unwrap();
+
+    let event = events.message().await.unwrap().unwrap();
+    assert_eq!((event.kind(), event.key.as_str(), event.value.as_str()), (Kind::Put, "users:1", "2"));
+
+    let event = events.message().await.unwrap().unwrap();
+    assert_eq!((event.kind(), event.key.as_str(), event.value.as_str()), (Kind::Delete, "users:1", ""));
+}
+
+#[actix_web::test]
+async fn puts_are_refused_with_a_pre_write_webhook() {
+    let mut client = connect(true, "").await;
+
+    assert_eq!(client.put(put("a", "1")).await.unwrap_err().code(), Code::FailedPrecondition);
+    assert_eq!(client.get(key("a")).await.unwrap_err().code(), Code::NotFound);
+}
+
+#[actix_web::test]
+async fn calls_need_a_token_with_the_scope_of_the_call() {
+    let mut client = connect(false, TOKENS).await;
+
+    assert_eq!(client.put(put("a", "1")).await.unwrap_err().code(), Code::Unauthenticated);
+    assert_eq!(client.put(with_token(put("a", "1"), "nope")).await.unwrap_err().code(), Code::Unauthenticated);
+    assert_eq!(client.get(key("a")).await.unwrap_err().code(), Code::Unauthenticated);
+
+    // Read tokens may look but not touch
+    assert_eq!(client.put(with_token(put("a", "1"), "r3ad")).await.unwrap_err().code(), Code::PermissionDenied);
+    let delete = DeleteRequest {
+        namespace: "kv".to_string(),
+        key: "a".to_string(),
+    };
+    let denied = client.delete(with_token(delete.clone(), "r3ad")).await.unwrap_err();
+    assert_eq!(denied.code(), Code::PermissionDenied);
+    assert_eq!(denied.message(), "This call needs a token with the write scope");
+
+    client.put(with_token(put("a", "1"), "wr1te")).await.unwrap();
+    assert_eq!(client.get(with_token(key("a"), "r3ad")).await.unwrap().into_inner().value, "1");
+
+    let list = ListRequest {
+        namespace: "kv".to_string(),
+        prefix: String::new(),
+        limit: 0,
+        cursor: String::new(),
+    };
+    assert_eq!(client.list(list.clone()).await.unwrap_err().code(), Code::Unauthenticated);
+    assert_eq!(client.list(with_token(list, "r3ad")).await.unwrap().into_inner().documents.len(), 1);
+
+    let watch = WatchRequest { prefix: String::new() };
+    assert_eq!(client.watch(watch.clone()).await.unwrap_err().code(), Code::Unauthenticated);
+    client.watch(with_token(watch, "r3ad")).await.unwrap();
+
+    client.delete(with_token(delete, "wr1te")).await.unwrap();
+    assert_eq!(client.get(with_token(key("a"), "r3ad")).await.unwrap_err().code(), Code::NotFound);
+}
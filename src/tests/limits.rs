@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use serde_json::json;
+
+use super::{config, get, patch, post, put, send, start};
+use crate::config::Config;
+
+#[actix_web::test]
+async fn body_over_max_payload_size_is_rejected() {
+    let (app, kvs) = start("max_payload_size = 64").await;
+
+    let reply = send(&app, put("/kv/big", json!({ "data": "x".repeat(100) }))).await;
+    assert_eq!(reply.status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(kvs.document_count(), 0);
+
+    let reply = send(&app, put("/kv/small", json!({ "data": "x" }))).await;
+    assert_eq!(reply.status, StatusCode::CREATED);
+
+    let reply = send(&app, get("/kv/small")).await;
+    assert_eq!(reply.json(), json!({ "data": "x" }));
+}
+
+#[test]
+fn limits_default_to_the_documented_values() {
+    let config = config("");
+
+    assert_eq!(config.max_payload_size, 1024 * 1024);
+    assert_eq!(config.max_connections, 1024);
+    assert_eq!(config.keep_alive, Duration::from_secs(5));
+    assert_eq!(config.client_request_timeout, Duration::from_millis(5000));
+}
+
+#[test]
+fn zero_limits_are_rejected() {
+    assert!(Config::from_toml("persistence = \"off\"\nmax_payload_size = 0").is_err());
+    assert!(Config::from_toml("persistence = \"off\"\nmax_connections = 0").is_err());
+}
+
+#[actix_web::test]
+async fn keys_over_max_key_len_are_rejected_on_every_write() {
+    let (app, kvs) = start("max_key_len = 16").await;
+
+    let at_limit = "k".repeat(16);
+    let over = "k".repeat(17);
+
+    assert_eq!(send(&app, put(&format!("/kv/{}", at_limit), json!(1))).await.status, StatusCode::CREATED);
+    assert_eq!(send(&app, get(&format!("/kv/{}", at_limit))).await.json(), json!(1));
+
+    let writes = [
+        put(&format!("/kv/{}", over), json!(1)),
+        patch(&format!("/kv/{}", over), json!(1)),
+        post(&format!("/kv/{}/incr-field?path=n", over), json!(null)),
+        post(&format!("/kv/{}/max", over), json!(1)),
+        post(&format!("/kv/{}/copy?to={}", at_limit, over), json!(null)),
+        post(&format!("/kv/{}/move?to={}", at_limit, over), json!(null)),
+        post("/admin/rename-prefix?from=k&to=kk", json!(null)),
+    ];
+    for write in writes {
+        let reply = send(&app, write).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        assert!(String::from_utf8_lossy(&reply.body).contains("longer than 16 bytes"));
+    }
+
+    let documents = json!([{ "key": over, "value": 1 }, { "key": "short", "value": 2 }]);
+    let reply = send(&app, post("/batch/put", documents)).await;
+    assert_eq!(reply.json()["results"][0]["status"], json!(400));
+    assert_eq!(reply.json()["results"][1]["status"], json!(200));
+
+    // Only the key at the limit and the short one were written
+    assert_eq!(kvs.document_count(), 2);
+    assert_eq!(send(&app, get(&format!("/kv/{}", at_limit))).await.json(), json!(1));
+}
+
+#[actix_web::test]
+async fn keys_outside_the_allowlist_or_in_the_denylist_are_forbidden() {
+    let (app, kvs) = start("key_allow = \"^(user|session):\"\nkey_deny = \"^user:admin\"").await;
+
+    assert_eq!(send(&app, put("/kv/user:1", json!(1))).await.status, StatusCode::CREATED);
+    assert_eq!(send(&app, put("/kv/session:1", json!(1))).await.status, StatusCode::CREATED);
+
+    // The denylist wins over a key the allowlist matches
+    for key in ["other:1", "user:admin", "user:administrator", "xuser:1"] {
+        let writes = [
+            put(&format!("/kv/{}", key), json!(1)),
+            patch(&format!("/kv/{}", key), json!(1)),
+            post(&format!("/kv/{}/incr-field?path=n", key), json!(null)),
+            post(&format!("/kv/{}/max", key), json!(1)),
+            post(&format!("/kv/user:1/copy?to={}", key), json!(null)),
+        ];
+        for write in writes {
+            let reply = send(&app, write).await;
+            assert_eq!(reply.status, StatusCode::FORBIDDEN, "{}", key);
+            assert!(String::from_utf8_lossy(&reply.body).contains("Key is not allowed"));
+        }
+    }
+
+    let documents = json!([{ "key": "user:admin", "value": 1 }, { "key": "user:2", "value": 2 }]);
+    let reply = send(&app, post("/batch/put", documents)).await;
+    assert_eq!(reply.json()["results"][0]["status"], json!(403));
+    assert_eq!(reply.json()["results"][1]["status"], json!(200));
+
+    let reply = send(&app, post("/admin/rename-prefix?from=user:&to=member:", json!(null))).await;
+    assert_eq!(reply.status, StatusCode::FORBIDDEN);
+
+    assert_eq!(kvs.document_count(), 3);
+    assert_eq!(send(&app, get("/kv/user:1")).await.json(), json!(1));
+}
+
+#[actix_web::test]
+async fn every_key_is_allowed_by_default() {
+    let (app, _) = start("").await;
+
+    for key in ["other:1", "user:admin", "x"] {
+        assert_eq!(send(&app, put(&format!("/kv/{}", key), json!(1))).await.status, StatusCode::CREATED);
+    }
+}
+
+#[test]
+fn invalid_key_patterns_are_rejected() {
+    assert!(Config::from_toml("persistence = \"off\"\nkey_allow = \"(\"").is_err());
+    assert!(Config::from_toml("persistence = \"off\"\nkey_deny = \"[a-\"").is_err());
+}
+
+#[actix_web::test]
+async fn listings_are_capped_at_list_max_limit() {
+    let (app, _) = start("list_default_limit = 3\nlist_max_limit = 5").await;
+
+    let documents: Vec<_> = (0..10).map(|n| json!({ "key": format!("k:{}", n), "value": { "n": n } })).collect();
+    send(&app, post("/batch/put", json!(documents))).await;
+
+    // The listed items are at `pointer` in the reply
+    let count = |reply: serde_json::Value, pointer: &str| reply.pointer(pointer).unwrap().as_array().unwrap().len();
+
+    for (uri, pointer) in [
+        ("/kv/list/", ""),
+        ("/keys", ""),
+        ("/changes?since=0", ""),
+        ("/scan?prefix=k:", "/items"),
+        ("/tree?prefix=k:", "/keys"),
+    ] {
+        assert_eq!(count(send(&app, get(uri)).await.json(), pointer), 3, "{} by default", uri);
+
+        let separator = if uri.contains('?') { '&' } else { '?' };
+        for (limit, expected) in [(4, 4), (5, 5), (1_000_000, 5)] {
+            let reply = send(&app, get(&format!("{}{}limit={}", uri, separator, limit))).await;
+            assert_eq!(reply.status, StatusCode::OK);
+            assert_eq!(count(reply.json(), pointer), expected, "{} with limit {}", uri, limit);
+        }
+    }
+
+    // Streams are capped the same way
+    for (uri, expected) in [
+        ("/kv/list/?stream=true", 3),
+        ("/kv/list/?stream=true&limit=4", 4),
+        ("/kv/list/?stream=true&limit=1000000", 5),
+        ("/kv/list/?stream=true&skip=8&limit=1000000", 2),
+    ] {
+        let reply = send(&app, get(uri)).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(String::from_utf8_lossy(&reply.body).lines().count(), expected, "{}", uri);
+    }
+}
+
+#[test]
+fn list_limits_must_be_consistent() {
+    let config = config("");
+    assert_eq!(config.list_default_limit, 1000);
+    assert_eq!(config.list_max_limit, 10_000);
+
+    assert!(Config::from_toml("persistence = \"off\"\nlist_default_limit = 0").is_err());
+    assert!(Config::from_toml("persistence = \"off\"\nlist_default_limit = 20\nlist_max_limit = 10").is_err());
+}
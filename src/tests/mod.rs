@@ -0,0 +1,156 @@
+use std::sync::Arc;
+
+use actix_http::Request;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::http::StatusCode;
+use actix_web::test::{self, TestRequest};
+use actix_web::web::{self, Bytes};
+use serde_json::Value;
+use tempfile::TempDir;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::access_log::AccessLog;
+use crate::advisory::AdvisoryLocks;
+use crate::auth::Auth;
+use crate::config::Config;
+use crate::cors::Cors;
+use crate::idempotency::IdempotencyCache;
+use crate::kvstore::KVStore;
+use crate::telemetry::LogLevel;
+use crate::{app, AppState};
+
+mod access_log;
+mod admin;
+mod advisory;
+mod auth;
+mod batch;
+mod blobs;
+mod config;
+mod cors;
+mod deletes;
+mod documents;
+mod expiry;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod limits;
+mod quotas;
+mod reads;
+mod rpc;
+mod store;
+mod telemetry;
+mod updates;
+mod webhook;
+
+pub struct Reply {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl Reply {
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body)
+            .unwrap_or_else(|e| panic!("Body is not JSON ({}): {}", e, String::from_utf8_lossy(&self.body)))
+    }
+}
+
+// The settings of `toml`, like `distkv.toml` would hold them, with persistence
+// off unless they say otherwise so tests never touch a data file by accident
+pub fn config(toml: &str) -> Config {
+    let toml = if toml.contains("persistence") {
+        toml.to_string()
+    } else {
+        format!("persistence = \"off\"\n{}", toml)
+    };
+
+    Config::from_toml(&toml).unwrap()
+}
+
+// An array of keys in order, since the `hashmap` feature lists them in any
+pub fn sorted(keys: Value) -> Value {
+    let mut keys: Vec<String> = serde_json::from_value(keys).unwrap();
+    keys.sort();
+    keys.into()
+}
+
+// A `db_path` setting for a data file in `dir`, which also keeps the blobs of
+// the store there
+pub fn data_file(dir: &TempDir) -> String {
+    format!("db_path = {:?}", dir.path().join("database.vbank").to_str().unwrap())
+}
+
+// The app the server runs, with every route and middleware, over a new store
+pub async fn start(
+    toml: &str,
+) -> (
+    impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error>,
+    web::Data<KVStore>,
+) {
+    start_with(config(toml)).await
+}
+
+pub async fn start_with(
+    config: Config,
+) -> (
+    impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error>,
+    web::Data<KVStore>,
+) {
+    start_with_log_level(config, LogLevel::detached(LevelFilter::INFO)).await
+}
+
+pub async fn start_with_log_level(
+    config: Config,
+    log_level: LogLevel,
+) -> (
+    impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error>,
+    web::Data<KVStore>,
+) {
+    let kvs = web::Data::new(KVStore::new(&config));
+
+    let state = AppState {
+        kvs: kvs.clone(),
+        idempotency: web::Data::new(IdempotencyCache::new(config.idempotency_ttl)),
+        advisory_locks: web::Data::new(AdvisoryLocks::new()),
+        log_level: web::Data::new(log_level),
+        access_log: config
+            .access_log
+            .map(|format| Arc::new(AccessLog::open(format, config.access_log_path.as_deref()).unwrap())),
+        cors: Cors::from_config(&config).unwrap().map(Arc::new),
+        auth: Auth::from_config(&config).unwrap().map(Arc::new),
+        config,
+    };
+
+    (test::init_service(app(state)).await, kvs)
+}
+
+pub async fn send<S, B>(app: &S, req: TestRequest) -> Reply
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let response = test::call_service(app, req.to_request()).await;
+
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = test::read_body(response).await;
+
+    Reply { status, headers, body }
+}
+
+pub fn get(uri: &str) -> TestRequest {
+    TestRequest::get().uri(uri)
+}
+
+pub fn put(uri: &str, value: Value) -> TestRequest {
+    TestRequest::put().uri(uri).set_json(value)
+}
+
+pub fn post(uri: &str, value: Value) -> TestRequest {
+    TestRequest::post().uri(uri).set_json(value)
+}
+
+pub fn patch(uri: &str, value: Value) -> TestRequest {
+    TestRequest::patch().uri(uri).set_json(value)
+}
@@ -0,0 +1,214 @@
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{data_file, get, patch, post, put, send, start};
+use crate::config::NamespaceQuotas;
+
+const TENANT_QUOTAS: &str = "
+[namespace_quotas.tenant]
+max_keys = 2
+max_bytes = 1024
+";
+
+#[actix_web::test]
+async fn namespace_key_quota_is_enforced() {
+    let (app, _) = start(TENANT_QUOTAS).await;
+
+    assert_eq!(send(&app, put("/tenant/a", json!(1))).await.status, StatusCode::CREATED);
+    assert_eq!(send(&app, put("/tenant/b", json!(2))).await.status, StatusCode::CREATED);
+
+    let reply = send(&app, put("/tenant/c", json!(3))).await;
+    assert_eq!(reply.status, StatusCode::INSUFFICIENT_STORAGE);
+    assert_eq!(send(&app, get("/tenant/c")).await.status, StatusCode::NOT_FOUND);
+
+    // Other namespaces and keys the namespace already holds are not affected
+    assert_eq!(send(&app, put("/other/d", json!(3))).await.status, StatusCode::CREATED);
+    assert_eq!(send(&app, patch("/tenant/a", json!(10))).await.status, StatusCode::OK);
+
+    // Deleting a key frees its place
+    send(&app, TestRequest::delete().uri("/tenant/a")).await;
+    assert_eq!(send(&app, put("/tenant/c", json!(3))).await.status, StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn namespace_byte_quota_is_enforced() {
+    let (app, _) = start(TENANT_QUOTAS).await;
+
+    let reply = send(&app, put("/tenant/big", json!("x".repeat(2000)))).await;
+    assert_eq!(reply.status, StatusCode::INSUFFICIENT_STORAGE);
+
+    assert_eq!(send(&app, put("/tenant/small", json!("x"))).await.status, StatusCode::CREATED);
+    assert_eq!(send(&app, patch("/tenant/small", json!("x".repeat(2000)))).await.status, StatusCode::INSUFFICIENT_STORAGE);
+    assert_eq!(send(&app, get("/tenant/small")).await.json(), json!("x"));
+
+    assert_eq!(send(&app, put("/other/big", json!("x".repeat(2000)))).await.status, StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn stats_report_usage_per_namespace() {
+    let (app, _) = start(TENANT_QUOTAS).await;
+
+    let stats = send(&app, get("/stats")).await.json();
+    assert_eq!(
+        stats["usage"]["namespaces"],
+        json!({ "tenant": { "keys": 0, "bytes": 0, "max_keys": 2, "max_bytes": 1024 } })
+    );
+
+    send(&app, put("/tenant/a", json!(1))).await;
+    send(&app, put("/other/b", json!(2))).await;
+    send(&app, put("/other/c", json!(3))).await;
+    send(&app, TestRequest::delete().uri("/other/c")).await;
+
+    // Keys plus their base64 values, "MQ==" and "Mg=="
+    let stats = send(&app, get("/stats")).await.json();
+    assert_eq!(stats["usage"]["namespaces"]["tenant"]["keys"], json!(1));
+    assert_eq!(stats["usage"]["namespaces"]["tenant"]["bytes"], json!(5));
+    assert_eq!(
+        stats["usage"]["namespaces"]["other"],
+        json!({ "keys": 1, "bytes": 5, "max_keys": null, "max_bytes": null })
+    );
+}
+
+#[actix_web::test]
+async fn namespace_usage_survives_a_restart() {
+    for format in ["legacy", "cbor"] {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = format!("persistence = \"on\"\ndisk_format = \"{}\"\n{}\n{}", format, data_file(&dir), TENANT_QUOTAS);
+
+        {
+            let (app, _) = start(&toml).await;
+            send(&app, put("/tenant/a", json!(1))).await;
+            send(&app, put("/tenant/b", json!(2))).await;
+        }
+
+        let (app, _) = start(&toml).await;
+
+        let stats = send(&app, get("/stats")).await.json();
+        assert_eq!(stats["usage"]["namespaces"]["tenant"]["keys"], json!(2), "{} format", format);
+        assert_eq!(send(&app, put("/tenant/c", json!(3))).await.status, StatusCode::INSUFFICIENT_STORAGE);
+    }
+}
+
+#[test]
+fn namespace_quotas_parse_from_the_environment_format() {
+    let quotas: NamespaceQuotas = "a=10/2048, b=/64,c=5".parse().unwrap();
+
+    assert_eq!(quotas.get("a").map(|quota| (quota.max_keys, quota.max_bytes)), Some((Some(10), Some(2048))));
+    assert_eq!(quotas.get("b").map(|quota| (quota.max_keys, quota.max_bytes)), Some((None, Some(64))));
+    assert_eq!(quotas.get("c").map(|quota| (quota.max_keys, quota.max_bytes)), Some((Some(5), None)));
+
+    assert!("a=ten/".parse::<NamespaceQuotas>().is_err());
+    assert!("a".parse::<NamespaceQuotas>().is_err());
+}
+
+#[actix_web::test]
+async fn namespaces_over_max_namespaces_are_rejected() {
+    let (app, _) = start("max_namespaces = 2").await;
+
+    assert_eq!(send(&app, put("/tenant-a/x", json!(1))).await.status, StatusCode::CREATED);
+    assert_eq!(send(&app, put("/tenant-b/y", json!(2))).await.status, StatusCode::CREATED);
+
+    // At the limit, only namespaces that already hold keys take writes
+    let reply = send(&app, put("/tenant-c/z", json!(3))).await;
+    assert_eq!(reply.status, StatusCode::INSUFFICIENT_STORAGE);
+    assert!(String::from_utf8_lossy(&reply.body).contains("limit is 2 namespaces"));
+    assert_eq!(send(&app, get("/tenant-c/z")).await.status, StatusCode::NOT_FOUND);
+
+    let reply = send(&app, post("/batch/put?namespace=tenant-c", json!([{ "key": "z", "value": 3 }]))).await;
+    assert_eq!(reply.json()["results"][0]["status"], json!(507));
+
+    assert_eq!(send(&app, put("/tenant-a/w", json!(4))).await.status, StatusCode::CREATED);
+
+    // Moving the last key of a namespace to a new one keeps the count
+    assert_eq!(send(&app, patch("/tenant-c/y", json!(5))).await.status, StatusCode::OK);
+    assert_eq!(send(&app, patch("/tenant-d/x", json!(6))).await.status, StatusCode::INSUFFICIENT_STORAGE);
+
+    let stats = send(&app, get("/stats")).await.json();
+    assert_eq!(stats["usage"]["namespace_count"], json!(2));
+    assert_eq!(stats["usage"]["max_namespaces"], json!(2));
+
+    // A namespace whose last key is deleted frees its place
+    send(&app, TestRequest::delete().uri("/tenant-c/y")).await;
+    assert_eq!(send(&app, put("/tenant-d/z", json!(7))).await.status, StatusCode::CREATED);
+    assert_eq!(send(&app, get("/stats")).await.json()["usage"]["namespace_count"], json!(2));
+}
+
+#[actix_web::test]
+async fn namespaces_are_counted_without_a_limit() {
+    let (app, _) = start("").await;
+
+    for namespace in ["a", "b", "c"] {
+        send(&app, put(&format!("/{}/key-{}", namespace, namespace), json!(1))).await;
+    }
+
+    let stats = send(&app, get("/stats")).await.json();
+    assert_eq!(stats["usage"]["namespace_count"], json!(3));
+    assert_eq!(stats["usage"]["max_namespaces"], json!(null));
+}
+
+#[actix_web::test]
+async fn batch_cas_counts_every_new_key_against_the_quotas() {
+    let (app, kvs) = start("max_keys = 3").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, put("/kv/b", json!(2))).await;
+
+    // One place is left, and the batch creates two keys
+    let reply = send(&app, post("/batch/cas", json!([
+        { "key": "c", "expected": null, "new": 3 },
+        { "key": "d", "expected": null, "new": 4 },
+    ]))).await;
+    assert_eq!(reply.status, StatusCode::INSUFFICIENT_STORAGE);
+    assert_eq!(kvs.document_count(), 2);
+
+    // Replacing keys takes no place, so one new key still fits
+    let reply = send(&app, post("/batch/cas", json!([
+        { "key": "a", "expected": 1, "new": 10 },
+        { "key": "b", "expected": 2, "new": 20 },
+        { "key": "c", "expected": null, "new": 3 },
+    ]))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(kvs.document_count(), 3);
+}
+
+#[actix_web::test]
+async fn batch_cas_adds_up_the_bytes_and_namespace_keys_it_writes() {
+    let (app, kvs) = start(TENANT_QUOTAS).await;
+
+    send(&app, put("/tenant/a", json!(1))).await;
+
+    let reply = send(&app, post("/batch/cas?namespace=tenant", json!([
+        { "key": "b", "expected": null, "new": 2 },
+        { "key": "c", "expected": null, "new": 3 },
+    ]))).await;
+    assert_eq!(reply.status, StatusCode::INSUFFICIENT_STORAGE);
+
+    // Each value fits the 1024 bytes of the namespace, both together do not
+    let reply = send(&app, post("/batch/cas?namespace=tenant", json!([
+        { "key": "a", "expected": 1, "new": "x".repeat(500) },
+        { "key": "b", "expected": null, "new": "x".repeat(500) },
+    ]))).await;
+    assert_eq!(reply.status, StatusCode::INSUFFICIENT_STORAGE);
+
+    assert_eq!(kvs.document_count(), 1);
+    assert_eq!(send(&app, get("/tenant/a")).await.json(), json!(1));
+
+    let reply = send(&app, post("/batch/cas?namespace=tenant", json!([
+        { "key": "a", "expected": 1, "new": "x".repeat(300) },
+        { "key": "b", "expected": null, "new": "x".repeat(300) },
+    ]))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn batch_cas_adds_up_the_bytes_against_max_bytes() {
+    let (app, kvs) = start("max_bytes = 1024").await;
+
+    let reply = send(&app, post("/batch/cas", json!([
+        { "key": "a", "expected": null, "new": "x".repeat(500) },
+        { "key": "b", "expected": null, "new": "x".repeat(500) },
+    ]))).await;
+    assert_eq!(reply.status, StatusCode::INSUFFICIENT_STORAGE);
+    assert_eq!(kvs.document_count(), 0);
+}
@@ -0,0 +1,628 @@
+use std::io::Read;
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use serde_json::{json, Value};
+
+use super::{data_file, get, patch, post, put, send, sorted, start};
+
+#[actix_web::test]
+async fn pretty_responses_are_indented_and_parse_the_same() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!({ "name": "x", "tags": [1, 2] }))).await;
+
+    for uri in ["/kv/a", "/kv/list/"] {
+        let compact = send(&app, get(uri)).await;
+        let pretty = send(&app, get(&format!("{}?pretty=true", uri))).await;
+
+        assert_eq!(pretty.status, StatusCode::OK);
+        assert!(!compact.body.contains(&b'\n'), "{} is not compact by default", uri);
+        assert!(pretty.body.contains(&b'\n'), "{} is not indented", uri);
+        assert_eq!(pretty.json(), compact.json());
+    }
+}
+
+#[actix_web::test]
+async fn keys_are_searched_by_regex() {
+    let (app, _) = start("").await;
+
+    for key in ["user:1:session", "user:22:session", "user:x:session", "user:1:profile"] {
+        send(&app, put(&format!("/kv/{}", key), json!(1))).await;
+    }
+
+    // ^user:\d+:session$
+    let reply = send(&app, get("/keys?regex=%5Euser%3A%5Cd%2B%3Asession%24")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(sorted(reply.json()), json!(["user:1:session", "user:22:session"]));
+
+    let reply = send(&app, get("/keys?regex=%5Eorder%3A")).await;
+    assert_eq!(reply.json(), json!([]));
+
+    let reply = send(&app, get("/keys?regex=session&limit=1")).await;
+    assert_eq!(reply.json().as_array().unwrap().len(), 1);
+}
+
+#[actix_web::test]
+async fn invalid_or_oversized_regexes_are_rejected() {
+    let (app, _) = start("").await;
+
+    assert_eq!(send(&app, get("/keys?regex=user%5B")).await.status, StatusCode::BAD_REQUEST);
+
+    let long = format!("/keys?regex={}", "a".repeat(2000));
+    assert_eq!(send(&app, get(&long)).await.status, StatusCode::BAD_REQUEST);
+
+    // Compiles to more than the size limit
+    let huge = format!("/keys?regex={}", "%5Cw%7B500%7D".repeat(20));
+    assert_eq!(send(&app, get(&huge)).await.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn type_is_reported_for_every_json_type() {
+    let (app, _) = start("").await;
+
+    let values = [
+        ("object", json!({ "a": 1 })),
+        ("array", json!([1, 2])),
+        ("string", json!("text")),
+        ("number", json!(1.5)),
+        ("boolean", json!(false)),
+        ("null", json!(null)),
+    ];
+
+    for (value_type, value) in values {
+        send(&app, put(&format!("/kv/{}", value_type), value)).await;
+
+        let reply = send(&app, get(&format!("/kv/{}/type", value_type))).await;
+        assert_eq!(reply.json(), json!({ "type": value_type }));
+    }
+
+    assert_eq!(send(&app, get("/kv/missing/type")).await.status, StatusCode::NOT_FOUND);
+}
+
+fn recent_keys(recent: Value) -> Vec<String> {
+    recent.as_array().unwrap().iter().map(|entry| entry["key"].as_str().unwrap().to_string()).collect()
+}
+
+#[actix_web::test]
+async fn recent_lists_the_last_modified_keys_first() {
+    let dir = tempfile::tempdir().unwrap();
+    let toml = format!("persistence = \"on\"\n{}", data_file(&dir));
+
+    let recent = {
+        let (app, _) = start(&toml).await;
+
+        // Modification times are in milliseconds, so writes are kept apart
+        for (key, value) in [("a", 1), ("b", 2), ("c", 3)] {
+            send(&app, put(&format!("/kv/{}", key), json!(value))).await;
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        send(&app, patch("/kv/a", json!(4))).await;
+        // PATCH is only written to disk by the next flush
+        send(&app, put("/kv/d", json!(5))).await;
+
+        let recent = send(&app, get("/recent")).await.json();
+        assert_eq!(recent_keys(recent.clone()), ["d", "a", "c", "b"]);
+        assert_eq!(recent_keys(send(&app, get("/recent?limit=2")).await.json()), ["d", "a"]);
+
+        recent
+    };
+
+    // The times are kept in the data file
+    let (app, _) = start(&toml).await;
+    assert_eq!(send(&app, get("/recent")).await.json(), recent);
+}
+
+#[actix_web::test]
+async fn count_reports_the_keys_under_a_prefix() {
+    let (app, _) = start("").await;
+
+    for key in ["user:1", "user:2", "users", "order:1"] {
+        send(&app, put(&format!("/kv/{}", key), json!(1))).await;
+    }
+
+    assert_eq!(send(&app, get("/count?prefix=user:")).await.json(), json!({ "prefix": "user:", "count": 2 }));
+    assert_eq!(send(&app, get("/count?prefix=user")).await.json()["count"], json!(3));
+    assert_eq!(send(&app, get("/count?prefix=none")).await.json()["count"], json!(0));
+    assert_eq!(send(&app, get("/count")).await.json(), json!({ "prefix": "", "count": 4 }));
+
+    send(&app, TestRequest::delete().uri("/kv/user:1")).await;
+    assert_eq!(send(&app, get("/count?prefix=user:")).await.json()["count"], json!(1));
+    assert_eq!(send(&app, get("/count")).await.json()["count"], json!(3));
+}
+
+fn unix_seconds() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[actix_web::test]
+async fn changes_lists_the_keys_modified_since_a_time() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, put("/kv/b", json!(2))).await;
+
+    // `since` is in seconds, so the later writes wait for the next one
+    let before = unix_seconds();
+    while unix_seconds() == before {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let since = before + 1;
+
+    send(&app, put("/kv/c", json!(3))).await;
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    send(&app, patch("/kv/a", json!(4))).await;
+
+    let changes = send(&app, get(&format!("/changes?since={}", since))).await.json();
+    assert_eq!(recent_keys(changes.clone()), ["c", "a"]);
+    assert!(changes[0].get("value").is_none());
+
+    let changes = send(&app, get(&format!("/changes?since={}&values=true", since))).await.json();
+    assert_eq!(changes[1]["value"], json!(4));
+    assert!(changes[0]["updated_at"].as_u64().unwrap() >= since * 1000);
+
+    assert_eq!(recent_keys(send(&app, get("/changes?since=0")).await.json()), ["b", "c", "a"]);
+    assert_eq!(recent_keys(send(&app, get("/changes?since=0&limit=1")).await.json()), ["b"]);
+    assert_eq!(send(&app, get(&format!("/changes?since={}", since + 3600))).await.json(), json!([]));
+
+    send(&app, TestRequest::delete().uri("/kv/c")).await;
+    assert_eq!(recent_keys(send(&app, get(&format!("/changes?since={}", since))).await.json()), ["a"]);
+}
+
+#[actix_web::test]
+async fn exists_answers_in_the_body_for_present_and_absent_keys() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!(null))).await;
+
+    let reply = send(&app, get("/kv/a/exists")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "exists": true }));
+
+    let reply = send(&app, get("/kv/missing/exists")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "exists": false }));
+
+    send(&app, TestRequest::delete().uri("/kv/a")).await;
+    assert_eq!(send(&app, get("/kv/a/exists")).await.json(), json!({ "exists": false }));
+}
+
+fn scanned_keys(scan: &Value) -> Vec<String> {
+    recent_keys(scan["items"].clone())
+}
+
+#[actix_web::test]
+async fn scan_filters_the_documents_under_a_prefix() {
+    let (app, _) = start("").await;
+
+    let documents = [
+        ("orders:1", json!({ "status": "shipped", "paid": true })),
+        ("orders:2", json!({ "status": "pending", "paid": false })),
+        ("orders:3", json!({ "status": "shipped", "paid": false })),
+        ("orders:4", json!({ "status": "shipped", "paid": true, "ship": { "carrier": "ups" } })),
+        ("orders:5", json!({ "paid": true })),
+        ("other:1", json!({ "status": "shipped" })),
+    ];
+    for (key, value) in &documents {
+        send(&app, put(&format!("/kv/{}", key), value.clone())).await;
+    }
+
+    let scan = send(&app, get("/scan?prefix=orders:&filter_field=status&filter_eq=shipped")).await.json();
+    assert_eq!(scanned_keys(&scan), ["orders:1", "orders:3", "orders:4"]);
+    assert_eq!(scan["items"][0]["data"], documents[0].1);
+    assert_eq!(scan["cursor"], json!(null));
+
+    // Pages pick up after the cursor until no more documents match
+    let scan = send(&app, get("/scan?prefix=orders:&filter_field=status&filter_eq=shipped&limit=2")).await.json();
+    assert_eq!(scanned_keys(&scan), ["orders:1", "orders:3"]);
+    assert_eq!(scan["cursor"], json!("orders:3"));
+
+    let next = "/scan?prefix=orders:&filter_field=status&filter_eq=shipped&limit=2&cursor=orders:3";
+    let scan = send(&app, get(next)).await.json();
+    assert_eq!(scanned_keys(&scan), ["orders:4"]);
+    assert_eq!(scan["cursor"], json!(null));
+
+    // Non-string fields compare by their JSON, and paths can be nested
+    let scan = send(&app, get("/scan?prefix=orders:&filter_field=paid&filter_eq=true")).await.json();
+    assert_eq!(scanned_keys(&scan), ["orders:1", "orders:4", "orders:5"]);
+    let scan = send(&app, get("/scan?prefix=orders:&filter_field=ship.carrier&filter_eq=ups")).await.json();
+    assert_eq!(scanned_keys(&scan), ["orders:4"]);
+
+    // Without a filter every document under the prefix is returned
+    assert_eq!(scanned_keys(&send(&app, get("/scan?prefix=orders:")).await.json()).len(), 5);
+
+    let reply = send(&app, get("/scan?prefix=orders:&filter_field=status")).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn comma_separated_gets_return_the_present_keys() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, put("/kv/c", json!({ "n": 3 }))).await;
+
+    let reply = send(&app, get("/kv/a,b,c")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "a": 1, "c": { "n": 3 } }));
+
+    assert_eq!(send(&app, get("/kv/x,y")).await.json(), json!({}));
+
+    // A key holding a comma is still read as that key
+    send(&app, put("/kv/a,c", json!("comma"))).await;
+    assert_eq!(send(&app, get("/kv/a,c")).await.json(), json!("comma"));
+
+    let keys: Vec<String> = (0..101).map(|n| n.to_string()).collect();
+    let reply = send(&app, get(&format!("/kv/{}", keys.join(",")))).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+
+    let reply = send(&app, get(&format!("/kv/{}", keys[..100].join(",")))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+}
+
+fn ndjson_keys(body: &[u8]) -> Vec<String> {
+    body.split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice::<Value>(line).unwrap()["key"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[actix_web::test]
+async fn streamed_listings_yield_every_document() {
+    let (app, _) = start("").await;
+
+    let reply = send(&app, get("/kv/list/?stream=true")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert!(reply.body.is_empty());
+
+    // More documents than fit in one chunk of the stream
+    let mut expected: Vec<String> = (0..550).map(|n| format!("doc:{:03}", n)).collect();
+    for (n, key) in expected.iter().enumerate() {
+        send(&app, put(&format!("/kv/{}", key), json!({ "n": n }))).await;
+    }
+    expected.sort();
+
+    let reply = send(&app, get("/kv/list/?stream=true")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.headers.get("content-type").unwrap(), "application/x-ndjson");
+
+    let mut keys = ndjson_keys(&reply.body);
+    keys.sort();
+    assert_eq!(keys, expected);
+
+    let first: Value = serde_json::from_slice(reply.body.split(|&byte| byte == b'\n').next().unwrap()).unwrap();
+    assert_eq!(first["data"]["n"], json!(first["key"].as_str().unwrap()[4..].parse::<u64>().unwrap()));
+
+    let reply = send(&app, get("/kv/list/?stream=true&skip=500&limit=30")).await;
+    assert_eq!(ndjson_keys(&reply.body).len(), 30);
+    let reply = send(&app, get("/kv/list/?stream=true&skip=500")).await;
+    assert_eq!(ndjson_keys(&reply.body).len(), 50);
+}
+
+#[actix_web::test]
+async fn tree_lists_one_level_of_nested_prefixes() {
+    let (app, _) = start("").await;
+
+    for key in ["users:1:profile", "users:1:settings", "users:2:profile", "users:admin", "orders:1", "root"] {
+        send(&app, put(&format!("/kv/{}", key), json!(1))).await;
+    }
+
+    let tree = send(&app, get("/tree")).await.json();
+    assert_eq!(tree["folders"], json!([{ "prefix": "orders:", "count": 1 }, { "prefix": "users:", "count": 4 }]));
+    assert_eq!(tree["keys"], json!(["root"]));
+    assert_eq!(tree["truncated"], json!(false));
+
+    let tree = send(&app, get("/tree?prefix=users:")).await.json();
+    assert_eq!(tree["folders"], json!([{ "prefix": "users:1:", "count": 2 }, { "prefix": "users:2:", "count": 1 }]));
+    assert_eq!(tree["keys"], json!(["users:admin"]));
+
+    let tree = send(&app, get("/tree?prefix=users:1:")).await.json();
+    assert_eq!(tree["folders"], json!([]));
+    assert_eq!(tree["keys"], json!(["users:1:profile", "users:1:settings"]));
+
+    // Other delimiters split the keys elsewhere
+    let tree = send(&app, get("/tree?prefix=users:1&delimiter=:s")).await.json();
+    assert_eq!(tree["folders"], json!([{ "prefix": "users:1:s", "count": 1 }]));
+    assert_eq!(tree["keys"], json!(["users:1:profile"]));
+
+    let tree = send(&app, get("/tree?prefix=users:&limit=1")).await.json();
+    assert_eq!(tree["folders"].as_array().unwrap().len(), 1);
+    assert_eq!(tree["truncated"], json!(true));
+
+    assert_eq!(send(&app, get("/tree?delimiter=")).await.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn list_etags_answer_304_until_the_store_changes() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+
+    for uri in ["/kv/list/", "/keys"] {
+        let reply = send(&app, get(uri)).await;
+        let etag = reply.headers.get("etag").unwrap().to_str().unwrap().to_string();
+
+        // Reads change nothing
+        send(&app, get("/kv/a")).await;
+
+        let reply = send(&app, get(uri).insert_header(("If-None-Match", etag.as_str()))).await;
+        assert_eq!(reply.status, StatusCode::NOT_MODIFIED, "{}", uri);
+        assert!(reply.body.is_empty());
+        assert_eq!(reply.headers.get("etag").unwrap(), etag.as_str());
+
+        let tags = format!("\"other\", W/{}", etag);
+        let reply = send(&app, get(uri).insert_header(("If-None-Match", tags.as_str()))).await;
+        assert_eq!(reply.status, StatusCode::NOT_MODIFIED, "{}", uri);
+
+        send(&app, patch("/kv/a", json!(2))).await;
+
+        let reply = send(&app, get(uri).insert_header(("If-None-Match", etag.as_str()))).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", uri);
+        assert_ne!(reply.headers.get("etag").unwrap(), etag.as_str());
+    }
+}
+
+#[actix_web::test]
+async fn list_etags_do_not_match_after_a_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let toml = format!("persistence = \"on\"\n{}", data_file(&dir));
+
+    let etag = {
+        let (app, _) = start(&toml).await;
+        send(&app, put("/kv/a", json!(1))).await;
+        send(&app, get("/keys")).await.headers.get("etag").unwrap().to_str().unwrap().to_string()
+    };
+
+    let (app, _) = start(&toml).await;
+    let reply = send(&app, get("/keys").insert_header(("If-None-Match", etag.as_str()))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn batch_get_lines_values_up_with_the_keys() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, put("/kv/c", json!({ "n": 3 }))).await;
+    send(&app, put("/kv/null", json!(null))).await;
+
+    let keys = json!({ "keys": ["missing", "a", "b", "c", "a", "null", "z"] });
+    let reply = send(&app, post("/batch/get", keys)).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!([null, 1, null, { "n": 3 }, 1, null, null]));
+
+    assert_eq!(send(&app, post("/batch/get", json!({ "keys": [] }))).await.json(), json!([]));
+
+    let keys: Vec<String> = (0..101).map(|n| n.to_string()).collect();
+    assert_eq!(send(&app, post("/batch/get", json!({ "keys": keys }))).await.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn snapshot_read_returns_the_values_by_key() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, put("/kv/b", json!({ "n": 2 }))).await;
+
+    let reply = send(&app, post("/snapshot-read", json!({ "keys": ["a", "b", "missing"] }))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "a": 1, "b": { "n": 2 }, "missing": null }));
+
+    let keys: Vec<String> = (0..101).map(|n| n.to_string()).collect();
+    assert_eq!(send(&app, post("/snapshot-read", json!({ "keys": keys }))).await.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn large_responses_are_compressed_when_the_client_accepts_it() {
+    let (app, _) = start("response_compression = true\nresponse_compression_min_bytes = 256").await;
+
+    let value = json!({ "text": "compressible ".repeat(500) });
+    send(&app, put("/kv/big", value.clone())).await;
+    send(&app, put("/kv/small", json!(1))).await;
+
+    let reply = send(&app, get("/kv/big").insert_header(("Accept-Encoding", "gzip"))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.headers.get("Content-Encoding").unwrap(), "gzip");
+
+    let mut body = Vec::new();
+    flate2::read::GzDecoder::new(&reply.body[..]).read_to_end(&mut body).unwrap();
+    assert!(reply.body.len() < body.len() / 10, "{} compressed bytes for {}", reply.body.len(), body.len());
+    assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), value);
+
+    // Bodies under the threshold and clients that did not ask get plain JSON
+    let reply = send(&app, get("/kv/small").insert_header(("Accept-Encoding", "gzip"))).await;
+    assert_eq!(reply.headers.get("Content-Encoding").unwrap(), "identity");
+    assert_eq!(reply.json(), json!(1));
+
+    let reply = send(&app, get("/kv/big")).await;
+    assert!(reply.headers.get("Content-Encoding").is_none_or(|encoding| encoding == "identity"));
+    assert_eq!(reply.json(), value);
+}
+
+#[actix_web::test]
+async fn responses_are_not_compressed_by_default() {
+    let (app, _) = start("").await;
+
+    let value = json!({ "text": "compressible ".repeat(500) });
+    send(&app, put("/kv/big", value.clone())).await;
+
+    let reply = send(&app, get("/kv/big").insert_header(("Accept-Encoding", "gzip, br"))).await;
+    assert!(reply.headers.get("Content-Encoding").is_none());
+    assert_eq!(reply.json(), value);
+}
+
+#[actix_web::test]
+async fn subscribe_waits_until_a_write_changes_the_version() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/job", json!({ "state": "queued" }))).await;
+
+    let waiter = send(&app, get("/kv/job/subscribe?version=1&timeout_secs=10"));
+    let writer = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        send(&app, put("/kv/other", json!(1))).await;
+        send(&app, patch("/kv/job", json!({ "state": "done" }))).await
+    };
+
+    let started = std::time::Instant::now();
+    let (reply, _) = tokio::join!(waiter, writer);
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "key": "job", "data": { "state": "done" }, "version": 2 }));
+
+    // A version that is already stale returns straight away
+    let reply = send(&app, get("/kv/job/subscribe?version=1")).await;
+    assert_eq!(reply.json()["version"], json!(2));
+}
+
+#[actix_web::test]
+async fn subscribe_without_a_change_is_not_modified() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/job", json!(1))).await;
+
+    let reply = send(&app, get("/kv/job/subscribe?version=1&timeout_secs=1")).await;
+    assert_eq!(reply.status, StatusCode::NOT_MODIFIED);
+    assert!(reply.body.is_empty());
+
+    // Version 0 waits for a missing key to be created
+    let waiter = send(&app, get("/kv/created/subscribe?version=0&timeout_secs=10"));
+    let writer = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        send(&app, put("/kv/created", json!("new"))).await
+    };
+    let (reply, _) = tokio::join!(waiter, writer);
+    assert_eq!(reply.json(), json!({ "key": "created", "data": "new", "version": 1 }));
+
+    // A deleted key is not found
+    let waiter = send(&app, get("/kv/job/subscribe?version=1&timeout_secs=10"));
+    let deleter = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        send(&app, TestRequest::delete().uri("/kv/job")).await
+    };
+    let (reply, _) = tokio::join!(waiter, deleter);
+    assert_eq!(reply.status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn batch_exists_maps_present_and_absent_keys() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, put("/kv/null", json!(null))).await;
+    send(&app, put("/kv/gone", json!(2))).await;
+    send(&app, TestRequest::delete().uri("/kv/gone")).await;
+
+    let keys = json!({ "keys": ["a", "null", "gone", "missing"] });
+    let reply = send(&app, post("/batch/exists", keys)).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "a": true, "null": true, "gone": false, "missing": false }));
+
+    // More keys than a batch get allows
+    let keys: Vec<String> = (0..1000).map(|n| n.to_string()).collect();
+    let reply = send(&app, post("/batch/exists", json!({ "keys": keys }))).await;
+    assert_eq!(reply.json().as_object().unwrap().len(), 1000);
+    assert!(reply.json().as_object().unwrap().values().all(|exists| *exists == json!(false)));
+}
+
+#[actix_web::test]
+async fn field_index_follows_inserts_updates_and_deletes() {
+    let dir = tempfile::tempdir().unwrap();
+    let toml = format!("persistence = \"on\"\nindexed_fields = [\"email\", \"address.city\"]\n{}", data_file(&dir));
+    let (app, _) = start(&toml).await;
+
+    send(&app, put("/kv/user:1", json!({ "email": "a@x.io", "address": { "city": "Oslo" } }))).await;
+    send(&app, put("/kv/user:2", json!({ "email": "b@x.io", "address": { "city": "Oslo" } }))).await;
+    send(&app, put("/kv/user:3", json!({ "email": ["not", "indexed"] }))).await;
+
+    assert_eq!(send(&app, get("/by/email/a@x.io")).await.json(), json!(["user:1"]));
+    assert_eq!(send(&app, get("/by/address.city/Oslo")).await.json(), json!(["user:1", "user:2"]));
+    assert_eq!(send(&app, get("/by/email/missing@x.io")).await.json(), json!([]));
+
+    // An update moves the key to its new value
+    send(&app, patch("/kv/user:1", json!({ "email": "new@x.io", "address": { "city": "Bergen" } }))).await;
+    assert_eq!(send(&app, get("/by/email/a@x.io")).await.json(), json!([]));
+    assert_eq!(send(&app, get("/by/email/new@x.io")).await.json(), json!(["user:1"]));
+    assert_eq!(send(&app, get("/by/address.city/Oslo")).await.json(), json!(["user:2"]));
+
+    send(&app, TestRequest::post().uri("/kv/user:2/move?to=user:20")).await;
+    assert_eq!(send(&app, get("/by/email/b@x.io")).await.json(), json!(["user:20"]));
+
+    send(&app, TestRequest::delete().uri("/kv/user:20")).await;
+    assert_eq!(send(&app, get("/by/email/b@x.io")).await.json(), json!([]));
+    assert_eq!(send(&app, get("/by/address.city/Oslo")).await.json(), json!([]));
+
+    let reply = send(&app, get("/by/name/a")).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+
+    // The index is rebuilt from the data file
+    let (app, _) = start(&toml).await;
+    assert_eq!(send(&app, get("/by/email/new@x.io")).await.json(), json!(["user:1"]));
+    assert_eq!(send(&app, get("/by/address.city/Bergen")).await.json(), json!(["user:1"]));
+}
+
+#[actix_web::test]
+async fn keys_are_filtered_by_value_type_and_prefix() {
+    let (app, _) = start("").await;
+
+    let values = [
+        ("a:object", json!({ "n": 1 })),
+        ("a:array", json!([1, 2])),
+        ("a:string", json!("text")),
+        ("a:number", json!(1.5)),
+        ("a:boolean", json!(false)),
+        ("a:null", json!(null)),
+        ("b:null", json!(null)),
+        ("b:array", json!([])),
+    ];
+    for (key, value) in values {
+        send(&app, put(&format!("/kv/{}", key), value)).await;
+    }
+
+    for (value_type, keys) in [
+        ("object", json!(["a:object"])),
+        ("array", json!(["a:array", "b:array"])),
+        ("string", json!(["a:string"])),
+        ("number", json!(["a:number"])),
+        ("boolean", json!(["a:boolean"])),
+        ("null", json!(["a:null", "b:null"])),
+    ] {
+        let reply = send(&app, get(&format!("/keys?value_type={}", value_type))).await;
+        assert_eq!(sorted(reply.json()), keys, "{}", value_type);
+    }
+
+    assert_eq!(send(&app, get("/keys?value_type=null&prefix=b:")).await.json(), json!(["b:null"]));
+    assert_eq!(sorted(send(&app, get("/keys?prefix=b:")).await.json()), json!(["b:array", "b:null"]));
+    assert_eq!(send(&app, get("/keys?value_type=array&prefix=a:&limit=1")).await.json(), json!(["a:array"]));
+    assert_eq!(send(&app, get("/keys?value_type=null&regex=%5Ea")).await.json(), json!(["a:null"]));
+
+    assert_eq!(send(&app, get("/keys?value_type=date")).await.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn estimated_counts_are_within_their_error_bound() {
+    let (app, _) = start("max_payload_size = 16777216").await;
+
+    let mut documents: Vec<_> = (0..50_000).map(|n| json!({ "key": format!("big:{}", n), "value": n })).collect();
+    documents.extend((0..500).map(|n| json!({ "key": format!("small:{}", n), "value": n })));
+    assert_eq!(send(&app, post("/batch/put", json!(documents))).await.status, StatusCode::OK);
+
+    let estimate = send(&app, get("/count?prefix=big:&estimate=true")).await.json();
+    assert_eq!(estimate["estimated"], json!(true));
+
+    let count = estimate["count"].as_u64().unwrap();
+    let error_bound = estimate["error_bound"].as_u64().unwrap();
+    assert!(error_bound > 0 && error_bound < 5_000, "{}", estimate);
+    assert!(count.abs_diff(50_000) <= error_bound, "{}", estimate);
+
+    // Small counts are exact, and without estimate the count is exact regardless
+    let reply = send(&app, get("/count?prefix=small:&estimate=true")).await.json();
+    if cfg!(feature = "hashmap") {
+        assert_eq!(reply["estimated"], json!(true));
+    } else {
+        assert_eq!(reply, json!({ "prefix": "small:", "count": 500, "estimated": false, "error_bound": 0 }));
+    }
+    assert_eq!(send(&app, get("/count?prefix=big:")).await.json(), json!({ "prefix": "big:", "count": 50_000 }));
+}
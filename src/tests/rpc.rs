@@ -0,0 +1,92 @@
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use serde_json::{json, Value};
+
+use super::{post, send, start};
+
+fn call(method: &str, params: Value, id: u64) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": id })
+}
+
+#[actix_web::test]
+async fn single_calls_map_to_the_store() {
+    let (app, kvs) = start("").await;
+
+    let reply = send(&app, post("/rpc", call("put", json!({ "key": "a", "value": { "n": 1 } }), 1))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "jsonrpc": "2.0", "result": "Document created: a", "id": 1 }));
+
+    let reply = send(&app, post("/rpc", call("get", json!({ "key": "a" }), 2))).await;
+    assert_eq!(reply.json(), json!({ "jsonrpc": "2.0", "result": { "n": 1 }, "id": 2 }));
+
+    // Without a key the store generates one
+    let reply = send(&app, post("/rpc", call("put", json!({ "value": 2 }), 3))).await;
+    let key = reply.json()["result"]["key"].as_str().unwrap().to_string();
+    assert_eq!(send(&app, post("/rpc", call("get", json!({ "key": key }), 4))).await.json()["result"], json!(2));
+
+    let reply = send(&app, post("/rpc", call("delete", json!({ "key": "a" }), 5))).await;
+    assert!(reply.json()["result"].is_string());
+    assert_eq!(kvs.document_count(), 1);
+}
+
+#[actix_web::test]
+async fn batches_answer_every_call_but_notifications() {
+    let (app, _) = start("").await;
+
+    let batch = json!([
+        call("put", json!({ "key": "a", "value": 1 }), 1),
+        call("put", json!({ "key": "b", "value": 2 }), 2),
+        { "jsonrpc": "2.0", "method": "put", "params": { "key": "c", "value": 3 } },
+        call("batch_get", json!({ "keys": ["a", "c", "missing"] }), 3),
+        call("get", json!({ "key": "missing" }), 4),
+    ]);
+
+    let reply = send(&app, post("/rpc", batch)).await;
+    assert_eq!(reply.status, StatusCode::OK);
+
+    let responses = reply.json();
+    let responses = responses.as_array().unwrap();
+    assert_eq!(responses.len(), 4);
+    assert_eq!(responses[2], json!({ "jsonrpc": "2.0", "result": { "a": 1, "c": 3 }, "id": 3 }));
+    assert_eq!(responses[3]["error"]["code"], json!(-32001));
+    assert_eq!(responses[3]["id"], json!(4));
+
+    let list = send(&app, post("/rpc", call("list", Value::Null, 5))).await.json();
+    assert_eq!(list["result"].as_array().unwrap().len(), 3);
+
+    // Only notifications leave nothing to answer
+    let notification = json!({ "jsonrpc": "2.0", "method": "delete", "params": { "key": "a" } });
+    let reply = send(&app, post("/rpc", json!([notification]))).await;
+    assert_eq!(reply.status, StatusCode::NO_CONTENT);
+    let reply = send(&app, post("/rpc", call("get", json!({ "key": "a" }), 6))).await;
+    assert_eq!(reply.json()["error"]["code"], json!(-32001));
+}
+
+#[actix_web::test]
+async fn malformed_calls_get_error_objects() {
+    let (app, _) = start("").await;
+
+    let error = |reply: super::Reply| {
+        let response = reply.json();
+        (response["error"]["code"].as_i64().unwrap(), response["id"].clone())
+    };
+
+    let reply = send(&app, TestRequest::post().uri("/rpc").set_payload("{not json")).await;
+    assert_eq!(error(reply), (-32700, Value::Null));
+
+    let reply = send(&app, post("/rpc", json!([]))).await;
+    assert_eq!(error(reply), (-32600, Value::Null));
+
+    let reply = send(&app, post("/rpc", json!({ "method": "get", "id": 1 }))).await;
+    assert_eq!(error(reply), (-32600, json!(1)));
+
+    let reply = send(&app, post("/rpc", call("frobnicate", Value::Null, 2))).await;
+    assert_eq!(error(reply), (-32601, json!(2)));
+
+    let reply = send(&app, post("/rpc", call("get", json!({ "name": "a" }), 3))).await;
+    assert_eq!(error(reply), (-32602, json!(3)));
+
+    send(&app, post("/rpc", call("put", json!({ "key": "a", "value": 1 }), 4))).await;
+    let reply = send(&app, post("/rpc", call("put", json!({ "key": "a", "value": 2 }), 5))).await;
+    assert_eq!(error(reply), (-32002, json!(5)));
+}
@@ -0,0 +1,620 @@
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{config, data_file, get, patch, post, put, send, start};
+use crate::config::Config;
+use crate::kvstore::KVStore;
+use crate::webhook::PreWriteHook;
+
+#[actix_web::test]
+async fn snapshots_do_not_see_later_writes() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+
+    let snapshot = kvs.store.read();
+
+    send(&app, put("/kv/b", json!(2))).await;
+    send(&app, patch("/kv/a", json!(10))).await;
+
+    assert!(!snapshot.contains_key("b"));
+    assert_eq!(snapshot.get("a").map(|entry| entry.version), Some(1));
+
+    assert!(kvs.store.read().contains_key("b"));
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!(10));
+}
+
+#[test]
+fn concurrent_writers_do_not_lose_updates() {
+    const THREADS: usize = 8;
+    const WRITES: usize = 50;
+
+    let config = config("");
+    let kvs = KVStore::new(&config);
+
+    std::thread::scope(|scope| {
+        for thread in 0..THREADS {
+            let kvs = &kvs;
+            let config = &config;
+
+            scope.spawn(move || {
+                let hook = PreWriteHook::new(config);
+                let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+                runtime.block_on(async {
+                    for write in 0..WRITES {
+                        let key = format!("thread{}:{}", thread, write);
+                        kvs.create_document_with_key("kv".to_string(), key, json!(write), None).await.unwrap();
+                        kvs.increment_field("kv".to_string(), "counter".to_string(), "n".to_string(), 1.0, &hook)
+                            .await
+                            .unwrap();
+                    }
+                });
+            });
+        }
+    });
+
+    let snapshot = kvs.store.read();
+
+    // Every document plus the counter
+    assert_eq!(snapshot.len(), THREADS * WRITES + 1);
+    assert_eq!(snapshot.get("counter").map(|entry| entry.version), Some((THREADS * WRITES) as u64));
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let hook = PreWriteHook::new(&config);
+    let counter = runtime.block_on(kvs.increment_field("kv".to_string(), "counter".to_string(), "n".to_string(), 0.0, &hook));
+    assert_eq!(counter.unwrap(), json!({ "value": THREADS * WRITES }));
+}
+
+#[actix_web::test]
+async fn data_file_round_trips_in_both_formats() {
+    for format in ["legacy", "cbor"] {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = format!("persistence = \"on\"\ndisk_format = \"{}\"\ncompress_threshold = 64\n{}", format, data_file(&dir));
+
+        let values = [
+            ("a", json!({ "nested": { "list": [1, 2, 3] } })),
+            ("b", json!("text with | in it")),
+            ("large", json!("x".repeat(1000))),
+        ];
+
+        {
+            let (app, _) = start(&toml).await;
+            for (key, value) in &values {
+                send(&app, put(&format!("/kv/{}?ttl_seconds=600", key), value.clone())).await;
+            }
+            send(&app, put("/kv/gone", json!(1))).await;
+            send(&app, TestRequest::delete().uri("/kv/gone")).await;
+        }
+
+        let (app, kvs) = start(&toml).await;
+
+        assert_eq!(kvs.document_count(), values.len(), "{} format", format);
+        for (key, value) in values {
+            assert_eq!(send(&app, get(&format!("/kv/{}", key))).await.json(), value, "{} in {} format", key, format);
+            assert!(kvs.store.read().get(key).and_then(|entry| entry.expires_at).is_some());
+        }
+    }
+}
+
+#[actix_web::test]
+async fn cbor_keeps_large_integers_and_floats() {
+    let dir = tempfile::tempdir().unwrap();
+    let toml = format!("persistence = \"on\"\ndisk_format = \"cbor\"\n{}", data_file(&dir));
+
+    let value = json!({
+        "max": i64::MAX,
+        "min": i64::MIN,
+        "unsigned": u64::MAX,
+        "float": 0.1,
+        "whole_float": 2.0,
+        "tiny": 5e-324,
+    });
+
+    {
+        let (app, _) = start(&toml).await;
+        send(&app, put("/kv/numbers", value.clone())).await;
+    }
+
+    let (app, _) = start(&toml).await;
+    assert_eq!(send(&app, get("/kv/numbers")).await.json(), value);
+}
+
+#[actix_web::test]
+async fn legacy_data_files_are_read_and_migrated_to_cbor() {
+    let dir = tempfile::tempdir().unwrap();
+    let legacy = format!("persistence = \"on\"\n{}", data_file(&dir));
+    let cbor = format!("persistence = \"on\"\ndisk_format = \"cbor\"\n{}", data_file(&dir));
+
+    {
+        let (app, _) = start(&legacy).await;
+        send(&app, put("/kv/a", json!({ "n": 1 }))).await;
+    }
+
+    // The first write after the switch rewrites the whole file as CBOR
+    {
+        let (app, _) = start(&cbor).await;
+        assert_eq!(send(&app, get("/kv/a")).await.json(), json!({ "n": 1 }));
+        send(&app, put("/kv/b", json!(2))).await;
+    }
+
+    let contents = std::fs::read(dir.path().join("database.vbank")).unwrap();
+    assert!(!String::from_utf8_lossy(&contents).contains("a|"));
+
+    let (app, _) = start(&cbor).await;
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!({ "n": 1 }));
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!(2));
+}
+
+#[actix_web::test]
+async fn persistence_off_never_creates_a_data_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let (app, kvs) = start(&format!("persistence = \"off\"\n{}", data_file(&dir))).await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+    send(&app, patch("/kv/a", json!(2))).await;
+    send(&app, TestRequest::delete().uri("/kv/a")).await;
+    send(&app, put("/kv/b", json!(3))).await;
+
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!(3));
+    assert_eq!(kvs.document_count(), 1);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[actix_web::test]
+async fn values_over_the_compress_threshold_are_compressed_on_disk() {
+    for format in ["legacy", "cbor"] {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = format!("persistence = \"on\"\ndisk_format = \"{}\"\ncompress_threshold = 256\n{}", format, data_file(&dir));
+
+        let small = json!({ "name": "x" });
+        let large = json!({ "text": "abc".repeat(10_000) });
+
+        {
+            let (app, _) = start(&toml).await;
+            send(&app, put("/kv/small", small.clone())).await;
+            send(&app, put("/kv/large", large.clone())).await;
+
+            let storage = send(&app, get("/kv/small/storage")).await.json();
+            assert_eq!(storage["compressed"], json!(false), "{} format", format);
+
+            let storage = send(&app, get("/kv/large/storage")).await.json();
+            assert_eq!(storage["compressed"], json!(true), "{} format", format);
+            assert!(storage["record_bytes"].as_u64().unwrap() < 1000, "{} format: {}", format, storage);
+        }
+
+        // The large value only takes its compressed size in the file
+        let file_size = std::fs::metadata(dir.path().join("database.vbank")).unwrap().len();
+        assert!(file_size < 1000, "{} format: {} bytes", format, file_size);
+
+        let (app, _) = start(&toml).await;
+        assert_eq!(send(&app, get("/kv/small")).await.json(), small, "{} format", format);
+        assert_eq!(send(&app, get("/kv/large")).await.json(), large, "{} format", format);
+    }
+}
+
+#[actix_web::test]
+async fn values_are_not_compressed_without_a_threshold() {
+    let dir = tempfile::tempdir().unwrap();
+    let toml = format!("persistence = \"on\"\n{}", data_file(&dir));
+
+    let large = json!({ "text": "abc".repeat(10_000) });
+
+    {
+        let (app, _) = start(&toml).await;
+        send(&app, put("/kv/large", large.clone())).await;
+        assert_eq!(send(&app, get("/kv/large/storage")).await.json()["compressed"], json!(false));
+    }
+
+    let file_size = std::fs::metadata(dir.path().join("database.vbank")).unwrap().len();
+    assert!(file_size > 30_000, "{} bytes", file_size);
+
+    let (app, _) = start(&toml).await;
+    assert_eq!(send(&app, get("/kv/large")).await.json(), large);
+}
+
+#[actix_web::test]
+async fn bloom_filter_never_hides_a_present_key() {
+    let dir = tempfile::tempdir().unwrap();
+
+    // A filter far too small for the keys, so counters collide and saturate
+    let toml = format!("persistence = \"on\"\nbloom_filter_size = 64\n{}", data_file(&dir));
+
+    {
+        let (app, _) = start(&toml).await;
+
+        for n in 0..400 {
+            send(&app, put(&format!("/kv/key:{}", n), json!(n))).await;
+        }
+        for n in (0..400).step_by(2) {
+            send(&app, TestRequest::delete().uri(&format!("/kv/key:{}", n))).await;
+        }
+        send(&app, TestRequest::post().uri("/kv/key:1/move?to=moved")).await;
+
+        for n in (3..400).step_by(2) {
+            assert_eq!(send(&app, get(&format!("/kv/key:{}", n))).await.json(), json!(n), "key:{}", n);
+        }
+        assert_eq!(send(&app, get("/kv/moved")).await.json(), json!(1));
+    }
+
+    // The filter is rebuilt from the data file on load
+    let (app, kvs) = start(&toml).await;
+    assert_eq!(kvs.document_count(), 200);
+
+    for n in (3..400).step_by(2) {
+        assert_eq!(send(&app, get(&format!("/kv/key:{}/exists", n))).await.json(), json!({ "exists": true }));
+    }
+    assert_eq!(send(&app, get("/kv/moved")).await.json(), json!(1));
+    assert_eq!(send(&app, get("/kv/key:0")).await.status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn a_held_key_only_blocks_writers_of_that_key() {
+    let config = config("");
+    let kvs = KVStore::new(&config);
+
+    std::thread::scope(|scope| {
+        let held = kvs.lock_key("a");
+        let (done, finished) = mpsc::channel();
+
+        for key in ["a", "b"] {
+            let (kvs, config, done) = (&kvs, &config, done.clone());
+
+            scope.spawn(move || {
+                let hook = PreWriteHook::new(config);
+                let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+                runtime
+                    .block_on(kvs.increment_field("kv".to_string(), key.to_string(), "n".to_string(), 1.0, &hook))
+                    .unwrap();
+                done.send(key).unwrap();
+            });
+        }
+
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok("b"));
+        assert!(finished.recv_timeout(Duration::from_millis(200)).is_err());
+
+        drop(held);
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok("a"));
+    });
+}
+
+#[test]
+fn swaps_in_opposite_orders_do_not_deadlock() {
+    const SWAPS: usize = 200;
+
+    let config = config("");
+    let kvs = KVStore::new(&config);
+
+    let (done, finished) = mpsc::channel();
+
+    std::thread::scope(|scope| {
+        for (a, b) in [("a", "b"), ("b", "a"), ("b", "c"), ("c", "a")] {
+            let (kvs, config, done) = (&kvs, &config, done.clone());
+
+            scope.spawn(move || {
+                let hook = PreWriteHook::new(config);
+                let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+                runtime.block_on(async {
+                    for key in ["a", "b", "c"] {
+                        _ = kvs.create_document_with_key("kv".to_string(), key.to_string(), json!(key), None).await;
+                    }
+                    for _ in 0..SWAPS {
+                        kvs.swap_documents(a.to_string(), b.to_string(), &hook).await.unwrap();
+                    }
+                });
+                done.send(()).unwrap();
+            });
+        }
+
+        for _ in 0..4 {
+            finished.recv_timeout(Duration::from_secs(10)).expect("Swaps deadlocked");
+        }
+    });
+
+    // Swaps only ever move the values around
+    let snapshot = kvs.store.read();
+    let mut values: Vec<String> = ["a", "b", "c"]
+        .iter()
+        .map(|key| serde_json::from_slice(&base64::decode(snapshot[*key].value.as_bytes()).unwrap()).unwrap())
+        .collect();
+    values.sort();
+    assert_eq!(values, ["a", "b", "c"]);
+}
+
+#[actix_web::test]
+async fn concurrent_writes_share_coalesced_flushes() {
+    const WRITES: usize = 32;
+
+    let dir = tempfile::tempdir().unwrap();
+    let toml = format!("persistence = \"on\"\nflush_coalesce_ms = 50\n{}", data_file(&dir));
+    let (app, _) = start(&toml).await;
+
+    // Every write is in the file by the time it returns
+    let writes = (0..WRITES).map(|n| {
+        let (app, toml) = (&app, &toml);
+        async move {
+            let key = format!("burst:{}", n);
+            assert_eq!(send(app, put(&format!("/kv/{}", key), json!(n))).await.status, StatusCode::CREATED);
+            assert!(KVStore::new(&config(toml)).store.read().contains_key(&key), "{} is not on disk", key);
+        }
+    });
+    futures_util::future::join_all(writes).await;
+
+    let metrics = String::from_utf8(send(&app, get("/metrics")).await.body.to_vec()).unwrap();
+    let flushes: usize = metrics
+        .lines()
+        .find_map(|line| line.strip_prefix("distkv_flushes_total "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(flushes > 0 && flushes < WRITES, "{} flushes for {} writes", flushes, WRITES);
+
+    assert_eq!(KVStore::new(&config(&toml)).document_count(), WRITES);
+}
+
+#[actix_web::test]
+async fn storage_reports_the_sizes_of_the_key() {
+    let value = json!({ "name": "x".repeat(200), "n": 1 });
+    let text = serde_json::to_string(&value).unwrap();
+
+    for format in ["legacy", "cbor"] {
+        for (threshold, compressed) in [("", false), ("compress_threshold = 64", true)] {
+            let dir = tempfile::tempdir().unwrap();
+            let toml = format!("persistence = \"on\"\ndisk_format = \"{}\"\n{}\n{}", format, threshold, data_file(&dir));
+            let (app, _) = start(&toml).await;
+            let file_len = || std::fs::metadata(dir.path().join("database.vbank")).unwrap().len();
+
+            send(&app, put("/kv/first", value.clone())).await;
+            let before = file_len();
+            send(&app, put("/kv/second", value.clone())).await;
+
+            let reply = send(&app, get("/kv/second/storage")).await;
+            assert_eq!(reply.status, StatusCode::OK);
+
+            let report = reply.json();
+            assert_eq!(report["format"], json!(format));
+            assert_eq!(report["value_bytes"], json!(text.len()));
+            assert_eq!(report["encoded_bytes"], json!(base64::encode(&text).len()));
+            assert_eq!(report["compressed"], json!(compressed), "{} {}", format, threshold);
+
+            // The record is what the second key added to the file
+            assert_eq!(report["record_bytes"], json!(file_len() - before), "{} {}", format, threshold);
+        }
+    }
+
+    let (app, _) = start("").await;
+    assert_eq!(send(&app, get("/kv/missing/storage")).await.status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn snapshot_reads_never_see_half_of_a_batch() {
+    const BATCHES: u64 = 300;
+
+    let kvs = KVStore::new(&config(""));
+    let keys = || vec!["pair:a".to_string(), "pair:b".to_string(), "pair:c".to_string()];
+
+    let runtime = || tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+    std::thread::scope(|scope| {
+        let writer = scope.spawn(|| {
+            runtime().block_on(async {
+                for n in 1..=BATCHES {
+                    let documents = keys().into_iter().map(|key| (key, json!(n), None)).collect();
+                    kvs.put_documents("kv".to_string(), documents).await.unwrap();
+                }
+            })
+        });
+
+        runtime().block_on(async {
+            let mut reads = 0;
+            while !writer.is_finished() || reads == 0 {
+                let values = kvs.snapshot_read(keys()).await.unwrap();
+                let values: Vec<_> = keys().iter().map(|key| values[key].clone()).collect();
+
+                assert!(values.iter().all(|value| *value == values[0]), "Torn read {:?}", values);
+                reads += 1;
+            }
+        });
+    });
+
+    // Missing keys read as null
+    let mut read = keys();
+    read.push("pair:missing".to_string());
+    let values = runtime().block_on(kvs.snapshot_read(read)).unwrap();
+    assert_eq!(
+        values,
+        json!({ "pair:a": BATCHES, "pair:b": BATCHES, "pair:c": BATCHES, "pair:missing": null })
+    );
+}
+
+#[actix_web::test]
+async fn flush_cache_encodes_each_written_value_once() {
+    fn encodes(metrics: &[u8]) -> u64 {
+        let metrics = String::from_utf8_lossy(metrics).into_owned();
+        metrics.lines().find_map(|line| line.strip_prefix("distkv_value_encodes_total ")).unwrap().parse().unwrap()
+    }
+
+    for (format, cache) in [("cbor", true), ("legacy", true), ("cbor", false)] {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = format!(
+            "persistence = \"on\"\ndisk_format = \"{}\"\nflush_cache = {}\ncompress_threshold = 64\n{}",
+            format, cache, data_file(&dir)
+        );
+        let (app, _) = start(&toml).await;
+
+        for n in 0..10 {
+            send(&app, put(&format!("/kv/key:{}", n), json!({ "n": n, "text": "x".repeat(100) }))).await;
+        }
+        for n in 0..3 {
+            let value = json!({ "n": n + 100, "text": "y".repeat(100) });
+            send(&app, put(&format!("/kv/key:{}?if_version=1", n), value)).await;
+        }
+        send(&app, TestRequest::delete().uri("/kv/key:9")).await;
+
+        let expected = if cache {
+            // One encode per write, across 14 flushes
+            13
+        } else {
+            // Every value on every flush
+            (1..=10).sum::<u64>() + 3 * 10 + 9
+        };
+        assert_eq!(encodes(&send(&app, get("/metrics")).await.body), expected, "{} {}", format, cache);
+
+        // The copied values read back the same
+        let reopened = KVStore::new(&config(&toml));
+        let read = |key: &str| reopened.get("kv".to_string(), key.to_string());
+        assert_eq!(reopened.document_count(), 9);
+        assert_eq!(read("key:0").await.unwrap(), json!({ "n": 100, "text": "y".repeat(100) }));
+        assert_eq!(read("key:5").await.unwrap(), json!({ "n": 5, "text": "x".repeat(100) }));
+    }
+}
+
+#[actix_web::test]
+async fn equal_values_share_one_copy_until_their_last_key_lets_go() {
+    let (app, kvs) = start("dedup_values = true").await;
+
+    let encoded_len = |value: &serde_json::Value| base64::encode(serde_json::to_string(value).unwrap()).len();
+    let value = json!({ "template": "x".repeat(1000) });
+
+    send(&app, put("/kv/a", value.clone())).await;
+    send(&app, put("/kv/b", value.clone())).await;
+    send(&app, put("/kv/c", json!("other"))).await;
+
+    {
+        let snapshot = kvs.store.read();
+        assert!(Arc::ptr_eq(&snapshot["a"].value, &snapshot["b"].value));
+        assert!(!Arc::ptr_eq(&snapshot["a"].value, &snapshot["c"].value));
+    }
+
+    let dedup = |stats: serde_json::Value| stats["dedup"].clone();
+    let stats = send(&app, get("/stats")).await.json();
+    assert_eq!(dedup(stats), json!({ "unique_values": 2, "saved_bytes": encoded_len(&value) }));
+
+    // A copy shares the value as well
+    send(&app, TestRequest::post().uri("/kv/a/copy?to=d")).await;
+    assert!(Arc::ptr_eq(&kvs.store.read()["a"].value, &kvs.store.read()["d"].value));
+    let stats = send(&app, get("/stats")).await.json();
+    assert_eq!(dedup(stats), json!({ "unique_values": 2, "saved_bytes": 2 * encoded_len(&value) }));
+
+    // Replacing or deleting a key drops its reference
+    send(&app, patch("/kv/a", json!("other"))).await;
+    send(&app, TestRequest::delete().uri("/kv/b")).await;
+    let stats = send(&app, get("/stats")).await.json();
+    assert_eq!(dedup(stats), json!({ "unique_values": 2, "saved_bytes": encoded_len(&json!("other")) }));
+    assert_eq!(send(&app, get("/kv/d")).await.json(), value);
+
+    send(&app, TestRequest::delete().uri("/kv/d")).await;
+    let stats = send(&app, get("/stats")).await.json();
+    assert_eq!(dedup(stats)["unique_values"], json!(1));
+}
+
+#[actix_web::test]
+async fn values_are_not_shared_without_dedup() {
+    let (app, kvs) = start("").await;
+
+    send(&app, put("/kv/a", json!("same"))).await;
+    send(&app, put("/kv/b", json!("same"))).await;
+
+    let snapshot = kvs.store.read();
+    assert!(!Arc::ptr_eq(&snapshot["a"].value, &snapshot["b"].value));
+    assert_eq!(send(&app, get("/stats")).await.json()["dedup"], json!(null));
+}
+
+#[actix_web::test]
+async fn stored_objects_have_sorted_keys_whatever_order_they_were_sent_in() {
+    let (app, kvs) = start("dedup_values = true").await;
+
+    let raw = |uri: &str, body: &'static str| {
+        TestRequest::put().uri(uri).insert_header(("Content-Type", "application/json")).set_payload(body)
+    };
+
+    send(&app, raw("/kv/a", r#"{ "zeta": 1, "alpha": { "y": [ { "b": 2, "a": 1 } ], "x": null } }"#)).await;
+    send(&app, raw("/kv/b", r#"{"alpha":{"x":null,"y":[{"a":1,"b":2}]},"zeta":1}"#)).await;
+
+    let expected = r#"{"alpha":{"x":null,"y":[{"a":1,"b":2}]},"zeta":1}"#;
+    assert_eq!(String::from_utf8_lossy(&send(&app, get("/kv/a")).await.body), expected);
+    assert_eq!(String::from_utf8_lossy(&send(&app, get("/kv/b")).await.body), expected);
+
+    // Equal documents are the same bytes, so they share one copy
+    let snapshot = kvs.store.read();
+    assert!(Arc::ptr_eq(&snapshot["a"].value, &snapshot["b"].value));
+}
+
+// The keys are sorted by `serde_json::Map` itself, which holds them in a
+// BTreeMap as long as serde_json's `preserve_order` feature stays off. Every
+// write path stores through it, so each one is pinned here in case a
+// dependency turns that feature on.
+#[actix_web::test]
+async fn every_write_path_stores_objects_with_sorted_keys() {
+    let (app, kvs) = start("").await;
+
+    let document = || json!({ "zeta": 1, "alpha": { "y": [{ "b": 2, "a": 1 }], "x": null } });
+
+    send(&app, put("/kv/put", document())).await;
+    send(&app, put("/kv/patched", json!(0))).await;
+    send(&app, patch("/kv/patched", document())).await;
+    send(&app, post("/batch/put", json!([{ "key": "batch", "value": document() }]))).await;
+
+    let expected = r#"{"alpha":{"x":null,"y":[{"a":1,"b":2}]},"zeta":1}"#;
+    let snapshot = kvs.store.read();
+    for key in ["put", "patched", "batch"] {
+        let stored = base64::decode(snapshot[key].value.as_bytes()).unwrap();
+        assert_eq!(String::from_utf8(stored).unwrap(), expected, "{}", key);
+    }
+}
+
+// A legacy data file in `dir` where `a` appears twice, first as "first" and
+// then as "last"
+fn write_duplicate_keys(dir: &tempfile::TempDir) {
+    let line = |key: &str, value: serde_json::Value| format!("{}|{}\n", key, base64::encode(value.to_string()));
+    let contents = line("a", json!("first")) + &line("b", json!(1)) + &line("a", json!("last"));
+
+    std::fs::write(dir.path().join("database.vbank"), contents).unwrap();
+}
+
+#[actix_web::test]
+async fn duplicate_keys_in_the_data_file_are_handled_as_configured() {
+    let modes = [(None, "last"), (Some("warn"), "last"), (Some("last-wins"), "last"), (Some("first-wins"), "first")];
+
+    for (mode, expected) in modes {
+        let dir = tempfile::tempdir().unwrap();
+        write_duplicate_keys(&dir);
+
+        let setting = mode.map(|mode| format!("duplicate_keys = \"{}\"", mode)).unwrap_or_default();
+        let (app, kvs) = start(&format!("persistence = \"on\"\n{}\n{}", setting, data_file(&dir))).await;
+
+        assert_eq!(kvs.document_count(), 2, "{:?}", mode);
+        assert_eq!(send(&app, get("/kv/a")).await.json(), json!(expected), "{:?}", mode);
+        assert_eq!(send(&app, get("/kv/b")).await.json(), json!(1), "{:?}", mode);
+    }
+}
+
+#[test]
+fn duplicate_keys_in_the_data_file_stop_the_start_when_they_are_an_error() {
+    let dir = tempfile::tempdir().unwrap();
+    write_duplicate_keys(&dir);
+
+    let config = config(&format!("persistence = \"on\"\nduplicate_keys = \"error\"\n{}", data_file(&dir)));
+    assert!(std::panic::catch_unwind(|| KVStore::new(&config)).is_err());
+
+    assert!(Config::from_toml("persistence = \"off\"\nduplicate_keys = \"newest\"").is_err());
+}
+
+#[actix_web::test]
+async fn reload_rejects_a_data_file_with_duplicate_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let (app, _) = start(&format!("persistence = \"on\"\nduplicate_keys = \"last-wins\"\n{}", data_file(&dir))).await;
+
+    send(&app, put("/kv/c", json!(1))).await;
+    write_duplicate_keys(&dir);
+
+    let reply = send(&app, TestRequest::post().uri("/admin/reload")).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+    assert_eq!(send(&app, get("/kv/c")).await.json(), json!(1));
+}
@@ -0,0 +1,509 @@
+use std::time::{Duration, SystemTime};
+
+use actix_web::http::header::HttpDate;
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{get, patch, post, put, send, start};
+
+#[actix_web::test]
+async fn incr_field_adds_to_an_existing_field() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/page", json!({ "stats": { "count": 41 }, "name": "home" }))).await;
+
+    let reply = send(&app, TestRequest::post().uri("/kv/page/incr-field?path=stats.count")).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "value": 42 }));
+
+    let reply = send(&app, TestRequest::post().uri("/kv/page/incr-field?path=stats.count&by=-2.5")).await;
+    assert_eq!(reply.json(), json!({ "value": 39.5 }));
+
+    assert_eq!(send(&app, get("/kv/page")).await.json(), json!({ "stats": { "count": 39.5 }, "name": "home" }));
+}
+
+#[actix_web::test]
+async fn incr_field_creates_missing_documents_and_fields() {
+    let (app, _) = start("").await;
+
+    let reply = send(&app, TestRequest::post().uri("/kv/new/incr-field?path=a.b.c&by=3")).await;
+    assert_eq!(reply.json(), json!({ "value": 3 }));
+    assert_eq!(send(&app, get("/kv/new")).await.json(), json!({ "a": { "b": { "c": 3 } } }));
+
+    send(&app, put("/kv/page", json!({ "name": "home" }))).await;
+    send(&app, TestRequest::post().uri("/kv/page/incr-field?path=stats.count")).await;
+    assert_eq!(send(&app, get("/kv/page")).await.json(), json!({ "name": "home", "stats": { "count": 1 } }));
+}
+
+#[actix_web::test]
+async fn incr_field_rejects_non_numeric_fields() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/page", json!({ "name": "home", "tags": ["a"] }))).await;
+
+    for path in ["name", "tags", "name.first", "stats..count"] {
+        let reply = send(&app, TestRequest::post().uri(&format!("/kv/page/incr-field?path={}", path))).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST, "{}", path);
+    }
+
+    assert_eq!(send(&app, get("/kv/page")).await.json(), json!({ "name": "home", "tags": ["a"] }));
+}
+
+#[actix_web::test]
+async fn incr_field_rejects_overflows() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/c", json!({ "n": i64::MAX, "x": 1e308 }))).await;
+
+    for uri in ["/kv/c/incr-field?path=n", "/kv/c/incr-field?path=x&by=1e308", "/kv/c/incr-field?path=y&by=NaN"] {
+        let reply = send(&app, TestRequest::post().uri(uri)).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST, "{}", uri);
+    }
+
+    assert_eq!(send(&app, get("/kv/c")).await.json(), json!({ "n": i64::MAX, "x": 1e308 }));
+
+    // The key lock was released, so the key still takes writes
+    let reply = send(&app, TestRequest::post().uri("/kv/c/incr-field?path=n&by=-1")).await;
+    assert_eq!(reply.json(), json!({ "value": i64::MAX - 1 }));
+}
+
+#[actix_web::test]
+async fn max_and_min_only_move_the_value_one_way() {
+    let (app, _) = start("").await;
+
+    let reply = send(&app, post("/kv/high/max", json!(10))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "changed": true, "value": 10 }));
+
+    assert_eq!(send(&app, post("/kv/high/max", json!(7))).await.json(), json!({ "changed": false, "value": 10 }));
+    assert_eq!(send(&app, post("/kv/high/max", json!(10))).await.json(), json!({ "changed": false, "value": 10 }));
+    assert_eq!(send(&app, post("/kv/high/max", json!(10.5))).await.json(), json!({ "changed": true, "value": 10.5 }));
+    assert_eq!(send(&app, get("/kv/high")).await.json(), json!(10.5));
+
+    assert_eq!(send(&app, post("/kv/low/min", json!(-3))).await.json(), json!({ "changed": true, "value": -3 }));
+    assert_eq!(send(&app, post("/kv/low/min", json!(0))).await.json(), json!({ "changed": false, "value": -3 }));
+    assert_eq!(send(&app, post("/kv/low/min", json!(-4))).await.json(), json!({ "changed": true, "value": -4 }));
+    assert_eq!(send(&app, get("/kv/low")).await.json(), json!(-4));
+}
+
+#[actix_web::test]
+async fn max_and_min_need_numbers() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/text", json!("x"))).await;
+
+    assert_eq!(send(&app, post("/kv/text/max", json!(1))).await.status, StatusCode::BAD_REQUEST);
+    assert_eq!(send(&app, get("/kv/text")).await.json(), json!("x"));
+
+    assert_eq!(send(&app, post("/kv/n/min", json!("1"))).await.status, StatusCode::BAD_REQUEST);
+    assert_eq!(send(&app, get("/kv/n")).await.status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn if_version_writes_only_over_the_expected_version() {
+    let (app, _) = start("").await;
+
+    // Version 0 stands for a key that does not exist yet
+    let reply = send(&app, put("/kv/a?if_version=0", json!("first"))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "version": 1 }));
+
+    assert_eq!(send(&app, put("/kv/a?if_version=1", json!("second"))).await.json(), json!({ "version": 2 }));
+    assert_eq!(send(&app, get("/kv/a/version")).await.json(), json!({ "version": 2 }));
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!("second"));
+
+    for stale in [0, 1, 3] {
+        let reply = send(&app, put(&format!("/kv/a?if_version={}", stale), json!("stale"))).await;
+        assert_eq!(reply.status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(reply.json(), json!({ "version": 2 }));
+    }
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!("second"));
+
+    // Other writes bump the version too
+    send(&app, patch("/kv/a", json!("third"))).await;
+    assert_eq!(send(&app, get("/kv/a/version")).await.json(), json!({ "version": 3 }));
+    assert_eq!(send(&app, put("/kv/a?if_version=2", json!("stale"))).await.status, StatusCode::PRECONDITION_FAILED);
+
+    let reply = send(&app, put("/kv/missing?if_version=1", json!(1))).await;
+    assert_eq!(reply.status, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(reply.json(), json!({ "version": 0 }));
+    assert_eq!(send(&app, get("/kv/missing/version")).await.status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn if_unmodified_since_refuses_writes_after_the_date() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/a", json!("first"))).await;
+
+    let reply = send(&app, get("/kv/a")).await;
+    let last_modified = reply.headers.get("Last-Modified").unwrap().to_str().unwrap().to_string();
+    let modified_at = SystemTime::from(last_modified.parse::<HttpDate>().unwrap());
+    assert!(SystemTime::now().duration_since(modified_at).unwrap() < Duration::from_secs(5));
+
+    let before = HttpDate::from(modified_at - Duration::from_secs(3600)).to_string();
+    let stale = [put("/kv/a", json!("stale")), patch("/kv/a", json!("stale"))];
+    for write in stale {
+        let reply = send(&app, write.insert_header(("If-Unmodified-Since", before.as_str()))).await;
+        assert_eq!(reply.status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(reply.json(), json!({ "version": 1 }));
+    }
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!("first"));
+
+    // The date of the last write itself is fresh
+    let reply = send(&app, put("/kv/a", json!("second")).insert_header(("If-Unmodified-Since", last_modified.as_str()))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "version": 2 }));
+
+    let after = HttpDate::from(SystemTime::now() + Duration::from_secs(3600)).to_string();
+    let reply = send(&app, patch("/kv/a", json!("third")).insert_header(("If-Unmodified-Since", after.as_str()))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!("third"));
+
+    // A missing key was never modified, and a date that cannot be read is ignored
+    let reply = send(&app, put("/kv/b", json!(1)).insert_header(("If-Unmodified-Since", before.as_str()))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    let reply = send(&app, patch("/kv/a", json!("fourth")).insert_header(("If-Unmodified-Since", "yesterday"))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!("fourth"));
+}
+
+#[actix_web::test]
+async fn upsert_reports_whether_the_key_was_created() {
+    let (app, kvs) = start("").await;
+
+    let reply = send(&app, put("/kv/a?upsert=true&ttl_seconds=600", json!(1))).await;
+    assert_eq!(reply.status, StatusCode::CREATED);
+    assert_eq!(reply.json(), json!({ "created": true }));
+
+    let reply = send(&app, put("/kv/a?upsert=true", json!(2))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "created": false }));
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!(2));
+    assert_eq!(send(&app, get("/kv/a/version")).await.json(), json!({ "version": 2 }));
+
+    // A replaced value keeps its expiry unless given a new one
+    let expires_at = kvs.store.read()["a"].expires_at.unwrap();
+    send(&app, put("/kv/a?upsert=true&ttl_seconds=7200", json!(3))).await;
+    assert!(kvs.store.read()["a"].expires_at.unwrap() > expires_at);
+
+    // Without upsert an existing key is still a conflict
+    assert_eq!(send(&app, put("/kv/a", json!(4))).await.status, StatusCode::CONFLICT);
+
+    // A condition takes precedence
+    let reply = send(&app, put("/kv/a?upsert=true&if_version=1", json!(5))).await;
+    assert_eq!(reply.status, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!(3));
+}
+
+#[actix_web::test]
+async fn trim_keeps_the_index_range_of_an_array() {
+    let (app, _) = start("").await;
+
+    let trims = [
+        ("start=0&stop=2", json!([0, 1, 2])),
+        ("start=-3&stop=-1", json!([7, 8, 9])),
+        ("start=2&stop=-3", json!([2, 3, 4, 5, 6, 7])),
+        ("start=8&stop=100", json!([8, 9])),
+        ("start=-100&stop=1", json!([0, 1])),
+        ("start=0&stop=-1", json!([0, 1, 2, 3, 4, 5, 6, 7, 8, 9])),
+        // Ranges with nothing in them
+        ("start=5&stop=2", json!([])),
+        ("start=10&stop=20", json!([])),
+        ("start=-100&stop=-50", json!([])),
+    ];
+    for (range, kept) in trims {
+        send(&app, patch("/list/log", json!([0, 1, 2, 3, 4, 5, 6, 7, 8, 9]))).await;
+
+        let reply = send(&app, post(&format!("/list/log/trim?{}", range), json!(null))).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", range);
+        assert_eq!(reply.json(), json!({ "length": kept.as_array().unwrap().len() }), "{}", range);
+        assert_eq!(send(&app, get("/list/log")).await.json(), kept, "{}", range);
+    }
+}
+
+#[actix_web::test]
+async fn trim_of_a_missing_key_or_another_value_fails() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/name", json!("text"))).await;
+
+    let reply = send(&app, post("/kv/name/trim?start=0&stop=1", json!(null))).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+    assert_eq!(send(&app, get("/kv/name")).await.json(), json!("text"));
+
+    assert_eq!(send(&app, post("/kv/missing/trim?start=0&stop=1", json!(null))).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(send(&app, post("/kv/name/trim?start=0", json!(null))).await.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn set_add_skips_members_already_held() {
+    let (app, _) = start("").await;
+
+    // A missing key is an empty set
+    let reply = send(&app, post("/set/tags/add", json!(["rust", "db", "rust"]))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "added": 2, "size": 2 }));
+
+    let reply = send(&app, post("/set/tags/add", json!(["db", "kv", 1]))).await;
+    assert_eq!(reply.json(), json!({ "added": 2, "size": 4 }));
+    assert_eq!(send(&app, get("/set/tags")).await.json(), json!(["rust", "db", "kv", 1]));
+
+    // Adding only held members changes nothing
+    assert_eq!(send(&app, get("/set/tags/version")).await.json(), json!({ "version": 2 }));
+    let reply = send(&app, post("/set/tags/add", json!(["rust", "kv"]))).await;
+    assert_eq!(reply.json(), json!({ "added": 0, "size": 4 }));
+    assert_eq!(send(&app, get("/set/tags/version")).await.json(), json!({ "version": 2 }));
+}
+
+#[actix_web::test]
+async fn set_remove_drops_every_occurrence() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/set/tags", json!(["a", "b", "a", "c"]))).await;
+
+    let reply = send(&app, post("/set/tags/remove", json!(["a", "missing"]))).await;
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.json(), json!({ "removed": 2, "size": 2 }));
+    assert_eq!(send(&app, get("/set/tags")).await.json(), json!(["b", "c"]));
+
+    let reply = send(&app, post("/set/other/remove", json!(["a"]))).await;
+    assert_eq!(reply.json(), json!({ "removed": 0, "size": 0 }));
+    assert_eq!(send(&app, get("/set/other")).await.status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn set_membership_matches_strings_and_json() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/set/tags", json!(["rust", 1, true]))).await;
+
+    for (member, has) in [("rust", true), ("1", true), ("true", true), ("db", false), ("%221%22", false)] {
+        let reply = send(&app, get(&format!("/set/tags/has?member={}", member))).await;
+        assert_eq!(reply.json(), json!({ "has": has }), "{}", member);
+    }
+    assert_eq!(send(&app, get("/set/missing/has?member=rust")).await.json(), json!({ "has": false }));
+
+    // Only arrays are sets
+    send(&app, put("/set/name", json!("text"))).await;
+    assert_eq!(send(&app, get("/set/name/has?member=t")).await.status, StatusCode::BAD_REQUEST);
+    assert_eq!(send(&app, post("/set/name/add", json!(["t"]))).await.status, StatusCode::BAD_REQUEST);
+    assert_eq!(send(&app, post("/set/name/remove", json!(["t"]))).await.status, StatusCode::BAD_REQUEST);
+    assert_eq!(send(&app, get("/set/name")).await.json(), json!("text"));
+}
+
+#[actix_web::test]
+async fn append_keeps_points_in_ts_order() {
+    let (app, _) = start("").await;
+
+    for (ts, length) in [(2000, 1), (3000, 2), (1000, 3)] {
+        let reply = send(&app, post("/ts/metric:cpu/append", json!({ "ts": ts, "val": ts / 1000 }))).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.json(), json!({ "ts": ts, "length": length, "trimmed": 0 }));
+    }
+
+    let points = json!([{ "ts": 1000, "val": 1 }, { "ts": 2000, "val": 2 }, { "ts": 3000, "val": 3 }]);
+    assert_eq!(send(&app, get("/kv/metric:cpu")).await.json(), points);
+
+    // Without a `ts` the point is stamped with the current time
+    let reply = send(&app, post("/ts/metric:cpu/append", json!({ "val": 4 }))).await;
+    assert!(reply.json()["ts"].as_u64().unwrap() > 1_600_000_000_000);
+
+    send(&app, put("/kv/plain", json!([1, 2]))).await;
+    let reply = send(&app, post("/ts/plain/append", json!({ "ts": 1, "val": 1 }))).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+    assert_eq!(send(&app, get("/kv/plain")).await.json(), json!([1, 2]));
+}
+
+#[actix_web::test]
+async fn append_trims_to_max_len_and_the_window() {
+    let (app, _) = start("").await;
+
+    for ts in [1000, 2000, 3000, 4000] {
+        send(&app, post("/ts/s/append", json!({ "ts": ts, "val": 0 }))).await;
+    }
+
+    let reply = send(&app, post("/ts/s/append?max_len=3", json!({ "ts": 5000, "val": 0 }))).await;
+    assert_eq!(reply.json(), json!({ "ts": 5000, "length": 3, "trimmed": 2 }));
+
+    // Points more than 2 seconds older than the newest one are dropped
+    let reply = send(&app, post("/ts/s/append?window_secs=2", json!({ "ts": 6000, "val": 0 }))).await;
+    assert_eq!(reply.json(), json!({ "ts": 6000, "length": 3, "trimmed": 1 }));
+
+    let points = send(&app, get("/kv/s")).await.json();
+    let ts: Vec<_> = points.as_array().unwrap().iter().map(|point| point["ts"].clone()).collect();
+    assert_eq!(ts, [json!(4000), json!(5000), json!(6000)]);
+
+    // A window too large to add to a timestamp keeps every point
+    let uri = format!("/ts/s/append?window_secs={}", u64::MAX);
+    let reply = send(&app, post(&uri, json!({ "ts": u64::MAX, "val": 0 }))).await;
+    assert_eq!(reply.json()["trimmed"], json!(0));
+    assert_eq!(reply.json()["length"], json!(4));
+
+    let reply = send(&app, post("/ts/s/append?max_len=0", json!({ "ts": 1, "val": 0 }))).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn points_since_returns_the_later_points() {
+    let (app, _) = start("").await;
+
+    for ts in [1000, 2000, 3000] {
+        send(&app, post("/ts/metric:cpu/append", json!({ "ts": ts, "val": ts / 1000 }))).await;
+    }
+
+    let later = json!([{ "ts": 3000, "val": 3 }]);
+    for uri in ["/ts/metric:cpu/points?since=2000", "/ts/metric:cpu?since=2000", "/ts/metric:cpu?since=2500"] {
+        let reply = send(&app, get(uri)).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.json(), later, "{}", uri);
+    }
+
+    assert_eq!(send(&app, get("/ts/metric:cpu")).await.json().as_array().unwrap().len(), 3);
+    assert_eq!(send(&app, get("/ts/metric:cpu?since=3000")).await.json(), json!([]));
+
+    assert_eq!(send(&app, get("/ts/missing?since=0")).await.status, StatusCode::NOT_FOUND);
+
+    send(&app, put("/kv/plain", json!({ "a": 1 }))).await;
+    assert_eq!(send(&app, get("/ts/plain?since=0")).await.status, StatusCode::BAD_REQUEST);
+    assert_eq!(send(&app, get("/ts/plain/points")).await.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn apply_runs_each_operation_on_the_value() {
+    let (app, _) = start("").await;
+
+    let cases = [
+        (json!(10), "add", json!(5), json!(15)),
+        (json!(10), "add", json!(0.5), json!(10.5)),
+        (json!(10), "subtract", json!(15), json!(-5)),
+        (json!(10), "multiply", json!(3), json!(30)),
+        (json!(10), "divide", json!(4), json!(2.5)),
+        (json!(10), "divide", json!(5), json!(2)),
+        (json!(i64::MAX), "add", json!(1), json!(i64::MAX as f64 + 1.0)),
+        (json!("ada"), "append", json!("!"), json!("ada!")),
+        (json!("ada"), "prepend", json!("hi "), json!("hi ada")),
+        (json!("Ada"), "upper", json!(null), json!("ADA")),
+        (json!("Ada"), "lower", json!(null), json!("ada")),
+        (json!("  ada \n"), "trim", json!(null), json!("ada")),
+    ];
+
+    for (value, op, arg, expected) in cases {
+        send(&app, put("/kv/a?upsert=true", value.clone())).await;
+
+        let reply = send(&app, post("/kv/a/apply", json!({ "op": op, "arg": arg }))).await;
+        assert_eq!(reply.status, StatusCode::OK, "{} on {}", op, value);
+        assert_eq!(reply.json(), json!({ "value": expected }), "{} on {}", op, value);
+        assert_eq!(send(&app, get("/kv/a")).await.json(), expected);
+    }
+
+    // A field of the value can be changed in place
+    send(&app, put("/kv/doc", json!({ "stats": { "n": 1 }, "name": "x" }))).await;
+    let reply = send(&app, post("/kv/doc/apply", json!({ "op": "add", "arg": 2, "path": "stats.n" }))).await;
+    assert_eq!(reply.json(), json!({ "value": 3 }));
+    assert_eq!(send(&app, get("/kv/doc")).await.json(), json!({ "stats": { "n": 3 }, "name": "x" }));
+}
+
+#[actix_web::test]
+async fn apply_rejects_unknown_operations_and_bad_input_without_writing() {
+    let (app, _) = start("").await;
+
+    send(&app, put("/kv/n", json!(1e308))).await;
+    send(&app, put("/kv/s", json!("ada"))).await;
+
+    let rejected = [
+        ("n", json!({ "op": "eval", "arg": "1 + 1" })),
+        ("n", json!({ "op": "add" })),
+        ("n", json!({ "op": "add", "arg": "1" })),
+        ("s", json!({ "op": "append", "arg": 1 })),
+        ("n", json!({ "op": "divide", "arg": 0 })),
+        ("n", json!({ "op": "multiply", "arg": 10 })),
+        ("n", json!({ "op": "divide", "arg": 1e-10 })),
+        ("n", json!({ "op": "upper" })),
+        ("s", json!({ "op": "add", "arg": 1 })),
+        ("n", json!({ "op": "add", "arg": 1, "path": "missing" })),
+    ];
+
+    for (key, body) in rejected {
+        let reply = send(&app, post(&format!("/kv/{}/apply", key), body.clone())).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST, "{}", body);
+    }
+
+    assert_eq!(send(&app, get("/kv/n")).await.json(), json!(1e308));
+    assert_eq!(send(&app, get("/kv/s")).await.json(), json!("ada"));
+    assert_eq!(send(&app, get("/kv/n/version")).await.json(), json!({ "version": 1 }));
+
+    let reply = send(&app, post("/kv/missing/apply", json!({ "op": "add", "arg": 1 }))).await;
+    assert_eq!(reply.status, StatusCode::NOT_FOUND);
+}
+
+// Known city centres and the great-circle distances between them
+const PARIS: (f64, f64) = (48.8566, 2.3522);
+const CITIES: [(&str, f64, f64); 4] = [
+    ("london", 51.5074, -0.1278),
+    ("berlin", 52.5200, 13.4050),
+    ("new-york", 40.7128, -74.0060),
+    ("paris", 48.8566, 2.3522),
+];
+
+#[actix_web::test]
+async fn geo_add_stores_named_points() {
+    let (app, _) = start("").await;
+
+    for (size, (name, lat, lng)) in CITIES.iter().enumerate() {
+        let reply = send(&app, post("/geo/cities/add", json!({ "name": name, "lat": lat, "lng": lng }))).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.json(), json!({ "added": true, "size": size + 1 }));
+    }
+
+    // Adding a name again moves it
+    let reply = send(&app, post("/geo/cities/add", json!({ "name": "paris", "lat": 48.0, "lng": 2.0 }))).await;
+    assert_eq!(reply.json(), json!({ "added": false, "size": 4 }));
+    assert_eq!(send(&app, get("/kv/cities")).await.json()["paris"], json!({ "lat": 48.0, "lng": 2.0 }));
+
+    for (lat, lng) in [(90.5, 0.0), (-91.0, 0.0), (0.0, 180.5), (0.0, -181.0)] {
+        let reply = send(&app, post("/geo/cities/add", json!({ "name": "x", "lat": lat, "lng": lng }))).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST, "{} {}", lat, lng);
+    }
+
+    send(&app, put("/kv/plain", json!({ "a": 1 }))).await;
+    let reply = send(&app, post("/geo/plain/add", json!({ "name": "x", "lat": 0.0, "lng": 0.0 }))).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn geo_nearby_returns_the_points_within_the_radius_closest_first() {
+    let (app, _) = start("").await;
+
+    for (name, lat, lng) in CITIES {
+        send(&app, post("/geo/cities/add", json!({ "name": name, "lat": lat, "lng": lng }))).await;
+    }
+
+    let (lat, lng) = PARIS;
+    let nearby = |radius_km: f64| get(&format!("/geo/cities/nearby?lat={}&lng={}&radius_km={}", lat, lng, radius_km));
+
+    let points = send(&app, nearby(1000.0)).await.json();
+    let names: Vec<_> = points.as_array().unwrap().iter().map(|point| point["name"].clone()).collect();
+    assert_eq!(names, [json!("paris"), json!("london"), json!("berlin")]);
+
+    // Paris to London is 344 km and Paris to Berlin 878 km
+    let distance = |index: usize| points[index]["distance_km"].as_f64().unwrap();
+    assert!(distance(0) < 0.001);
+    assert!((distance(1) - 344.0).abs() < 2.0, "{}", distance(1));
+    assert!((distance(2) - 878.0).abs() < 2.0, "{}", distance(2));
+    assert_eq!(points[1]["lat"], json!(51.5074));
+
+    assert_eq!(send(&app, nearby(500.0)).await.json().as_array().unwrap().len(), 2);
+    assert_eq!(send(&app, nearby(0.0)).await.json().as_array().unwrap().len(), 1);
+
+    // New York is 5837 km away
+    let points = send(&app, nearby(6000.0)).await.json();
+    assert_eq!(points[3]["name"], json!("new-york"));
+    assert!((points[3]["distance_km"].as_f64().unwrap() - 5837.0).abs() < 10.0);
+
+    let reply = send(&app, get("/geo/cities/nearby?lat=0&lng=0&radius_km=-1")).await;
+    assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+    let reply = send(&app, get("/geo/missing/nearby?lat=0&lng=0&radius_km=1")).await;
+    assert_eq!(reply.status, StatusCode::NOT_FOUND);
+}
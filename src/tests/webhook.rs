@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::test::TestRequest;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use serde_json::{json, Value};
+
+use super::{get, patch, post, put, send, start};
+
+// Marks objects as checked and rejects every key starting with `deny`. Keys
+// starting with `slow` wait a while first.
+async fn review(body: web::Json<Value>) -> HttpResponse {
+    let key = body["key"].as_str().unwrap_or_default();
+
+    if key.starts_with("slow") {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+    }
+
+    if key.starts_with("deny") {
+        return HttpResponse::UnprocessableEntity().body(format!("{} is not allowed", key));
+    }
+
+    match &body["value"] {
+        Value::Object(fields) => {
+            let mut fields = fields.clone();
+            fields.insert("checked".to_string(), json!(true));
+            HttpResponse::Ok().json(json!({ "value": fields }))
+        }
+        _ => HttpResponse::Ok().finish(),
+    }
+}
+
+// The setting for a webhook running `review` on a free local port
+fn webhook() -> String {
+    let server = HttpServer::new(|| App::new().route("/", web::post().to(review)))
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+
+    let address = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+
+    format!("pre_write_webhook = \"http://{}/\"", address)
+}
+
+#[actix_web::test]
+async fn webhook_transforms_values() {
+    let (app, _) = start(&webhook()).await;
+
+    assert_eq!(send(&app, put("/kv/a", json!({ "n": 1 }))).await.status, StatusCode::CREATED);
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!({ "n": 1, "checked": true }));
+
+    send(&app, patch("/kv/b", json!({ "n": 2 }))).await;
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!({ "n": 2, "checked": true }));
+
+    // An empty reply keeps the value as it was sent
+    send(&app, put("/kv/c", json!([1, 2]))).await;
+    assert_eq!(send(&app, get("/kv/c")).await.json(), json!([1, 2]));
+}
+
+#[actix_web::test]
+async fn webhook_rejections_are_returned() {
+    let (app, kvs) = start(&webhook()).await;
+
+    let reply = send(&app, put("/kv/deny-a", json!({ "n": 1 }))).await;
+    assert_eq!(reply.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(String::from_utf8_lossy(&reply.body), "deny-a is not allowed");
+
+    assert_eq!(send(&app, patch("/kv/deny-a", json!(1))).await.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(kvs.document_count(), 0);
+}
+
+#[actix_web::test]
+async fn derived_writes_go_through_the_webhook() {
+    let (app, _) = start(&webhook()).await;
+
+    send(&app, TestRequest::post().uri("/kv/page/incr-field?path=views")).await;
+    assert_eq!(send(&app, get("/kv/page")).await.json(), json!({ "views": 1, "checked": true }));
+
+    send(&app, put("/kv/doc", json!({ "n": 1 }))).await;
+    send(&app, post("/kv/doc/apply", json!({ "op": "add", "arg": 2, "path": "n" }))).await;
+    assert_eq!(send(&app, get("/kv/doc")).await.json(), json!({ "n": 3, "checked": true }));
+
+    send(&app, post("/geo/places/add", json!({ "name": "office", "lat": 1.0, "lng": 2.0 }))).await;
+    assert_eq!(send(&app, get("/kv/places")).await.json()["checked"], json!(true));
+}
+
+#[actix_web::test]
+async fn derived_writes_are_rejected_by_the_webhook() {
+    let (app, kvs) = start(&webhook()).await;
+
+    let uris = [
+        TestRequest::post().uri("/kv/deny-count/incr-field?path=n"),
+        post("/kv/deny-set/add", json!(["a"])),
+        post("/kv/deny-max/max", json!(5)),
+        post("/kv/deny-series/append", json!({ "val": 1 })),
+        post("/geo/deny-places/add", json!({ "name": "office", "lat": 1.0, "lng": 2.0 })),
+        post("/batch/cas", json!([{ "key": "deny-cas", "expected": null, "new": 1 }])),
+    ];
+
+    for req in uris {
+        assert_eq!(send(&app, req).await.status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    assert_eq!(kvs.document_count(), 0);
+}
+
+#[actix_web::test]
+async fn copies_are_rejected_for_the_key_they_are_written_to() {
+    let (app, kvs) = start(&webhook()).await;
+
+    send(&app, put("/kv/a", json!(1))).await;
+
+    let uris = [
+        "/kv/a/copy?to=deny-b",
+        "/kv/a/move?to=deny-b",
+        "/admin/swap?a=a&b=deny-b",
+        "/admin/rename-prefix?from=a&to=deny-",
+    ];
+
+    for uri in uris {
+        let reply = send(&app, TestRequest::post().uri(uri)).await;
+        assert_eq!(reply.status, StatusCode::UNPROCESSABLE_ENTITY, "{}", uri);
+    }
+
+    let reply = send(&app, TestRequest::post().uri("/admin/import").set_payload("{\"key\": \"deny-c\", \"value\": 1}\n")).await;
+    assert_eq!(reply.json()["failed"], json!(1));
+
+    assert_eq!(kvs.document_count(), 1);
+    assert_eq!(send(&app, get("/kv/a")).await.json(), json!(1));
+
+    assert_eq!(send(&app, TestRequest::post().uri("/kv/a/copy?to=b")).await.status, StatusCode::CREATED);
+    assert_eq!(send(&app, get("/kv/b")).await.json(), json!(1));
+}
+
+#[actix_web::test]
+async fn keys_changed_while_the_webhook_runs_are_not_written() {
+    let (app, kvs) = start(&webhook()).await;
+
+    let (reply, _) = tokio::join!(send(&app, TestRequest::post().uri("/kv/slow/incr-field?path=n")), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        kvs.insert("kv".to_string(), "slow".to_string(), json!({ "n": 5 })).await.unwrap();
+    });
+
+    assert_eq!(reply.status, StatusCode::CONFLICT);
+    assert_eq!(send(&app, get("/kv/slow")).await.json(), json!({ "n": 5 }));
+}
@@ -0,0 +1,127 @@
+use std::error::Error;
+use std::fmt;
+
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::kvstore::WriteFilter;
+
+// Optional hook that validates or rewrites a value before it is stored. The
+// proposed `{key, value}` is posted to the configured URL and the write goes
+// ahead with the `value` of the reply, or the original value if there is none.
+pub struct PreWriteHook {
+    target: Option<(String, awc::Client)>,
+    max_reply_size: usize,
+}
+
+// A write the webhook refused, answered with the status and body it replied with
+#[derive(Debug)]
+pub struct Rejection {
+    status: StatusCode,
+    body: Bytes,
+}
+
+impl Rejection {
+    fn new(status: StatusCode, body: impl Into<Bytes>) -> Self {
+        Rejection {
+            status,
+            body: body.into(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).body(self.body.clone())
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.body))
+    }
+}
+
+impl Error for Rejection {}
+
+impl PreWriteHook {
+    pub fn new(config: &Config) -> Self {
+        let target = config.pre_write_webhook.clone().map(|url| {
+            let client = awc::Client::builder().timeout(config.webhook_timeout).finish();
+            (url, client)
+        });
+
+        PreWriteHook {
+            target,
+            max_reply_size: config.max_payload_size,
+        }
+    }
+
+    pub async fn apply(&self, key: Option<&str>, value: Value) -> Result<Value, Rejection> {
+        let (url, client) = match &self.target {
+            Some(target) => target,
+            None => return Ok(value),
+        };
+
+        let mut response = match client
+            .post(url.as_str())
+            .send_json(&serde_json::json!({ "key": key, "value": value }))
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Write rejected - pre-write webhook failed: {}", e);
+                return Err(Rejection::new(StatusCode::BAD_GATEWAY, format!("Pre-write webhook failed: {}", e)));
+            }
+        };
+
+        let body = match response.body().limit(self.max_reply_size).await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Write rejected - pre-write webhook reply unreadable: {}", e);
+                return Err(Rejection::new(
+                    StatusCode::BAD_GATEWAY,
+                    format!("Pre-write webhook reply unreadable: {}", e),
+                ));
+            }
+        };
+
+        if !response.status().is_success() {
+            warn!("Write rejected by pre-write webhook with status {}", response.status());
+            return Err(Rejection::new(response.status(), body));
+        }
+
+        if body.is_empty() {
+            return Ok(value);
+        }
+
+        match serde_json::from_slice::<Value>(&body) {
+            Ok(mut reply) => Ok(reply.get_mut("value").map(Value::take).unwrap_or(value)),
+            Err(e) => {
+                warn!("Write rejected - pre-write webhook reply is not JSON: {}", e);
+                Err(Rejection::new(
+                    StatusCode::BAD_GATEWAY,
+                    format!("Pre-write webhook reply is not JSON: {}", e),
+                ))
+            }
+        }
+    }
+}
+
+// Writes whose value comes from the store, like `incr-field` or a copy, go
+// through the hook from inside the store
+impl WriteFilter for PreWriteHook {
+    fn is_active(&self) -> bool {
+        self.target.is_some()
+    }
+
+    async fn filter(&self, key: &str, value: Value) -> Result<Value, Box<dyn Error>> {
+        Ok(self.apply(Some(key), value).await?)
+    }
+}